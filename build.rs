@@ -0,0 +1,52 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// with the `embed` feature enabled, every file under the directories listed in
+// FERROPRESS_EMBED_DIRS (comma separated, relative to the crate root; defaults to
+// "templates,static,media") is compiled into the binary via include_bytes!.
+// see `ferropress::assets`.
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=FERROPRESS_EMBED_DIRS");
+    if env::var_os("CARGO_FEATURE_EMBED").is_none() {
+        return;
+    }
+
+    let root = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let dirs = env::var("FERROPRESS_EMBED_DIRS").unwrap_or(String::from("templates,static,media"));
+    let mut files = Vec::new();
+    for dir in dirs.split(',').map(|d| d.trim().trim_start_matches("./").trim_end_matches('/')) {
+        if dir.is_empty() {
+            continue;
+        }
+        println!("cargo:rerun-if-changed={}", dir);
+        collect_files(&root, &root.join(dir), &mut files);
+    }
+    files.sort();
+
+    let mut out = String::from("pub static EMBEDDED: &[(&str, &[u8])] = &[\n");
+    for (key, path) in files {
+        out.push_str(&format!("    ({:?}, include_bytes!({:?})),\n", key, path));
+    }
+    out.push_str("];\n");
+    let out_path = Path::new(&env::var("OUT_DIR").unwrap()).join("embedded_assets.rs");
+    fs::write(out_path, out).unwrap();
+}
+
+// keys are paths relative to the crate root with '/' separators, e.g. "static/styles.css"
+fn collect_files(root: &Path, dir: &Path, files: &mut Vec<(String, PathBuf)>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        println!("cargo:warning=embed: cannot read {}", dir.display());
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_files(root, &path, files);
+        } else {
+            let key = path.strip_prefix(root).unwrap().to_string_lossy().replace('\\', "/");
+            files.push((key, path));
+        }
+    }
+}
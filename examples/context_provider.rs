@@ -0,0 +1,48 @@
+// the usual site, plus /greet, rendered from templates/greeting.html with what two
+// context providers work out from the request:
+//   cargo run --example context_provider
+//   curl 'localhost:7878/greet?name=Ada'
+//   curl -H 'Cookie: theme=dark' 'localhost:7878/greet?name=Ada'
+//   curl -i 'localhost:7878/greet?name='
+use ferropress::app::ViewContext;
+use ferropress::http::{percent_decode, Request, Response};
+use ferropress::template::Context;
+use ferropress::{serve, App, Settings};
+
+
+// the `name` query parameter as `{{ visitor.name }}`, or "stranger" without one. an empty
+// name is refused with a 400, and the providers after this one don't run
+fn visitor(request: &Request, ctx: &mut Context) -> Result<(), Response> {
+    let query = request.path.split_once('?').map_or("", |(_, query)| query);
+    let name = query.split('&').find_map(|param| param.strip_prefix("name="));
+    let name = match name.map(|name| percent_decode(&name.replace('+', " "))) {
+        Some(name) if name.trim().is_empty() => return Err(Response::error(400)),
+        Some(name) => name,
+        None => String::from("stranger"),
+    };
+    ctx.insert("visitor.name".into(), name);
+    Ok(())
+}
+
+// the `theme` cookie as `{{ theme }}`, "light" by default
+fn theme(request: &Request, ctx: &mut Context) -> Result<(), Response> {
+    let cookies = request.headers.get("cookie").map_or("", |cookies| cookies.as_str());
+    let theme = cookies.split(';').find_map(|cookie| cookie.trim().strip_prefix("theme=")).unwrap_or("light");
+    ctx.insert("theme".into(), theme.to_string());
+    Ok(())
+}
+
+async fn greet(_request: Request, ctx: ViewContext) -> Response {
+    ctx.render("greeting.html", &Context::new()).await
+}
+
+#[async_std::main]
+async fn main() {
+    ferropress::logging::init();
+    let settings = Settings::load_from_file("./settings.json").unwrap_or_default();
+    let app = App::new().provide(visitor).provide(theme).get("/greet", greet);
+    if let Err(e) = serve(app, settings).await {
+        eprintln!("{}; exiting!", e);
+        std::process::exit(1);
+    }
+}
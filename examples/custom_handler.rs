@@ -0,0 +1,71 @@
+// serves the usual site plus a few routes and middleware of our own:
+//   cargo run --example custom_handler
+//   curl localhost:7878/hello
+//   curl -d 'some text' localhost:7878/echo
+//   curl -X PATCH -d 'a change' localhost:7878/echo
+//   curl -u admin:secret localhost:7878/admin/
+//   curl -H 'Accept: application/json' localhost:7878/status
+use ferropress::app::ViewContext;
+use ferropress::http::{HttpContentType, Request, Response};
+use ferropress::middleware::{AuthenticatedUser, BasicAuth, Cors};
+use ferropress::template::Context;
+use ferropress::{serve, App, Negotiate, Next, Settings};
+
+
+async fn hello(_request: Request, ctx: ViewContext) -> Response {
+    Response::ok(HttpContentType::Plain, format!("hello, {}\n", ctx.peer))
+}
+
+async fn echo(request: Request, _ctx: ViewContext) -> Response {
+    Response::ok(HttpContentType::Plain, request.body)
+}
+
+// a middleware of our own: tags every response on its way out
+async fn powered_by(request: Request, ctx: ViewContext, next: Next) -> Response {
+    let mut response = next.run(request, ctx).await;
+    response.set_header("X-Powered-By", "ferropress");
+    response
+}
+
+// gives every template a `{{ user.name }}`: whoever `BasicAuth` let in, which it leaves
+// on the request
+fn current_user(request: &Request, ctx: &mut Context) -> Result<(), Response> {
+    let user = request.extensions.get::<AuthenticatedUser>().map_or("stranger", |user| &user.0);
+    ctx.insert("user.name".into(), user.to_string());
+    Ok(())
+}
+
+// renders templates/admin.html, which greets `user.name`
+async fn admin(_request: Request, ctx: ViewContext) -> Response {
+    ctx.render("admin.html", &Context::new()).await
+}
+
+// the same page for browsers and api clients; see the `/status` route
+async fn status_page(_request: Request, _ctx: ViewContext) -> Response {
+    Response::ok(HttpContentType::Html, "<p>all systems go</p>\n")
+}
+
+async fn status_json(_request: Request, _ctx: ViewContext) -> Response {
+    Response::ok(HttpContentType::Json, "{\"status\": \"ok\"}\n")
+}
+
+#[async_std::main]
+async fn main() {
+    ferropress::logging::init();
+    let settings = Settings::load_from_file("./settings.json").unwrap_or_default();
+    let app = App::new()
+        .wrap(Cors::new(&["http://localhost:3000"]))
+        .wrap(BasicAuth::new("admin", "admin", "secret").protecting("/admin/"))
+        .wrap(powered_by)
+        .provide(current_user)
+        .get("/hello", hello)
+        .post("/echo", echo)
+        .patch("/echo", echo)
+        .delete("/echo", echo)
+        .get("/admin/*", admin)
+        .get("/status", Negotiate::new().with("text/html", status_page).with("application/json", status_json));
+    if let Err(e) = serve(app, settings).await {
+        eprintln!("{}; exiting!", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,29 @@
+// the usual site, plus a server-sent event stream at /events/counter that counts up
+// once a second:
+//   cargo run --example event_stream
+//   curl -N localhost:7878/events/counter
+use std::time::Duration;
+use ferropress::app::ViewContext;
+use ferropress::http::Request;
+use ferropress::sse::Events;
+use ferropress::{serve, App, Settings};
+
+
+async fn counter(_request: Request, _ctx: ViewContext, mut events: Events) -> std::io::Result<()> {
+    for count in 0u64.. {
+        events.send(&count.to_string()).await?;
+        async_std::task::sleep(Duration::from_secs(1)).await;
+    }
+    Ok(())
+}
+
+#[async_std::main]
+async fn main() {
+    ferropress::logging::init();
+    let settings = Settings::load_from_file("./settings.json").unwrap_or_default();
+    let app = App::new().event_stream("/events/counter", counter);
+    if let Err(e) = serve(app, settings).await {
+        eprintln!("{}; exiting!", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,84 @@
+// saves uploads to disk as they arrive, without holding them in memory:
+//   cargo run --example upload
+//   curl -T big.iso localhost:7878/upload/big.iso
+//   curl -H 'Transfer-Encoding: chunked' -T - localhost:7878/upload/piped.txt < notes.txt
+//   curl -F title=notes -F file=@notes.txt localhost:7878/form
+// files go to $UPLOAD_DIR, or ./uploads
+use async_std::fs::File;
+use async_std::prelude::*;
+use ferropress::app::ViewContext;
+use ferropress::http::{HttpContentType, Request, Response};
+use ferropress::multipart::Form;
+use ferropress::{serve, App, Settings};
+
+
+async fn upload(mut request: Request, _ctx: ViewContext) -> Response {
+    // only the last path segment is used, so an upload can't land outside the directory
+    let name = request.path.trim_start_matches("/upload/").rsplit('/').next().unwrap_or_default().to_string();
+    if name.is_empty() || name.starts_with('.') {
+        return Response::error(400);
+    }
+    let Some(mut body) = request.body_stream.take() else {
+        return Response::error(400);
+    };
+    let dir = std::env::var("UPLOAD_DIR").unwrap_or(String::from("./uploads"));
+    let path = format!("{}/{}", dir, name);
+    let saved = async {
+        async_std::fs::create_dir_all(&dir).await?;
+        let mut file = File::create(&path).await?;
+        let mut size = 0;
+        while let Some(chunk) = body.next_chunk().await? {
+            file.write_all(&chunk).await?;
+            size += chunk.len();
+        }
+        file.sync_all().await?;
+        Ok::<_, std::io::Error>(size)
+    };
+    match saved.await {
+        Ok(size) => Response::ok(HttpContentType::Plain, format!("saved {} bytes to {}\n", size, path)),
+        Err(e) => {
+            // don't leave half an upload behind
+            let _ = async_std::fs::remove_file(&path).await;
+            eprintln!("upload to {} failed: {}", path, e);
+            Response::error(400)
+        },
+    }
+}
+
+// an html form post: files are kept under their submitted name, fields are echoed back
+async fn form(mut request: Request, ctx: ViewContext) -> Response {
+    let form = match Form::from_request(&mut request, &ctx.settings).await {
+        Ok(form) => form,
+        Err(e) => return e.into(),
+    };
+    let dir = std::env::var("UPLOAD_DIR").unwrap_or(String::from("./uploads"));
+    let mut report = String::new();
+    for (name, value) in form.fields.iter() {
+        report.push_str(&format!("{} = {}\n", name, value));
+    }
+    for file in form.files {
+        let name = file.filename.rsplit(['/', '\\']).next().unwrap_or_default().to_string();
+        if name.is_empty() || name.starts_with('.') {
+            return Response::error(400);
+        }
+        let (field, size) = (file.name.clone(), file.size);
+        if async_std::fs::create_dir_all(&dir).await.is_err() || file.persist(format!("{}/{}", dir, name)).await.is_err() {
+            return Response::error(500);
+        }
+        report.push_str(&format!("{}: saved {} ({} bytes)\n", field, name, size));
+    }
+    Response::ok(HttpContentType::Plain, report)
+}
+
+#[async_std::main]
+async fn main() {
+    ferropress::logging::init();
+    let mut settings = Settings::load_from_file("./settings.json").unwrap_or_default();
+    settings.streamed_body_paths.extend([String::from("/upload/*"), String::from("/form")]);
+    settings.max_body_bytes = 1024 * 1024 * 1024;
+    let app = App::new().put("/upload/*", upload).post("/upload/*", upload).post("/form", form);
+    if let Err(e) = serve(app, settings).await {
+        eprintln!("{}; exiting!", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,26 @@
+// the usual site, plus a websocket at /ws/echo that sends every message back:
+//   cargo run --example websocket_echo
+//   websocat ws://localhost:7878/ws/echo
+use ferropress::app::ViewContext;
+use ferropress::http::Request;
+use ferropress::websocket::Socket;
+use ferropress::{serve, App, Settings};
+
+
+async fn echo(_request: Request, _ctx: ViewContext, mut socket: Socket) -> std::io::Result<()> {
+    while let Some(message) = socket.recv().await? {
+        socket.send(message).await?;
+    }
+    Ok(())
+}
+
+#[async_std::main]
+async fn main() {
+    ferropress::logging::init();
+    let settings = Settings::load_from_file("./settings.json").unwrap_or_default();
+    let app = App::new().websocket("/ws/echo", echo);
+    if let Err(e) = serve(app, settings).await {
+        eprintln!("{}; exiting!", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,384 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::str::FromStr;
+
+
+/// who is on the other end of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// a client of `Settings::unix_socket`. it has no ip address: it only matches
+    /// `unix` in `allow_ips`, `deny_ips` and `cache_admin_ips`, never an ip range
+    /// (loopback included, since a reverse proxy on the socket may forward anyone),
+    /// and isn't counted by `max_connections_per_ip`
+    Unix,
+}
+
+impl Peer {
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Peer::Tcp(addr) => Some(addr.ip()),
+            Peer::Unix => None,
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{}", addr),
+            Peer::Unix => write!(f, "unix socket"),
+        }
+    }
+}
+
+// the entry in an address list that stands for `Peer::Unix`
+const UNIX_ENTRY: &str = "unix";
+
+/// a single address or a cidr block, e.g. `10.0.0.0/8`, `192.168.1.7`, `fd00::/8`
+#[derive(Debug, Clone, PartialEq)]
+pub struct IpRange {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+#[derive(Debug)]
+pub struct InvalidIpRange(pub String);
+
+impl fmt::Display for InvalidIpRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid ip address or cidr range: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidIpRange {}
+
+impl FromStr for IpRange {
+    type Err = InvalidIpRange;
+
+    fn from_str(s: &str) -> Result<IpRange, InvalidIpRange> {
+        let err = || InvalidIpRange(s.to_string());
+        let (addr, prefix_len) = match s.trim().split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s.trim(), None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| err())?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len.parse::<u8>().ok().filter(|len| *len <= max_len).ok_or_else(err)?,
+            None => max_len,
+        };
+        // an ipv4-mapped range (::ffff:a.b.c.d/96 or narrower) is stored as its ipv4 equivalent
+        match addr.to_canonical() {
+            IpAddr::V4(v4) if addr.is_ipv6() && prefix_len >= 96 => {
+                Ok(IpRange { addr: IpAddr::V4(v4), prefix_len: prefix_len - 96 })
+            },
+            _ => Ok(IpRange { addr, prefix_len }),
+        }
+    }
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // ipv4-mapped ipv6 peers (::ffff:a.b.c.d) match ipv4 ranges
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(u32::from(net) as u128, u32::from(ip) as u128, self.prefix_len, 32)
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), self.prefix_len, 128)
+            },
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, prefix_len: u8, bits: u8) -> bool {
+    if prefix_len == 0 {
+        return true;
+    }
+    let shift = bits - prefix_len;
+    (net >> shift) == (ip >> shift)
+}
+
+/// decides which peers may talk to the server. the deny list is checked first and
+/// always wins; then, if the allow list is non-empty, only peers on it get through.
+/// with both lists empty everyone is allowed. besides ip ranges the lists take `unix`,
+/// for unix socket peers (see `Peer::Unix`).
+#[derive(Debug, Clone, Default)]
+pub struct AccessControl {
+    allow: Vec<IpRange>,
+    deny: Vec<IpRange>,
+    // whether the lists name `unix`
+    allow_unix: bool,
+    deny_unix: bool,
+    // an allow list of only `unix` still turns tcp peers away
+    restricted: bool,
+}
+
+impl AccessControl {
+    pub fn new(allow: &[String], deny: &[String]) -> Result<AccessControl, InvalidIpRange> {
+        let is_unix = |entry: &&String| entry.trim().eq_ignore_ascii_case(UNIX_ENTRY);
+        let parse = |ranges: &[String]| ranges.iter().filter(|r| !is_unix(r)).map(|r| r.parse()).collect::<Result<Vec<IpRange>, _>>();
+        Ok(AccessControl {
+            allow: parse(allow)?,
+            deny: parse(deny)?,
+            allow_unix: allow.iter().any(|r| is_unix(&r)),
+            deny_unix: deny.iter().any(|r| is_unix(&r)),
+            restricted: !allow.is_empty(),
+        })
+    }
+
+    pub fn allows(&self, peer: &Peer) -> bool {
+        match peer {
+            Peer::Tcp(addr) => self.is_allowed(addr.ip()),
+            Peer::Unix => !self.deny_unix && (!self.restricted || self.allow_unix),
+        }
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|range| range.contains(ip)) {
+            return false;
+        }
+        !self.restricted || self.allow.iter().any(|range| range.contains(ip))
+    }
+}
+
+/// caps how many connections one peer address can hold open at once; see
+/// `Settings::max_connections_per_ip`
+#[derive(Debug, Default)]
+pub struct ConnectionLimit {
+    max: Option<usize>,
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// one of a peer's connections, counted until this is dropped
+#[derive(Debug)]
+pub struct ConnectionSlot<'a> {
+    limit: &'a ConnectionLimit,
+    ip: IpAddr,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: Option<usize>) -> ConnectionLimit {
+        ConnectionLimit { max, open: Mutex::default() }
+    }
+
+    /// counts a new connection from `ip`, or `None` when it already has the maximum open.
+    /// the count only goes down when the slot is dropped, so it can't leak on error or panic.
+    pub fn acquire(&self, ip: IpAddr) -> Option<ConnectionSlot<'_>> {
+        // ipv4-mapped ipv6 peers count against their ipv4 address
+        let ip = ip.to_canonical();
+        let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        let count = open.entry(ip).or_insert(0);
+        if self.max.is_some_and(|max| *count >= max) {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionSlot { limit: self, ip })
+    }
+
+    /// connections currently open from `ip`
+    pub fn open(&self, ip: IpAddr) -> usize {
+        let open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        open.get(&ip.to_canonical()).copied().unwrap_or(0)
+    }
+}
+
+impl Drop for ConnectionSlot<'_> {
+    fn drop(&mut self) {
+        let mut open = self.limit.open.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            // peers that have gone away aren't kept around
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// connections open now and accepted since startup, over every peer and listener; see
+/// `ViewContext::connections` and `Settings::connection_stats_secs`
+#[derive(Debug, Default)]
+pub struct ConnectionStats {
+    open: AtomicUsize,
+    total: AtomicU64,
+}
+
+/// an accepted connection, counted as open until this is dropped
+#[derive(Debug)]
+pub struct OpenConnection<'a> {
+    stats: &'a ConnectionStats,
+}
+
+impl ConnectionStats {
+    /// counts a newly accepted connection. like `ConnectionLimit::acquire`, it stays
+    /// open until the returned guard is dropped, so an error or panic can't leak it.
+    pub fn accept(&self) -> OpenConnection<'_> {
+        self.open.fetch_add(1, Ordering::Relaxed);
+        self.total.fetch_add(1, Ordering::Relaxed);
+        OpenConnection { stats: self }
+    }
+
+    /// connections open right now
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    /// connections accepted since startup
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for OpenConnection<'_> {
+    fn drop(&mut self) {
+        self.stats.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lists(allow: &[&str], deny: &[&str]) -> AccessControl {
+        let owned = |list: &[&str]| list.iter().map(|entry| entry.to_string()).collect::<Vec<_>>();
+        AccessControl::new(&owned(allow), &owned(deny)).unwrap()
+    }
+
+    fn tcp(addr: &str) -> Peer {
+        Peer::Tcp(addr.parse().unwrap())
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    fn range(range: &str) -> IpRange {
+        range.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv4_ranges_contain_the_addresses_in_them() {
+        assert!(range("10.0.0.0/8").contains(ip("10.255.1.2")));
+        assert!(!range("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(range("192.168.1.7").contains(ip("192.168.1.7")));
+        assert!(!range("192.168.1.7").contains(ip("192.168.1.8")));
+        assert!(range("192.168.1.0/23").contains(ip("192.168.0.200")));
+        assert!(!range("192.168.1.0/24").contains(ip("192.168.0.200")));
+        assert!(range("0.0.0.0/0").contains(ip("203.0.113.9")));
+    }
+
+    #[test]
+    fn ipv6_ranges_contain_the_addresses_in_them() {
+        assert!(range("fd00::/8").contains(ip("fd12:3456::1")));
+        assert!(!range("fd00::/8").contains(ip("fe80::1")));
+        assert!(range("::1").contains(ip("::1")));
+        assert!(range("2001:db8::/32").contains(ip("2001:db8:ffff::1")));
+        assert!(!range("2001:db8::/32").contains(ip("2001:db9::1")));
+        // the families don't mix
+        assert!(!range("::/0").contains(ip("10.0.0.1")));
+        assert!(!range("0.0.0.0/0").contains(ip("::2")));
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_match_ipv4_ranges() {
+        assert!(range("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(range("::ffff:10.0.0.0/104").contains(ip("10.1.2.3")));
+        assert!(!range("::ffff:10.0.0.0/104").contains(ip("11.1.2.3")));
+    }
+
+    #[test]
+    fn malformed_ranges_are_refused() {
+        for malformed in ["", "10.0.0", "10.0.0.0/33", "::/129", "10.0.0.0/", "10.0.0.0/x", "example.com"] {
+            assert!(malformed.parse::<IpRange>().is_err(), "{}", malformed);
+        }
+        assert_eq!(" 10.0.0.0/8 ".parse::<IpRange>().unwrap(), range("10.0.0.0/8"));
+    }
+
+    #[test]
+    fn deny_wins_then_allow_restricts() {
+        let open = lists(&[], &[]);
+        assert!(open.is_allowed(ip("203.0.113.9")));
+        let allow = lists(&["10.0.0.0/8", "fd00::/8"], &[]);
+        assert!(allow.is_allowed(ip("10.1.1.1")));
+        assert!(allow.is_allowed(ip("fd00::1")));
+        assert!(!allow.is_allowed(ip("192.168.1.1")));
+        // in the allowed range, but denied all the same
+        let both = lists(&["10.0.0.0/8"], &["10.0.0.5"]);
+        assert!(both.is_allowed(ip("10.0.0.4")));
+        assert!(!both.is_allowed(ip("10.0.0.5")));
+        let deny = lists(&[], &["192.168.0.0/16"]);
+        assert!(deny.is_allowed(ip("10.0.0.1")));
+        assert!(!deny.is_allowed(ip("192.168.3.4")));
+        assert!(!deny.allows(&tcp("[::ffff:192.168.3.4]:80")));
+    }
+
+    #[test]
+    fn unix_peers_only_match_the_unix_entry() {
+        assert!(lists(&[], &[]).allows(&Peer::Unix));
+        assert!(!lists(&["127.0.0.1", "::1"], &[]).allows(&Peer::Unix));
+        assert!(!lists(&["0.0.0.0/0", "::/0"], &[]).allows(&Peer::Unix));
+        assert!(lists(&["127.0.0.1", "unix"], &[]).allows(&Peer::Unix));
+        assert!(!lists(&[], &["unix"]).allows(&Peer::Unix));
+        assert!(!lists(&["UNIX"], &["unix"]).allows(&Peer::Unix));
+        assert!(lists(&[], &["127.0.0.1"]).allows(&Peer::Unix));
+    }
+
+    #[test]
+    fn the_unix_entry_doesnt_match_tcp_peers() {
+        assert!(!lists(&["unix"], &[]).allows(&tcp("127.0.0.1:80")));
+        assert!(lists(&[], &["unix"]).allows(&tcp("127.0.0.1:80")));
+        assert!(lists(&["unix", "10.0.0.0/8"], &[]).allows(&tcp("10.1.2.3:80")));
+    }
+
+    #[test]
+    fn ranges_still_parse_around_it() {
+        assert!(AccessControl::new(&[String::from("unix"), String::from("not an address")], &[]).is_err());
+    }
+
+    #[test]
+    fn peers_display_as_their_address() {
+        assert_eq!(tcp("10.0.0.1:5000").to_string(), "10.0.0.1:5000");
+        assert_eq!(Peer::Unix.to_string(), "unix socket");
+        assert_eq!(Peer::Unix.ip(), None);
+    }
+
+    #[test]
+    fn each_peer_holds_at_most_its_cap() {
+        let limit = ConnectionLimit::new(Some(2));
+        let first = limit.acquire(ip("10.0.0.1")).unwrap();
+        // an ipv4-mapped address is the same peer
+        let second = limit.acquire(ip("::ffff:10.0.0.1")).unwrap();
+        assert!(limit.acquire(ip("10.0.0.1")).is_none());
+        assert_eq!(limit.open(ip("10.0.0.1")), 2);
+        // other peers have caps of their own
+        let _other = limit.acquire(ip("10.0.0.2")).unwrap();
+        drop(first);
+        assert_eq!(limit.open(ip("10.0.0.1")), 1);
+        let _again = limit.acquire(ip("10.0.0.1")).unwrap();
+        drop(second);
+        assert_eq!(limit.open(ip("10.0.0.1")), 1);
+        // without a cap connections are only counted
+        let unlimited = ConnectionLimit::new(None);
+        let slots: Vec<_> = (0..100).map(|_| unlimited.acquire(ip("10.0.0.1")).unwrap()).collect();
+        assert_eq!(unlimited.open(ip("10.0.0.1")), 100);
+        drop(slots);
+        assert_eq!(unlimited.open(ip("10.0.0.1")), 0);
+        assert!(unlimited.open.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn connections_are_open_until_their_guard_drops() {
+        let stats = ConnectionStats::default();
+        let guards: Vec<_> = (0..3).map(|_| stats.accept()).collect();
+        assert_eq!((stats.open(), stats.total()), (3, 3));
+        drop(guards);
+        let _fourth = stats.accept();
+        assert_eq!((stats.open(), stats.total()), (1, 4));
+    }
+}
@@ -0,0 +1,123 @@
+use std::fmt;
+use chrono::Local;
+
+
+/// nginx "combined"-like default, with the duration and request id appended
+pub const DEFAULT_FORMAT: &str =
+    "%remote - - [%time] \"%method %path %version\" %status %bytes \"%referer\" \"%user_agent\" %duration_ms %request_id";
+
+/// placeholders understood in an access log format. `%%` is a literal percent sign.
+///   %method %path %version  - from the request line
+///   %status %bytes          - response status code and body size
+///   %duration_ms            - time from parsing the request to finishing the response
+///   %remote                 - client address
+///   %request_id             - id assigned to the request by the server
+///   %time                   - local time the request finished, in common log format
+///   %referer %user_agent    - request headers, `-` when missing
+pub const PLACEHOLDERS: &[&str] = &[
+    "method", "path", "version", "status", "bytes", "duration_ms", "remote",
+    "request_id", "time", "referer", "user_agent",
+];
+
+/// what is known about a finished request; missing values are logged as `-`
+#[derive(Debug, Default)]
+pub struct AccessRecord {
+    pub method: String,
+    pub path: String,
+    pub version: String,
+    pub status: Option<i32>,
+    pub bytes: Option<usize>,
+    pub duration_ms: u128,
+    pub remote: String,
+    pub request_id: String,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Placeholder(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct LogFormat {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug)]
+pub struct UnknownPlaceholder(pub String);
+
+impl fmt::Display for UnknownPlaceholder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown access log placeholder %{} (expected one of: {})", self.0, PLACEHOLDERS.join(", "))
+    }
+}
+
+impl std::error::Error for UnknownPlaceholder {}
+
+impl LogFormat {
+    /// parses a format string, failing on the first placeholder that isn't known
+    pub fn parse(format: &str) -> Result<LogFormat, UnknownPlaceholder> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = format.chars().peekable();
+        while let Some(ch) = chars.next() {
+            if ch != '%' {
+                literal.push(ch);
+                continue;
+            }
+            if chars.peek() == Some(&'%') {
+                chars.next();
+                literal.push('%');
+                continue;
+            }
+            let mut name = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break;
+                }
+                name.push(c);
+                chars.next();
+            }
+            if !PLACEHOLDERS.contains(&name.as_str()) {
+                return Err(UnknownPlaceholder(name));
+            }
+            if !literal.is_empty() {
+                segments.push(Segment::Literal(std::mem::take(&mut literal)));
+            }
+            segments.push(Segment::Placeholder(name));
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(LogFormat { segments })
+    }
+
+    pub fn render(&self, record: &AccessRecord) -> String {
+        fn or_dash<T: ToString>(value: Option<T>) -> String {
+            value.map_or(String::from("-"), |v| v.to_string())
+        }
+        let mut line = String::new();
+        for segment in self.segments.iter() {
+            match segment {
+                Segment::Literal(s) => line.push_str(s),
+                Segment::Placeholder(name) => line.push_str(&match name.as_str() {
+                    "method" => record.method.clone(),
+                    "path" => record.path.clone(),
+                    "version" => record.version.clone(),
+                    "status" => or_dash(record.status),
+                    "bytes" => or_dash(record.bytes),
+                    "duration_ms" => record.duration_ms.to_string(),
+                    "remote" => record.remote.clone(),
+                    "request_id" => record.request_id.clone(),
+                    "time" => Local::now().format("%d/%b/%Y:%H:%M:%S %z").to_string(),
+                    "referer" => or_dash(record.referer.as_ref()),
+                    "user_agent" => or_dash(record.user_agent.as_ref()),
+                    _ => unreachable!("placeholders are validated in LogFormat::parse"),
+                }),
+            }
+        }
+        line
+    }
+}
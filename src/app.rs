@@ -0,0 +1,522 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use async_lock::Semaphore;
+use futures::future::BoxFuture;
+use log::error;
+use crate::access_control::{ConnectionStats, Peer};
+use crate::http::{HttpMethod, Request, Response};
+use crate::background::BackgroundTasks;
+use crate::middleware::{self, Chain, Middleware, Next};
+use crate::server::ContentCache;
+use crate::template::{Context, RenderError, TemplateCache, TemplateFunctions};
+use crate::views::{DecompressedFiles, FoldedListings, MinifiedFiles, MissingFiles};
+use crate::sse::EventStreamHandler;
+use crate::websocket::WebSocketHandler;
+use crate::{path_matches, views, Settings, VirtualHost};
+
+
+/// everything a handler gets besides the request itself
+pub struct ViewContext {
+    pub settings: Arc<Settings>,
+    pub(crate) cache: ContentCache,
+    pub(crate) listings: FoldedListings,
+    pub(crate) minified: MinifiedFiles,
+    pub(crate) decompressed: DecompressedFiles,
+    pub(crate) missing: MissingFiles,
+    pub(crate) tasks: BackgroundTasks,
+    /// compiled templates; see `template::TemplateCache::render`
+    pub templates: Arc<TemplateCache>,
+    pub(crate) renders: Arc<Semaphore>,
+    pub(crate) connections: Arc<ConnectionStats>,
+    /// who sent the request: a tcp address, or a client of `Settings::unix_socket`
+    pub peer: Peer,
+    /// content roots of the virtual host the request was addressed to
+    pub site: VirtualHost,
+    /// what the app's context providers added for this request; `render` includes it
+    pub context: Context,
+}
+
+impl ViewContext {
+    /// runs `task` on its own, without holding up the response, e.g. to send an email.
+    /// the task starts right away on the server's executor and may outlive the request
+    /// and its connection. once `serve_with_shutdown` is told to stop, it stops
+    /// accepting connections and then waits for the tasks still running, up to
+    /// `Settings::shutdown_timeout_secs`, before returning; any left after that are
+    /// abandoned with a warning, and end with the process. tasks spawned once shutdown
+    /// has begun still run but aren't waited for. a panicking task is logged and counts
+    /// as finished.
+    pub fn spawn<F: Future<Output = ()> + Send + 'static>(&self, task: F) {
+        self.tasks.spawn(task);
+    }
+
+    /// renders `template` from the site's templates directory, with
+    /// `Settings::global_context`, the request's `context` and then `ctx` as its context, served as the content
+    /// type `TemplateCache::render_response` picks for it (usually html). a template that can't
+    /// be rendered is logged and answered with a 500 (see `Settings::error_pages`).
+    /// renders run on a thread of their own, at most `Settings::max_concurrent_renders`
+    /// at a time; beyond that they wait for one to finish.
+    pub async fn render(&self, template: &str, ctx: &Context) -> Response {
+        let path = format!("{}/{}", self.site.templates_dir, template);
+        let mut context = self.settings.global_context.clone();
+        context.extend(self.context.iter().map(|(k, v)| (k.clone(), v.clone())));
+        context.extend(ctx.iter().map(|(k, v)| (k.clone(), v.clone())));
+        let _permit = self.renders.acquire().await;
+        let templates = Arc::clone(&self.templates);
+        // dev mode shows syntax errors on the page; see `TemplateCache::render_recovering`
+        let recover = self.settings.dev_mode;
+        let rendered = async_std::task::spawn_blocking(move || {
+            if recover {
+                templates.render_response_recovering(&path, &context)
+            } else {
+                templates.render_response(&path, &context)
+            }
+        }).await;
+        match rendered {
+            Ok((contents, content_type)) => Response::ok(content_type, contents),
+            Err(e) => e.into(),
+        }
+    }
+
+    /// every file in the content cache (see `Settings::preload_cache`) by path, such as
+    /// `./static/app.css`, with its size in bytes, in path order
+    pub async fn cached_files(&self) -> Vec<(String, usize)> {
+        let mut files: Vec<_> = self.cache.read().await.iter().map(|(path, contents)| (path.clone(), contents.len())).collect();
+        files.sort();
+        files
+    }
+
+    /// empties the content cache, along with what was worked out from the files (minified
+    /// css and js, decompressed `.gz` files, case-insensitive listings, paths found
+    /// missing), so every file is read
+    /// from disk again; e.g. after deploying new assets. returns how many files were cached
+    pub async fn flush_cache(&self) -> usize {
+        let flushed = std::mem::take(&mut *self.cache.write().await).len();
+        self.minified.write().await.clear();
+        self.decompressed.write().await.clear();
+        self.listings.write().await.clear();
+        self.missing.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        flushed
+    }
+
+    /// drops one file from the content cache and its minified or decompressed copy, and
+    /// forgets it if it was found missing; returns whether it was cached
+    pub async fn evict_cached(&self, path: &str) -> bool {
+        self.minified.write().await.remove(path);
+        self.decompressed.write().await.remove(path);
+        self.missing.lock().unwrap_or_else(|e| e.into_inner()).remove(path);
+        self.cache.write().await.remove(path).is_some()
+    }
+
+    /// the server's connection counts, e.g. for a metrics endpoint: `open()` right now,
+    /// including the one this request came on, and `total()` accepted since startup
+    pub fn connections(&self) -> &ConnectionStats {
+        &self.connections
+    }
+}
+
+#[cfg(test)]
+impl ViewContext {
+    // a context of its own, as the server would build one for a request from loopback
+    pub(crate) fn for_tests(settings: Settings) -> ViewContext {
+        let site = settings.default_vhost();
+        ViewContext{settings: Arc::new(settings), cache: Arc::default(), listings: Arc::default(), minified: Arc::default(),
+            decompressed: Arc::default(), missing: Arc::default(), tasks: BackgroundTasks::default(),
+            templates: Arc::new(TemplateCache::new()), renders: Arc::new(Semaphore::new(1)), connections: Arc::default(),
+            peer: Peer::Tcp(([127, 0, 0, 1], 0).into()), site, context: Context::new()}
+    }
+}
+
+impl From<RenderError> for Response {
+    fn from(e: RenderError) -> Response {
+        match &e {
+            RenderError::TemplateNotFound(_) => error!("{}; check templates_dir and the template name", e),
+            RenderError::Io(..) | RenderError::MissingKey(_) | RenderError::Function(..) | RenderError::Syntax(_)
+                | RenderError::Write(_) | RenderError::TimedOut(_) => error!("{}", e),
+        }
+        Response::error(500)
+    }
+}
+
+/// adds values that depend on the request (the signed-in user, a query parameter) to
+/// the context templates are rendered with. an app's providers run in the order they
+/// were added, after its middleware and before the route's handler, on every request;
+/// returning a response answers the request with it, skipping the rest of the providers
+/// and the handler. work that has to wait on something (a session store, say) belongs in
+/// a middleware, which can leave what it found in the request's `extensions`.
+///
+/// implemented for every `Fn(&Request, &mut Context) -> Result<(), Response>`.
+pub trait ContextProvider: Send + Sync + 'static {
+    fn provide(&self, request: &Request, ctx: &mut Context) -> Result<(), Response>;
+}
+
+impl<F> ContextProvider for F
+where
+    F: Fn(&Request, &mut Context) -> Result<(), Response> + Send + Sync + 'static,
+{
+    fn provide(&self, request: &Request, ctx: &mut Context) -> Result<(), Response> {
+        self(request, ctx)
+    }
+}
+
+type Providers = Arc<Vec<Arc<dyn ContextProvider>>>;
+
+// a route's handler with the app's context providers run ahead of it
+struct Provided {
+    providers: Providers,
+    handler: Arc<dyn Handler>,
+}
+
+impl Handler for Provided {
+    fn call(&self, request: Request, mut ctx: ViewContext) -> BoxFuture<'static, Response> {
+        let (providers, handler) = (Arc::clone(&self.providers), Arc::clone(&self.handler));
+        // inside the future, so a panicking provider is caught like a panicking handler
+        Box::pin(async move {
+            for provider in providers.iter() {
+                if let Err(response) = provider.provide(&request, &mut ctx.context) {
+                    return response;
+                }
+            }
+            handler.call(request, ctx).await
+        })
+    }
+}
+
+/// something that answers requests. implemented for every
+/// `async fn(Request, ViewContext) -> Response`, and closures returning such a future.
+pub trait Handler: Send + Sync + 'static {
+    fn call(&self, request: Request, ctx: ViewContext) -> BoxFuture<'static, Response>;
+}
+
+impl<F, Fut> Handler for F
+where
+    F: Fn(Request, ViewContext) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Response> + Send + 'static,
+{
+    fn call(&self, request: Request, ctx: ViewContext) -> BoxFuture<'static, Response> {
+        Box::pin(self(request, ctx))
+    }
+}
+
+/// runs a handler at most `max` times at once. requests arriving while it is at
+/// capacity are answered with a 503 straight away instead of queueing, so an expensive
+/// route can't take over the server:
+/// `App::new().get("/report", ConcurrencyLimit::new(report, 4))`
+pub struct ConcurrencyLimit<H> {
+    handler: H,
+    max: usize,
+    running: Arc<AtomicUsize>,
+}
+
+impl<H: Handler> ConcurrencyLimit<H> {
+    pub fn new(handler: H, max: usize) -> ConcurrencyLimit<H> {
+        ConcurrencyLimit { handler, max, running: Arc::default() }
+    }
+}
+
+// a taken slot, given back when the handler's future finishes, fails or is dropped
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl<H: Handler> Handler for ConcurrencyLimit<H> {
+    fn call(&self, request: Request, ctx: ViewContext) -> BoxFuture<'static, Response> {
+        // counted before checking the limit; a refused request hands its count back on drop
+        let slot = Slot(Arc::clone(&self.running));
+        if self.running.fetch_add(1, Ordering::AcqRel) >= self.max {
+            return Box::pin(async { Response::error(503) });
+        }
+        let response = self.handler.call(request, ctx);
+        Box::pin(async move {
+            let _slot = slot;
+            response.await
+        })
+    }
+}
+
+/// one route answered by a different handler per media type, picked with the request's
+/// `Accept` header: the representation given the highest quality wins, the one added
+/// first on a tie or when there is no `Accept` header. when the client accepts none of
+/// them the answer is a 406. responses get `Vary: Accept`.
+/// `App::new().get("/post", Negotiate::new().with("text/html", page).with("application/json", api))`
+#[derive(Default)]
+pub struct Negotiate {
+    representations: Vec<(String, Arc<dyn Handler>)>,
+}
+
+impl Negotiate {
+    pub fn new() -> Negotiate {
+        Negotiate::default()
+    }
+
+    /// adds `handler` as the representation for `media_type`, e.g. `application/json`
+    pub fn with(mut self, media_type: &str, handler: impl Handler) -> Negotiate {
+        self.representations.push((media_type.to_ascii_lowercase(), Arc::new(handler)));
+        self
+    }
+}
+
+// the quality `accept` gives `media_type`, from the most specific range matching it
+// (`text/html` over `text/*` over `*/*`); 0 when nothing matches. media type
+// parameters other than `q` are ignored.
+fn accept_quality(accept: &str, media_type: &str) -> f32 {
+    let (kind, subtype) = media_type.split_once('/').unwrap_or((media_type, ""));
+    let mut best: Option<(u8, f32)> = None;
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let range = params.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let Some((range_kind, range_subtype)) = range.split_once('/') else {
+            continue;
+        };
+        let specificity = match (range_kind, range_subtype) {
+            ("*", "*") => 0,
+            (k, "*") if k == kind => 1,
+            (k, s) if k == kind && s == subtype => 2,
+            _ => continue,
+        };
+        let quality = params
+            .filter_map(|param| param.split_once('='))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case("q"))
+            .and_then(|(_, q)| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if best.is_none_or(|(most_specific, _)| specificity > most_specific) {
+            best = Some((specificity, quality));
+        }
+    }
+    best.map_or(0.0, |(_, quality)| quality)
+}
+
+impl Handler for Negotiate {
+    fn call(&self, request: Request, ctx: ViewContext) -> BoxFuture<'static, Response> {
+        let chosen = match request.headers.get("accept").filter(|accept| !accept.trim().is_empty()) {
+            None => self.representations.first(),
+            Some(accept) => {
+                let mut chosen = None;
+                let mut chosen_quality = 0.0;
+                for representation in self.representations.iter() {
+                    let quality = accept_quality(accept, &representation.0);
+                    if quality > chosen_quality {
+                        chosen = Some(representation);
+                        chosen_quality = quality;
+                    }
+                }
+                chosen
+            },
+        };
+        let Some((_, handler)) = chosen else {
+            let mut response = Response::error(406);
+            response.add_vary("Accept");
+            return Box::pin(async { response });
+        };
+        let response = handler.call(request, ctx);
+        Box::pin(async move {
+            let mut response = response.await;
+            response.add_vary("Accept");
+            response
+        })
+    }
+}
+
+struct Route {
+    // `None` matches every method
+    method: Option<HttpMethod>,
+    pattern: String,
+    handler: Arc<dyn Handler>,
+}
+
+/// maps methods and paths to handlers. a pattern ending in `*` matches by prefix,
+/// anything else must match the path exactly; the query string isn't part of the path.
+/// when several routes match, the one added last wins, so a route can be replaced by
+/// adding another for the same path.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// adds a route; a `method` of `None` matches every method
+    pub fn add(&mut self, method: Option<HttpMethod>, pattern: &str, handler: impl Handler) {
+        self.routes.push(Route {
+            method,
+            pattern: pattern.to_string(),
+            handler: Arc::new(handler),
+        });
+    }
+
+    /// the handler for `method` and `path`; any query string on `path` is ignored
+    pub fn find(&self, method: &HttpMethod, path: &str) -> Option<Arc<dyn Handler>> {
+        let path = path.split_once('?').map_or(path, |(path, _)| path);
+        self.routes.iter().rev()
+            .find(|r| r.method.as_ref().is_none_or(|m| m == method) && path_matches(&r.pattern, path))
+            .map(|r| Arc::clone(&r.handler))
+    }
+}
+
+/// a site to serve: the built-in views, plus any handlers registered on top of them,
+/// wrapped in a chain of middleware (see `middleware::Middleware` for the ordering).
+/// websocket, event stream and proxy routes are dispatched before the router is consulted.
+///
+/// ```no_run
+/// use ferropress::{App, Settings};
+/// use ferropress::http::{HttpContentType, Request, Response};
+/// use ferropress::app::ViewContext;
+///
+/// async fn hello(_request: Request, _ctx: ViewContext) -> Response {
+///     Response::ok(HttpContentType::Plain, "hello")
+/// }
+///
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let app = App::new().get("/hello", hello);
+/// ferropress::serve(app, Settings::default()).await
+/// # }
+/// ```
+pub struct App {
+    router: Router,
+    middleware: Chain,
+    providers: Providers,
+    functions: TemplateFunctions,
+    // paths taking websocket upgrades, and their handlers
+    websockets: Vec<(String, Arc<dyn WebSocketHandler>)>,
+    // paths served as server-sent event streams, and their handlers
+    event_streams: Vec<(String, Arc<dyn EventStreamHandler>)>,
+}
+
+impl Default for App {
+    fn default() -> App {
+        App::new()
+    }
+}
+
+impl App {
+    /// an app with only the built-in views: `/` serves the site's index page and any
+    /// other path a file from its static or media directory. the built-in middleware log
+    /// requests, switch on maintenance mode (see `Settings::maintenance_file`), answer
+    /// conditional requests (see `Settings::etags`), compress responses (see
+    /// `Settings::brotli_quality`), apply `Settings::route_headers` and add the dev mode
+    /// reload script.
+    pub fn new() -> App {
+        let mut router = Router::new();
+        router.add(None, "*", views::resource_view);
+        router.add(None, "/", views::index_view);
+        // the cache view takes its key from the query string, which is left on the path
+        router.add(None, &format!("{}*", views::CACHE_ADMIN_PATH), views::cache_view);
+        #[cfg(debug_assertions)]
+        router.add(None, "/__debug/slow", views::slow_view);
+        App { router, middleware: Chain::default(), providers: Providers::default(), functions: TemplateFunctions::new(), websockets: Vec::new(), event_streams: Vec::new() }
+            .wrap(middleware::log_requests)
+            .wrap(middleware::Maintenance::default())
+            .wrap(middleware::compress)
+            .wrap(middleware::conditional_get)
+            .wrap(middleware::route_headers)
+            .wrap(middleware::dev_reload)
+    }
+
+    /// adds a middleware inside the ones already added
+    pub fn wrap(mut self, middleware: impl Middleware) -> App {
+        Arc::make_mut(&mut self.middleware).push(Arc::new(middleware));
+        self
+    }
+
+    /// adds a context provider after the ones already added
+    pub fn provide(mut self, provider: impl ContextProvider) -> App {
+        Arc::make_mut(&mut self.providers).push(Arc::new(provider));
+        self
+    }
+
+    /// makes `function` callable from templates as `{{ name(arg, ...) }}`, alongside the
+    /// built-ins listed under `TemplateFunctions`; it gets the arguments' values
+    pub fn template_function(
+        mut self, name: &str, function: impl Fn(&[String]) -> Result<String, String> + Send + Sync + 'static,
+    ) -> App {
+        self.functions.insert(name, function);
+        self
+    }
+
+    /// serves `pattern` with `handler` for requests using `method`
+    pub fn route(mut self, method: HttpMethod, pattern: &str, handler: impl Handler) -> App {
+        self.router.add(Some(method), pattern, handler);
+        self
+    }
+
+    /// serves `pattern` with `handler` whatever the request method
+    pub fn any(mut self, pattern: &str, handler: impl Handler) -> App {
+        self.router.add(None, pattern, handler);
+        self
+    }
+
+    pub fn get(self, pattern: &str, handler: impl Handler) -> App {
+        self.route(HttpMethod::Get, pattern, handler)
+    }
+
+    pub fn post(self, pattern: &str, handler: impl Handler) -> App {
+        self.route(HttpMethod::Post, pattern, handler)
+    }
+
+    pub fn put(self, pattern: &str, handler: impl Handler) -> App {
+        self.route(HttpMethod::Put, pattern, handler)
+    }
+
+    pub fn patch(self, pattern: &str, handler: impl Handler) -> App {
+        self.route(HttpMethod::Patch, pattern, handler)
+    }
+
+    pub fn delete(self, pattern: &str, handler: impl Handler) -> App {
+        self.route(HttpMethod::Delete, pattern, handler)
+    }
+
+    /// upgrades GET requests to `pattern` that ask for a websocket, and hands the
+    /// connection to `handler`; other requests to it are routed as usual. a pattern ending
+    /// in `*` matches by prefix, and the one added last wins, as with routes.
+    /// `App::new().websocket("/ws/chat", chat)`
+    pub fn websocket(mut self, pattern: &str, handler: impl WebSocketHandler) -> App {
+        self.websockets.push((pattern.to_string(), Arc::new(handler)));
+        self
+    }
+
+    pub(crate) fn websocket_handler(&self, path: &str) -> Option<Arc<dyn WebSocketHandler>> {
+        let path = path.split_once('?').map_or(path, |(path, _)| path);
+        self.websockets.iter().rev()
+            .find(|(pattern, _)| path_matches(pattern, path))
+            .map(|(_, handler)| Arc::clone(handler))
+    }
+
+    /// answers GET requests to `pattern` with a server-sent event stream, which `handler`
+    /// sends events on until it returns. patterns match as with routes.
+    /// `App::new().event_stream("/events/prices", prices)`
+    pub fn event_stream(mut self, pattern: &str, handler: impl EventStreamHandler) -> App {
+        self.event_streams.push((pattern.to_string(), Arc::new(handler)));
+        self
+    }
+
+    pub(crate) fn event_stream_handler(&self, path: &str) -> Option<Arc<dyn EventStreamHandler>> {
+        let path = path.split_once('?').map_or(path, |(path, _)| path);
+        self.event_streams.iter().rev()
+            .find(|(pattern, _)| path_matches(pattern, path))
+            .map(|(_, handler)| Arc::clone(handler))
+    }
+
+    pub(crate) fn template_functions(&self) -> &TemplateFunctions {
+        &self.functions
+    }
+
+    pub(crate) async fn handle(&self, request: Request, ctx: ViewContext) -> Response {
+        let mut handler = self.router.find(&request.method, &request.path)
+            .unwrap_or_else(|| Arc::new(not_found));
+        if !self.providers.is_empty() {
+            handler = Arc::new(Provided { providers: Arc::clone(&self.providers), handler });
+        }
+        Next::new(Arc::clone(&self.middleware), handler).run(request, ctx).await
+    }
+}
+
+async fn not_found(_request: Request, _ctx: ViewContext) -> Response {
+    Response::error(404)
+}
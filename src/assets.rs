@@ -0,0 +1,71 @@
+use std::io;
+
+// reads a file the server is going to serve or cache. with the `embed` feature the
+// file comes from the copy compiled into the binary (see build.rs) and the
+// filesystem is never touched; otherwise it is read from disk.
+#[cfg(not(feature = "embed"))]
+pub async fn read(path: &str) -> io::Result<Vec<u8>> {
+    async_std::fs::read(path).await
+}
+
+#[cfg(feature = "embed")]
+pub async fn read(path: &str) -> io::Result<Vec<u8>> {
+    embedded::get(path)
+        .map(|contents| contents.to_vec())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} is not embedded", path)))
+}
+
+// every file below `dir`, as paths starting with `dir`
+#[cfg(not(feature = "embed"))]
+pub async fn list_files(dir: &str) -> io::Result<Vec<String>> {
+    use async_std::prelude::*;
+
+    let mut files = Vec::new();
+    let mut pending = vec![dir.trim_end_matches('/').to_string()];
+    while let Some(dir) = pending.pop() {
+        let mut entries = async_std::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next().await {
+            let entry = entry?;
+            let path = format!("{}/{}", dir, entry.file_name().to_string_lossy());
+            if entry.file_type().await?.is_dir() {
+                pending.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(feature = "embed")]
+pub async fn list_files(dir: &str) -> io::Result<Vec<String>> {
+    let dir = dir.trim_end_matches('/');
+    let prefix = format!("{}/", embedded::normalize(dir));
+    Ok(embedded::EMBEDDED.iter()
+        .filter_map(|(key, _)| key.strip_prefix(&prefix))
+        .map(|rest| format!("{}/{}", dir, rest))
+        .collect())
+}
+
+#[cfg(feature = "embed")]
+mod embedded {
+    use std::collections::HashMap;
+    use std::sync::OnceLock;
+
+    include!(concat!(env!("OUT_DIR"), "/embedded_assets.rs"));
+
+    // embedded keys are relative to the crate root, so "./static/a.css" and
+    // "static/a.css" find the same file
+    pub fn get(path: &str) -> Option<&'static [u8]> {
+        static INDEX: OnceLock<HashMap<&'static str, &'static [u8]>> = OnceLock::new();
+        let index = INDEX.get_or_init(|| EMBEDDED.iter().copied().collect());
+        index.get(normalize(path)).copied()
+    }
+
+    pub fn normalize(mut path: &str) -> &str {
+        while let Some(rest) = path.strip_prefix("./") {
+            path = rest;
+        }
+        path
+    }
+}
@@ -0,0 +1,107 @@
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use async_std::channel::{self, Receiver, Sender};
+use futures::FutureExt;
+use log::{error, info, warn};
+use crate::middleware::panic_message;
+
+
+// tasks handlers started with `ViewContext::spawn`. each holds a clone of `running`'s
+// sender until it finishes (or panics), so once shutdown drops the original, the
+// receiver closes exactly when the last of them is done. after that `running` is
+// empty: later tasks get no sender, and so aren't waited for.
+#[derive(Clone)]
+pub(crate) struct BackgroundTasks {
+    running: Arc<Mutex<Option<Sender<()>>>>,
+    done: Receiver<()>,
+}
+
+impl Default for BackgroundTasks {
+    fn default() -> BackgroundTasks {
+        let (sender, receiver) = channel::bounded(1);
+        BackgroundTasks { running: Arc::new(Mutex::new(Some(sender))), done: receiver }
+    }
+}
+
+impl BackgroundTasks {
+    pub(crate) fn spawn<F: Future<Output = ()> + Send + 'static>(&self, task: F) {
+        // once shutdown has begun nothing waits for new tasks, but they still run
+        let tracker = self.running.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        async_std::task::spawn(async move {
+            if let Err(panic) = AssertUnwindSafe(task).catch_unwind().await {
+                error!("background task panicked: {}", panic_message(&panic));
+            }
+            drop(tracker);
+        });
+    }
+
+    // stops tracking new tasks and waits up to `timeout` for the running ones
+    pub(crate) async fn shutdown(&self, timeout: Duration) {
+        self.running.lock().unwrap_or_else(|e| e.into_inner()).take();
+        let running = self.done.sender_count();
+        if running == 0 {
+            return;
+        }
+        info!("waiting up to {:?} for {} background task(s)", timeout, running);
+        // nothing is ever sent, so this only returns once every sender is gone
+        if async_std::future::timeout(timeout, self.done.recv()).await.is_err() {
+            warn!("abandoning {} background task(s) still running", self.done.sender_count());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+    use async_std::task::{block_on, sleep};
+
+    // a task that sleeps for `ms` and then counts itself in `finished`
+    fn sleeper(finished: &Arc<AtomicUsize>, ms: u64) -> impl Future<Output = ()> {
+        let finished = Arc::clone(finished);
+        async move {
+            sleep(Duration::from_millis(ms)).await;
+            finished.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn shutdown_waits_for_running_tasks() {
+        let tasks = BackgroundTasks::default();
+        let finished = Arc::new(AtomicUsize::new(0));
+        tasks.spawn(sleeper(&finished, 100));
+        tasks.spawn(sleeper(&finished, 200));
+        tasks.spawn(async { panic!("lost the mail server") });
+        block_on(tasks.shutdown(Duration::from_secs(10)));
+        assert_eq!(finished.load(Ordering::SeqCst), 2);
+        // with nothing running, it returns at once
+        let started = Instant::now();
+        block_on(BackgroundTasks::default().shutdown(Duration::from_secs(10)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn shutdown_gives_up_after_its_timeout() {
+        let tasks = BackgroundTasks::default();
+        let finished = Arc::new(AtomicUsize::new(0));
+        tasks.spawn(sleeper(&finished, 10_000));
+        let started = Instant::now();
+        block_on(tasks.shutdown(Duration::from_millis(100)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(finished.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn tasks_spawned_during_shutdown_run_but_arent_waited_for() {
+        let tasks = BackgroundTasks::default();
+        let finished = Arc::new(AtomicUsize::new(0));
+        block_on(tasks.shutdown(Duration::from_secs(10)));
+        tasks.spawn(sleeper(&finished, 10));
+        block_on(tasks.shutdown(Duration::from_secs(10)));
+        block_on(sleep(Duration::from_millis(500)));
+        assert_eq!(finished.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use async_std::channel::{self, Receiver, Sender};
+use async_std::io::BufRead;
+use async_std::prelude::*;
+use futures::FutureExt;
+use crate::http::{read_line_limited, within, RequestError};
+
+
+// bodies are read off the socket (and handed to a streaming handler) this much at a time
+const CHUNK_SIZE: usize = 16 * 1024;
+
+// chunks read ahead of a streaming handler before the connection stops reading
+const STREAM_AHEAD: usize = 4;
+
+// longest accepted chunk-size line of a chunked body, extensions included
+const CHUNK_LINE_LIMIT: usize = 1024;
+
+// how a request body is delimited
+enum Framing {
+    // `Content-Length` bytes; the count is what is still unread
+    Length(usize),
+    // `Transfer-Encoding: chunked`; the count is what is left of the current chunk
+    Chunked(usize),
+    Done,
+}
+
+// reads a request body a piece at a time, decoding chunked transfer encoding and
+// refusing bodies over `max` bytes
+pub(crate) struct BodyReader {
+    framing: Framing,
+    read: usize,
+    max: usize,
+    // the request asks to switch protocols (`Upgrade`), so what follows its body may be
+    // in the new protocol rather than another request, and isn't checked
+    upgrade: bool,
+}
+
+impl BodyReader {
+    // a declared length over `max` is refused up front, before anything is read
+    pub(crate) fn new(headers: &HashMap<String, String>, max: usize) -> Result<BodyReader, RequestError> {
+        let framing = match (headers.get("transfer-encoding"), headers.get("content-length")) {
+            // a length alongside chunked encoding is ignored (rfc 9112 6.3)
+            (Some(encoding), _) if encoding.eq_ignore_ascii_case("chunked") => Framing::Chunked(0),
+            (Some(_), _) => return Err(RequestError::BadRequest),
+            (None, Some(len)) => {
+                let len: usize = len.parse().map_err(|_| RequestError::BadRequest)?;
+                if len > max {
+                    return Err(RequestError::PayloadTooLarge);
+                }
+                Framing::Length(len)
+            },
+            (None, None) => Framing::Done,
+        };
+        Ok(BodyReader { framing, read: 0, max, upgrade: headers.contains_key("upgrade") })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        matches!(self.framing, Framing::Done)
+    }
+
+    // the next piece of the body, or `None` once all of it has been read
+    pub(crate) async fn next<R: BufRead + Unpin>(&mut self, reader: &mut R) -> Result<Option<Vec<u8>>, RequestError> {
+        let want = match self.framing {
+            Framing::Done => return Ok(None),
+            Framing::Length(0) => {
+                self.framing = Framing::Done;
+                return Ok(None);
+            },
+            Framing::Length(remaining) => remaining,
+            Framing::Chunked(0) => match read_chunk_size(reader).await? {
+                0 => {
+                    skip_trailers(reader).await?;
+                    self.framing = Framing::Done;
+                    return Ok(None);
+                },
+                size => size,
+            },
+            Framing::Chunked(remaining) => remaining,
+        };
+        let n = want.min(CHUNK_SIZE);
+        self.read += n;
+        if self.read > self.max {
+            return Err(RequestError::PayloadTooLarge);
+        }
+        let mut piece = vec![0; n];
+        reader.read_exact(&mut piece).await.map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => RequestError::BadRequest,
+            _ => RequestError::Io(e),
+        })?;
+        self.framing = match self.framing {
+            Framing::Length(remaining) => Framing::Length(remaining - n),
+            Framing::Chunked(_) if want == n => {
+                // every chunk's data is followed by a bare line ending
+                let mut line = String::new();
+                match read_line_limited(reader, &mut line, 2).await? {
+                    Some(_) if line.trim_end().is_empty() && !line.is_empty() => {},
+                    _ => return Err(RequestError::BadRequest),
+                }
+                Framing::Chunked(0)
+            },
+            Framing::Chunked(remaining) => Framing::Chunked(remaining - n),
+            Framing::Done => Framing::Done,
+        };
+        Ok(Some(piece))
+    }
+
+    // the whole body in one buffer
+    pub(crate) async fn read_to_end<R: BufRead + Unpin>(&mut self, reader: &mut R) -> Result<Vec<u8>, RequestError> {
+        let mut body = Vec::new();
+        while let Some(piece) = self.next(reader).await? {
+            body.extend_from_slice(&piece);
+        }
+        if !self.upgrade {
+            check_body_end(reader).await?;
+        }
+        Ok(body)
+    }
+}
+
+// whatever the client sent right after a body has to be the start of its next request.
+// anything else means the body was longer than declared, and the rest of it would be
+// read as a request, so the connection is given up on. only data that has already
+// arrived is looked at; a mismatch noticed later fails to parse as a request instead.
+async fn check_body_end<R: BufRead + Unpin>(reader: &mut R) -> Result<(), RequestError> {
+    let Some(Ok(next)) = futures::AsyncBufReadExt::fill_buf(reader).now_or_never() else {
+        return Ok(());
+    };
+    // stray line endings before a request line are allowed (rfc 9112 2.2)
+    let next = &next[next.iter().take_while(|b| matches!(b, b'\r' | b'\n')).count()..];
+    let method = next.iter().take_while(|b| is_token(**b)).count();
+    match next.get(method) {
+        // nothing, or only part of a method so far
+        None => Ok(()),
+        Some(b' ') if method > 0 => Ok(()),
+        Some(_) => Err(RequestError::BadRequest),
+    }
+}
+
+// a character allowed in a method name (`tchar` in rfc 9110)
+fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+// the size line starting a chunk; extensions after a `;` are ignored
+async fn read_chunk_size<R: BufRead + Unpin>(reader: &mut R) -> Result<usize, RequestError> {
+    let mut line = String::new();
+    match read_line_limited(reader, &mut line, CHUNK_LINE_LIMIT).await? {
+        Some(0) | None => return Err(RequestError::BadRequest),
+        Some(_) => {},
+    }
+    let size = line.split(';').next().unwrap_or_default().trim();
+    usize::from_str_radix(size, 16).map_err(|_| RequestError::BadRequest)
+}
+
+// trailer fields after the last chunk are read and dropped
+async fn skip_trailers<R: BufRead + Unpin>(reader: &mut R) -> Result<(), RequestError> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match read_line_limited(reader, &mut line, CHUNK_LINE_LIMIT).await? {
+            Some(0) | None => return Err(RequestError::BadRequest),
+            Some(_) if line.trim_end().is_empty() => return Ok(()),
+            Some(_) => {},
+        }
+    }
+}
+
+/// the body of a request on one of `Settings::streamed_body_paths`, read from the
+/// connection as the handler asks for it instead of being buffered up front. only a
+/// few chunks are ever held in memory, so uploads of any size (up to
+/// `Settings::max_body_bytes`) can be written out as they arrive.
+pub struct BodyStream {
+    chunks: Receiver<io::Result<Vec<u8>>>,
+}
+
+impl BodyStream {
+    /// the next chunk of the body, or `None` once it has all been read. a body that is
+    /// cut short, malformed, too large or too slow gives an error.
+    pub async fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match self.chunks.recv().await {
+            Ok(chunk) => chunk.map(Some),
+            // the connection side hangs up once the body is done
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+impl fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("BodyStream")
+    }
+}
+
+// the connection's half of a `BodyStream`: feeds it the body as it arrives
+pub(crate) struct BodyPump {
+    body: BodyReader,
+    chunks: Sender<io::Result<Vec<u8>>>,
+}
+
+pub(crate) fn stream(body: BodyReader) -> (BodyStream, BodyPump) {
+    let (sender, receiver) = channel::bounded(STREAM_AHEAD);
+    (BodyStream { chunks: receiver }, BodyPump { body, chunks: sender })
+}
+
+impl BodyPump {
+    // reads the whole body into the stream, within `limit_secs`. once the handler stops
+    // listening the rest is read and dropped, so the next request on the connection
+    // starts in the right place. an error means the connection can't be reused.
+    pub(crate) async fn run<R: BufRead + Unpin>(mut self, reader: &mut R, limit_secs: Option<u64>) -> Result<(), RequestError> {
+        let result = within(limit_secs, RequestError::Timeout, self.pump(reader)).await;
+        if let Err(e) = &result {
+            let message = match e {
+                RequestError::PayloadTooLarge => "request body too large",
+                RequestError::Timeout => "request body timed out",
+                _ => "request body malformed, cut short or longer than declared",
+            };
+            let _ = self.chunks.send(Err(io::Error::new(io::ErrorKind::InvalidData, message))).await;
+        }
+        result
+    }
+
+    async fn pump<R: BufRead + Unpin>(&mut self, reader: &mut R) -> Result<(), RequestError> {
+        while let Some(piece) = self.body.next(reader).await? {
+            // a closed stream means the handler is done with the body; keep draining
+            let _ = self.chunks.send(Ok(piece)).await;
+        }
+        match self.body.upgrade {
+            true => Ok(()),
+            false => check_body_end(reader).await,
+        }
+    }
+}
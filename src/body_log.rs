@@ -0,0 +1,154 @@
+use base64::Engine;
+use serde_json::Value;
+use crate::http::{percent_decode, HttpHeader, Response};
+use crate::Settings;
+
+
+// request and response bodies as logged with `Settings::log_bodies`: fields named in
+// `Settings::log_redact` are blanked out of form and json bodies, binary bodies are
+// base64 encoded, and everything is cut to `Settings::log_body_max_bytes`
+
+const REDACTED: &str = "[redacted]";
+
+// whether `name` (a header or field name) is one of `Settings::log_redact`
+pub(crate) fn is_redacted(name: &str, settings: &Settings) -> bool {
+    settings.log_redact.iter().any(|redacted| redacted.eq_ignore_ascii_case(name))
+}
+
+// a body with the given `Content-Type`, ready for the log
+pub(crate) fn describe(body: &[u8], content_type: Option<&str>, settings: &Settings) -> String {
+    if body.is_empty() {
+        return String::from("(empty)");
+    }
+    let mime = content_type.unwrap_or_default().split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    // uploads can't be redacted field by field, and are mostly files anyway
+    if mime.starts_with("multipart/") {
+        return format!("({} byte {} body not shown)", body.len(), mime);
+    }
+    let Ok(text) = std::str::from_utf8(body) else {
+        let shown = &body[..body.len().min(settings.log_body_max_bytes)];
+        let encoded = base64::engine::general_purpose::STANDARD.encode(shown);
+        let cut = if shown.len() < body.len() { "..." } else { "" };
+        return format!("({} bytes of binary, base64) {}{}", body.len(), encoded, cut);
+    };
+    let text = if mime == "application/x-www-form-urlencoded" {
+        redact_form(text, settings)
+    } else if mime == "application/json" || mime.ends_with("+json") {
+        match serde_json::from_str::<Value>(text) {
+            Ok(mut json) => {
+                redact_json(&mut json, settings);
+                json.to_string()
+            },
+            Err(_) => text.to_string(),
+        }
+    } else {
+        text.to_string()
+    };
+    truncate(&text, body.len(), settings.log_body_max_bytes)
+}
+
+// a response's body along with its own content type
+pub(crate) fn describe_response(response: &Response, settings: &Settings) -> String {
+    let content_type = response.headers.iter().flatten().find_map(|header| match header {
+        HttpHeader::ContentType(content_type) => Some(content_type.to_string()),
+        HttpHeader::Custom(name, value) if name.eq_ignore_ascii_case("content-type") => Some(value.clone()),
+        _ => None,
+    });
+    describe(&response.contents, content_type.as_deref(), settings)
+}
+
+fn redact_form(form: &str, settings: &Settings) -> String {
+    let pairs = form.split('&').map(|pair| match pair.split_once('=') {
+        Some((name, _)) if is_redacted(&percent_decode(&name.replace('+', " ")), settings) => {
+            format!("{}={}", name, REDACTED)
+        },
+        _ => pair.to_string(),
+    });
+    pairs.collect::<Vec<_>>().join("&")
+}
+
+// blanks out matching keys at any depth
+fn redact_json(json: &mut Value, settings: &Settings) {
+    match json {
+        Value::Object(fields) => {
+            for (name, value) in fields.iter_mut() {
+                if is_redacted(name, settings) {
+                    *value = Value::String(REDACTED.to_string());
+                } else {
+                    redact_json(value, settings);
+                }
+            }
+        },
+        Value::Array(items) => items.iter_mut().for_each(|item| redact_json(item, settings)),
+        _ => {},
+    }
+}
+
+// `text` cut to `max` bytes on a character boundary, noting the full size when cut
+fn truncate(text: &str, total: usize, max: usize) -> String {
+    if text.len() <= max {
+        return text.to_string();
+    }
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}... ({} bytes in all)", &text[..end], total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::HttpContentType;
+
+    fn settings(max_bytes: usize) -> Settings {
+        Settings { log_bodies: true, log_body_max_bytes: max_bytes, ..Settings::default() }
+    }
+
+    #[test]
+    fn named_fields_are_redacted_in_forms_and_json() {
+        let settings = settings(2048);
+        assert_eq!(
+            describe(b"user=ann&Password=hunter2&pass+word=x&token=", Some("application/x-www-form-urlencoded"), &settings),
+            "user=ann&Password=[redacted]&pass+word=x&token=[redacted]",
+        );
+        let json = br#"{"user": "ann", "auth": {"TOKEN": "abc", "scopes": [{"password": 1}]}}"#;
+        assert_eq!(
+            describe(json, Some("application/vnd.api+json; charset=utf-8"), &settings),
+            r#"{"auth":{"TOKEN":"[redacted]","scopes":[{"password":"[redacted]"}]},"user":"ann"}"#,
+        );
+        // json that doesn't parse, and other text, are shown as they are
+        assert_eq!(describe(b"{\"password\": ", Some("application/json"), &settings), "{\"password\": ");
+        assert_eq!(describe(b"password=hunter2", Some("text/plain"), &settings), "password=hunter2");
+        assert!(is_redacted("Authorization", &settings));
+        assert!(!is_redacted("accept", &settings));
+    }
+
+    #[test]
+    fn long_bodies_are_cut_to_the_limit() {
+        let settings = settings(8);
+        assert_eq!(describe(b"12345678", None, &settings), "12345678");
+        assert_eq!(describe(b"123456789", None, &settings), "12345678... (9 bytes in all)");
+        // not partway through a character
+        assert_eq!(describe("1234567\u{e9}".as_bytes(), None, &settings), "1234567... (9 bytes in all)");
+        // redaction comes first, so the limit counts what is shown
+        assert_eq!(
+            describe(b"token=abcdefgh", Some("application/x-www-form-urlencoded"), &settings),
+            "token=[r... (14 bytes in all)",
+        );
+        assert_eq!(describe(b"", Some("application/json"), &settings), "(empty)");
+    }
+
+    #[test]
+    fn binary_and_multipart_bodies_are_summarized() {
+        let settings = settings(4);
+        assert_eq!(describe(&[0xff, 0xfe, 0, 1], None, &settings), "(4 bytes of binary, base64) //4AAQ==");
+        assert_eq!(describe(&[0xff, 0xfe, 0, 1, 2, 3], None, &settings), "(6 bytes of binary, base64) //4AAQ==...");
+        assert_eq!(
+            describe(b"--x\r\n\r\npassword\r\n--x--", Some("multipart/form-data; boundary=x"), &settings),
+            "(22 byte multipart/form-data body not shown)",
+        );
+        let response = Response::ok(HttpContentType::Json, r#"{"token": "abc"}"#);
+        assert_eq!(describe_response(&response, &settings), r#"{"to... (16 bytes in all)"#);
+    }
+}
@@ -0,0 +1,48 @@
+// legacy text encodings, for `Settings::file_charsets`. only the ones that need no more
+// than a short table are converted: utf-8, utf-16 in either byte order, and
+// windows-1252 along with the labels browsers treat as it (iso-8859-1, latin1 and
+// us-ascii, per the whatwg encoding standard). other charsets can still be advertised.
+
+// what windows-1252 has at 0x80..=0x9f, where iso-8859-1 has control characters. the
+// five bytes it leaves undefined map to those controls
+const WINDOWS_1252_HIGH: [char; 32] = [
+    '\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}', '\u{8f}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}', '\u{178}',
+];
+
+// the charset a byte order mark at the start of `contents` names, and the mark's length
+pub(crate) fn bom(contents: &[u8]) -> Option<(&'static str, usize)> {
+    match contents {
+        [0xef, 0xbb, 0xbf, ..] => Some(("utf-8", 3)),
+        [0xff, 0xfe, ..] => Some(("utf-16le", 2)),
+        [0xfe, 0xff, ..] => Some(("utf-16be", 2)),
+        _ => None,
+    }
+}
+
+// `contents`, in `charset`, as utf-8, or `None` for a charset this can't convert.
+// bytes that aren't valid in the charset become U+FFFD
+pub(crate) fn to_utf8(contents: &[u8], charset: &str) -> Option<String> {
+    let utf16 = |unit: fn([u8; 2]) -> u16| {
+        let units: Vec<u16> = contents.chunks(2).map(|pair| match *pair {
+            [a, b] => unit([a, b]),
+            // an odd byte out at the end
+            _ => 0xfffd,
+        }).collect();
+        String::from_utf16_lossy(&units)
+    };
+    Some(match charset.to_ascii_lowercase().as_str() {
+        "utf-8" | "utf8" => String::from_utf8_lossy(contents).into_owned(),
+        "utf-16le" => utf16(u16::from_le_bytes),
+        "utf-16be" => utf16(u16::from_be_bytes),
+        "windows-1252" | "cp1252" | "iso-8859-1" | "iso8859-1" | "latin1" | "us-ascii" | "ascii" => contents.iter()
+            .map(|&byte| match byte {
+                0x80..=0x9f => WINDOWS_1252_HIGH[usize::from(byte - 0x80)],
+                _ => char::from(byte),
+            })
+            .collect(),
+        _ => return None,
+    })
+}
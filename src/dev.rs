@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use log::info;
+use crate::assets;
+use crate::template::TemplateCache;
+
+
+/// event stream the injected script listens on; only routed in dev mode
+pub const RELOAD_PATH: &str = "/__dev/reload";
+
+/// how often the content directories are scanned for changes
+pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// added to html responses in dev mode. it reloads the page when the server sends a
+/// `reload` event, which happens once any watched file changes.
+pub const RELOAD_SCRIPT: &str = concat!(
+    "<script>new EventSource(\"/__dev/reload\")",
+    ".addEventListener(\"reload\", () => location.reload());</script>",
+);
+
+/// inserts `RELOAD_SCRIPT` before the closing body tag, or at the end without one
+pub fn inject_reload_script(html: &[u8]) -> Vec<u8> {
+    let at = html.windows(7).rposition(|w| w.eq_ignore_ascii_case(b"</body>")).unwrap_or(html.len());
+    let mut out = Vec::with_capacity(html.len() + RELOAD_SCRIPT.len());
+    out.extend_from_slice(&html[..at]);
+    out.extend_from_slice(RELOAD_SCRIPT.as_bytes());
+    out.extend_from_slice(&html[at..]);
+    out
+}
+
+// modification time and size of every file below `dirs`
+async fn snapshot(dirs: &[String]) -> HashMap<String, (Option<SystemTime>, u64)> {
+    let mut files = HashMap::new();
+    for dir in dirs {
+        for path in assets::list_files(dir).await.unwrap_or_default() {
+            if let Ok(meta) = async_std::fs::metadata(&path).await {
+                files.insert(path, (meta.modified().ok(), meta.len()));
+            }
+        }
+    }
+    files
+}
+
+/// polls `dirs` forever, bumping `generation` whenever a file is added, removed or changed.
+/// compiled templates built from a changed file are dropped from `templates` first.
+pub async fn watch(dirs: Vec<String>, generation: Arc<AtomicU64>, templates: Arc<TemplateCache>) {
+    let mut last = snapshot(&dirs).await;
+    loop {
+        async_std::task::sleep(POLL_INTERVAL).await;
+        let current = snapshot(&dirs).await;
+        if current != last {
+            let changed = current.keys().chain(last.keys()).filter(|path| current.get(*path) != last.get(*path));
+            let stale: usize = changed.map(|path| templates.invalidate(path)).sum();
+            info!("content changed; dropped {} compiled templates, reloading connected pages", stale);
+            generation.fetch_add(1, Ordering::Relaxed);
+            last = current;
+        }
+    }
+}
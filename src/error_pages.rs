@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use async_std::sync::RwLock;
+use log::warn;
+use crate::assets;
+use crate::http::{prefers_json, HttpContentType, HttpHeader, HttpStatus, Request, Response};
+use crate::template::{Context, TemplateCache};
+use crate::{path_matches, ErrorPage, Settings, VirtualHost};
+
+
+// replaces built-in error bodies with the pages configured in `Settings::error_pages`,
+// or with `Response::error_json` bodies for clients that want json.
+// resolved bodies are kept per site and status, except in dev mode where content
+// isn't cached. a page that can't be produced falls back to the built-in body, so
+// resolving never produces another error.
+// keyed by the site's templates directory and the status code
+type Resolved = HashMap<(String, i32), Arc<Vec<u8>>>;
+
+pub(crate) struct ErrorPages {
+    settings: Arc<Settings>,
+    templates: Arc<TemplateCache>,
+    resolved: RwLock<Resolved>,
+}
+
+impl ErrorPages {
+    pub(crate) fn new(settings: Arc<Settings>, templates: Arc<TemplateCache>) -> ErrorPages {
+        ErrorPages { settings, templates, resolved: RwLock::default() }
+    }
+
+    // an error response for `code` using the configured page, or json
+    pub(crate) async fn response(&self, code: i32, site: &VirtualHost, json: bool) -> Response {
+        self.apply(Response::error(code), site, json).await
+    }
+
+    // swaps in the configured page, or the json body when `json` is set (see
+    // `wants_json`), when `response` still has the body `Response::error` gave it; a
+    // handler's own error body is left alone
+    pub(crate) async fn apply(&self, mut response: Response, site: &VirtualHost, json: bool) -> Response {
+        let HttpStatus::HttpErr(code) = response.status else {
+            return response;
+        };
+        let builtin = *response.contents == response.status.to_string().as_bytes();
+        if builtin && json {
+            // other headers, such as a 405's `Allow`, stay
+            let headers = response.headers.get_or_insert_with(Vec::new);
+            headers.retain(|header| !matches!(header, HttpHeader::ContentType(_)));
+            headers.insert(0, HttpHeader::ContentType(HttpContentType::Json));
+            response.contents = Response::error_json(code).contents;
+            return response;
+        }
+        if !builtin || !self.settings.error_pages.contains_key(&code) {
+            return response;
+        }
+        let key = (site.templates_dir.clone(), code);
+        if let Some(contents) = self.resolved.read().await.get(&key) {
+            response.contents = Arc::clone(contents);
+            return response;
+        }
+        // failures are cached as the built-in body too, so they are only logged once
+        let contents = self.resolve(&response.status, site).await.map(Arc::new).unwrap_or_else(|e| {
+            warn!("can't produce the {} error page: {}; using the built-in one", code, e);
+            Arc::clone(&response.contents)
+        });
+        if self.settings.caches_content() {
+            self.resolved.write().await.insert(key, Arc::clone(&contents));
+        }
+        response.contents = contents;
+        response
+    }
+
+    // whether errors for `request` get json bodies: its path is in
+    // `Settings::json_error_paths`, or its `Accept` header prefers json to html
+    pub(crate) fn wants_json(&self, request: &Request) -> bool {
+        let route = request.path.split_once('?').map_or(&request.path[..], |(route, _)| route);
+        self.settings.json_error_paths.iter().any(|pattern| path_matches(pattern, route))
+            || request.headers.get("accept").is_some_and(|accept| prefers_json(accept))
+    }
+
+    async fn resolve(&self, status: &HttpStatus, site: &VirtualHost) -> Result<Vec<u8>, String> {
+        match &self.settings.error_pages[&status.code()] {
+            ErrorPage::Builtin => Ok(status.to_string().into_bytes()),
+            ErrorPage::File(path) => assets::read(path).await.map_err(|e| format!("{}: {}", path, e)),
+            ErrorPage::Template(name) => {
+                let path = format!("{}/{}", site.templates_dir, name);
+                let mut ctx: Context = self.settings.global_context.clone();
+                ctx.insert("status".into(), status.to_string());
+                ctx.insert("status_code".into(), status.code().to_string());
+                self.templates.render(&path, &ctx).map(String::into_bytes).map_err(|e| e.to_string())
+            },
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use crate::template::{Context, RenderError, TemplateCache};
+
+
+// unchanged lines shown around each difference
+const DIFF_CONTEXT: usize = 2;
+
+/// why a template didn't match its fixture
+#[derive(Debug)]
+pub enum FixtureError {
+    /// the context or expected output file couldn't be read
+    Io(String, io::Error),
+    /// the context file isn't a json object of strings
+    Context(String, serde_json::Error),
+    /// the template couldn't be rendered; a key missing from the context counts
+    Render(RenderError),
+    /// the template rendered to something else; holds a line diff of expected and
+    /// rendered output
+    Mismatch(String),
+}
+
+impl fmt::Display for FixtureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FixtureError::Io(path, e) => write!(f, "can't read {}: {}", path, e),
+            FixtureError::Context(path, e) => write!(f, "{} isn't a json object of strings: {}", path, e),
+            FixtureError::Render(e) => write!(f, "{}", e),
+            FixtureError::Mismatch(diff) => write!(f, "rendered output differs from the fixture:\n{}", diff.trim_end()),
+        }
+    }
+}
+
+impl std::error::Error for FixtureError {}
+
+impl From<RenderError> for FixtureError {
+    fn from(e: RenderError) -> FixtureError {
+        FixtureError::Render(e)
+    }
+}
+
+fn read(path: &str) -> Result<String, FixtureError> {
+    fs::read_to_string(path).map_err(|e| FixtureError::Io(path.to_string(), e))
+}
+
+/// renders `template` with the context in `context_file` (a json object such as
+/// `{"title": "hello"}`) and compares the result to the contents of `expected_file`,
+/// ignoring trailing line endings. the render is strict, so a key the context lacks is
+/// an error rather than a `CTX MISS`. built-ins like `now` and `year` change between
+/// runs; give them fixed values in the context when a template uses them.
+pub fn check(template: &str, context_file: &str, expected_file: &str) -> Result<(), FixtureError> {
+    let ctx: Context = serde_json::from_str(&read(context_file)?)
+        .map_err(|e| FixtureError::Context(context_file.to_string(), e))?;
+    let expected = read(expected_file)?;
+    let rendered = TemplateCache::new().render_strict(template, &ctx)?;
+    let (expected, rendered) = (expected.trim_end_matches(['\r', '\n']), rendered.trim_end_matches(['\r', '\n']));
+    if expected == rendered {
+        return Ok(());
+    }
+    Err(FixtureError::Mismatch(diff(expected_file, expected, template, rendered)))
+}
+
+/// a line diff from `old` to `new`: removed lines start with `-`, added ones with `+`,
+/// and each group of changes is headed by where it starts in both (`@@ -3 +3 @@`)
+pub fn diff(old_name: &str, old: &str, new_name: &str, new: &str) -> String {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    // longest common subsequence lengths of every pair of suffixes
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    // walk it into an edit script of (marker, old line number, new line number, text)
+    let mut edits = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            edits.push((' ', i, j, old[i]));
+            i += 1;
+            j += 1;
+        } else if j == new.len() || (i < old.len() && common[i + 1][j] >= common[i][j + 1]) {
+            edits.push(('-', i, j, old[i]));
+            i += 1;
+        } else {
+            edits.push(('+', i, j, new[j]));
+            j += 1;
+        }
+    }
+
+    let mut out = format!("--- {}\n+++ {}\n", old_name, new_name);
+    let changed: Vec<usize> = edits.iter().enumerate().filter(|(_, e)| e.0 != ' ').map(|(at, _)| at).collect();
+    let mut at = 0;
+    while at < changed.len() {
+        // changes close enough to share their context go in one group
+        let mut end = at;
+        while end + 1 < changed.len() && changed[end + 1] - changed[end] <= 2 * DIFF_CONTEXT + 1 {
+            end += 1;
+        }
+        let first = changed[at].saturating_sub(DIFF_CONTEXT);
+        let last = (changed[end] + DIFF_CONTEXT).min(edits.len() - 1);
+        let (_, old_line, new_line, _) = edits[first];
+        out.push_str(&format!("@@ -{} +{} @@\n", old_line + 1, new_line + 1));
+        for (marker, _, _, line) in &edits[first..=last] {
+            out.push_str(&format!("{}{}\n", marker, line));
+        }
+        at = end + 1;
+    }
+    out
+}
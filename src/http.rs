@@ -0,0 +1,1281 @@
+use std::any::{Any, TypeId};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use async_std::io::{BufRead, Write};
+use async_std::prelude::*;
+use log::{error, info};
+use crate::body::{self, BodyPump, BodyReader, BodyStream};
+use crate::body_log;
+use crate::{path_matches, ParseStrictness, Settings};
+
+
+/// a parsed request. header names are lowercased.
+pub struct Request {
+    pub method: HttpMethod,
+    pub path: String,
+    pub version: String,
+    pub headers: HashMap<String, String>,
+    /// empty when the body is streamed instead
+    pub body: Vec<u8>,
+    /// the body, for requests on `Settings::streamed_body_paths`
+    pub body_stream: Option<BodyStream>,
+    /// values attached by middleware for the handler; see `Extensions`
+    pub extensions: Extensions,
+}
+
+/// per-request values keyed by their type, so middleware can pass what it learned
+/// (the signed-in user, when the request started) on to handlers without either
+/// side knowing about the other. each type holds at most one value; wrap common types
+/// like `String` in a struct of your own rather than storing them directly.
+#[derive(Default)]
+pub struct Extensions {
+    values: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    /// stores `value`, returning the one of the same type it replaces
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) -> Option<T> {
+        self.values.insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|old| old.downcast().ok().map(|old| *old))
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.values.get(&TypeId::of::<T>()).and_then(|value| value.downcast_ref())
+    }
+
+    pub fn get_mut<T: Any + Send + Sync>(&mut self) -> Option<&mut T> {
+        self.values.get_mut(&TypeId::of::<T>()).and_then(|value| value.downcast_mut())
+    }
+
+    pub fn remove<T: Any + Send + Sync>(&mut self) -> Option<T> {
+        self.values.remove(&TypeId::of::<T>()).and_then(|value| value.downcast().ok().map(|value| *value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Extensions({} values)", self.values.len())
+    }
+}
+
+/// a request method. methods are case-sensitive, so anything other than these exact
+/// names (including `get`) is kept as sent in `Other`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum HttpMethod {
+    Get, Post, Put, Delete, Head, Options, Patch, Other(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpContentType {
+    Html, Css, Js, Json, Plain, Svg, Jpeg, Png, Gif, Webp, Pdf, Icon, EventStream, OctetStream,
+}
+
+pub enum HttpHeader {
+    ContentType(HttpContentType),
+    ContentLength(i32),
+    Custom(String, String),
+}
+
+pub enum HttpStatus {
+    HttpOk(i32),
+    HttpErr(i32),
+}
+
+impl HttpContentType {
+    /// the content type for a file extension, html for anything unknown
+    pub fn from_extension(s: &str) -> HttpContentType {
+        HttpContentType::from_known_extension(s).unwrap_or(HttpContentType::Html)
+    }
+
+    /// the content type for a file extension, if it is one this server knows
+    pub fn from_known_extension(s: &str) -> Option<HttpContentType> {
+        Some(match s {
+            "html" => HttpContentType::Html,
+            "css" => HttpContentType::Css,
+            "js" => HttpContentType::Js,
+            "json" => HttpContentType::Json,
+            "txt" => HttpContentType::Plain,
+            "svg" => HttpContentType::Svg,
+            "jpg" | "jpeg" => HttpContentType::Jpeg,
+            "png" => HttpContentType::Png,
+            "gif" => HttpContentType::Gif,
+            "webp" => HttpContentType::Webp,
+            "pdf" => HttpContentType::Pdf,
+            "ico" => HttpContentType::Icon,
+            _ => return None,
+        })
+    }
+
+    /// guesses the content type from the first bytes of a file: image and pdf signatures,
+    /// then html or plain text for utf-8 without control characters, and octet-stream
+    /// for anything else. only the first `SNIFF_BYTES` are looked at.
+    pub fn sniff(contents: &[u8]) -> HttpContentType {
+        let prefix = &contents[..contents.len().min(SNIFF_BYTES)];
+        match prefix {
+            [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', ..] => return HttpContentType::Png,
+            [0xff, 0xd8, 0xff, ..] => return HttpContentType::Jpeg,
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => return HttpContentType::Gif,
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => return HttpContentType::Webp,
+            [b'%', b'P', b'D', b'F', b'-', ..] => return HttpContentType::Pdf,
+            [0, 0, 1, 0, ..] => return HttpContentType::Icon,
+            _ => {},
+        }
+        // the prefix may end partway through a character
+        let text = match std::str::from_utf8(prefix) {
+            Ok(text) => text,
+            Err(e) if e.error_len().is_none() => std::str::from_utf8(&prefix[..e.valid_up_to()]).unwrap_or_default(),
+            Err(_) => return HttpContentType::OctetStream,
+        };
+        if text.chars().any(|c| c.is_control() && !matches!(c, '\t' | '\n' | '\r' | '\x0c')) {
+            return HttpContentType::OctetStream;
+        }
+        let start = text.trim_start().get(..14).unwrap_or_default().to_ascii_lowercase();
+        if start.starts_with("<!doctype html") || start.starts_with("<html") {
+            HttpContentType::Html
+        } else {
+            HttpContentType::Plain
+        }
+    }
+}
+
+/// how much of a file `HttpContentType::sniff` looks at
+pub const SNIFF_BYTES: usize = 512;
+
+impl HttpContentType {
+    // textual types get a charset parameter on their Content-Type header
+    pub fn is_text(&self) -> bool {
+        matches!(self, HttpContentType::Html | HttpContentType::Css | HttpContentType::Js
+            | HttpContentType::Json | HttpContentType::Plain | HttpContentType::Svg | HttpContentType::EventStream)
+    }
+}
+
+impl HttpMethod {
+    /// the method's name as it appears in a request line
+    pub fn as_str(&self) -> &str {
+        match self {
+            HttpMethod::Get => "GET",
+            HttpMethod::Post => "POST",
+            HttpMethod::Put => "PUT",
+            HttpMethod::Delete => "DELETE",
+            HttpMethod::Head => "HEAD",
+            HttpMethod::Options => "OPTIONS",
+            HttpMethod::Patch => "PATCH",
+            HttpMethod::Other(method) => method,
+        }
+    }
+}
+
+// the body is left out, since it may hold credentials; see `Settings::log_bodies`
+impl fmt::Debug for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Request")
+            .field("method", &self.method)
+            .field("path", &self.path)
+            .field("version", &self.version)
+            .field("headers", &self.headers.keys().collect::<Vec<_>>())
+            .field("body", &format_args!("{} bytes", self.body.len()))
+            .field("body_stream", &self.body_stream)
+            .field("extensions", &self.extensions)
+            .finish()
+    }
+}
+
+impl From<&str> for HttpMethod {
+    fn from(method: &str) -> HttpMethod {
+        match method {
+            "GET" => HttpMethod::Get,
+            "POST" => HttpMethod::Post,
+            "PUT" => HttpMethod::Put,
+            "DELETE" => HttpMethod::Delete,
+            "HEAD" => HttpMethod::Head,
+            "OPTIONS" => HttpMethod::Options,
+            "PATCH" => HttpMethod::Patch,
+            other => HttpMethod::Other(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for HttpMethod {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(self.as_str())
+    }
+}
+
+impl fmt::Display for HttpContentType {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", match self {
+            HttpContentType::Html => "text/html",
+            HttpContentType::Css => "text/css",
+            HttpContentType::Js => "text/javascript",
+            HttpContentType::Json => "application/json",
+            HttpContentType::Plain => "text/plain",
+            HttpContentType::Svg => "image/svg+xml",
+            HttpContentType::Jpeg => "image/jpeg",
+            HttpContentType::Png => "image/png",
+            HttpContentType::Gif => "image/gif",
+            HttpContentType::Webp => "image/webp",
+            HttpContentType::Pdf => "application/pdf",
+            HttpContentType::Icon => "image/x-icon",
+            HttpContentType::EventStream => "text/event-stream",
+            HttpContentType::OctetStream => "application/octet-stream",
+        })
+    }
+}
+
+impl HttpHeader {
+    pub fn name(&self) -> &str {
+        match self {
+            HttpHeader::ContentType(_) => "Content-Type",
+            HttpHeader::ContentLength(_) => "Content-Length",
+            HttpHeader::Custom(name, _) => name,
+        }
+    }
+}
+
+impl fmt::Display for HttpHeader {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", match self {
+            HttpHeader::ContentType(s) => format!("Content-Type: {}", s),
+            HttpHeader::ContentLength(n) => format!("Content-Length: {}", n),
+            HttpHeader::Custom(name, value) => format!("{}: {}", name, value),
+        })
+    }
+}
+
+impl HttpStatus {
+    /// the status for `code`, classed by its first digit: 4xx and 5xx codes are errors
+    pub fn from_code(code: u16) -> HttpStatus {
+        match i32::from(code) {
+            code @ 400.. => HttpStatus::HttpErr(code),
+            code => HttpStatus::HttpOk(code),
+        }
+    }
+
+    pub fn code(&self) -> i32 {
+        match self {
+            HttpStatus::HttpOk(code) | HttpStatus::HttpErr(code) => *code,
+        }
+    }
+}
+
+/// the reason phrase sent after `code` in a status line, e.g. "Not Found" for 404.
+/// codes without one of their own get "OK" below 400 and "Unknown Error" from there on
+pub fn reason_phrase(code: i32) -> &'static str {
+    match code {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        417 => "Expectation Failed",
+        418 => "I'm a teapot",
+        422 => "Unprocessable Content",
+        426 => "Upgrade Required",
+        428 => "Precondition Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        451 => "Unavailable For Legal Reasons",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        ..=399 => "OK",
+        _ => "Unknown Error",
+    }
+}
+
+impl fmt::Display for HttpStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.code(), reason_phrase(self.code()))
+    }
+}
+
+
+pub struct Response {
+    pub status: HttpStatus,
+    /// shared so cached files can be served without copying them
+    pub contents: Arc<Vec<u8>>,
+    pub headers: Option<Vec<HttpHeader>>,
+    // the file a `Response::file` body is streamed from. kept out of the headers so
+    // that nothing a handler or a client controls can name a file to send
+    pub(crate) file: Option<String>,
+}
+
+#[derive(Debug)]
+pub(crate) enum RequestError {
+    // the client closed the connection before sending anything
+    ConnectionClosed,
+    // the request line or headers were malformed or cut short
+    BadRequest,
+    // too many header lines, or one that is too long
+    HeadersTooLarge,
+    // the declared body is over `Settings::max_body_bytes`, or the request as a whole
+    // over `Settings::max_request_bytes`
+    PayloadTooLarge,
+    // an `Expect` header other than `100-continue`
+    ExpectationFailed,
+    // the request uri is longer than `Settings::max_uri_bytes`
+    UriTooLong,
+    // the peer address is not allowed by `Settings::allow_ips`/`deny_ips`
+    Forbidden,
+    // the peer already has `Settings::max_connections_per_ip` connections open
+    TooManyConnections,
+    // no request started within `Settings::idle_keepalive_timeout_secs`
+    IdleTimeout,
+    // the head or body took longer than its read timeout
+    Timeout,
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for RequestError {
+    fn from(e: std::io::Error) -> RequestError {
+        RequestError::Io(e)
+    }
+}
+
+
+/// an entity tag, as sent in `ETag` and compared against `If-None-Match`: `"tag"`, or
+/// `W/"tag"` when weak. a weak tag promises the same content, not the same bytes, so it
+/// suits a body that is re-encoded (compressed, say) on its way out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntityTag {
+    pub weak: bool,
+    /// the opaque part, without its quotes
+    pub tag: String,
+}
+
+impl EntityTag {
+    pub fn strong(tag: &str) -> EntityTag {
+        EntityTag { weak: false, tag: tag.to_string() }
+    }
+
+    pub fn weak(tag: &str) -> EntityTag {
+        EntityTag { weak: true, tag: tag.to_string() }
+    }
+
+    /// a strong tag for `contents`, from its length and a hash of its bytes
+    pub fn of(contents: &[u8]) -> EntityTag {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        contents.hash(&mut hasher);
+        EntityTag::strong(&format!("{:x}-{:016x}", contents.len(), hasher.finish()))
+    }
+
+    /// reads one tag, such as an `ETag` header value
+    pub fn parse(s: &str) -> Option<EntityTag> {
+        match EntityTag::parse_list(s)?.as_slice() {
+            [tag] => Some(tag.clone()),
+            _ => None,
+        }
+    }
+
+    /// reads a comma separated list of tags, as `If-None-Match` and `If-Match` hold
+    /// (other than `*`). quotes delimit each tag, so a comma inside one is part of it;
+    /// anything malformed makes the whole list `None`.
+    pub fn parse_list(s: &str) -> Option<Vec<EntityTag>> {
+        let mut tags = Vec::new();
+        let mut rest = s.trim_start_matches(|c: char| c == ',' || c.is_ascii_whitespace());
+        while !rest.is_empty() {
+            let (weak, quoted) = match rest.strip_prefix("W/") {
+                Some(quoted) => (true, quoted),
+                None => (false, rest),
+            };
+            let (tag, after) = quoted.strip_prefix('"')?.split_once('"')?;
+            tags.push(EntityTag { weak, tag: tag.to_string() });
+            let after = after.trim_start_matches(|c: char| c.is_ascii_whitespace());
+            if !after.is_empty() && !after.starts_with(',') {
+                return None;
+            }
+            rest = after.trim_start_matches(|c: char| c == ',' || c.is_ascii_whitespace());
+        }
+        Some(tags)
+    }
+
+    /// the strong comparison (rfc 9110 8.8.3.2): both tags strong and the same. used for
+    /// `If-Match`, where the bytes have to be the ones the client has
+    pub fn strong_eq(&self, other: &EntityTag) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    /// the weak comparison: the same tag, weak or not. used for `If-None-Match`, where
+    /// equivalent content is enough for the client to keep its copy
+    pub fn weak_eq(&self, other: &EntityTag) -> bool {
+        self.tag == other.tag
+    }
+}
+
+impl fmt::Display for EntityTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}\"{}\"", if self.weak { "W/" } else { "" }, self.tag)
+    }
+}
+
+/// whether an `Accept` header asks for json ahead of html: `application/json` or a
+/// `+json` type is named, with a higher quality than any `text/html` range. wildcards
+/// count for neither, so browsers and `*/*` keep getting html
+pub fn prefers_json(accept: &str) -> bool {
+    let (mut json, mut html) = (0.0, 0.0);
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let media_type = params.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q=").or_else(|| param.trim().strip_prefix("Q=")))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if media_type == "application/json" || media_type.ends_with("+json") {
+            json = quality.max(json);
+        } else if media_type == "text/html" {
+            html = quality.max(html);
+        }
+    }
+    json > 0.0 && json > html
+}
+
+/// whether an `If-None-Match` value matches the current representation's `etag`, so a
+/// `GET` can be answered with a 304: `*` matches any, otherwise some tag in the list has
+/// to match weakly. a malformed list matches nothing.
+pub fn if_none_match(header: &str, etag: &EntityTag) -> bool {
+    if header.trim() == "*" {
+        return true;
+    }
+    EntityTag::parse_list(header).is_some_and(|tags| tags.iter().any(|tag| tag.weak_eq(etag)))
+}
+
+/// whether an `If-Match` value lets a request go ahead against the current
+/// representation's `etag` (`None` when there isn't one): `*` matches any existing
+/// representation, otherwise some tag in the list has to match strongly, so a weak tag
+/// never does
+pub fn if_match(header: &str, etag: Option<&EntityTag>) -> bool {
+    let Some(etag) = etag else {
+        return false;
+    };
+    if header.trim() == "*" {
+        return true;
+    }
+    EntityTag::parse_list(header).is_some_and(|tags| tags.iter().any(|tag| tag.strong_eq(etag)))
+}
+
+/// `Content-Disposition` value for downloading a file as `filename`. quotes and
+/// backslashes are escaped, and a name with non-ascii characters gets an ascii
+/// fallback plus the exact name as a `filename*=UTF-8''...` parameter (rfc 6266).
+pub fn attachment_disposition(filename: &str) -> String {
+    let fallback: String = filename.chars().map(|c| match c {
+        '"' | '\\' => format!("\\{}", c),
+        c if c.is_ascii() && !c.is_ascii_control() => c.to_string(),
+        _ => String::from("_"),
+    }).collect();
+    if filename.is_ascii() {
+        return format!("attachment; filename=\"{}\"", fallback);
+    }
+    // rfc 5987 attr-chars go through as they are, every other byte is percent-encoded
+    let encoded: String = filename.bytes().map(|b| match b {
+        b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.'
+            | b'^' | b'_' | b'`' | b'|' | b'~' => (b as char).to_string(),
+        b => format!("%{:02X}", b),
+    }).collect();
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, encoded)
+}
+
+/// `s` made safe for a header value: bytes other than visible ascii (controls, line
+/// breaks, spaces, anything non-ascii) are percent-encoded
+pub fn header_escape(s: &str) -> String {
+    s.bytes().map(|b| match b {
+        b'!'..=b'~' => (b as char).to_string(),
+        b => format!("%{:02X}", b),
+    }).collect()
+}
+
+/// decodes `%xx` escapes in a url path; invalid escapes are kept as they are
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+        match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            },
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// room for the method, version, spaces and line ending around the uri in a request line
+const REQUEST_LINE_SLACK: usize = 64;
+
+// reads a line without buffering more than `limit` bytes of it. `None` means the
+// line was longer than that; otherwise the byte count, which is 0 at eof.
+pub(crate) async fn read_line_limited<R: BufRead + Unpin>(reader: &mut R, line: &mut String, limit: usize) -> std::io::Result<Option<usize>> {
+    // one extra byte to tell a line of exactly `limit` bytes from a longer one
+    let n = (&mut *reader).take(limit as u64 + 1).read_line(line).await?;
+    Ok(if n > limit { None } else { Some(n) })
+}
+
+// runs `read`, failing with `on_timeout` if it takes longer than `limit_secs`
+pub(crate) async fn within<T>(
+    limit_secs: Option<u64>, on_timeout: RequestError, read: impl Future<Output = Result<T, RequestError>>
+) -> Result<T, RequestError> {
+    match limit_secs {
+        Some(secs) => async_std::future::timeout(Duration::from_secs(secs), read).await.map_err(|_| on_timeout)?,
+        None => read.await,
+    }
+}
+
+impl Request {
+    // reads the request line and headers a line at a time from a buffered reader,
+    // so the head of the request is pulled off the socket in as few reads as possible
+    // the writer is only used for an interim `100 Continue` when the client asks for one.
+    // bodies on `Settings::streamed_body_paths` are left unread, to be fed to the
+    // request's `body_stream` by the returned pump while the handler runs.
+    pub(crate) async fn from_stream<R: BufRead + Unpin, W: Write + Unpin>(
+        reader: &mut R, writer: &mut W, settings: &Settings
+    ) -> Result<(Request, Option<BodyPump>), RequestError> {
+        // waiting for a request to start is bounded separately from reading it, so an
+        // idle connection can be dropped quietly while a stalled request gets a 408
+        let pending = within(settings.idle_keepalive_timeout_secs, RequestError::IdleTimeout, async {
+            Ok(futures::AsyncBufReadExt::fill_buf(reader).await?.len())
+        }).await?;
+        if pending == 0 {
+            return Err(RequestError::ConnectionClosed);
+        }
+        let (method, path, version, headers, head_bytes) = within(
+            settings.header_read_timeout_secs, RequestError::Timeout, Self::read_head(reader, settings)
+        ).await?;
+
+        let expect_continue = match headers.get("expect") {
+            Some(expect) if expect.eq_ignore_ascii_case("100-continue") => true,
+            Some(_) => return Err(RequestError::ExpectationFailed),
+            None => false,
+        };
+
+        // the body gets whatever the head left of the overall limit
+        let max_body = match settings.max_request_bytes {
+            Some(max) => settings.max_body_bytes.min(max.saturating_sub(head_bytes)),
+            None => settings.max_body_bytes,
+        };
+        let body = BodyReader::new(&headers, max_body)?;
+        // a declared length that is too big was refused above, so a client waiting on
+        // `100 Continue` never sends that body
+        if expect_continue && !body.is_empty() {
+            let interim = Response{status: HttpStatus::HttpOk(100), contents: Arc::default(), headers: None, file: None};
+            writer.write_all(&interim.fmt_head(None, &settings.charset)).await?;
+            writer.flush().await?;
+        }
+
+        let route = path.split_once('?').map_or(&path[..], |(route, _)| route);
+        if settings.streamed_body_paths.iter().any(|pattern| path_matches(pattern, route)) {
+            let (stream, pump) = body::stream(body);
+            let request = Request { method, path, version, headers, body: Vec::new(), body_stream: Some(stream), extensions: Extensions::default() };
+            return Ok((request, Some(pump)));
+        }
+        let mut body = body;
+        let body = within(settings.body_read_timeout_secs, RequestError::Timeout, body.read_to_end(reader)).await?;
+        Ok((Request { method, path, version, headers, body, body_stream: None, extensions: Extensions::default() }, None))
+    }
+
+    // the request line and headers: method, path, version, lowercased headers, and how
+    // many bytes they took up
+    async fn read_head<R: BufRead + Unpin>(
+        reader: &mut R, settings: &Settings
+    ) -> Result<(HttpMethod, String, String, HashMap<String, String>, usize), RequestError> {
+        // counts a line read towards `Settings::max_request_bytes`
+        let mut head_bytes = 0;
+        let mut count = |line: &str| {
+            head_bytes += line.len();
+            match settings.max_request_bytes {
+                Some(max) if head_bytes > max => Err(RequestError::PayloadTooLarge),
+                _ => Ok(()),
+            }
+        };
+        let mut request_line = String::new();
+        // empty lines before the request line (some clients end a body with one) are
+        // skipped, as rfc 9112 2.2 asks
+        while request_line.trim().is_empty() {
+            request_line.clear();
+            // the method, version and separators get some room on top of the uri itself
+            match read_line_limited(reader, &mut request_line, settings.max_uri_bytes + REQUEST_LINE_SLACK).await? {
+                Some(0) => return Err(RequestError::ConnectionClosed),
+                Some(_) => {},
+                None => return Err(RequestError::UriTooLong),
+            }
+            count(&request_line)?;
+        }
+        info!("Request line: {}", request_line.trim_end());
+
+        let strict = settings.parse_strictness == ParseStrictness::Strict;
+        if strict && !request_line.ends_with("\r\n") {
+            return Err(RequestError::BadRequest);
+        }
+        let parts: Vec<&str> = if strict {
+            request_line.trim_end_matches(['\r', '\n']).split(' ').collect()
+        } else {
+            request_line.split_whitespace().collect()
+        };
+        let (Some(&method), Some(&path), Some(&version)) = (parts.first(), parts.get(1), parts.get(2)) else {
+            return Err(RequestError::BadRequest);
+        };
+        if strict && (parts.len() > 3 || parts.contains(&"")) {
+            return Err(RequestError::BadRequest);
+        }
+        if path.len() > settings.max_uri_bytes {
+            return Err(RequestError::UriTooLong);
+        }
+        // `*` (asterisk-form) only ever names the server itself, for `OPTIONS`
+        if path == "*" && method != "OPTIONS" {
+            return Err(RequestError::BadRequest);
+        }
+        // an absolute-form target, as sent to proxies, is routed by its path; its authority
+        // replaces any Host header (rfc 9112 3.2.2)
+        let (path, authority) = match absolute_form(path) {
+            Some(Some((authority, path))) => (path, Some(authority)),
+            Some(None) => return Err(RequestError::BadRequest),
+            None => (path.to_string(), None),
+        };
+
+        let mut headers: HashMap<String, String> = HashMap::new();
+        let mut line = String::new();
+        let mut header_count = 0;
+        // the header a folded line continues
+        let mut last_name: Option<String> = None;
+        loop {
+            line.clear();
+            match read_line_limited(reader, &mut line, settings.max_header_line_bytes).await? {
+                // eof before the blank line that ends the headers
+                Some(0) => return Err(RequestError::BadRequest),
+                Some(_) => {},
+                None => return Err(RequestError::HeadersTooLarge),
+            }
+            count(&line)?;
+            let header = match line.strip_suffix("\r\n") {
+                Some(header) if strict => header,
+                None if strict => return Err(RequestError::BadRequest),
+                _ => line.trim_end(),
+            };
+            if header.is_empty() {
+                break;
+            }
+            header_count += 1;
+            if header_count > settings.max_headers {
+                return Err(RequestError::HeadersTooLarge);
+            }
+            // obsolete line folding (rfc 9112 5.2)
+            if header.starts_with([' ', '\t']) {
+                if strict {
+                    return Err(RequestError::BadRequest);
+                }
+                if let Some(value) = last_name.as_ref().and_then(|name| headers.get_mut(name)) {
+                    if !value.is_empty() {
+                        value.push(' ');
+                    }
+                    value.push_str(header.trim());
+                }
+                continue;
+            }
+            let Some((name, value)) = header.split_once(':') else {
+                if strict {
+                    return Err(RequestError::BadRequest);
+                }
+                continue;
+            };
+            if strict && (name.is_empty() || name.contains(char::is_whitespace)) {
+                return Err(RequestError::BadRequest);
+            }
+            let (name, mut value) = (name.trim().to_ascii_lowercase(), value.trim().to_string());
+            if name == "content-length" {
+                value = content_length(headers.get(&name), &value, strict)?;
+            }
+            headers.insert(name.clone(), value);
+            last_name = Some(name);
+        }
+        if let Some(authority) = authority {
+            headers.insert(String::from("host"), authority.to_string());
+        }
+        let logged = headers.iter().map(|(name, value)| {
+            (name, if body_log::is_redacted(name, settings) { "[redacted]" } else { value.as_str() })
+        });
+        info!("Request headers: {:?}", logged.collect::<HashMap<_, _>>());
+        Ok((HttpMethod::from(method), path, version.to_string(), headers, head_bytes))
+    }
+}
+
+// the authority and path of an absolute-form request target (`http://host:8080/a?b`
+// gives `host:8080` and `/a?b`), `Some(None)` for a malformed one, or `None` when
+// `target` isn't in absolute form
+fn absolute_form(target: &str) -> Option<Option<(&str, String)>> {
+    let (scheme, rest) = target.split_once("://")?;
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+        return None;
+    }
+    let (authority, path) = rest.split_at(rest.find(['/', '?']).unwrap_or(rest.len()));
+    // http uris can't carry a user name or password (rfc 9110 4.2.4)
+    if authority.is_empty() || authority.contains('@') {
+        return Some(None);
+    }
+    let path = if path.starts_with('/') { path.to_string() } else { format!("/{}", path) };
+    Some(Some((authority, path)))
+}
+
+// the one length a `Content-Length` header adds up to, given the value of an earlier
+// one if there was one. repeats (also within a list, `5, 5`) are refused in strict
+// mode, and otherwise accepted as long as they agree
+fn content_length(previous: Option<&String>, value: &str, strict: bool) -> Result<String, RequestError> {
+    let lengths: Vec<&str> = previous.map(String::as_str).into_iter().chain([value])
+        .flat_map(|lengths| lengths.split(','))
+        .map(str::trim)
+        .collect();
+    if lengths.len() > 1 && (strict || lengths.iter().any(|length| *length != lengths[0])) {
+        return Err(RequestError::BadRequest);
+    }
+    Ok(lengths[0].to_string())
+}
+
+
+// whether `s` is a well-formed uri reference (rfc 3986 4.1): an absolute url with a
+// scheme and host, or a relative one. spaces, control characters (which would also let a
+// header be forged) and other characters that must be percent-encoded are refused.
+fn is_uri_reference(s: &str) -> bool {
+    const ALLOWED: &[u8] = b"-._~:/?#[]@!$&'()*+,;=%";
+    let bytes = s.as_bytes();
+    if s.is_empty() || !bytes.iter().all(|b| b.is_ascii_alphanumeric() || ALLOWED.contains(b)) {
+        return false;
+    }
+    let well_escaped = bytes.iter().enumerate().filter(|(_, b)| **b == b'%').all(|(i, _)| {
+        bytes.get(i + 1..i + 3).is_some_and(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+    });
+    // a colon before any `/`, `?` or `#` ends a scheme, which then needs a host
+    let first_delimiter = s.find(['/', '?', '#']).unwrap_or(s.len());
+    match s[..first_delimiter].split_once(':') {
+        Some((scheme, _)) => {
+            let valid_scheme = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+            let host = s[scheme.len() + 1..].strip_prefix("//").map(|rest| rest.split(['/', '?', '#']).next().unwrap_or_default());
+            well_escaped && valid_scheme && host.is_some_and(|host| !host.is_empty())
+        },
+        None => well_escaped,
+    }
+}
+
+impl Response {
+    /// a 200 response with the given body
+    pub fn ok(content_type: HttpContentType, contents: impl Into<Vec<u8>>) -> Response {
+        let headers = Some(Vec::from([HttpHeader::ContentType(content_type)]));
+        Response{status: HttpStatus::HttpOk(200), contents: Arc::new(contents.into()), headers, file: None}
+    }
+
+    /// a 200 response with the file at `path` as its body. the file isn't read here: it
+    /// is streamed from disk as the response is written, with sendfile(2) on linux, so
+    /// a large one is never held in memory. until then the body looks empty to
+    /// middleware (see `is_file`); a file that can't be opened by then is answered with
+    /// a 404.
+    pub fn file(path: &str, content_type: HttpContentType) -> Response {
+        Response{file: Some(path.to_string()), ..Response::ok(content_type, Vec::new())}
+    }
+
+    /// whether the body is a file still to be streamed from disk (see `Response::file`),
+    /// so `contents` doesn't hold it
+    pub fn is_file(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// an html error page showing the status
+    pub fn error(code: i32) -> Response {
+        let status = HttpStatus::HttpErr(code);
+        let contents = Arc::new(status.to_string().into_bytes());
+        let headers = Some(Vec::from([HttpHeader::ContentType(HttpContentType::Html)]));
+        Response{status, contents, headers, file: None}
+    }
+
+    /// a json error body for api clients, `{"error": "Not Found", "status": 404}`: the
+    /// status's reason phrase and its code
+    pub fn error_json(code: i32) -> Response {
+        let status = HttpStatus::HttpErr(code);
+        let body = serde_json::json!({"error": reason_phrase(code), "status": code});
+        Response{status, ..Response::ok(HttpContentType::Json, body.to_string())}
+    }
+
+    /// a redirect to `location` with a 301, 302, 303, 307 or 308 status, and a small
+    /// html body linking to it for clients that show one. `location` must be an
+    /// absolute url (`https://example.com/a`) or a relative one (`/a`, `../b`, `?page=2`);
+    /// anything else, or another status, is logged and answered with a 500 instead
+    pub fn redirect(location: &str, code: i32) -> Response {
+        if !matches!(code, 301 | 302 | 303 | 307 | 308) {
+            error!("{} is not a redirect status; redirecting to {}", code, location);
+            return Response::error(500);
+        }
+        if !is_uri_reference(location) {
+            error!("can't redirect to {:?}: not a well-formed url", location);
+            return Response::error(500);
+        }
+        let status = HttpStatus::HttpOk(code);
+        // the checks above leave `&` as the only character needing escaping
+        let href = location.replace('&', "&amp;");
+        let body = format!(
+            "<!DOCTYPE html>\n<html><head><title>{}</title></head><body><a href=\"{}\">{}</a></body></html>\n",
+            status, href, href,
+        );
+        let mut response = Response::ok(HttpContentType::Html, body);
+        response.status = status;
+        response.set_header("Location", location);
+        response
+    }
+
+    /// `redirect` with a 308: the resource has moved for good, and the method and body
+    /// are kept
+    pub fn redirect_permanent(location: &str) -> Response {
+        Response::redirect(location, 308)
+    }
+
+    /// `redirect` with a 307: try `location` this time only, keeping the method and body
+    pub fn redirect_temporary(location: &str) -> Response {
+        Response::redirect(location, 307)
+    }
+
+    pub fn is_html(&self) -> bool {
+        self.headers.iter().flatten().any(|header| matches!(header, HttpHeader::ContentType(HttpContentType::Html)))
+    }
+
+    /// makes browsers save the body as `filename` instead of displaying it
+    pub fn set_attachment(&mut self, filename: &str) {
+        self.set_header("Content-Disposition", &attachment_disposition(filename));
+    }
+
+    /// the value of a header added with `set_header`, matched ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().flatten().find_map(|header| match header {
+            HttpHeader::Custom(n, value) if n.eq_ignore_ascii_case(name) => Some(value.as_str()),
+            _ => None,
+        })
+    }
+
+    /// removes the headers added with `set_header` under `name`, returning the last value
+    pub fn remove_header(&mut self, name: &str) -> Option<String> {
+        let mut removed = None;
+        self.headers.get_or_insert_with(Vec::new).retain(|header| match header {
+            HttpHeader::Custom(n, value) if n.eq_ignore_ascii_case(name) => {
+                removed = Some(value.clone());
+                false
+            },
+            _ => true,
+        });
+        removed
+    }
+
+    /// adds a header, replacing any existing ones with the same name
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        let headers = self.headers.get_or_insert_with(Vec::new);
+        headers.retain(|header| !header.name().eq_ignore_ascii_case(name));
+        headers.push(HttpHeader::Custom(name.to_string(), value.to_string()));
+    }
+
+    /// adds `field` to the `Vary` header, keeping the fields already listed there
+    pub fn add_vary(&mut self, field: &str) {
+        let vary = match self.header("Vary") {
+            Some(vary) if vary.split(',').any(|f| f.trim() == "*" || f.trim().eq_ignore_ascii_case(field)) => return,
+            Some(vary) if !vary.trim().is_empty() => format!("{}, {}", vary, field),
+            _ => field.to_string(),
+        };
+        self.set_header("Vary", &vary);
+    }
+
+    // status line and headers; without a content length the body runs until the
+    // connection closes, which is how streamed responses are delimited. text content
+    // types are labelled with `charset` unless it is empty.
+    pub(crate) fn fmt_head(&self, content_length: Option<usize>, charset: &str) -> Vec<u8> {
+        let mut head = Vec::new();
+        self.write_head(&mut head, content_length, charset);
+        head
+    }
+
+    // appends the head to `out`, so a pooled buffer can be reused for it
+    pub(crate) fn write_head(&self, out: &mut Vec<u8>, content_length: Option<usize>, charset: &str) {
+        // writing into a vec can't fail
+        fn push(out: &mut Vec<u8>, args: fmt::Arguments) {
+            let _ = std::io::Write::write_fmt(out, args);
+        }
+        let start = out.len();
+        push(out, format_args!("HTTP/1.1 {}\r\n", &self.status));
+        for header in self.headers.iter().flatten() {
+            match header {
+                HttpHeader::ContentLength(_) => {},
+                HttpHeader::ContentType(t) if t.is_text() && !charset.is_empty() => {
+                    push(out, format_args!("{}; charset={}\r\n", header, charset));
+                },
+                _ => push(out, format_args!("{}\r\n", header)),
+            }
+        }
+        // informational, no-content and not-modified responses have no body to measure
+        let has_body = !matches!(self.status, HttpStatus::HttpOk(code) if code < 200 || code == 204 || code == 304);
+        if let (true, Some(content_length)) = (has_body, content_length) {
+            push(out, format_args!("Content-Length: {}\r\n", content_length));
+        }
+        out.extend_from_slice(b"\r\n");
+
+        info!("Headers:\n{}", String::from_utf8_lossy(&out[start..]));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::Cursor;
+    use async_std::task::block_on;
+
+    type Head = (HttpMethod, String, String, HashMap<String, String>, usize);
+
+    fn read_head(raw: &str, strictness: ParseStrictness) -> Result<Head, RequestError> {
+        let settings = Settings { parse_strictness: strictness, ..Settings::default() };
+        block_on(Request::read_head(&mut Cursor::new(raw.as_bytes()), &settings))
+    }
+
+    fn strict(raw: &str) -> Result<Head, RequestError> {
+        read_head(raw, ParseStrictness::Strict)
+    }
+
+    fn lenient(raw: &str) -> Result<Head, RequestError> {
+        read_head(raw, ParseStrictness::Lenient)
+    }
+
+    fn is_bad_request(head: Result<Head, RequestError>) -> bool {
+        matches!(head, Err(RequestError::BadRequest))
+    }
+
+    #[test]
+    fn a_well_formed_head_reads_the_same_either_way() {
+        let raw = "GET /a?b=1 HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\r\n";
+        for head in [strict(raw), lenient(raw)] {
+            let (method, path, version, headers, bytes) = head.unwrap();
+            assert_eq!(method, HttpMethod::Get);
+            assert_eq!(path, "/a?b=1");
+            assert_eq!(version, "HTTP/1.1");
+            assert_eq!(headers["host"], "example.com");
+            assert_eq!(headers["content-length"], "5");
+            assert_eq!(bytes, raw.len());
+        }
+    }
+
+    #[test]
+    fn bare_lf_line_endings() {
+        let raw = "GET / HTTP/1.1\nHost: example.com\n\n";
+        assert!(is_bad_request(strict(raw)));
+        assert_eq!(lenient(raw).unwrap().3["host"], "example.com");
+        // one bare lf among crlfs is enough
+        assert!(is_bad_request(strict("GET / HTTP/1.1\r\nHost: example.com\n\r\n")));
+    }
+
+    #[test]
+    fn folded_header_lines() {
+        let raw = "GET / HTTP/1.1\r\nX-Long: first\r\n  second\r\n\tthird\r\n\r\n";
+        assert!(is_bad_request(strict(raw)));
+        assert_eq!(lenient(raw).unwrap().3["x-long"], "first second third");
+        // a fold with nothing to continue is dropped
+        assert!(lenient("GET / HTTP/1.1\r\n  stray\r\n\r\n").unwrap().3.is_empty());
+    }
+
+    #[test]
+    fn whitespace_before_the_colon() {
+        let raw = "GET / HTTP/1.1\r\nHost : example.com\r\n\r\n";
+        assert!(is_bad_request(strict(raw)));
+        assert_eq!(lenient(raw).unwrap().3["host"], "example.com");
+        // whitespace after it is fine in both
+        assert_eq!(strict("GET / HTTP/1.1\r\nHost:   example.com  \r\n\r\n").unwrap().3["host"], "example.com");
+    }
+
+    #[test]
+    fn header_lines_without_a_colon() {
+        let raw = "GET / HTTP/1.1\r\nnot a header\r\nHost: example.com\r\n\r\n";
+        assert!(is_bad_request(strict(raw)));
+        assert_eq!(lenient(raw).unwrap().3.len(), 1);
+    }
+
+    #[test]
+    fn repeated_content_length() {
+        let repeated = "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\n";
+        assert!(is_bad_request(strict(repeated)));
+        assert_eq!(lenient(repeated).unwrap().3["content-length"], "5");
+        let listed = "POST / HTTP/1.1\r\nContent-Length: 5, 5\r\n\r\n";
+        assert!(is_bad_request(strict(listed)));
+        assert_eq!(lenient(listed).unwrap().3["content-length"], "5");
+        // values that disagree are refused either way
+        let differing = "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n";
+        assert!(is_bad_request(strict(differing)));
+        assert!(is_bad_request(lenient(differing)));
+        assert!(is_bad_request(lenient("POST / HTTP/1.1\r\nContent-Length: 5, 6\r\n\r\n")));
+    }
+
+    #[test]
+    fn extra_spaces_in_the_request_line() {
+        let raw = "GET  /  HTTP/1.1\r\n\r\n";
+        assert!(is_bad_request(strict(raw)));
+        assert_eq!(lenient(raw).unwrap().1, "/");
+        assert!(is_bad_request(strict("GET / HTTP/1.1 extra\r\n\r\n")));
+    }
+
+    #[test]
+    fn content_length_accepts_one_length() {
+        assert_eq!(content_length(None, " 42 ", true).unwrap(), "42");
+        assert_eq!(content_length(None, "42", false).unwrap(), "42");
+        let previous = String::from("42");
+        assert!(matches!(content_length(Some(&previous), "42", true), Err(RequestError::BadRequest)));
+        assert_eq!(content_length(Some(&previous), "42", false).unwrap(), "42");
+        assert!(matches!(content_length(Some(&previous), "43", false), Err(RequestError::BadRequest)));
+        assert_eq!(content_length(None, "7,7, 7", false).unwrap(), "7");
+        assert!(matches!(content_length(None, "7,8", false), Err(RequestError::BadRequest)));
+    }
+
+    fn is_error(status: &HttpStatus) -> bool {
+        matches!(status, HttpStatus::HttpErr(_))
+    }
+
+    #[test]
+    fn codes_are_classed_by_their_first_digit() {
+        for code in [100, 101, 200, 204, 299, 301, 304, 399] {
+            let status = HttpStatus::from_code(code);
+            assert!(!is_error(&status), "{}", code);
+            assert_eq!(status.code(), i32::from(code));
+        }
+        for code in [400, 404, 451, 499, 500, 503, 599] {
+            let status = HttpStatus::from_code(code);
+            assert!(is_error(&status), "{}", code);
+            assert_eq!(status.code(), i32::from(code));
+        }
+    }
+
+    #[test]
+    fn known_codes_have_their_own_phrase() {
+        assert_eq!(reason_phrase(100), "Continue");
+        assert_eq!(reason_phrase(200), "OK");
+        assert_eq!(reason_phrase(304), "Not Modified");
+        assert_eq!(reason_phrase(404), "Not Found");
+        assert_eq!(reason_phrase(406), "Not Acceptable");
+        assert_eq!(reason_phrase(503), "Service Unavailable");
+        assert_eq!(HttpStatus::from_code(404).to_string(), "404 Not Found");
+    }
+
+    #[test]
+    fn other_codes_fall_back_by_class() {
+        // up to 399 everything went well enough for "OK"
+        for code in [102, 199, 203, 226, 305, 399] {
+            assert_eq!(reason_phrase(code), "OK", "{}", code);
+        }
+        for code in [420, 499, 507, 599, 600, 999] {
+            assert_eq!(reason_phrase(code), "Unknown Error", "{}", code);
+        }
+        assert_eq!(HttpStatus::from_code(599).to_string(), "599 Unknown Error");
+    }
+
+    fn tags(list: &str) -> Option<Vec<EntityTag>> {
+        EntityTag::parse_list(list)
+    }
+
+    #[test]
+    fn tags_parse_strong_and_weak() {
+        assert_eq!(EntityTag::parse("\"abc\""), Some(EntityTag::strong("abc")));
+        assert_eq!(EntityTag::parse("W/\"abc\""), Some(EntityTag::weak("abc")));
+        assert_eq!(EntityTag::parse(" \"\" "), Some(EntityTag::strong("")));
+        assert_eq!(EntityTag::weak("abc").to_string(), "W/\"abc\"");
+        assert_eq!(EntityTag::strong("abc").to_string(), "\"abc\"");
+        // `parse` takes exactly one
+        assert_eq!(EntityTag::parse("\"a\", \"b\""), None);
+        assert_eq!(EntityTag::parse(""), None);
+    }
+
+    #[test]
+    fn malformed_tags_are_refused() {
+        for malformed in ["abc", "\"abc", "abc\"", "w/\"abc\"", "W/abc", "W/ \"abc\"", "\"a\"b", "*"] {
+            assert_eq!(EntityTag::parse(malformed), None, "{}", malformed);
+        }
+    }
+
+    #[test]
+    fn lists_split_on_commas_outside_quotes() {
+        assert_eq!(tags("\"a\", W/\"b\",\"c\""), Some(vec![EntityTag::strong("a"), EntityTag::weak("b"), EntityTag::strong("c")]));
+        assert_eq!(tags("\"a,b\", \"c\""), Some(vec![EntityTag::strong("a,b"), EntityTag::strong("c")]));
+        // empty elements, as rfc 9110 5.6.1 allows
+        assert_eq!(tags(" , \"a\" ,, \"b\" ,"), Some(vec![EntityTag::strong("a"), EntityTag::strong("b")]));
+        assert_eq!(tags(""), Some(Vec::new()));
+        // one bad element spoils the list
+        assert_eq!(tags("\"a\", b"), None);
+        assert_eq!(tags("\"a\" \"b\""), None);
+        assert_eq!(tags("\"a\", *"), None);
+        assert_eq!(tags("\"a\", \"b"), None);
+    }
+
+    #[test]
+    fn strong_and_weak_comparison() {
+        let (strong, weak) = (EntityTag::strong("v1"), EntityTag::weak("v1"));
+        assert!(strong.strong_eq(&strong));
+        assert!(!strong.strong_eq(&weak));
+        assert!(!weak.strong_eq(&weak));
+        assert!(strong.weak_eq(&weak));
+        assert!(weak.weak_eq(&weak));
+        assert!(!strong.weak_eq(&EntityTag::strong("v2")));
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        let etag = EntityTag::strong("v1");
+        assert!(if_none_match("\"v1\"", &etag));
+        assert!(if_none_match("W/\"v1\"", &etag));
+        assert!(if_none_match("\"v0\", W/\"v1\"", &etag));
+        assert!(if_none_match("W/\"v1\"", &EntityTag::weak("v1")));
+        assert!(if_none_match(" * ", &etag));
+        assert!(!if_none_match("\"v2\"", &etag));
+        assert!(!if_none_match("\"v1,v2\"", &etag));
+        // malformed matches nothing, even with the tag in it
+        assert!(!if_none_match("\"v1\", v2", &etag));
+        assert!(!if_none_match("v1", &etag));
+        assert!(!if_none_match("\"v1\", *", &etag));
+    }
+
+    #[test]
+    fn if_match_compares_strongly() {
+        let etag = EntityTag::strong("v1");
+        assert!(if_match("\"v1\"", Some(&etag)));
+        assert!(if_match("\"v0\", \"v1\"", Some(&etag)));
+        assert!(!if_match("W/\"v1\"", Some(&etag)));
+        assert!(!if_match("\"v1\"", Some(&EntityTag::weak("v1"))));
+        assert!(if_match("*", Some(&EntityTag::weak("v1"))));
+        // nothing there to match, not even `*`
+        assert!(!if_match("*", None));
+        assert!(!if_match("\"v1\"", None));
+        assert!(!if_match("\"v1\" junk", Some(&etag)));
+    }
+
+    #[test]
+    fn add_vary_merges_fields() {
+        let mut response = Response::error(404);
+        response.add_vary("Accept");
+        assert_eq!(response.header("Vary"), Some("Accept"));
+        response.add_vary("Accept-Encoding");
+        response.add_vary("accept");
+        response.add_vary("Origin");
+        response.add_vary("accept-encoding");
+        assert_eq!(response.header("Vary"), Some("Accept, Accept-Encoding, Origin"));
+        // everything already varies
+        let mut response = Response::error(404);
+        response.set_header("Vary", "*");
+        response.add_vary("Accept");
+        assert_eq!(response.header("Vary"), Some("*"));
+    }
+
+    fn from_stream(raw: &[u8]) -> Result<Request, RequestError> {
+        let mut written = Vec::new();
+        let settings = Settings::default();
+        block_on(Request::from_stream(&mut Cursor::new(raw), &mut written, &settings)).map(|(request, _)| request)
+    }
+
+    #[test]
+    fn only_a_request_can_follow_a_body() {
+        let request = from_stream(b"POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc\r\nGET / HTTP/1.1\r\n").unwrap();
+        assert_eq!(request.body, b"abc");
+        assert!(matches!(from_stream(b"POST / HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcdef\r\n"), Err(RequestError::BadRequest)));
+        assert!(matches!(from_stream(b"GET / HTTP/1.1\r\n\r\n\x81\x85"), Err(RequestError::BadRequest)));
+        // unless the request switches to another protocol
+        let upgrade = b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n\x81\x85";
+        assert_eq!(from_stream(upgrade).unwrap().path, "/ws");
+    }
+
+    fn content_type_line(content_type: HttpContentType, charset: &str) -> String {
+        let head = String::from_utf8(Response::ok(content_type, "x").fmt_head(Some(1), charset)).unwrap();
+        head.lines().find(|line| line.starts_with("Content-Type:")).unwrap().to_string()
+    }
+
+    #[test]
+    fn only_text_types_get_a_charset() {
+        use HttpContentType::*;
+        for content_type in [Html, Css, Js, Json, Plain, Svg, EventStream] {
+            let expected = format!("Content-Type: {}; charset=utf-8", content_type);
+            assert_eq!(content_type_line(content_type, "utf-8"), expected);
+        }
+        for content_type in [Jpeg, Png, Gif, Webp, Pdf, Icon, OctetStream] {
+            let expected = format!("Content-Type: {}", content_type);
+            assert_eq!(content_type_line(content_type, "utf-8"), expected);
+        }
+        // the configured charset, or none at all when it's empty
+        assert_eq!(content_type_line(Html, "iso-8859-1"), "Content-Type: text/html; charset=iso-8859-1");
+        assert_eq!(content_type_line(Html, ""), "Content-Type: text/html");
+    }
+
+    #[test]
+    fn attachment_names_are_quoted_and_encoded() {
+        assert_eq!(attachment_disposition("report.pdf"), "attachment; filename=\"report.pdf\"");
+        assert_eq!(attachment_disposition("a \"b\"\\c.txt"), "attachment; filename=\"a \\\"b\\\"\\\\c.txt\"");
+        assert_eq!(
+            attachment_disposition("r\u{e9}sum\u{e9} 1.txt"),
+            "attachment; filename=\"r_sum_ 1.txt\"; filename*=UTF-8''r%C3%A9sum%C3%A9%201.txt",
+        );
+    }
+
+    #[test]
+    fn sniffing_reads_signatures_then_text() {
+        use HttpContentType::*;
+        assert_eq!(HttpContentType::sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Png);
+        assert_eq!(HttpContentType::sniff(b"\xff\xd8\xff\xe0"), Jpeg);
+        assert_eq!(HttpContentType::sniff(b"%PDF-1.7"), Pdf);
+        assert_eq!(HttpContentType::sniff(b"  <!DOCTYPE html><p>hi"), Html);
+        assert_eq!(HttpContentType::sniff("plain caf\u{e9}\n".as_bytes()), Plain);
+        assert_eq!(HttpContentType::sniff(b""), Plain);
+        assert_eq!(HttpContentType::sniff(b"\x7fELF\x02\x01\x01\0"), OctetStream);
+        // only the prefix counts: a character cut off at its end is still text, and
+        // anything past it isn't looked at
+        let mut text = "a".repeat(SNIFF_BYTES - 1).into_bytes();
+        text.extend("\u{e9}".as_bytes());
+        assert_eq!(HttpContentType::sniff(&text), Plain);
+        text.extend(b"\0\0\0");
+        assert_eq!(HttpContentType::sniff(&text), Plain);
+    }
+
+    #[test]
+    fn only_well_formed_urls_are_redirect_targets() {
+        for url in ["/new", "new/page?a=1&b=2#top", "https://example.com/x", "//cdn.example/a.js", "/caf%C3%A9", "?page=2"] {
+            assert!(is_uri_reference(url), "{}", url);
+        }
+        for url in ["", "/with space", "/caf\u{e9}", "/bad%2", "/bad%zz", "https:/missing-host", "1http://x", "/\"a\""] {
+            assert!(!is_uri_reference(url), "{}", url);
+            assert_eq!(Response::redirect(url, 307).status.code(), 500, "{}", url);
+        }
+        assert_eq!(Response::redirect("/new", 200).status.code(), 500);
+    }
+}
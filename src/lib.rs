@@ -1,20 +1,784 @@
 use std::fs;
+use std::path::Path;
+use std::collections::HashMap;
 use serde::Deserialize;
 
+pub mod access_control;
+pub mod access_log;
+pub mod app;
+pub mod assets;
+mod background;
+mod body_log;
+mod charset;
+pub mod body;
+pub mod dev;
+mod error_pages;
+pub mod fixture;
+pub mod http;
+pub mod logging;
+pub mod middleware;
+pub mod minify;
+pub mod multipart;
+pub mod pool;
+#[cfg(unix)]
+mod reload;
+mod sendfile;
+mod server;
+pub mod sse;
+pub mod template;
+mod views;
+pub mod websocket;
+
+pub use app::{App, ConcurrencyLimit, ContextProvider, Handler, Negotiate, Router};
+pub use middleware::{Middleware, Next};
+pub use server::{serve, serve_with_shutdown};
+
+
+/// requests whose path is under `prefix` are forwarded to `upstream`, an
+/// `http://host:port[/base]` url. the prefix matches whole segments: `/api` takes `/api`,
+/// `/api/users` and `/api?page=2`, but not `/apiary`. if the upstream has a base path it replaces the
+/// prefix (`/api/users?page=2` goes to `/base/users?page=2`, `/api` to `/base`), otherwise the
+/// full request path is forwarded unchanged.
+#[derive(Clone, Deserialize, Debug)]
+pub struct ProxyRoute {
+    pub prefix: String,
+    pub upstream: String,
+}
+
+/// extra headers for responses whose path matches `pattern`. a pattern ending in `*`
+/// matches by prefix (`/assets/*`), anything else must match the path exactly; the query
+/// string is ignored. configured headers replace a header of the same name set by the
+/// handler; when several patterns match they are applied in the order listed, so later
+/// ones win.
+#[derive(Clone, Deserialize, Debug)]
+pub struct RouteHeaders {
+    pub pattern: String,
+    pub headers: HashMap<String, String>,
+}
+
+/// a pattern ending in `*` matches paths starting with the rest of it, anything else
+/// only the exact path
+pub fn path_matches(pattern: &str, path: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => path.starts_with(prefix),
+        None => path == pattern,
+    }
+}
+
+impl ProxyRoute {
+    /// whether `path` (with any query string) is under `prefix`
+    pub fn matches(&self, path: &str) -> bool {
+        self.rest(path).is_some()
+    }
+
+    // what follows the prefix in `path`, if it is under it
+    pub(crate) fn rest<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let rest = path.strip_prefix(self.prefix.trim_end_matches('/'))?;
+        (rest.is_empty() || rest.starts_with(['/', '?'])).then_some(rest)
+    }
+}
+
+impl RouteHeaders {
+    pub fn matches(&self, path: &str) -> bool {
+        path_matches(&self.pattern, path)
+    }
+}
+
+/// a path served from a fixed file or template instead of the static directories, e.g.
+/// `{"path": "/home", "file": "./templates/index.html"}` or
+/// `{"path": "/", "template": "landing.html"}`
+#[derive(Clone, Deserialize, Debug)]
+pub struct RouteAlias {
+    pub path: String,
+    #[serde(flatten)]
+    pub target: AliasTarget,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum AliasTarget {
+    /// a file served as is; the path is relative to the working directory
+    File(String),
+    /// a template in the site's templates directory, rendered with the global context
+    Template(String),
+}
+
+/// where `/robots.txt` comes from: `{"file": "./config/robots.txt"}` or
+/// `{"content": "User-agent: *\nDisallow: /admin/\n"}`
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum RobotsTxt {
+    /// a file served as is; the path is relative to the working directory
+    File(String),
+    /// the text itself
+    Content(String),
+}
+
+/// what `/` serves when the site has no index page: `"placeholder"`, `"notfound"` or
+/// `{"redirect": "/docs/"}`
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingIndex {
+    /// a short page saying the site has nothing to show yet, with a 200
+    Placeholder,
+    /// a 404, with the page in `error_pages` for 404 if any
+    NotFound,
+    /// a temporary redirect to this location
+    Redirect(String),
+}
+
+/// where the body of an error response comes from. in `settings.json` this is
+/// `"builtin"`, `{"file": "./static/404.html"}` or `{"template": "error.html"}`.
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorPage {
+    /// the short page `Response::error` produces
+    Builtin,
+    /// a file served as is; the path is relative to the working directory
+    File(String),
+    /// a template in the site's templates directory, rendered with the global context
+    /// plus `status` ("404 Not Found") and `status_code` ("404")
+    Template(String),
+}
+
+/// whether responses of one content type are compressed, and from what size; see
+/// `Settings::compression`. both fields are optional, so `{}` compresses the type at
+/// any size and `{"enabled": false}` never does
+#[derive(Clone, Deserialize, Debug)]
+#[serde(default)]
+pub struct CompressionRule {
+    pub enabled: bool,
+    /// bodies shorter than this are sent as they are
+    pub min_bytes: usize,
+}
+
+impl Default for CompressionRule {
+    fn default() -> CompressionRule {
+        CompressionRule { enabled: true, min_bytes: 0 }
+    }
+}
+
+/// how closely request lines and headers have to follow rfc 9112. `"strict"` answers
+/// any of these with a 400; `"lenient"` accepts them as noted:
+///
+/// - a line ending in a bare `\n` instead of `\r\n` (lenient: accepted)
+/// - a request line split by something other than single spaces (lenient: any run of
+///   whitespace separates the method, target and version)
+/// - whitespace inside a header name, as in `Host : x` (lenient: trimmed)
+/// - a header line without a colon (lenient: ignored)
+/// - a line starting with whitespace, continuing the previous header (lenient: joined
+///   onto that header's value with a space)
+/// - `Content-Length` given more than once, as repeated headers or a list like `5, 5`
+///   (lenient: accepted if every value is the same; different values are refused in
+///   both modes, since the body's end would be a guess)
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ParseStrictness {
+    Strict,
+    Lenient,
+}
+
+/// a directory serving the files with some extensions in place of the static or media
+/// directory: `{"extensions": ["jpg", "png"], "dir": "/mnt/images"}`
+#[derive(Clone, Deserialize, Debug)]
+pub struct ExtensionRoot {
+    pub extensions: Vec<String>,
+    pub dir: String,
+}
+
+/// the encoding of the text files at a path pattern (a trailing `*` matches by prefix),
+/// for content that isn't utf-8: `{"path": "/legacy/*", "charset": "windows-1252"}`.
+/// with `transcode` on, the files are converted and served as utf-8; otherwise they are
+/// served as they are, labelled with `charset`. a byte order mark overrides `charset`
+#[derive(Clone, Deserialize, Debug)]
+pub struct FileCharset {
+    pub path: String,
+    pub charset: String,
+    #[serde(default)]
+    pub transcode: bool,
+}
+
+/// extensions served from the media directory rather than the static one, unless
+/// `Settings::extension_roots` lists them
+pub const MEDIA_EXTENSIONS: &[&str] = &["ico", "jpg", "jpeg", "png"];
+
+/// a site with its own content roots, chosen by the request's Host header.
+/// host names are matched case-insensitively and without the port.
+#[derive(Clone, Deserialize, Debug)]
+pub struct VirtualHost {
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    pub templates_dir: String,
+    pub static_dir: String,
+    pub media_dir: String,
+}
 
 #[derive(Clone, Deserialize, Debug)]
+#[serde(default)]
 pub struct Settings {
     pub host: String,
     pub port: u16,
     pub templates_dir: String,
     pub static_dir: String,
+    pub media_dir: String,
+    /// directories files are served from by extension, ahead of the media directory (for
+    /// `MEDIA_EXTENSIONS`) and the static directory (for the rest); the first entry
+    /// listing an extension wins, compared case-insensitively. these apply to every site,
+    /// and with the `embed` feature their directories have to be embedded as well
+    pub extension_roots: Vec<ExtensionRoot>,
+    /// pending connection queue length passed to listen(2); raise it if bursts of
+    /// connections are being refused faster than they can be accepted
+    pub listen_backlog: i32,
+    /// disables nagle's algorithm on accepted connections so small responses
+    /// go out immediately instead of waiting to be coalesced
+    pub tcp_nodelay: bool,
+    /// idle seconds before tcp keep-alive probes are sent on accepted connections;
+    /// `None` leaves the os default (usually off)
+    pub tcp_keepalive_secs: Option<u64>,
+    /// also accept connections on a unix domain socket at this path (unix only), e.g.
+    /// for a reverse proxy on the same machine. a socket file left behind by a server
+    /// that didn't shut down cleanly is removed at startup, and `serve_with_shutdown`
+    /// removes the file when it stops. peers on it have no ip address (see
+    /// `access_control::Peer::Unix`): they are only matched by `unix` in `allow_ips`,
+    /// `deny_ips` and `cache_admin_ips`, aren't limited by `max_connections_per_ip`, and
+    /// are logged as `unix`
+    pub unix_socket: Option<String>,
+    /// listen on `host` and `port`; turn it off to serve only on `unix_socket`
+    pub listen_tcp: bool,
+    /// static file extensions (without the dot) whose contents have `{{ key }}`
+    /// placeholders filled from the global context when served
+    pub inject_extensions: Vec<String>,
+    /// extra key/value pairs for the global context; these override built-ins such as `year`
+    pub global_context: HashMap<String, String>,
+    /// serve `/__debug/slow`, which waits 5 seconds before responding; for concurrency
+    /// testing only, and never available in release builds
+    pub debug_slow_endpoint: bool,
+    /// path prefixes reverse-proxied to another server, checked in order before routing.
+    /// the upstream gets `X-Forwarded-For` and `X-Forwarded-Proto` from the connection,
+    /// and has `body_read_timeout_secs` to accept it and for each read of its response;
+    /// an unreachable upstream gets the client a 502, a timed out one a 504
+    pub proxies: Vec<ProxyRoute>,
+    /// access log line written (at info level, target `access`) for every request;
+    /// see `access_log::PLACEHOLDERS` for the supported `%name` fields
+    pub access_log_format: String,
+    /// longest accepted request uri in bytes; longer request lines are refused with a 414
+    /// before the rest of the line is buffered
+    pub max_uri_bytes: usize,
+    /// seconds a connection may sit idle before its request starts; it is then closed
+    /// without a response. `None` waits forever
+    pub idle_keepalive_timeout_secs: Option<u64>,
+    /// requests served on one kept-alive connection before it is closed; 1 closes every
+    /// connection after its first response
+    pub max_requests_per_connection: usize,
+    /// seconds allowed between the first byte of a request and the end of its headers,
+    /// answered with a 408 when exceeded; `None` disables it
+    pub header_read_timeout_secs: Option<u64>,
+    /// seconds allowed for reading a request body, answered with a 408 when exceeded;
+    /// `None` disables it
+    pub body_read_timeout_secs: Option<u64>,
+    /// requests with more header lines than this are refused with a 431
+    pub max_headers: usize,
+    /// longest accepted header line in bytes, including the line ending; longer gets a 431
+    pub max_header_line_bytes: usize,
+    /// what to make of request lines and headers that bend the protocol; lenient by
+    /// default. see `ParseStrictness` for the rules
+    pub parse_strictness: ParseStrictness,
+    /// bytes read from a connection's socket at a time, and the most buffered ahead of
+    /// the request being parsed. larger means fewer reads for big heads and bodies, at
+    /// this much memory per open connection; a line longer than the buffer is put
+    /// together over several reads. values under 64 are raised to 64
+    pub read_buffer_bytes: usize,
+    /// largest accepted request body; bigger declared bodies are refused with a 413
+    /// before they are read, chunked ones as soon as they pass it
+    pub max_body_bytes: usize,
+    /// largest accepted request as a whole, request line, headers and body together; a
+    /// request that passes it while being read is refused with a 413, even when each part
+    /// is within its own limit. `None` (the default) leaves only the separate limits
+    pub max_request_bytes: Option<usize>,
+    /// path patterns (a trailing `*` matches by prefix) whose request bodies are handed
+    /// to the handler as `Request::body_stream` instead of being read into memory first;
+    /// for uploads. `max_body_bytes` and `body_read_timeout_secs` still apply.
+    pub streamed_body_paths: Vec<String>,
+    /// where `multipart::Form` keeps uploaded files until they are persisted or dropped;
+    /// `None` uses the system temporary directory
+    pub upload_temp_dir: Option<String>,
+    /// charset advertised on text responses (html, css, js, json, plain text);
+    /// an empty string leaves the charset parameter off
+    pub charset: String,
+    /// encodings of legacy text files, for those not in `charset`; the first entry
+    /// matching a path applies. transcoding supports utf-8, utf-16le, utf-16be and
+    /// windows-1252 (also as iso-8859-1, latin1 or us-ascii)
+    pub file_charsets: Vec<FileCharset>,
+    /// per-path extra response headers, e.g. long cache lifetimes for `/assets/*`
+    pub route_headers: Vec<RouteHeaders>,
+    /// path patterns (a trailing `*` matches by prefix) whose files are served with
+    /// `Content-Disposition: attachment`, so browsers download them instead of showing
+    /// them. any static file can also be downloaded by adding `?download` to its url.
+    pub download_paths: Vec<String>,
+    /// path patterns (a trailing `*` matches by prefix) whose error responses have a json
+    /// body, `{"error": "Not Found", "status": 404}` (the status's reason phrase and
+    /// code), instead of an html page. requests whose `Accept` header names json ahead of
+    /// html get one on any path. only the built-in body is replaced, as for `error_pages`
+    pub json_error_paths: Vec<String>,
+    /// exact paths answered from a chosen file or template, checked before the index
+    /// page and static files; routes added to the `App` still take precedence
+    pub aliases: Vec<RouteAlias>,
+    /// file names tried in order for the index page (in the templates directory) and
+    /// for a static path ending in `/` (in the static directory); the first that exists
+    /// is served as html
+    pub index_files: Vec<String>,
+    /// what `/` serves when none of `index_files` exists in the templates directory,
+    /// logging an error each time. `None` (the default) refuses to start without an
+    /// index page, and answers a 500 if it goes missing later; otherwise a missing
+    /// index page at startup is only a warning
+    pub missing_index: Option<MissingIndex>,
+    /// an `.ico`, `.png` or `.jpg` file (relative to the working directory) served as
+    /// `/favicon.ico`, with a day long `Cache-Control`. `None` looks for it in the media
+    /// directory like any other file
+    pub favicon: Option<String>,
+    /// what `/robots.txt` serves, cached like `favicon`; `None` looks for it in the static
+    /// directory like any other file
+    pub robots_txt: Option<RobotsTxt>,
+    /// when a static or media file isn't found, look for one whose path differs only
+    /// in case (`/Photo.JPG` for `/photo.jpg`). off by default since filesystems
+    /// disagree on case; the directory listing it scans is cached outside dev mode.
+    pub case_insensitive_static: bool,
+    /// serve `style.css.br` in place of `style.css` to clients whose `Accept-Encoding`
+    /// allows `br`, with `Content-Encoding: br`. only files `compression` allows are
+    /// swapped, and not ones listed in `inject_extensions`; the `.br` files have to be
+    /// made beforehand (e.g. `brotli -k static/*.css`); see `brotli_quality` for
+    /// compressing the rest on the fly.
+    pub precompressed_br: bool,
+    /// compress responses on the fly with brotli, at this quality (0 to 11; higher is
+    /// smaller but costs more cpu), for clients whose `Accept-Encoding` allows `br`. only
+    /// types and sizes `compression` allows are compressed, and a response already
+    /// encoded (e.g. from a `.br` file with `precompressed_br`) is left alone. off by
+    /// default
+    pub brotli_quality: Option<u32>,
+    /// serve `app.js` from `app.js.gz` when only the compressed file exists: as it is, with
+    /// `Content-Encoding: gzip`, to clients whose `Accept-Encoding` allows `gzip`, and
+    /// decompressed for the rest (kept in memory once done, outside dev mode). the content
+    /// type comes from the plain name's extension. off by default
+    pub gzip_only_files: bool,
+    /// the most a `gzip_only_files` file may decompress to; a larger one (or a gzip bomb)
+    /// is answered with a 500 for clients that need it decompressed, rather than filling
+    /// memory
+    pub max_decompressed_bytes: usize,
+    /// which content types are sent compressed, by mime type without parameters, e.g.
+    /// `{"application/json": {}, "text/css": {"min_bytes": 4096}}`. a type missing from
+    /// the table isn't compressed, and a table given here replaces the default one, which
+    /// has html, css, javascript, json, plain text and svg from 256 bytes up
+    pub compression: HashMap<String, CompressionRule>,
+    /// give `200` responses to `GET` and `HEAD` an `ETag` and answer a matching
+    /// `If-None-Match` with a 304 (see `middleware::conditional_get`). a static file swapped
+    /// for its `.br` gets a weak tag, the one its plain version has, since it holds the
+    /// same content in other bytes. off by default, since each response body is hashed
+    pub etags: bool,
+    /// serve `.css` and `.js` files with comments and needless whitespace removed (see
+    /// `minify`). each file is minified once and the result kept, outside dev mode.
+    /// files listed in `inject_extensions` are left alone.
+    pub minify_static: bool,
+    /// pick the content type of a static or media file whose extension isn't a known
+    /// one (or that has none) from its first bytes: png, jpeg, gif, webp, pdf and ico
+    /// signatures, html, plain text, or else `application/octet-stream`. off by default,
+    /// which serves such files as html. a known extension is always believed
+    pub sniff_content_type: bool,
+    /// static and media files of a binary type (images, pdfs and the like) at least this
+    /// many bytes long are streamed from disk as the response is written, instead of
+    /// being read into memory first (see `Response::file`); on linux with sendfile(2), so
+    /// the bytes never pass through userspace. files in the content cache are still
+    /// served from it. `None` (the default) reads every file whole
+    pub stream_min_bytes: Option<u64>,
+    /// error page to use per status code, e.g. `{"404": {"file": "./static/404.html"}}`.
+    /// only error responses with the built-in body are replaced, and a page that can't
+    /// be read or rendered falls back to the built-in one. unlisted codes use the built-in.
+    pub error_pages: HashMap<i32, ErrorPage>,
+    /// what an included template renders as when it fails (see
+    /// `TemplateCache::contain_partial_errors`), e.g. `""`, so the rest of the page still
+    /// renders; the failure is logged. `None` (the default) renders the misses in place as
+    /// usual. dev mode shows the error regardless
+    pub partial_error_placeholder: Option<String>,
+    /// milliseconds a template render may take before it is cut off and answered with a
+    /// 500, logging the template (see `TemplateCache::render_timeout`), so a template
+    /// that loops or recurses pathologically can't tie up the server. `None` lets
+    /// renders run as long as they take
+    pub render_timeout_ms: Option<u64>,
+    /// keep files in memory once read: the content cache (the index page, `favicon`,
+    /// `robots_txt` and `preload_cache`), minified css and js, directory listings, error
+    /// pages and recent misses. off, every request reads from disk, as in dev mode;
+    /// compiled templates are still kept. on by default
+    pub cache_enabled: bool,
+    /// read every file under the template, static and media directories into the
+    /// content cache at startup, warning about any that can't be read. off by default
+    /// since it holds the whole tree in memory.
+    pub preload_cache: bool,
+    /// milliseconds a request waits for the content cache before reading the file from
+    /// disk instead, with a warning, so a stuck cache slows the site down rather than
+    /// stopping it. `None` waits as long as it takes
+    pub cache_lock_timeout_ms: Option<u64>,
+    /// serve `/__cache` for looking after the content cache: `GET` lists the cached files
+    /// and their sizes as json, `POST` empties the cache (see `ViewContext::flush_cache`)
+    /// and `POST /__cache?key=./static/app.css` drops one file. off by default
+    pub cache_admin: bool,
+    /// ip addresses or cidr ranges allowed to use `/__cache`; everyone else gets a 403,
+    /// and so does everyone when this is empty. only localhost by default; `unix` admits
+    /// `unix_socket` peers, which aren't localhost
+    pub cache_admin_ips: Vec<String>,
+    /// seconds a path that wasn't found on disk is remembered as missing, so repeated
+    /// requests for it (such as scans for common exploit paths) don't reach the
+    /// filesystem. a file created meanwhile shows up once this runs out; `None` always
+    /// checks the disk, as does dev mode
+    pub missing_file_ttl_secs: Option<u64>,
+    /// additional sites served by host name. requests with a missing or unknown
+    /// Host header are served from the top-level directories above.
+    pub vhosts: Vec<VirtualHost>,
+    /// ip addresses or cidr ranges (ipv4 or ipv6) allowed to connect; empty allows everyone.
+    /// `unix` allows `unix_socket` peers, which a non-empty list otherwise refuses
+    pub allow_ips: Vec<String>,
+    /// connections one ip address may hold open at once; more are answered with a 503
+    /// and closed. `None` (the default) doesn't limit them, since clients behind a shared
+    /// proxy or nat all arrive from one address
+    pub max_connections_per_ip: Option<usize>,
+    /// log the connections open and accepted so far (target `connections`) every this
+    /// many seconds, e.g. to watch for leaks or saturation against the limits. `None`
+    /// (the default) doesn't; handlers can read them from `ViewContext::connections`
+    pub connection_stats_secs: Option<u64>,
+    /// templates `ViewContext::render` renders at once. rendering keeps a cpu busy, so
+    /// renders beyond this wait their turn rather than crowding out the threads serving
+    /// other requests. `None` (the default) allows one per cpu core
+    pub max_concurrent_renders: Option<usize>,
+    /// seconds `serve_with_shutdown` waits for tasks started with `ViewContext::spawn`
+    /// once it is told to stop; any still running then are abandoned
+    pub shutdown_timeout_secs: u64,
+    /// while this file exists the site is down for maintenance: routed requests get a
+    /// 503 (the page in `error_pages` for 503, if any) with `Retry-After`. create or
+    /// delete it to switch maintenance on or off without a restart; it is looked for
+    /// at most once a second
+    pub maintenance_file: Option<String>,
+    /// paths still served during maintenance, such as a health check that an
+    /// orchestrator polls; patterns as in `route_headers`
+    pub maintenance_exempt_paths: Vec<String>,
+    /// seconds clients are told to wait before retrying during maintenance
+    pub maintenance_retry_after_secs: u64,
+    /// idle buffers kept for assembling response heads, so busy servers reuse them
+    /// instead of allocating per request; 0 turns pooling off
+    pub buffer_pool_size: usize,
+    /// pooled buffers that grew larger than this many bytes are freed rather than kept
+    pub buffer_pool_max_bytes: usize,
+    /// log request and response bodies (at info level, target `body`) for debugging.
+    /// bodies often carry passwords, tokens and personal data, so this is off by default
+    /// and shouldn't be turned on in production. streamed bodies aren't logged.
+    pub log_bodies: bool,
+    /// logged bodies are cut to this many bytes
+    pub log_body_max_bytes: usize,
+    /// header names, and form or json field names, whose values are logged as
+    /// `[redacted]`; compared ignoring case
+    pub log_redact: Vec<String>,
+    /// requests whose handling takes longer than this many milliseconds are logged with a
+    /// warning (target `slow`), apart from the access log. `None` (the default) never warns
+    pub slow_request_ms: Option<u64>,
+    /// development mode, also turned on by running the server with `--dev`: nothing is
+    /// cached, the content directories are watched for changes, and html responses get a
+    /// script that reloads the page when they do. never enable this in production.
+    pub dev_mode: bool,
+    /// ip addresses or cidr ranges refused with a 403 (`unix` for `unix_socket` peers);
+    /// checked before `allow_ips`
+    pub deny_ips: Vec<String>,
+    /// where these settings were read from, so a running server can read them again on
+    /// SIGHUP. set by `load_profile` rather than by the file
+    #[serde(skip)]
+    pub source: Option<SettingsSource>,
+}
+
+/// the settings file and profile a `Settings` was loaded from
+#[derive(Clone, Debug)]
+pub struct SettingsSource {
+    pub file: String,
+    pub profile: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            host: String::from("127.0.0.1"),
+            port: 7878,
+            templates_dir: String::from("./templates"),
+            static_dir: String::from("./static"),
+            media_dir: String::from("./media"),
+            extension_roots: Vec::new(),
+            listen_backlog: 1024,
+            tcp_nodelay: true,
+            tcp_keepalive_secs: None,
+            unix_socket: None,
+            listen_tcp: true,
+            inject_extensions: Vec::new(),
+            global_context: HashMap::new(),
+            debug_slow_endpoint: false,
+            proxies: Vec::new(),
+            access_log_format: String::from(access_log::DEFAULT_FORMAT),
+            max_uri_bytes: 8192,
+            idle_keepalive_timeout_secs: Some(30),
+            max_requests_per_connection: 100,
+            header_read_timeout_secs: Some(10),
+            body_read_timeout_secs: Some(30),
+            max_headers: 100,
+            max_header_line_bytes: 8192,
+            parse_strictness: ParseStrictness::Lenient,
+            read_buffer_bytes: 8192,
+            max_body_bytes: 10 * 1024 * 1024,
+            max_request_bytes: None,
+            streamed_body_paths: Vec::new(),
+            upload_temp_dir: None,
+            charset: String::from("utf-8"),
+            file_charsets: Vec::new(),
+            route_headers: Vec::new(),
+            download_paths: Vec::new(),
+            json_error_paths: Vec::new(),
+            aliases: Vec::new(),
+            index_files: vec![String::from("index.html")],
+            missing_index: None,
+            favicon: None,
+            robots_txt: None,
+            case_insensitive_static: false,
+            precompressed_br: false,
+            brotli_quality: None,
+            gzip_only_files: false,
+            max_decompressed_bytes: 32 * 1024 * 1024,
+            compression: default_compression(),
+            etags: false,
+            minify_static: false,
+            sniff_content_type: false,
+            stream_min_bytes: None,
+            error_pages: HashMap::new(),
+            partial_error_placeholder: None,
+            render_timeout_ms: Some(5000),
+            cache_enabled: true,
+            preload_cache: false,
+            cache_lock_timeout_ms: Some(1000),
+            cache_admin: false,
+            cache_admin_ips: vec![String::from("127.0.0.1"), String::from("::1")],
+            missing_file_ttl_secs: Some(5),
+            vhosts: Vec::new(),
+            buffer_pool_size: 64,
+            buffer_pool_max_bytes: 16 * 1024,
+            log_bodies: false,
+            log_body_max_bytes: 2048,
+            log_redact: ["authorization", "proxy-authorization", "cookie", "set-cookie", "password", "token"]
+                .map(String::from).to_vec(),
+            slow_request_ms: None,
+            dev_mode: false,
+            allow_ips: Vec::new(),
+            max_connections_per_ip: None,
+            connection_stats_secs: None,
+            max_concurrent_renders: None,
+            shutdown_timeout_secs: 30,
+            maintenance_file: None,
+            maintenance_exempt_paths: Vec::new(),
+            maintenance_retry_after_secs: 300,
+            deny_ips: Vec::new(),
+            source: None,
+        }
+    }
+}
+
+fn default_compression() -> HashMap<String, CompressionRule> {
+    ["text/html", "text/css", "text/javascript", "application/json", "text/plain", "image/svg+xml"]
+        .into_iter()
+        .map(|mime| (mime.to_string(), CompressionRule { enabled: true, min_bytes: 256 }))
+        .collect()
 }
 
 impl Settings {
+    /// whether a `len` byte body of `content_type` may be sent compressed, per `compression`
+    pub fn compresses(&self, content_type: &http::HttpContentType, len: usize) -> bool {
+        self.compresses_mime(&content_type.to_string(), len)
+    }
+
+    // `compresses` for a mime type given as text, without parameters
+    pub(crate) fn compresses_mime(&self, mime: &str, len: usize) -> bool {
+        self.compression.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(mime))
+            .is_some_and(|(_, rule)| rule.enabled && len >= rule.min_bytes)
+    }
+
+    /// the site serving `host` (a Host header value), falling back to the default site
+    pub fn vhost(&self, host: Option<&str>) -> VirtualHost {
+        let name = host.map(|h| match h.rsplit_once(':') {
+            Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name,
+            _ => h,
+        });
+        name.and_then(|name| {
+            self.vhosts.iter().find(|v| v.hosts.iter().any(|h| h.eq_ignore_ascii_case(name)))
+        }).cloned().unwrap_or_else(|| self.default_vhost())
+    }
+
+    /// the directory `site` serves files with `extension` from; see `extension_roots`
+    pub fn root_for<'a>(&'a self, site: &'a VirtualHost, extension: &str) -> &'a str {
+        let media = MEDIA_EXTENSIONS.iter().map(|ext| (*ext, site.media_dir.as_str()));
+        self.extension_roots.iter()
+            .flat_map(|root| root.extensions.iter().map(|ext| (ext.trim_start_matches('.'), root.dir.as_str())))
+            .chain(media)
+            .find(|(ext, _)| ext.eq_ignore_ascii_case(extension))
+            .map_or(&site.static_dir, |(_, dir)| dir)
+    }
+
+    /// the entry of `file_charsets` for the file at `path`
+    pub fn file_charset(&self, path: &str) -> Option<&FileCharset> {
+        self.file_charsets.iter().find(|entry| path_matches(&entry.path, path))
+    }
+
+    /// whether files read from disk may be kept in memory; see `cache_enabled`
+    pub fn caches_content(&self) -> bool {
+        self.cache_enabled && !self.dev_mode
+    }
+
+    pub fn default_vhost(&self) -> VirtualHost {
+        VirtualHost {
+            hosts: Vec::new(),
+            templates_dir: self.templates_dir.clone(),
+            static_dir: self.static_dir.clone(),
+            media_dir: self.media_dir.clone(),
+        }
+    }
+
+    /// reads `filename`, overlaid with the profile named by the `FERROPRESS_ENV`
+    /// environment variable when it is set (see `load_profile`)
     pub fn load_from_file(filename: &str) -> Result<Settings, Box<dyn std::error::Error>> {
+        let profile = std::env::var("FERROPRESS_ENV").ok().filter(|profile| !profile.is_empty());
+        Settings::load_profile(filename, profile.as_deref())
+    }
+
+    /// reads `filename` and, for a `profile` such as `prod`, the overlay next to it named
+    /// after the profile (`settings.prod.json` for `settings.json`). the overlay only
+    /// needs the fields that differ: its values replace the base file's field by field,
+    /// objects such as `global_context` or `error_pages` are merged key by key the same
+    /// way, and lists and other values are replaced whole (`null` resets an optional
+    /// field). a profile without an overlay file is an error.
+    pub fn load_profile(filename: &str, profile: Option<&str>) -> Result<Settings, Box<dyn std::error::Error>> {
         let settings_content = fs::read_to_string(filename)?;
-        let settings: Settings = serde_json::from_str(&settings_content)?;
+        let mut settings: serde_json::Value = serde_json::from_str(&settings_content)?;
+        if let Some(profile) = profile {
+            let path = Path::new(filename);
+            let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or_default();
+            let name = match path.extension().and_then(|ext| ext.to_str()) {
+                Some(ext) => format!("{}.{}.{}", stem, profile, ext),
+                None => format!("{}.{}", stem, profile),
+            };
+            let overlay_path = path.with_file_name(name);
+            let overlay_content = fs::read_to_string(&overlay_path)
+                .map_err(|e| format!("can't read settings profile {}: {}", overlay_path.display(), e))?;
+            merge_settings(&mut settings, serde_json::from_str(&overlay_content)?);
+        }
+        let mut settings: Settings = serde_json::from_value(settings)?;
+        settings.source = Some(SettingsSource { file: filename.to_string(), profile: profile.map(String::from) });
         Ok(settings)
     }
 }
 
+// lays `overlay` over `base`: objects are merged key by key, anything else replaces
+fn merge_settings(base: &mut serde_json::Value, overlay: serde_json::Value) {
+    match (base, overlay) {
+        (serde_json::Value::Object(base), serde_json::Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_settings(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    },
+                }
+            }
+        },
+        (base, overlay) => *base = overlay,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use super::*;
+
+    // a settings file (and its overlays) in a dir of its own under the system temp dir
+    fn settings_dir(name: &str, files: &[(&str, &str)]) -> String {
+        let dir = env::temp_dir().join(format!("ferropress-settings-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (file, contents) in files {
+            fs::write(dir.join(file), contents).unwrap();
+        }
+        dir.join("settings.json").to_string_lossy().into_owned()
+    }
+
+    const BASE: &str = r#"{"port": 8080, "dev_mode": true, "index_files": ["index.html", "index.htm"],
+        "global_context": {"site": "base", "owner": "ops"}}"#;
+
+    #[test]
+    fn a_base_file_loads_on_its_own() {
+        let filename = settings_dir("base", &[("settings.json", BASE)]);
+        let settings = Settings::load_profile(&filename, None).unwrap();
+        assert_eq!(settings.port, 8080);
+        assert!(settings.dev_mode);
+        assert_eq!(settings.global_context["site"], "base");
+        assert!(settings.source.unwrap().profile.is_none());
+    }
+
+    #[test]
+    fn an_overlay_replaces_only_what_it_names() {
+        let overlay = r#"{"dev_mode": false, "index_files": ["home.html"], "global_context": {"site": "prod"}}"#;
+        let filename = settings_dir("overlay", &[("settings.json", BASE), ("settings.prod.json", overlay)]);
+        let settings = Settings::load_profile(&filename, Some("prod")).unwrap();
+        assert_eq!(settings.port, 8080);
+        assert!(!settings.dev_mode);
+        assert_eq!(settings.index_files, ["home.html"]);
+        assert_eq!(settings.global_context["site"], "prod");
+        assert_eq!(settings.global_context["owner"], "ops");
+        assert_eq!(settings.source.unwrap().profile.as_deref(), Some("prod"));
+    }
+
+    #[test]
+    fn a_profile_without_an_overlay_is_an_error() {
+        let filename = settings_dir("missing", &[("settings.json", BASE)]);
+        let e = Settings::load_profile(&filename, Some("staging")).unwrap_err();
+        assert!(e.to_string().contains("settings.staging.json"), "{}", e);
+    }
+
+    #[test]
+    fn merging_replaces_lists_and_resets_with_null() {
+        let mut base = serde_json::json!({"a": [1, 2], "b": {"c": 1, "d": 2}, "e": 5});
+        merge_settings(&mut base, serde_json::json!({"a": [3], "b": {"d": 4}, "e": null}));
+        assert_eq!(base, serde_json::json!({"a": [3], "b": {"c": 1, "d": 4}, "e": null}));
+    }
+
+    #[test]
+    fn hosts_are_matched_without_their_port() {
+        let site = VirtualHost {
+            hosts: vec![String::from("blog.example"), String::from("[::1]")],
+            templates_dir: String::from("blog/templates"), static_dir: String::from("blog/static"), media_dir: String::from("blog/media"),
+        };
+        let settings = Settings { vhosts: vec![site], ..Settings::default() };
+        for host in ["blog.example", "Blog.Example", "blog.example:8080", "[::1]", "[::1]:8080"] {
+            assert_eq!(settings.vhost(Some(host)).static_dir, "blog/static", "{}", host);
+        }
+        for host in [None, Some("blog.example.org"), Some("blog.example:http"), Some("")] {
+            assert_eq!(settings.vhost(host).static_dir, settings.static_dir, "{:?}", host);
+        }
+    }
+
+    #[test]
+    fn extension_roots_come_before_the_media_and_static_directories() {
+        let root = |extensions: &[&str], dir: &str| ExtensionRoot {
+            extensions: extensions.iter().map(|ext| ext.to_string()).collect(), dir: dir.into(),
+        };
+        let settings = Settings {
+            extension_roots: vec![root(&["jpg", ".PNG"], "/mnt/images"), root(&["css", "js", "png"], "/srv/assets")],
+            ..Settings::default()
+        };
+        let site = settings.vhost(None);
+        for (extension, dir) in [
+            ("jpg", "/mnt/images"), ("JPG", "/mnt/images"), ("png", "/mnt/images"),
+            ("css", "/srv/assets"), ("js", "/srv/assets"),
+            // the rest keep the media/static split
+            ("ico", settings.media_dir.as_str()), ("txt", settings.static_dir.as_str()),
+        ] {
+            assert_eq!(settings.root_for(&site, extension), dir, "{}", extension);
+        }
+    }
+}
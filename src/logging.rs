@@ -0,0 +1,141 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use chrono::{SecondsFormat, Utc};
+use log::Record;
+
+
+/// environment variable choosing the log format; `json` writes one json object per line
+pub const FORMAT_VAR: &str = "FERROPRESS_LOG_FORMAT";
+
+// whether lines are json, and so whether the request being served is tracked for them
+static JSON: AtomicBool = AtomicBool::new(false);
+
+// what is known about the request a connection's task is serving
+#[derive(Default)]
+struct RequestInfo {
+    id: String,
+    method: Option<String>,
+    path: Option<String>,
+    status: Option<i32>,
+}
+
+async_std::task_local! {
+    static REQUEST: RefCell<Option<RequestInfo>> = RefCell::new(None);
+}
+
+/// starts `env_logger`, filtered by `RUST_LOG` as usual. with `FERROPRESS_LOG_FORMAT=json`
+/// each line is a json object instead:
+/// `{"timestamp": "...", "level": "INFO", "target": "access", "message": "...", "request_id": "0000002a", "method": "GET", "path": "/", "status": 200}`.
+/// the request fields are there for lines logged while a request is being served, as
+/// far as they are known; `status` only once the response is written.
+pub fn init() {
+    let json = std::env::var(FORMAT_VAR).is_ok_and(|format| format.eq_ignore_ascii_case("json"));
+    let mut builder = env_logger::Builder::from_default_env();
+    if json {
+        builder.format(|buf, record| writeln!(buf, "{}", json_line(record)));
+        JSON.store(true, Ordering::Relaxed);
+    }
+    builder.init();
+}
+
+fn json_line(record: &Record) -> serde_json::Value {
+    let mut line = serde_json::json!({
+        "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        "level": record.level().as_str(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    });
+    // outside a task (or once its locals are gone) there is no request to describe
+    let _ = REQUEST.try_with(|request| {
+        let Some(request) = &*request.borrow() else {
+            return;
+        };
+        line["request_id"] = request.id.clone().into();
+        if let Some(method) = &request.method {
+            line["method"] = method.clone().into();
+        }
+        if let Some(path) = &request.path {
+            line["path"] = path.clone().into();
+        }
+        if let Some(status) = request.status {
+            line["status"] = status.into();
+        }
+    });
+    line
+}
+
+// updates the current task's request, when json lines need it
+fn update(f: impl FnOnce(&mut Option<RequestInfo>)) {
+    if JSON.load(Ordering::Relaxed) {
+        let _ = REQUEST.try_with(|request| f(&mut request.borrow_mut()));
+    }
+}
+
+// the connection's task has read a new request
+pub(crate) fn begin_request(id: &str, method: &str, path: &str) {
+    update(|request| *request = Some(RequestInfo {
+        id: id.to_string(),
+        method: Some(method.to_string()),
+        path: Some(path.to_string()),
+        status: None,
+    }));
+}
+
+// the request has been answered with `status`; requests refused before they could be
+// read are only known by their id
+pub(crate) fn set_status(id: &str, status: Option<i32>) {
+    update(|request| {
+        let request = request.get_or_insert_with(|| RequestInfo { id: id.to_string(), ..Default::default() });
+        request.status = status;
+    });
+}
+
+pub(crate) fn end_request() {
+    update(|request| *request = None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    // a line for a warning logged now, written out and read back
+    fn logged_line() -> serde_json::Value {
+        let line = json_line(&Record::builder().args(format_args!("said \"hi\"\nthen left")).level(Level::Warn).target("access").build());
+        let written = line.to_string();
+        assert!(!written.contains('\n'), "{}", written);
+        serde_json::from_str(&written).unwrap()
+    }
+
+    #[test]
+    fn a_line_outside_a_request_has_just_the_record() {
+        let line = logged_line();
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["target"], "access");
+        assert_eq!(line["message"], "said \"hi\"\nthen left");
+        let timestamp = line["timestamp"].as_str().unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(timestamp).is_ok(), "{}", timestamp);
+        assert_eq!(line.as_object().unwrap().len(), 4, "{}", line);
+    }
+
+    #[test]
+    fn a_line_inside_a_request_describes_it() {
+        JSON.store(true, Ordering::Relaxed);
+        let (reading, answered, after) = async_std::task::block_on(async {
+            begin_request("0000002a", "GET", "/about");
+            let reading = logged_line();
+            set_status("0000002a", Some(404));
+            let answered = logged_line();
+            end_request();
+            (reading, answered, logged_line())
+        });
+        assert_eq!(reading["request_id"], "0000002a");
+        assert_eq!(reading["method"], "GET");
+        assert_eq!(reading["path"], "/about");
+        assert!(reading.get("status").is_none(), "{}", reading);
+        assert_eq!(answered["status"], 404);
+        assert_eq!(answered["message"], "said \"hi\"\nthen left");
+        assert!(after.get("request_id").is_none(), "{}", after);
+    }
+}
@@ -0,0 +1,344 @@
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use base64::Engine;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use log::{error, info, warn};
+use crate::app::{Handler, ViewContext};
+use crate::dev;
+use crate::http::{self, EntityTag, HttpHeader, HttpMethod, HttpStatus, Request, Response};
+use crate::path_matches;
+use crate::views::accepts_encoding;
+
+
+/// code that wraps every routed request, e.g. for logging, auth or extra headers.
+/// middleware run in the order they were added to the `App`: the first one added sees
+/// the request first and the response last. each decides whether to pass the request
+/// on with `next.run(request, ctx)`, or to answer it itself (short-circuiting the rest
+/// of the chain and the handler). the route is chosen before the chain runs.
+///
+/// implemented for every `async fn(Request, ViewContext, Next) -> Response`.
+pub trait Middleware: Send + Sync + 'static {
+    fn call<'a>(&'a self, request: Request, ctx: ViewContext, next: Next) -> BoxFuture<'a, Response>;
+}
+
+impl<F, Fut> Middleware for F
+where
+    F: Fn(Request, ViewContext, Next) -> Fut + Send + Sync + 'static,
+    Fut: std::future::Future<Output = Response> + Send + 'static,
+{
+    fn call<'a>(&'a self, request: Request, ctx: ViewContext, next: Next) -> BoxFuture<'a, Response> {
+        Box::pin(self(request, ctx, next))
+    }
+}
+
+pub(crate) type Chain = Arc<Vec<Arc<dyn Middleware>>>;
+
+/// the rest of the middleware chain, ending with the route's handler
+pub struct Next {
+    chain: Chain,
+    index: usize,
+    handler: Arc<dyn Handler>,
+}
+
+impl Next {
+    pub(crate) fn new(chain: Chain, handler: Arc<dyn Handler>) -> Next {
+        Next { chain, index: 0, handler }
+    }
+
+    /// passes the request to the next middleware, or to the handler after the last one
+    pub async fn run(mut self, request: Request, ctx: ViewContext) -> Response {
+        let Some(middleware) = self.chain.get(self.index).cloned() else {
+            // a panicking handler becomes a 500 that still goes back through the chain
+            let (method, path, peer) = (request.method.clone(), request.path.clone(), ctx.peer);
+            return match AssertUnwindSafe(self.handler.call(request, ctx)).catch_unwind().await {
+                Ok(response) => response,
+                Err(panic) => {
+                    error!("handler panicked on {} {} from {}: {}", method, path, peer, panic_message(&panic));
+                    Response::error(500)
+                },
+            };
+        };
+        self.index += 1;
+        middleware.call(request, ctx, self).await
+    }
+}
+
+pub(crate) fn panic_message(panic: &Box<dyn std::any::Any + Send>) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+/// logs each routed request with its status and how long the rest of the chain took,
+/// and warns (target `slow`) about ones taking longer than `Settings::slow_request_ms`
+pub async fn log_requests(request: Request, ctx: ViewContext, next: Next) -> Response {
+    let (method, path, peer) = (request.method.clone(), request.path.clone(), ctx.peer);
+    let slow_ms = ctx.settings.slow_request_ms;
+    info!("{} {} from {}", method, path, peer);
+    let started = Instant::now();
+    let response = next.run(request, ctx).await;
+    let elapsed = started.elapsed().as_millis();
+    info!("{} {} from {}: {} in {}ms", method, path, peer, response.status.code(), elapsed);
+    if slow_ms.is_some_and(|ms| elapsed > u128::from(ms)) {
+        warn!(target: "slow", "{} {} from {} took {}ms ({})", method, path, peer, elapsed, response.status.code());
+    }
+    response
+}
+
+/// applies `Settings::route_headers` to responses whose path matches
+pub async fn route_headers(request: Request, ctx: ViewContext, next: Next) -> Response {
+    let path = request.path.split_once('?').map_or(&request.path[..], |(path, _)| path).to_string();
+    let settings = Arc::clone(&ctx.settings);
+    let mut response = next.run(request, ctx).await;
+    for route_headers in settings.route_headers.iter().filter(|r| r.matches(&path)) {
+        for (name, value) in route_headers.headers.iter() {
+            response.set_header(name, value);
+        }
+    }
+    response
+}
+
+/// answers routed requests with a 503 while `Settings::maintenance_file` exists,
+/// except those on `Settings::maintenance_exempt_paths`. added by `App::new`.
+#[derive(Default)]
+pub struct Maintenance {
+    // when the file was last looked for, and whether it was there
+    checked: Mutex<Option<(Instant, bool)>>,
+}
+
+impl Maintenance {
+    fn active(&self, file: &str) -> bool {
+        let mut checked = self.checked.lock().unwrap_or_else(|e| e.into_inner());
+        match *checked {
+            Some((at, active)) if at.elapsed() < Duration::from_secs(1) => active,
+            previous => {
+                let active = Path::new(file).exists();
+                if previous.map_or(active, |(_, was)| was != active) {
+                    info!("maintenance mode {}", if active { "on" } else { "off" });
+                }
+                *checked = Some((Instant::now(), active));
+                active
+            },
+        }
+    }
+}
+
+impl Middleware for Maintenance {
+    fn call<'a>(&'a self, request: Request, ctx: ViewContext, next: Next) -> BoxFuture<'a, Response> {
+        Box::pin(async move {
+            let settings = &ctx.settings;
+            let path = request.path.split_once('?').map_or(&request.path[..], |(path, _)| path);
+            let exempt = settings.maintenance_exempt_paths.iter().any(|pattern| path_matches(pattern, path));
+            match &settings.maintenance_file {
+                Some(file) if !exempt && self.active(file) => {
+                    let mut response = Response::error(503);
+                    response.set_header("Retry-After", &settings.maintenance_retry_after_secs.to_string());
+                    response
+                },
+                _ => next.run(request, ctx).await,
+            }
+        })
+    }
+}
+
+/// with `Settings::etags`, gives `200` responses to `GET` and `HEAD` an `ETag` (a hash
+/// of the body, unless the handler set one) and answers a request whose `If-None-Match`
+/// matches it with a bodiless `304 Not Modified`. added by `App::new`.
+pub async fn conditional_get(request: Request, ctx: ViewContext, next: Next) -> Response {
+    let enabled = ctx.settings.etags && matches!(request.method, HttpMethod::Get | HttpMethod::Head);
+    let if_none_match = request.headers.get("if-none-match").cloned();
+    let mut response = next.run(request, ctx).await;
+    if !enabled || response.status.code() != 200 {
+        return response;
+    }
+    let etag = match response.header("etag").and_then(EntityTag::parse) {
+        Some(etag) => etag,
+        // a `Response::file` body isn't here to hash
+        None if response.is_file() => return response,
+        None => {
+            let etag = EntityTag::of(&response.contents);
+            response.set_header("ETag", &etag.to_string());
+            etag
+        },
+    };
+    if if_none_match.is_some_and(|header| http::if_none_match(&header, &etag)) {
+        // the headers (`ETag`, `Cache-Control`, `Vary`) still describe the client's copy
+        response.status = HttpStatus::HttpOk(304);
+        response.contents = Arc::default();
+    }
+    response
+}
+
+/// with `Settings::brotli_quality`, compresses `200` responses with brotli for clients
+/// that accept `br`, for the types and sizes `Settings::compression` allows. runs
+/// outside `conditional_get`, so an `ETag` it computed for the plain body is weakened.
+/// added by `App::new`.
+pub async fn compress(request: Request, ctx: ViewContext, next: Next) -> Response {
+    let accepts_br = request.headers.get("accept-encoding").is_some_and(|header| accepts_encoding(header, "br"));
+    let settings = Arc::clone(&ctx.settings);
+    let mut response = next.run(request, ctx).await;
+    let Some(quality) = settings.brotli_quality else {
+        return response;
+    };
+    // a `Response::file` body isn't here to compress, and an encoded one already is
+    if response.status.code() != 200 || response.is_file() || response.header("content-encoding").is_some() {
+        return response;
+    }
+    let mime = response.headers.iter().flatten().find_map(|header| match header {
+        HttpHeader::ContentType(content_type) => Some(content_type.to_string()),
+        HttpHeader::Custom(name, value) if name.eq_ignore_ascii_case("content-type") => {
+            Some(value.split(';').next().unwrap_or_default().trim().to_string())
+        },
+        _ => None,
+    });
+    if !mime.is_some_and(|mime| settings.compresses_mime(&mime, response.contents.len())) {
+        return response;
+    }
+    response.add_vary("Accept-Encoding");
+    if !accepts_br {
+        return response;
+    }
+    let mut compressed = Vec::new();
+    {
+        let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, quality, 22);
+        if let Err(e) = std::io::Write::write_all(&mut writer, &response.contents) {
+            warn!("brotli compression failed: {}", e);
+            return response;
+        }
+    }
+    if let Some(etag) = response.header("etag").and_then(EntityTag::parse) {
+        response.set_header("ETag", &EntityTag { weak: true, ..etag }.to_string());
+    }
+    response.contents = Arc::new(compressed);
+    response.set_header("Content-Encoding", "br");
+    response
+}
+
+/// in dev mode, adds the auto-reload script to html responses; see `dev::RELOAD_SCRIPT`
+pub async fn dev_reload(request: Request, ctx: ViewContext, next: Next) -> Response {
+    let dev_mode = ctx.settings.dev_mode;
+    let mut response = next.run(request, ctx).await;
+    // a compressed body can't be edited, and a `Response::file` one isn't here yet
+    let encoded = response.is_file() || response.header("content-encoding").is_some();
+    if dev_mode && response.is_html() && !encoded {
+        response.contents = Arc::new(dev::inject_reload_script(&response.contents));
+    }
+    response
+}
+
+/// answers cross-origin requests from the listed origins (`*` allows any). preflight
+/// `OPTIONS` requests are answered directly with a 204; other requests get
+/// `Access-Control-Allow-Origin` added to their response. requests from other
+/// origins are passed through untouched, so browsers refuse them.
+pub struct Cors {
+    origins: Vec<String>,
+    methods: String,
+    max_age_secs: u64,
+}
+
+impl Cors {
+    pub fn new(origins: &[&str]) -> Cors {
+        Cors {
+            origins: origins.iter().map(|o| o.to_string()).collect(),
+            methods: String::from("GET, POST, PUT, PATCH, DELETE, OPTIONS"),
+            max_age_secs: 86400,
+        }
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.origins.iter().any(|o| o == "*" || o == origin)
+    }
+}
+
+impl Middleware for Cors {
+    fn call<'a>(&'a self, request: Request, ctx: ViewContext, next: Next) -> BoxFuture<'a, Response> {
+        Box::pin(async move {
+            let Some(origin) = request.headers.get("origin").filter(|o| self.allows(o)).cloned() else {
+                return next.run(request, ctx).await;
+            };
+            let preflight = request.method == HttpMethod::Options && request.headers.contains_key("access-control-request-method");
+            let mut response = if preflight {
+                let mut response = Response{status: HttpStatus::HttpOk(204), contents: Arc::default(), headers: None, file: None};
+                response.set_header("Access-Control-Allow-Methods", &self.methods);
+                if let Some(headers) = request.headers.get("access-control-request-headers") {
+                    response.set_header("Access-Control-Allow-Headers", headers);
+                }
+                response.set_header("Access-Control-Max-Age", &self.max_age_secs.to_string());
+                response
+            } else {
+                next.run(request, ctx).await
+            };
+            response.set_header("Access-Control-Allow-Origin", &origin);
+            response.add_vary("Origin");
+            response
+        })
+    }
+}
+
+/// the user `BasicAuth` let in, left in the request's extensions for the handler:
+/// `request.extensions.get::<AuthenticatedUser>()`
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser(pub String);
+
+/// requires http basic auth with the given credentials, answering a 401 otherwise.
+/// protects every path unless narrowed down with `protecting`. requests let through
+/// carry an `AuthenticatedUser`.
+pub struct BasicAuth {
+    realm: String,
+    username: String,
+    // only paths starting with this need credentials
+    prefix: Option<String>,
+    // the expected `Authorization` header value
+    expected: String,
+}
+
+impl BasicAuth {
+    pub fn new(realm: &str, username: &str, password: &str) -> BasicAuth {
+        let credentials = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", username, password));
+        BasicAuth {
+            realm: realm.to_string(),
+            username: username.to_string(),
+            prefix: None,
+            expected: format!("Basic {}", credentials),
+        }
+    }
+
+    /// only asks for credentials on paths starting with `prefix`
+    pub fn protecting(mut self, prefix: &str) -> BasicAuth {
+        self.prefix = Some(prefix.to_string());
+        self
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        // compared without an early exit so timing doesn't leak how much matched
+        request.headers.get("authorization").is_some_and(|given| {
+            given.len() == self.expected.len()
+                && given.bytes().zip(self.expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+        })
+    }
+}
+
+impl Middleware for BasicAuth {
+    fn call<'a>(&'a self, mut request: Request, ctx: ViewContext, next: Next) -> BoxFuture<'a, Response> {
+        Box::pin(async move {
+            let protected = self.prefix.as_ref().is_none_or(|prefix| request.path.starts_with(prefix));
+            if !protected {
+                return next.run(request, ctx).await;
+            }
+            if self.authorized(&request) {
+                request.extensions.insert(AuthenticatedUser(self.username.clone()));
+                return next.run(request, ctx).await;
+            }
+            let mut response = Response::error(401);
+            response.set_header("WWW-Authenticate", &format!("Basic realm=\"{}\"", self.realm));
+            response
+        })
+    }
+}
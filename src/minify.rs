@@ -0,0 +1,332 @@
+// both minifiers only drop comments and whitespace. strings (and in js, regular
+// expressions and template literals) are copied untouched, and anything they can't
+// make sense of is copied as is, so valid input stays valid.
+
+// a space next to one of these is never needed in css. a space before `:` is kept,
+// since `a :hover` and `a:hover` are different selectors
+const CSS_TIGHT_AFTER: &str = "{};:,>";
+const CSS_TIGHT_BEFORE: &str = "{};,>";
+
+// a space next to one of these is never needed in js
+const JS_TIGHT: &str = "{}()[];,:=";
+
+// a line break after or before these can't end a statement, so it can go
+const JS_LINE_AFTER: &str = "{([,;=:";
+const JS_LINE_BEFORE: &str = ")]},;";
+
+// words after which a `/` starts a regular expression rather than dividing
+const JS_KEYWORDS: &[&str] = &[
+    "return", "typeof", "instanceof", "in", "of", "new", "delete", "void", "throw", "case", "do", "else",
+    "yield", "await",
+];
+
+// copies the string starting with the quote at `at`, up to its closing quote or the end
+// of the line, returning where it ends
+fn copy_quoted(src: &[char], at: usize, out: &mut String) -> usize {
+    let quote = src[at];
+    out.push(quote);
+    let mut i = at + 1;
+    while i < src.len() {
+        let c = src[i];
+        out.push(c);
+        i += 1;
+        if c == '\\' {
+            if let Some(&escaped) = src.get(i) {
+                out.push(escaped);
+                i += 1;
+            }
+        } else if c == quote || c == '\n' {
+            break;
+        }
+    }
+    i
+}
+
+// skips the `/* */` comment at `at`, returning where it ends and whether it spanned lines
+fn skip_block_comment(src: &[char], at: usize) -> (usize, bool) {
+    let mut i = at + 2;
+    let mut line_break = false;
+    while i < src.len() && !(src[i] == '*' && src.get(i + 1) == Some(&'/')) {
+        line_break |= src[i] == '\n';
+        i += 1;
+    }
+    ((i + 2).min(src.len()), line_break)
+}
+
+/// `src` with comments removed and whitespace cut down to what separates tokens
+pub fn css(src: &str) -> String {
+    let src: Vec<char> = src.chars().collect();
+    let mut out = String::with_capacity(src.len());
+    let mut gap = false;
+    let mut i = 0;
+    while i < src.len() {
+        let c = src[i];
+        if c.is_whitespace() {
+            gap = true;
+            i += 1;
+            continue;
+        }
+        if c == '/' && src.get(i + 1) == Some(&'*') {
+            // a comment still separates the tokens around it
+            (i, _) = skip_block_comment(&src, i);
+            gap = true;
+            continue;
+        }
+        let prev = out.chars().next_back();
+        if gap && prev.is_some_and(|prev| !CSS_TIGHT_AFTER.contains(prev)) && !CSS_TIGHT_BEFORE.contains(c) {
+            out.push(' ');
+        }
+        gap = false;
+        match c {
+            '"' | '\'' => i = copy_quoted(&src, i, &mut out),
+            _ => {
+                // the last declaration in a block needs no semicolon
+                if c == '}' && prev == Some(';') {
+                    out.pop();
+                }
+                out.push(c);
+                i += 1;
+            },
+        }
+    }
+    out
+}
+
+// whether a `/` following `out` starts a regular expression. when unsure it is taken
+// as one, since a regular expression is copied as is.
+fn regex_allowed(out: &str) -> bool {
+    let out = out.trim_end();
+    let Some(prev) = out.chars().next_back() else {
+        return true;
+    };
+    let is_word = |c: char| c.is_alphanumeric() || c == '_' || c == '$';
+    if is_word(prev) {
+        let word = &out[out.trim_end_matches(is_word).len()..];
+        return JS_KEYWORDS.contains(&word);
+    }
+    !matches!(prev, ')' | ']')
+}
+
+// copies the regular expression starting at `at`, returning where it ends. one that
+// runs into the end of the line wasn't one, and is copied only up to there.
+fn copy_regex(src: &[char], at: usize, out: &mut String) -> usize {
+    out.push('/');
+    let mut i = at + 1;
+    let mut in_class = false;
+    while i < src.len() && src[i] != '\n' {
+        let c = src[i];
+        out.push(c);
+        i += 1;
+        match c {
+            '\\' => {
+                if let Some(&escaped) = src.get(i).filter(|c| **c != '\n') {
+                    out.push(escaped);
+                    i += 1;
+                }
+            },
+            '[' => in_class = true,
+            ']' => in_class = false,
+            '/' if !in_class => break,
+            _ => {},
+        }
+    }
+    i
+}
+
+// copies template literal text from `at` up to its closing backtick, or up to the next
+// `${`; returns where it stopped and whether it was at a `${`
+fn copy_template(src: &[char], at: usize, out: &mut String) -> (usize, bool) {
+    let mut i = at;
+    while i < src.len() {
+        let c = src[i];
+        out.push(c);
+        i += 1;
+        match c {
+            '\\' => {
+                if let Some(&escaped) = src.get(i) {
+                    out.push(escaped);
+                    i += 1;
+                }
+            },
+            '`' => return (i, false),
+            '$' if src.get(i) == Some(&'{') => {
+                out.push('{');
+                return (i + 1, true);
+            },
+            _ => {},
+        }
+    }
+    (i, false)
+}
+
+/// `src` with comments removed, lines trimmed and whitespace cut down to what separates
+/// tokens. line breaks are kept wherever one could end a statement, so code relying on
+/// automatic semicolon insertion still works.
+pub fn js(src: &str) -> String {
+    let src: Vec<char> = src.chars().collect();
+    let mut out = String::with_capacity(src.len());
+    // how many braces are open inside each `${ }` of the template literals being read
+    let mut substitutions: Vec<usize> = Vec::new();
+    // whitespace or comments since the last token, and whether they had a line break
+    let mut gap: Option<bool> = None;
+    let mut i = 0;
+    while i < src.len() {
+        let c = src[i];
+        let next = src.get(i + 1).copied();
+        if c.is_whitespace() {
+            gap = Some(gap.unwrap_or(false) || c == '\n');
+            i += 1;
+            continue;
+        }
+        if c == '/' && next == Some('/') {
+            // the line break ending the comment is seen next
+            while i < src.len() && src[i] != '\n' {
+                i += 1;
+            }
+            gap = Some(gap.unwrap_or(false));
+            continue;
+        }
+        if c == '/' && next == Some('*') {
+            let (end, line_break) = skip_block_comment(&src, i);
+            gap = Some(gap.unwrap_or(false) || line_break);
+            i = end;
+            continue;
+        }
+        if let Some(line_break) = gap.take() {
+            if let Some(prev) = out.chars().next_back() {
+                if line_break && !JS_LINE_AFTER.contains(prev) && !JS_LINE_BEFORE.contains(c) {
+                    out.push('\n');
+                } else if !line_break && !JS_TIGHT.contains(prev) && !JS_TIGHT.contains(c) {
+                    out.push(' ');
+                }
+            }
+        }
+        match c {
+            '"' | '\'' => i = copy_quoted(&src, i, &mut out),
+            '/' if regex_allowed(&out) => i = copy_regex(&src, i, &mut out),
+            '`' => {
+                out.push('`');
+                let (end, substitution) = copy_template(&src, i + 1, &mut out);
+                if substitution {
+                    substitutions.push(0);
+                }
+                i = end;
+            },
+            '}' if substitutions.last() == Some(&0) => {
+                // the end of a `${ }`, so back to the template text
+                substitutions.pop();
+                out.push('}');
+                let (end, substitution) = copy_template(&src, i + 1, &mut out);
+                if substitution {
+                    substitutions.push(0);
+                }
+                i = end;
+            },
+            _ => {
+                if let Some(open) = substitutions.last_mut() {
+                    match c {
+                        '{' => *open += 1,
+                        '}' => *open -= 1,
+                        _ => {},
+                    }
+                }
+                out.push(c);
+                i += 1;
+            },
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CSS: &str = r#"/* site styles */
+body {
+    margin: 0;
+    font-family: "Helvetica Neue", sans-serif;
+}
+
+a :hover , nav > a:hover {
+    color: red; /* brand red */
+    background: url("/img/bg.png");
+}
+
+.note::before {
+    content: "/* not a comment */ // nor this";
+    width: calc(100% - 2em);
+}
+"#;
+
+    #[test]
+    fn css_keeps_what_separates_tokens() {
+        let minified = css(CSS);
+        assert_eq!(
+            minified,
+            concat!(
+                r#"body{margin:0;font-family:"Helvetica Neue",sans-serif}"#,
+                r#"a :hover,nav>a:hover{color:red;background:url("/img/bg.png")}"#,
+                r#".note::before{content:"/* not a comment */ // nor this";width:calc(100% - 2em)}"#,
+            ),
+        );
+        assert!(minified.len() * 4 < CSS.len() * 3, "{} of {}", minified.len(), CSS.len());
+        // minifying again changes nothing
+        assert_eq!(css(&minified), minified);
+    }
+
+    #[test]
+    fn css_copies_a_broken_string_or_comment_as_far_as_it_goes() {
+        assert_eq!(css("a { content: 'open\n}"), "a{content:'open\n}");
+        assert_eq!(css("a { color: red } /* unclosed"), "a{color:red}");
+    }
+
+    const JS: &str = r#"// greets the visitor
+const url = "http://example.com/*path*/";  // a url, not a comment
+let greeting = 'hi // there';
+/* counts
+   the links */
+function count(list) {
+    let total = 0
+    for (const item of list) {
+        total += item.size / 2 / scale
+    }
+    return total
+}
+const pattern = /\/\/[a-z/]+/g;
+const label = `total: ${count([{ size: 4 }])} // done /* really */`;
+if (pattern.test(url)) {
+    console.log(greeting, label)
+}
+"#;
+
+    #[test]
+    fn js_keeps_strings_regexes_and_line_breaks_that_end_statements() {
+        let minified = js(JS);
+        assert_eq!(
+            minified,
+            concat!(
+                "const url=\"http://example.com/*path*/\";let greeting='hi // there';",
+                // the line break after `total = 0` could be ending its statement
+                "function count(list){let total=0\n",
+                "for(const item of list){total +=item.size / 2 / scale}\n",
+                "return total}\n",
+                "const pattern=/\\/\\/[a-z/]+/g;",
+                "const label=`total: ${count([{size:4}])} // done /* really */`;",
+                "if(pattern.test(url)){console.log(greeting,label)}",
+            ),
+        );
+        assert!(minified.len() * 4 < JS.len() * 3, "{} of {}", minified.len(), JS.len());
+        assert_eq!(js(&minified), minified);
+    }
+
+    #[test]
+    fn js_tells_a_regex_from_a_division() {
+        assert_eq!(js("x = a / b / c"), "x=a / b / c");
+        assert_eq!(js("x = (a) / 2 / b"), "x=(a)/ 2 / b");
+        assert_eq!(js("return /a b/.test(s)"), "return /a b/.test(s)");
+        assert_eq!(js("f(/ x /, y)"), "f(/ x /,y)");
+        // an unterminated one stops at the end of its line
+        assert_eq!(js("let r = /abc\nnext()"), "let r=/abc\nnext()");
+    }
+}
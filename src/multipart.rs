@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use async_std::fs::File;
+use async_std::prelude::*;
+use log::error;
+use crate::body::BodyStream;
+use crate::http::{Request, Response};
+use crate::Settings;
+
+
+// part headers longer than this are refused rather than buffered
+const MAX_PART_HEAD_BYTES: usize = 8 * 1024;
+
+/// a submitted `multipart/form-data` form: its plain fields by name, and its files.
+/// file parts are written to `Settings::upload_temp_dir` as they arrive rather than
+/// being held in memory.
+#[derive(Debug, Default)]
+pub struct Form {
+    pub fields: HashMap<String, String>,
+    pub files: Vec<UploadedFile>,
+}
+
+/// a file part of a form, saved to a temporary file that is deleted when this is
+/// dropped unless it is `persist`ed first
+#[derive(Debug)]
+pub struct UploadedFile {
+    /// the form field the file was submitted as
+    pub name: String,
+    /// the file's name as the client sent it; not safe to use as a path as is
+    pub filename: String,
+    pub content_type: Option<String>,
+    pub size: usize,
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl UploadedFile {
+    /// where the upload is stored until it is dropped
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// moves the upload to `to`, keeping it after this is dropped
+    pub async fn persist(mut self, to: impl AsRef<Path>) -> io::Result<()> {
+        // a rename can't cross filesystems, so fall back to copying
+        if async_std::fs::rename(&self.path, to.as_ref()).await.is_err() {
+            async_std::fs::copy(&self.path, to.as_ref()).await?;
+            async_std::fs::remove_file(&self.path).await?;
+        }
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for UploadedFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// why a form couldn't be read. converts into a 400 response, or a 500 when saving an
+/// upload failed.
+#[derive(Debug)]
+pub enum MultipartError {
+    /// the request isn't `multipart/form-data`, or has no boundary parameter
+    MissingBoundary,
+    /// the body doesn't follow the multipart format
+    Malformed,
+    /// the body ended before the closing boundary
+    Truncated,
+    /// the body itself couldn't be read (e.g. it was too large or too slow)
+    Body(io::Error),
+    /// an upload couldn't be written to its temporary file
+    Io(io::Error),
+}
+
+impl fmt::Display for MultipartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MultipartError::MissingBoundary => write!(f, "not multipart/form-data or missing boundary"),
+            MultipartError::Malformed => write!(f, "malformed multipart body"),
+            MultipartError::Truncated => write!(f, "multipart body ends before its closing boundary"),
+            MultipartError::Body(e) => write!(f, "can't read multipart body: {}", e),
+            MultipartError::Io(e) => write!(f, "can't save upload: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MultipartError {}
+
+impl From<MultipartError> for Response {
+    fn from(e: MultipartError) -> Response {
+        match e {
+            MultipartError::Io(e) => {
+                error!("can't save upload: {}", e);
+                Response::error(500)
+            },
+            _ => Response::error(400),
+        }
+    }
+}
+
+// the body, whether it was buffered or is being streamed
+enum Source<'a> {
+    Buffered(Option<Vec<u8>>),
+    Streamed(&'a mut BodyStream),
+}
+
+impl Source<'_> {
+    async fn next(&mut self) -> Result<Option<Vec<u8>>, MultipartError> {
+        match self {
+            Source::Buffered(body) => Ok(body.take()),
+            Source::Streamed(stream) => stream.next_chunk().await.map_err(MultipartError::Body),
+        }
+    }
+}
+
+// the `boundary` parameter of a `multipart/form-data` content type
+fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    if !params.next()?.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params.filter_map(|param| param.split_once('='))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+        .filter(|boundary| !boundary.is_empty())
+}
+
+// a parameter such as `name="x"` from a `Content-Disposition` header
+fn disposition_param(disposition: &str, key: &str) -> Option<String> {
+    disposition.split(';').skip(1)
+        .filter_map(|param| param.split_once('='))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case(key))
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+fn temp_path(settings: &Settings) -> PathBuf {
+    static NEXT_UPLOAD: AtomicU64 = AtomicU64::new(0);
+    let dir = settings.upload_temp_dir.as_ref().map_or_else(std::env::temp_dir, PathBuf::from);
+    let n = NEXT_UPLOAD.fetch_add(1, Ordering::Relaxed);
+    dir.join(format!("ferropress-upload-{}-{}", std::process::id(), n))
+}
+
+// where a part's payload goes
+enum Sink {
+    Field(String, Vec<u8>),
+    File(File, UploadedFile),
+}
+
+impl Sink {
+    async fn write(&mut self, data: &[u8]) -> Result<(), MultipartError> {
+        match self {
+            Sink::Field(_, value) => value.extend_from_slice(data),
+            Sink::File(file, upload) => {
+                file.write_all(data).await.map_err(MultipartError::Io)?;
+                upload.size += data.len();
+            },
+        }
+        Ok(())
+    }
+}
+
+impl Form {
+    /// reads a `multipart/form-data` request body, from `request.body_stream` when the
+    /// path streams its bodies (see `Settings::streamed_body_paths`), otherwise from
+    /// `request.body`
+    pub async fn from_request(request: &mut Request, settings: &Settings) -> Result<Form, MultipartError> {
+        let boundary = request.headers.get("content-type")
+            .and_then(|content_type| boundary(content_type))
+            .ok_or(MultipartError::MissingBoundary)?;
+        let mut source = match request.body_stream.as_mut() {
+            Some(stream) => Source::Streamed(stream),
+            None => Source::Buffered(Some(std::mem::take(&mut request.body))),
+        };
+        // every boundary but the first follows a line ending; starting with one lets
+        // the first match the same way
+        let delimiter = format!("\r\n--{}", boundary).into_bytes();
+        let mut buf = b"\r\n".to_vec();
+        let mut form = Form::default();
+
+        async fn fill(source: &mut Source<'_>, buf: &mut Vec<u8>) -> Result<(), MultipartError> {
+            let chunk = source.next().await?.ok_or(MultipartError::Truncated)?;
+            buf.extend_from_slice(&chunk);
+            Ok(())
+        }
+
+        // the preamble before the first boundary is skipped
+        loop {
+            if let Some(at) = find(&buf, &delimiter) {
+                buf.drain(..at + delimiter.len());
+                break;
+            }
+            buf.drain(..buf.len().saturating_sub(delimiter.len()));
+            fill(&mut source, &mut buf).await?;
+        }
+        loop {
+            // a boundary is followed by `--` after the last part, a line ending otherwise
+            while buf.len() < 2 {
+                fill(&mut source, &mut buf).await?;
+            }
+            if buf.starts_with(b"--") {
+                return Ok(form);
+            }
+            if !buf.starts_with(b"\r\n") {
+                return Err(MultipartError::Malformed);
+            }
+            buf.drain(..2);
+
+            let head_end = loop {
+                if let Some(at) = find(&buf, b"\r\n\r\n") {
+                    break at;
+                }
+                if buf.len() > MAX_PART_HEAD_BYTES {
+                    return Err(MultipartError::Malformed);
+                }
+                fill(&mut source, &mut buf).await?;
+            };
+            let head = String::from_utf8_lossy(&buf[..head_end]).into_owned();
+            buf.drain(..head_end + 4);
+            let mut disposition = None;
+            let mut content_type = None;
+            for line in head.split("\r\n") {
+                match line.split_once(':') {
+                    Some((name, value)) if name.trim().eq_ignore_ascii_case("content-disposition") => {
+                        disposition = Some(value.trim().to_string());
+                    },
+                    Some((name, value)) if name.trim().eq_ignore_ascii_case("content-type") => {
+                        content_type = Some(value.trim().to_string());
+                    },
+                    _ => {},
+                }
+            }
+            let disposition = disposition.ok_or(MultipartError::Malformed)?;
+            let name = disposition_param(&disposition, "name").ok_or(MultipartError::Malformed)?;
+
+            let mut sink = match disposition_param(&disposition, "filename") {
+                Some(filename) => {
+                    let path = temp_path(settings);
+                    let file = File::create(&path).await.map_err(MultipartError::Io)?;
+                    let upload = UploadedFile { name, filename, content_type, size: 0, path, persisted: false };
+                    Sink::File(file, upload)
+                },
+                None => Sink::Field(name, Vec::new()),
+            };
+            // payload is passed on as it arrives, holding back only what could be the
+            // start of the next boundary
+            loop {
+                if let Some(at) = find(&buf, &delimiter) {
+                    sink.write(&buf[..at]).await?;
+                    buf.drain(..at + delimiter.len());
+                    break;
+                }
+                let safe = buf.len().saturating_sub(delimiter.len() - 1);
+                sink.write(&buf[..safe]).await?;
+                buf.drain(..safe);
+                fill(&mut source, &mut buf).await?;
+            }
+            match sink {
+                Sink::Field(name, value) => {
+                    form.fields.insert(name, String::from_utf8_lossy(&value).into_owned());
+                },
+                Sink::File(mut file, upload) => {
+                    file.flush().await.map_err(MultipartError::Io)?;
+                    form.files.push(upload);
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+
+/// a bounded free list of byte buffers, so per-request scratch space is reused rather
+/// than allocated each time. buffers are cleared when they come back, and ones that
+/// grew past `max_capacity` are dropped instead of kept.
+pub struct BufferPool {
+    free: Mutex<Vec<Vec<u8>>>,
+    max_buffers: usize,
+    max_capacity: usize,
+}
+
+impl BufferPool {
+    /// a pool keeping at most `max_buffers` idle buffers; `0` disables pooling
+    pub fn new(max_buffers: usize, max_capacity: usize) -> BufferPool {
+        BufferPool { free: Mutex::new(Vec::new()), max_buffers, max_capacity }
+    }
+
+    /// an empty buffer, from the pool if one is idle; it returns to the pool on drop
+    pub fn get(&self) -> PooledBuffer<'_> {
+        // the lock is never held across a panic point, but don't let poisoning matter
+        let buf = self.free.lock().unwrap_or_else(|e| e.into_inner()).pop().unwrap_or_default();
+        PooledBuffer { buf, pool: self }
+    }
+
+    fn put(&self, mut buf: Vec<u8>) {
+        if buf.capacity() == 0 || buf.capacity() > self.max_capacity {
+            return;
+        }
+        buf.clear();
+        let mut free = self.free.lock().unwrap_or_else(|e| e.into_inner());
+        if free.len() < self.max_buffers {
+            free.push(buf);
+        }
+    }
+}
+
+pub struct PooledBuffer<'a> {
+    buf: Vec<u8>,
+    pool: &'a BufferPool,
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buf));
+    }
+}
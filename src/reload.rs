@@ -0,0 +1,51 @@
+use std::io;
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicI32, Ordering};
+use async_std::prelude::*;
+
+
+// the write end of the pipe the SIGHUP handler pokes, or -1 when nobody listens
+static HANGUP_FD: AtomicI32 = AtomicI32::new(-1);
+
+// runs inside the signal handler, where only async-signal-safe calls like `write` are
+// allowed. a full pipe just drops the byte; one pending wakeup is as good as several.
+extern "C" fn on_hangup(_: libc::c_int) {
+    let fd = HANGUP_FD.load(Ordering::Relaxed);
+    if fd >= 0 {
+        unsafe {
+            libc::write(fd, [1u8].as_ptr() as *const libc::c_void, 1);
+        }
+    }
+}
+
+// SIGHUPs sent to the process, delivered through a socket pair so they can be awaited.
+// only the most recent listener in a process hears them.
+pub(crate) struct Hangups {
+    reader: async_std::os::unix::net::UnixStream,
+}
+
+impl Hangups {
+    pub(crate) fn listen() -> io::Result<Hangups> {
+        let (reader, writer) = UnixStream::pair()?;
+        writer.set_nonblocking(true)?;
+        let previous = HANGUP_FD.swap(writer.into_raw_fd(), Ordering::Relaxed);
+        if previous >= 0 {
+            drop(unsafe { UnixStream::from_raw_fd(previous) });
+        }
+        let handler = on_hangup as extern "C" fn(libc::c_int);
+        if unsafe { libc::signal(libc::SIGHUP, handler as libc::sighandler_t) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Hangups { reader: reader.into() })
+    }
+
+    // waits for the next SIGHUP; hangups that arrived together count as one
+    pub(crate) async fn next(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; 64];
+        match self.reader.read(&mut buf).await? {
+            0 => Err(io::ErrorKind::UnexpectedEof.into()),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use std::fs::File;
+use std::io;
+use async_std::io::{BufReader, Write};
+use async_std::prelude::*;
+
+
+// streams files into responses (see `Response::file`) without holding them in memory.
+// on linux the bytes go from the page cache to the socket with sendfile(2), never
+// passing through userspace; elsewhere, or on a socket sendfile can't use, they are
+// read and written a chunk at a time.
+
+// bytes read at a time when copying through userspace
+const CHUNK_BYTES: usize = 64 * 1024;
+
+// bytes handed to one sendfile call; big enough to fill any socket buffer
+#[cfg(target_os = "linux")]
+const SENDFILE_MAX_BYTES: u64 = 8 * 1024 * 1024;
+
+// copied through the async writer when the socket is full, to wait for room in it
+#[cfg(target_os = "linux")]
+const WAIT_BYTES: u64 = 4 * 1024;
+
+// a connection's socket, as sendfile(2) needs it; `None` where it can't be used
+pub(crate) type RawSocket = Option<i32>;
+
+// a connection a response can be written to, and the socket underneath it
+pub(crate) trait HasSocket {
+    fn socket(&self) -> RawSocket;
+}
+
+impl HasSocket for &async_std::net::TcpStream {
+    fn socket(&self) -> RawSocket {
+        #[cfg(unix)]
+        return Some(std::os::unix::io::AsRawFd::as_raw_fd(*self));
+        #[cfg(not(unix))]
+        return None;
+    }
+}
+
+#[cfg(unix)]
+impl HasSocket for &async_std::os::unix::net::UnixStream {
+    fn socket(&self) -> RawSocket {
+        Some(std::os::unix::io::AsRawFd::as_raw_fd(*self))
+    }
+}
+
+/// writes the first `len` bytes of `file` to `writer`, or to `socket` directly when
+/// there is one; `writer` has to be flushed beforehand. returns the bytes written,
+/// and an `UnexpectedEof` error if the file turned out shorter than `len`.
+pub(crate) async fn send_file<W: Write + Unpin>(writer: &mut W, socket: RawSocket, file: File, len: u64) -> io::Result<u64> {
+    #[cfg(target_os = "linux")]
+    if let Some(socket) = socket {
+        return sendfile(writer, socket, file, len).await;
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = socket;
+    copy(writer, file, 0, len).await
+}
+
+// the chunked path, from `offset` on
+async fn copy<W: Write + Unpin>(writer: &mut W, file: File, offset: u64, len: u64) -> io::Result<u64> {
+    use std::io::{Seek, SeekFrom};
+
+    let mut file = file;
+    file.seek(SeekFrom::Start(offset))?;
+    let file = async_std::fs::File::from(file);
+    let copied = async_std::io::copy(&mut BufReader::with_capacity(CHUNK_BYTES, file).take(len - offset), writer).await?;
+    writer.flush().await?;
+    match offset + copied {
+        written if written < len => Err(io::ErrorKind::UnexpectedEof.into()),
+        written => Ok(written),
+    }
+}
+
+#[cfg(target_os = "linux")]
+async fn sendfile<W: Write + Unpin>(writer: &mut W, socket: i32, file: File, len: u64) -> io::Result<u64> {
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::io::AsRawFd;
+
+    let mut offset: libc::off_t = 0;
+    while (offset as u64) < len {
+        let count = (len - offset as u64).min(SENDFILE_MAX_BYTES) as usize;
+        // advances `offset` past what was sent
+        let sent = unsafe { libc::sendfile(socket, file.as_raw_fd(), &mut offset, count) };
+        if sent > 0 {
+            continue;
+        }
+        if sent == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let e = io::Error::last_os_error();
+        match e.kind() {
+            io::ErrorKind::Interrupted => {},
+            // the socket is full. the async writer knows how to wait for room, so the
+            // next few bytes go through it, and then sendfile carries on
+            io::ErrorKind::WouldBlock => {
+                let mut chunk = vec![0; (len - offset as u64).min(WAIT_BYTES) as usize];
+                let read = file.read_at(&mut chunk, offset as u64)?;
+                if read == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                writer.write_all(&chunk[..read]).await?;
+                writer.flush().await?;
+                offset += read as libc::off_t;
+            },
+            // nothing was sent yet, and this kind of socket or file isn't supported
+            _ if offset == 0 && matches!(e.raw_os_error(), Some(libc::EINVAL | libc::ENOSYS)) => {
+                return copy(writer, file, 0, len).await;
+            },
+            _ => return Err(e),
+        }
+    }
+    Ok(offset as u64)
+}
+
+// ends a connection whose response was cut short, since the client would otherwise
+// wait for the rest of a body that isn't coming
+pub(crate) fn abort(socket: RawSocket) {
+    #[cfg(unix)]
+    if let Some(socket) = socket {
+        unsafe {
+            libc::shutdown(socket, libc::SHUT_RDWR);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = socket;
+}
@@ -0,0 +1,1004 @@
+use std::time::{Duration, Instant};
+use async_std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use async_std::os::unix::net::{UnixListener, UnixStream};
+use async_std::io::{BufReader, BufWriter, Read, Write};
+use async_std::prelude::*;
+use async_lock::Semaphore;
+use futures::stream::StreamExt;
+use crate::{ProxyRoute, RobotsTxt, Settings};
+use crate::{assets, charset, dev};
+use crate::background::BackgroundTasks;
+use crate::{body_log, logging};
+use crate::access_control::{AccessControl, ConnectionLimit, ConnectionStats, Peer};
+use crate::access_log::{AccessRecord, LogFormat};
+use crate::error_pages::ErrorPages;
+use crate::app::{App, ViewContext};
+use crate::http::{header_escape, HttpContentType, HttpHeader, HttpMethod, HttpStatus, Request, RequestError, Response};
+use crate::sendfile::{self, HasSocket};
+use crate::middleware::panic_message;
+use crate::pool::BufferPool;
+use crate::sse::EventStream;
+use crate::template::{Context, TemplateCache};
+use crate::views::{DecompressedFiles, FoldedListings, MinifiedFiles, MissingFiles};
+use crate::websocket::{accept_key, WebSocket};
+use async_std::task::spawn;
+use std::future::Future;
+use std::sync::Arc;
+use std::panic::AssertUnwindSafe;
+use async_std::sync::RwLock;
+use futures::FutureExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::net::{SocketAddr, ToSocketAddrs};
+use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
+
+
+// an async lock rather than std's, so a panicking handler can't poison it. entries are
+// shared with the responses serving them, so a cache hit never copies the file.
+pub(crate) type ContentCache = Arc<RwLock<HashMap<String, Arc<Vec<u8>>>>>;
+
+// what the server builds from its settings. a reload replaces all of it at once (see
+// `reload_settings`), and each request is served with the config current when it began
+struct Config {
+    settings: Arc<Settings>,
+    access_log: LogFormat,
+    access_control: AccessControl,
+    error_pages: ErrorPages,
+}
+
+impl Config {
+    fn new(settings: Arc<Settings>, templates: &Arc<TemplateCache>) -> Result<Config, String> {
+        let access_log = LogFormat::parse(&settings.access_log_format)
+            .map_err(|e| format!("invalid access_log_format: {}", e))?;
+        let access_control = AccessControl::new(&settings.allow_ips, &settings.deny_ips)
+            .map_err(|e| format!("invalid allow_ips/deny_ips: {}", e))?;
+        AccessControl::new(&settings.cache_admin_ips, &[]).map_err(|e| format!("invalid cache_admin_ips: {}", e))?;
+        if let Some(entry) = settings.file_charsets.iter().find(|entry| entry.transcode && charset::to_utf8(&[], &entry.charset).is_none()) {
+            return Err(format!("invalid file_charsets: can't transcode from {} (for {})", entry.charset, entry.path));
+        }
+        if let Some(quality) = settings.brotli_quality.filter(|quality| *quality > 11) {
+            return Err(format!("invalid brotli_quality: {} (it goes up to 11)", quality));
+        }
+        let error_pages = ErrorPages::new(Arc::clone(&settings), Arc::clone(templates));
+        Ok(Config { settings, access_log, access_control, error_pages })
+    }
+}
+
+// state shared by every connection
+struct ServerState {
+    config: std::sync::RwLock<Arc<Config>>,
+    app: App,
+    cache: ContentCache,
+    listings: FoldedListings,
+    minified: MinifiedFiles,
+    decompressed: DecompressedFiles,
+    missing: MissingFiles,
+    tasks: BackgroundTasks,
+    connection_limit: ConnectionLimit,
+    connections: Arc<ConnectionStats>,
+    // scratch buffers for response heads
+    buffers: BufferPool,
+    templates: Arc<TemplateCache>,
+    // permits for `ViewContext::render`; see `Settings::max_concurrent_renders`
+    renders: Arc<Semaphore>,
+    // bumped by the dev mode file watcher whenever content changes
+    reload_generation: Arc<AtomicU64>,
+}
+
+impl ServerState {
+    fn config(&self) -> Arc<Config> {
+        Arc::clone(&self.config.read().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+// template, static and media directories of every site, and the `extension_roots`
+fn content_dirs(settings: &Settings) -> Vec<String> {
+    let sites = std::iter::once(settings.default_vhost()).chain(settings.vhosts.iter().cloned());
+    let mut dirs: Vec<String> = sites.flat_map(|site| [site.templates_dir, site.static_dir, site.media_dir]).collect();
+    for root in settings.extension_roots.iter() {
+        if !dirs.contains(&root.dir) {
+            dirs.push(root.dir.clone());
+        }
+    }
+    dirs
+}
+
+// reads every file under the configured directories into the cache
+async fn preload_cache(settings: &Settings, cache: &mut HashMap<String, Arc<Vec<u8>>>) {
+    for dir in content_dirs(settings).iter() {
+        let files = match assets::list_files(dir).await {
+            Ok(files) => files,
+            Err(e) => {
+                warn!("cannot preload {}: {}", dir, e);
+                continue;
+            },
+        };
+        for path in files {
+            match assets::read(&path).await {
+                Ok(contents) => {
+                    cache.insert(path, Arc::new(contents));
+                },
+                Err(e) => warn!("cannot preload {}: {}", path, e),
+            }
+        }
+    }
+    info!("preloaded {} files into the content cache", cache.len());
+}
+
+// the index page `views::read_index` will serve, read up front so a missing one is
+// reported at startup
+async fn preload_index(settings: &Settings) -> Result<(String, Vec<u8>), String> {
+    let dir = settings.default_vhost().templates_dir;
+    for name in settings.index_files.iter() {
+        let path = format!("{}/{}", dir, name);
+        match assets::read(&path).await {
+            Ok(contents) => return Ok((path, contents)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(format!("cannot read {}: {}", path, e)),
+        }
+    }
+    Err(format!("no index page in {}: tried {}", dir, settings.index_files.join(", ")))
+}
+
+/// runs `app` until the listener fails. configuration errors (an unreadable index page,
+/// favicon or robots.txt, an invalid access log format or ip range, an address that
+/// can't be bound) are returned before any connection is accepted.
+pub async fn serve(app: App, settings: Settings) -> Result<(), Box<dyn std::error::Error>> {
+    serve_with_shutdown(app, settings, futures::future::pending()).await
+}
+
+/// runs `app` like `serve` until `shutdown` completes (e.g. on a signal). then no more
+/// connections are accepted, tasks started with `ViewContext::spawn` get up to
+/// `Settings::shutdown_timeout_secs` to finish, and this returns. requests already
+/// being served are not waited for.
+pub async fn serve_with_shutdown(
+    app: App, settings: Settings, shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let settings = Arc::new(settings);
+    info!("Starting server!");
+    info!("{:?}", *settings);
+
+    // in dev mode, or with `cache_enabled` off, the cache stays empty, so every read goes
+    // to disk. the index page and configured files still have to be there
+    let mut content_cache = HashMap::new();
+    if !settings.dev_mode {
+        match preload_index(&settings).await {
+            Ok((index, index_contents)) if settings.cache_enabled => {
+                content_cache.insert(index, Arc::new(index_contents));
+            },
+            Ok(_) => {},
+            Err(e) if settings.missing_index.is_some() => warn!("{}; serving missing_index for / instead", e),
+            Err(e) => return Err(e.into()),
+        }
+        let robots_txt = match &settings.robots_txt {
+            Some(RobotsTxt::File(file)) => Some(file),
+            _ => None,
+        };
+        for file in settings.favicon.iter().chain(robots_txt) {
+            let contents = assets::read(file).await.map_err(|e| format!("cannot read {}: {}", file, e))?;
+            if settings.cache_enabled {
+                content_cache.insert(file.clone(), Arc::new(contents));
+            }
+        }
+        if settings.cache_enabled && settings.preload_cache {
+            preload_cache(&settings, &mut content_cache).await;
+        }
+    }
+    let content_cache = Arc::new(RwLock::new(content_cache));
+    let mut templates = TemplateCache::with_functions(app.template_functions().clone());
+    if let Some(placeholder) = &settings.partial_error_placeholder {
+        templates = templates.contain_partial_errors(placeholder);
+    }
+    if let Some(ms) = settings.render_timeout_ms {
+        templates = templates.render_timeout(Duration::from_millis(ms));
+    }
+    let templates = Arc::new(templates);
+    let config = Config::new(Arc::clone(&settings), &templates)?;
+    let reload_generation = Arc::new(AtomicU64::new(0));
+    if settings.dev_mode {
+        println!("Running in dev mode: caching is off and pages reload when content changes");
+        spawn(dev::watch(content_dirs(&settings), Arc::clone(&reload_generation), Arc::clone(&templates)));
+    }
+    let state = Arc::new(ServerState{
+        config: std::sync::RwLock::new(Arc::new(config)), app, cache: content_cache, listings: FoldedListings::default(),
+        minified: MinifiedFiles::default(), decompressed: DecompressedFiles::default(), missing: MissingFiles::default(), tasks: BackgroundTasks::default(),
+        connection_limit: ConnectionLimit::new(settings.max_connections_per_ip), connections: Arc::default(),
+        buffers: BufferPool::new(settings.buffer_pool_size, settings.buffer_pool_max_bytes),
+        templates, renders: Arc::new(Semaphore::new(render_limit(&settings))), reload_generation,
+    });
+    #[cfg(unix)]
+    spawn(reload_on_hangup(Arc::clone(&state)));
+    if let Some(secs) = settings.connection_stats_secs {
+        spawn(log_connections(Arc::clone(&state.connections), Duration::from_secs(secs.max(1))));
+    }
+
+    let listeners = bind_listeners(&settings).await?;
+    let running = Arc::clone(&state);
+    futures::stream::select_all(listeners.iter().map(Listener::incoming))
+        .take_until(shutdown)
+        .for_each_concurrent(None, move |connection| {
+            let state = Arc::clone(&state);
+            async move {
+                match connection {
+                    Ok(connection) => {
+                        spawn(serve_connection(connection, state));
+                    },
+                    Err(e) => warn!("failed to accept connection: {}", e),
+                }
+            }
+        }).await;
+    #[cfg(unix)]
+    if let Some(path) = &settings.unix_socket {
+        if let Err(e) = std::fs::remove_file(path) {
+            warn!("failed to remove {}: {}", path, e);
+        }
+    }
+    info!("shutting down");
+    let timeout = Duration::from_secs(running.config().settings.shutdown_timeout_secs);
+    running.tasks.shutdown(timeout).await;
+    Ok(())
+}
+
+// logs the connection counts every `interval`; see `Settings::connection_stats_secs`
+async fn log_connections(connections: Arc<ConnectionStats>, interval: Duration) {
+    loop {
+        async_std::task::sleep(interval).await;
+        info!(target: "connections", "{} connections open, {} accepted since startup", connections.open(), connections.total());
+    }
+}
+
+// rereads the settings on every SIGHUP; see `reload_settings`
+#[cfg(unix)]
+async fn reload_on_hangup(state: Arc<ServerState>) {
+    let mut hangups = match crate::reload::Hangups::listen() {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!("can't listen for SIGHUP, so settings won't be reloaded: {}", e);
+            return;
+        },
+    };
+    while hangups.next().await.is_ok() {
+        reload_settings(&state);
+    }
+}
+
+// swaps in the settings from the file the running ones came from. settings only read
+// at startup keep their running values, with a warning that they need a restart. a
+// file that can't be read or holds invalid settings is refused, changing nothing.
+#[cfg(unix)]
+fn reload_settings(state: &ServerState) {
+    let running = state.config();
+    let Some(source) = running.settings.source.as_ref() else {
+        warn!("got SIGHUP, but the settings weren't loaded from a file; nothing to reload");
+        return;
+    };
+    let mut settings = match Settings::load_profile(&source.file, source.profile.as_deref()) {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!("not reloading settings from {}: {}; keeping the current ones", source.file, e);
+            return;
+        },
+    };
+    for name in keep_startup_settings(&mut settings, &running.settings) {
+        warn!("{} changed in {}, but only takes effect after a restart", name, source.file);
+    }
+    match Config::new(Arc::new(settings), &state.templates) {
+        Ok(config) => {
+            *state.config.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(config);
+            info!("reloaded settings from {}", source.file);
+        },
+        Err(e) => error!("not reloading settings from {}: {}; keeping the current ones", source.file, e),
+    }
+}
+
+// gives `new` the running values of the settings only read at startup (the listener,
+// connection limit, buffer pool, template cache, preloading and dev mode), returning
+// the names of those it changed back
+#[cfg(unix)]
+fn keep_startup_settings(new: &mut Settings, running: &Settings) -> Vec<&'static str> {
+    let mut changed = Vec::new();
+    if new.host != running.host {
+        changed.push("host");
+        new.host = running.host.clone();
+    }
+    if new.unix_socket != running.unix_socket {
+        changed.push("unix_socket");
+        new.unix_socket = running.unix_socket.clone();
+    }
+    if new.partial_error_placeholder != running.partial_error_placeholder {
+        changed.push("partial_error_placeholder");
+        new.partial_error_placeholder = running.partial_error_placeholder.clone();
+    }
+    macro_rules! keep {
+        ($($field:ident),*) => {$(
+            if new.$field != running.$field {
+                changed.push(stringify!($field));
+                new.$field = running.$field;
+            }
+        )*};
+    }
+    keep!(port, listen_tcp, listen_backlog, tcp_nodelay, tcp_keepalive_secs, max_connections_per_ip,
+          connection_stats_secs, max_concurrent_renders, buffer_pool_size, buffer_pool_max_bytes, cache_enabled,
+          preload_cache, render_timeout_ms);
+    // `--dev` turns dev mode on without the file saying so, so it isn't compared
+    new.dev_mode = running.dev_mode;
+    changed
+}
+
+// `Settings::max_concurrent_renders`, or the number of cpu cores
+fn render_limit(settings: &Settings) -> usize {
+    let limit = settings.max_concurrent_renders.unwrap_or_else(|| {
+        std::thread::available_parallelism().map_or(1, |cores| cores.get())
+    });
+    limit.max(1)
+}
+
+// where connections are accepted from
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+// a connection accepted from one of the `Listener`s
+enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Listener {
+    fn incoming(&self) -> futures::stream::BoxStream<'_, std::io::Result<Connection>> {
+        match self {
+            Listener::Tcp(listener) => listener.incoming().map(|s| s.map(Connection::Tcp)).boxed(),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.incoming().map(|s| s.map(Connection::Unix)).boxed(),
+        }
+    }
+}
+
+// the tcp listener and the unix socket, as the settings ask for
+async fn bind_listeners(settings: &Settings) -> Result<Vec<Listener>, String> {
+    let mut listeners = Vec::new();
+    if settings.listen_tcp {
+        let host = format!("{}:{}", settings.host, settings.port);
+        let listener = bind_listener(settings).map_err(|e| format!("failed to bind {}: {}", host, e))?;
+        println!("Listening on {}", host);
+        listeners.push(Listener::Tcp(listener));
+    }
+    if let Some(path) = &settings.unix_socket {
+        #[cfg(unix)]
+        {
+            let listener = bind_unix_listener(path).await.map_err(|e| format!("failed to bind {}: {}", path, e))?;
+            println!("Listening on {}", path);
+            listeners.push(Listener::Unix(listener));
+        }
+        #[cfg(not(unix))]
+        return Err(format!("can't listen on {}: unix sockets aren't supported on this platform", path));
+    }
+    if listeners.is_empty() {
+        return Err(String::from("nothing to listen on: listen_tcp is off and no unix_socket is set"));
+    }
+    Ok(listeners)
+}
+
+// binds through socket2 so the listen backlog can be set, which std/async-std don't expose
+fn bind_listener(settings: &Settings) -> std::io::Result<TcpListener> {
+    let addr: SocketAddr = format!("{}:{}", settings.host, settings.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "no address to bind"))?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_reuse_address(true)?;
+    // keep-alive set on the listener is inherited by every accepted connection
+    if let Some(secs) = settings.tcp_keepalive_secs {
+        let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(secs));
+        socket.set_tcp_keepalive(&keepalive)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(settings.listen_backlog)?;
+    socket.set_nonblocking(true)?;
+    Ok(TcpListener::from(std::net::TcpListener::from(socket)))
+}
+
+// binds a unix socket at `path`, first removing a stale socket file there: one that
+// refuses connections was left behind by a server that is gone. a socket something
+// still listens on, or a file that isn't a socket, is an error rather than removed.
+#[cfg(unix)]
+async fn bind_unix_listener(path: &str) -> std::io::Result<UnixListener> {
+    use std::io::{Error, ErrorKind};
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => return Err(Error::new(ErrorKind::AddrInUse, "another server is listening on it")),
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                info!("removing stale socket file {}", path);
+                std::fs::remove_file(path)?;
+            },
+            Err(e) => return Err(e),
+        },
+        Ok(_) => return Err(Error::new(ErrorKind::AlreadyExists, "the file exists and isn't a socket")),
+        Err(e) if e.kind() == ErrorKind::NotFound => {},
+        Err(e) => return Err(e),
+    }
+    UnixListener::bind(path).await
+}
+
+fn configure_stream(stream: &TcpStream, settings: &Settings) -> std::io::Result<()> {
+    stream.set_nodelay(settings.tcp_nodelay)
+}
+
+// splits `http://host:port/base` into the address to connect to and the base path
+fn split_upstream(upstream: &str) -> std::io::Result<(&str, &str)> {
+    let rest = upstream.strip_prefix("http://").ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("unsupported upstream {}", upstream))
+    })?;
+    Ok(match rest.find('/') {
+        Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+        None => (rest, ""),
+    })
+}
+
+// `io` on an upstream connection, given up on after `limit_secs`
+async fn upstream_io<T>(limit_secs: Option<u64>, io: impl Future<Output = std::io::Result<T>>) -> std::io::Result<T> {
+    match limit_secs {
+        Some(secs) => async_std::io::timeout(Duration::from_secs(secs), io).await,
+        None => io.await,
+    }
+}
+
+// the status code in the first bytes of a response
+fn response_status(start: &[u8]) -> Option<i32> {
+    std::str::from_utf8(start).ok()?.split(' ').nth(1)?.parse().ok()
+}
+
+// forwards the request to the upstream and streams its response back to the client,
+// returning the upstream's status and the bytes passed on. connecting, sending and each
+// read of the response are limited to `Settings::body_read_timeout_secs`. errors are
+// only returned before anything has been written to the client, so the caller can
+// still answer with a 502 (or a 504, for a timeout).
+async fn proxy_request<W: Write + Unpin>(
+    request: &Request, proxy: &ProxyRoute, peer: Peer, writer: &mut W, settings: &Settings,
+) -> std::io::Result<(Option<i32>, u64)> {
+    let limit_secs = settings.body_read_timeout_secs;
+    // hop-by-hop headers are not forwarded; the forwarding headers are rewritten below,
+    // and the body (already read, and de-chunked) is sent with a length of its own
+    const SKIPPED_HEADERS: &[&str] = &[
+        "connection", "keep-alive", "proxy-connection", "x-forwarded-for", "x-forwarded-proto",
+        "transfer-encoding", "content-length", "expect",
+    ];
+    let (authority, base) = split_upstream(&proxy.upstream)?;
+    let mut upstream = upstream_io(limit_secs, TcpStream::connect(authority)).await?;
+
+    // an upstream with a base path replaces the matched prefix, otherwise the path is kept
+    let path = if base.is_empty() {
+        request.path.clone()
+    } else {
+        format!("{}{}", base, proxy.rest(&request.path).unwrap_or_default())
+    };
+    let mut head = format!("{} {} HTTP/1.1\r\n", request.method, path);
+    for (name, value) in request.headers.iter().filter(|(name, _)| !SKIPPED_HEADERS.contains(&name.as_str())) {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    // a unix socket peer has no address to add, so its list goes on as it came
+    let forwarded_for = match (request.headers.get("x-forwarded-for"), peer.ip()) {
+        (Some(previous), Some(ip)) => Some(format!("{}, {}", previous, ip)),
+        (previous, ip) => previous.cloned().or(ip.map(|ip| ip.to_string())),
+    };
+    if let Some(forwarded_for) = forwarded_for {
+        head.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded_for));
+    }
+    // how the client reached us, not what it says; connections are never tls here
+    head.push_str("X-Forwarded-Proto: http\r\n");
+    if !request.body.is_empty() || request.headers.contains_key("content-length") {
+        head.push_str(&format!("Content-Length: {}\r\n", request.body.len()));
+    }
+    // asking the upstream to close lets us stream its response until eof
+    head.push_str("Connection: close\r\n\r\n");
+
+    upstream_io(limit_secs, upstream.write_all(head.as_bytes())).await?;
+    upstream_io(limit_secs, upstream.write_all(&request.body)).await?;
+    let mut buf = vec![0; 8192];
+    // enough of the response to find its status in
+    let mut start = Vec::new();
+    let mut sent: u64 = 0;
+    loop {
+        let n = match upstream_io(limit_secs, upstream.read(&mut buf)).await {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if sent == 0 => return Err(e),
+            Err(e) => {
+                warn!("proxied response from {} ended early: {}", proxy.upstream, e);
+                break;
+            },
+        };
+        if start.len() < 16 {
+            start.extend_from_slice(&buf[..n.min(16 - start.len())]);
+        }
+        if let Err(e) = writer.write_all(&buf[..n]).await {
+            warn!("failed to pass on the response from {}: {}", proxy.upstream, e);
+            break;
+        }
+        sent += n as u64;
+    }
+    Ok((response_status(&start), sent))
+}
+
+fn is_websocket_upgrade(request: &Request) -> bool {
+    let upgrade = request.headers.get("upgrade").is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+    let connection = request.headers.get("connection").is_some_and(|v| {
+        v.split(',').any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+    });
+    upgrade && connection
+}
+
+// completes the handshake and hands the connection over to the route's handler (see
+// `App::websocket`)
+async fn upgrade_websocket<S>(
+    request: Request, peer: Peer, state: &ServerState, config: &Config, stream: &S, reader: BufReader<&S>, mut writer: BufWriter<&S>,
+) -> (Option<i32>, Option<usize>)
+where
+    for<'a> &'a S: Read + Write + Unpin,
+    S: Clone + Read + Write + Unpin + Send + 'static,
+{
+    let Some(handler) = state.app.websocket_handler(&request.path) else {
+        return write_response(&mut writer, Response::error(404), false, state, config).await;
+    };
+    let key = match (request.headers.get("sec-websocket-key"), request.headers.get("sec-websocket-version")) {
+        (Some(key), Some(version)) if version == "13" => key,
+        _ => return write_response(&mut writer, Response::error(400), false, state, config).await,
+    };
+    let headers = Vec::from([
+        HttpHeader::Custom("Upgrade".into(), "websocket".into()),
+        HttpHeader::Custom("Connection".into(), "Upgrade".into()),
+        HttpHeader::Custom("Sec-WebSocket-Accept".into(), accept_key(key)),
+    ]);
+    let response = Response{status: HttpStatus::HttpOk(101), contents: Arc::default(), headers: Some(headers), file: None};
+    let logged = write_response(&mut writer, response, false, state, config).await;
+
+    // the handler's future can't borrow the connection, so it gets halves of its own
+    let (reader, writer) = owned_halves(stream, reader);
+    let path = request.path.clone();
+    let ctx = view_context(&request, peer, state, config);
+    if let Err(e) = handler.call(request, ctx, WebSocket::new(reader, writer)).await {
+        warn!("websocket on {} failed: {}", path, e);
+    }
+    logged
+}
+
+// the connection's reading and writing halves, owned, for a handler that takes it over.
+// whatever the client sent that `reader` has already buffered is read first
+fn owned_halves<S>(stream: &S, reader: BufReader<&S>) -> (Box<dyn Read + Unpin + Send>, Box<dyn Write + Unpin + Send>)
+where
+    S: Clone + Read + Write + Unpin + Send + 'static,
+{
+    let buffered = async_std::io::Cursor::new(reader.buffer().to_vec());
+    (Box::new(BufReader::new(buffered.chain(stream.clone()))), Box::new(BufWriter::new(stream.clone())))
+}
+
+// whether `request` gets a server-sent event stream: a path registered with
+// `App::event_stream`, or the dev mode reload stream
+fn is_event_stream_route(request: &Request, app: &App, settings: &Settings) -> bool {
+    (settings.dev_mode && request.path == dev::RELOAD_PATH) || app.event_stream_handler(&request.path).is_some()
+}
+
+// dev mode: tells the page to reload once the watched content changes. the keep-alives
+// in between notice closed tabs so their streams don't pile up.
+async fn reload_events<W: Write + Unpin>(state: &ServerState, events: &mut EventStream<W>) -> std::io::Result<()> {
+    let seen = state.reload_generation.load(Ordering::Relaxed);
+    loop {
+        async_std::task::sleep(dev::POLL_INTERVAL).await;
+        if state.reload_generation.load(Ordering::Relaxed) != seen {
+            return events.send_event("reload", "").await;
+        }
+        events.keep_alive().await?;
+    }
+}
+
+// writes the event stream head and keeps the connection open for the route to
+// push events until it finishes or the client disconnects
+async fn serve_event_stream<S>(
+    request: Request, peer: Peer, state: &ServerState, config: &Config, stream: &S, reader: BufReader<&S>, mut writer: BufWriter<&S>,
+) -> (Option<i32>, Option<usize>)
+where
+    for<'a> &'a S: Read + Write + Unpin,
+    S: Clone + Read + Write + Unpin + Send + 'static,
+{
+    let settings = &config.settings;
+    let headers = Vec::from([
+        HttpHeader::ContentType(HttpContentType::EventStream),
+        HttpHeader::Custom("Cache-Control".into(), "no-cache".into()),
+        HttpHeader::Custom("Connection".into(), "close".into()),
+    ]);
+    let response = Response{status: HttpStatus::HttpOk(200), contents: Arc::default(), headers: Some(headers), file: None};
+    let logged = (Some(response.status.code()), None);
+    if writer.write_all(&response.fmt_head(None, &settings.charset)).await.is_err() || writer.flush().await.is_err() {
+        return logged;
+    }
+
+    let path = request.path.clone();
+    let streamed = if settings.dev_mode && request.path == dev::RELOAD_PATH {
+        reload_events(state, &mut EventStream::new(writer)).await
+    } else if let Some(handler) = state.app.event_stream_handler(&request.path) {
+        // the handler's future can't borrow the connection, so it gets a writer of its own
+        let (_, writer) = owned_halves(stream, reader);
+        let ctx = view_context(&request, peer, state, config);
+        handler.call(request, ctx, EventStream::new(writer)).await
+    } else {
+        Ok(())
+    };
+    if let Err(e) = streamed {
+        // a failed write is how a client disconnect shows up
+        info!("event stream on {} closed: {}", path, e);
+    }
+    logged
+}
+
+// writes a complete response, returning its status and body size for the access log.
+// the head goes through a pooled buffer and the body is written straight from the response.
+// the answer to a HEAD request (`head_only`) stops after the head, whose Content-Length is
+// still the body's, so the client knows where the next response starts.
+async fn write_response<W: Write + Unpin>(
+    writer: &mut W, response: Response, head_only: bool, state: &ServerState, config: &Config
+) -> (Option<i32>, Option<usize>) {
+    let sent = if head_only { 0 } else { response.contents.len() };
+    let logged = (Some(response.status.code()), Some(sent));
+    let mut head = state.buffers.get();
+    response.write_head(&mut head, Some(response.contents.len()), &config.settings.charset);
+    if let Err(e) = writer.write_all(&head).await {
+        warn!("failed to write response: {}", e);
+        return logged;
+    }
+    if let Err(e) = writer.write_all(&response.contents[..sent]).await {
+        warn!("failed to write response: {}", e);
+        return logged;
+    }
+    if let Err(e) = writer.flush().await {
+        warn!("failed to flush response: {}", e);
+    }
+    logged
+}
+
+// answers a request that failed before it could be routed. the request's Host header
+// isn't known, so the default site's error pages are used.
+// the connection is closed afterwards since the rest of the request may still be unread.
+async fn write_error<W: Write + Unpin>(
+    writer: &mut W, code: i32, state: &ServerState, config: &Config
+) -> (Option<i32>, Option<usize>) {
+    let mut response = config.error_pages.response(code, &config.settings.default_vhost(), false).await;
+    response.set_header("Connection", "close");
+    write_response(writer, response, false, state, config).await
+}
+
+// per-process request ids; unique for the lifetime of the server
+fn next_request_id() -> String {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    format!("{:08x}", NEXT_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+// whether the connection stays open for another request once this one is answered.
+// http/1.1 keeps it open unless the client says otherwise, http/1.0 only when asked.
+fn keeps_alive(request: &Request, served: usize, settings: &Settings) -> bool {
+    let has_token = |token: &str| request.headers.get("connection").is_some_and(|v| {
+        v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token))
+    });
+    let wanted = match &request.version[..] {
+        "HTTP/1.1" => !has_token("close"),
+        "HTTP/1.0" => has_token("keep-alive"),
+        _ => false,
+    };
+    let proxied = settings.proxies.iter().any(|p| p.matches(&request.path));
+    wanted && !proxied && served + 1 < settings.max_requests_per_connection
+}
+
+// advertises what happens to the connection after `response`
+fn set_connection_headers(response: &mut Response, keep_alive: bool, served: usize, settings: &Settings) {
+    if !keep_alive {
+        response.set_header("Connection", "close");
+        return;
+    }
+    response.set_header("Connection", "keep-alive");
+    let remaining = settings.max_requests_per_connection - served - 1;
+    let keep_alive = match settings.idle_keepalive_timeout_secs {
+        Some(timeout) => format!("timeout={}, max={}", timeout, remaining),
+        None => format!("max={}", remaining),
+    };
+    response.set_header("Keep-Alive", &keep_alive);
+}
+
+fn log_access(config: &Config, mut record: AccessRecord, started: Instant, (status, bytes): (Option<i32>, Option<usize>)) {
+    record.status = status;
+    record.bytes = bytes;
+    record.duration_ms = started.elapsed().as_millis();
+    logging::set_status(&record.request_id, status);
+    info!(target: "access", "{}", config.access_log.render(&record));
+    logging::end_request();
+}
+
+// smallest read buffer used, whatever `Settings::read_buffer_bytes` says; an empty one
+// would look like the client hanging up
+const MIN_READ_BUFFER_BYTES: usize = 64;
+
+// everything done with an accepted connection, in a task of its own so that nothing
+// about it can hold up or unwind into the accept loop. this is the one panic boundary
+// around a connection: handler and middleware panics already become a 500 in
+// `Next::run` and `dispatch`, so what gets here (a panic while writing a response, or in
+// a websocket or event stream) ends just this connection, and is logged rather than
+// dropped with the task by the executor
+async fn serve_connection(connection: Connection, state: Arc<ServerState>) {
+    let connections = Arc::clone(&state.connections);
+    let _open = connections.accept();
+    let (peer, served) = match connection {
+        Connection::Tcp(stream) => {
+            if let Err(e) = configure_stream(&stream, &state.config().settings) {
+                warn!("failed to set socket options: {}", e);
+            }
+            let Ok(remote_addr) = stream.peer_addr() else {
+                return;
+            };
+            let peer = Peer::Tcp(remote_addr);
+            (peer, AssertUnwindSafe(handle_connection(stream, peer, state)).catch_unwind().await)
+        },
+        #[cfg(unix)]
+        Connection::Unix(stream) => (Peer::Unix, AssertUnwindSafe(handle_connection(stream, Peer::Unix, state)).catch_unwind().await),
+    };
+    if let Err(panic) = served {
+        error!("connection from {} panicked: {}", peer, panic_message(&panic));
+    }
+}
+
+// serves http over `stream`, a tcp or unix socket connection, from `peer`
+async fn handle_connection<S>(stream: S, peer: Peer, state: Arc<ServerState>)
+where
+    for<'a> &'a S: Read + Write + Unpin + HasSocket,
+    S: Clone + Read + Write + Unpin + Send + 'static,
+{
+    // both halves are buffered; the writer must be flushed once each response is complete
+    let read_buffer_bytes = state.config().settings.read_buffer_bytes.max(MIN_READ_BUFFER_BYTES);
+    let mut reader = BufReader::with_capacity(read_buffer_bytes, &stream);
+    let mut writer = BufWriter::new(&stream);
+    let allowed = state.config().access_control.allows(&peer);
+    // held until the connection is done with, however that happens. unix socket peers
+    // have no address to count against (see `Peer::Unix`)
+    let slot = match peer.ip() {
+        Some(ip) => state.connection_limit.acquire(ip).map(Some),
+        None => Some(None),
+    };
+
+    // one request per pass; any path that doesn't loop around closes the connection
+    for served in 0.. {
+        let started = Instant::now();
+        // a reload between requests applies from the next one on
+        let config = state.config();
+        let settings = &config.settings;
+        let mut record = AccessRecord {
+            remote: peer.ip().map_or_else(|| String::from("unix"), |ip| ip.to_string()),
+            request_id: next_request_id(),
+            ..Default::default()
+        };
+        let request = if !allowed {
+            Err(RequestError::Forbidden)
+        } else if slot.is_none() {
+            Err(RequestError::TooManyConnections)
+        } else {
+            Request::from_stream(&mut reader, &mut writer, settings).await
+        };
+        let mut keep_alive = false;
+        let logged = match request {
+            Ok((request, pump)) => {
+                logging::begin_request(&record.request_id, request.method.as_str(), &request.path);
+                info!("{:?}", request);
+                if settings.log_bodies && pump.is_none() {
+                    let body = body_log::describe(&request.body, request.headers.get("content-type").map(String::as_str), settings);
+                    info!(target: "body", "request {} {}: {}", request.method, request.path, body);
+                }
+                record.method = request.method.to_string();
+                record.path = request.path.clone();
+                record.version = request.version.clone();
+                record.referer = request.headers.get("referer").cloned();
+                record.user_agent = request.headers.get("user-agent").cloned();
+                if takes_over_connection(&request, &state.app, settings) {
+                    let logged = hand_over(request, peer, &state, &config, &stream, reader, writer).await;
+                    log_access(&config, record, started, logged);
+                    return;
+                }
+                keep_alive = keeps_alive(&request, served, settings);
+                let dispatched = dispatch(request, peer, &state, &config, &mut writer, keep_alive, served);
+                // a streamed body is read while the handler runs, and has to be read in
+                // full before the connection can take another request
+                match pump {
+                    Some(pump) => {
+                        let pumped = pump.run(&mut reader, settings.body_read_timeout_secs);
+                        let (dispatched, pumped) = futures::join!(dispatched, pumped);
+                        if let Err(e) = pumped {
+                            info!("stopped reading the body of {} {} from {}: {:?}", record.method, record.path, peer, e);
+                            keep_alive = false;
+                        }
+                        dispatched
+                    },
+                    None => dispatched.await,
+                }
+            },
+            // nothing was sent, so there is nobody to answer
+            Err(RequestError::ConnectionClosed) => return,
+            Err(RequestError::IdleTimeout) => {
+                info!("closing idle connection from {}", peer);
+                return;
+            },
+            Err(RequestError::Timeout) => write_error(&mut writer, 408, &state, &config).await,
+            Err(RequestError::BadRequest) => write_error(&mut writer, 400, &state, &config).await,
+            Err(RequestError::HeadersTooLarge) => write_error(&mut writer, 431, &state, &config).await,
+            Err(RequestError::PayloadTooLarge) => write_error(&mut writer, 413, &state, &config).await,
+            Err(RequestError::UriTooLong) => write_error(&mut writer, 414, &state, &config).await,
+            Err(RequestError::ExpectationFailed) => write_error(&mut writer, 417, &state, &config).await,
+            // refused without reading anything from the peer
+            Err(RequestError::Forbidden) => write_error(&mut writer, 403, &state, &config).await,
+            Err(RequestError::TooManyConnections) => {
+                let open = peer.ip().map_or(0, |ip| state.connection_limit.open(ip));
+                info!("refusing connection from {}: {} already open", peer, open);
+                write_error(&mut writer, 503, &state, &config).await
+            },
+            Err(RequestError::Io(e)) => {
+                warn!("failed to read request: {}", e);
+                return;
+            },
+        };
+
+        log_access(&config, record, started, logged);
+        if !keep_alive {
+            return;
+        }
+    }
+}
+
+// websocket upgrades and event streams keep the connection for themselves
+fn takes_over_connection(request: &Request, app: &App, settings: &Settings) -> bool {
+    request.method == HttpMethod::Get && (
+        (is_websocket_upgrade(request) && app.websocket_handler(&request.path).is_some())
+            || is_event_stream_route(request, app, settings)
+    )
+}
+
+// serves a request that takes over the connection, returning the status and body size
+// for the access log
+async fn hand_over<S>(
+    request: Request, peer: Peer, state: &ServerState, config: &Config, stream: &S, reader: BufReader<&S>, writer: BufWriter<&S>,
+) -> (Option<i32>, Option<usize>)
+where
+    for<'a> &'a S: Read + Write + Unpin,
+    S: Clone + Read + Write + Unpin + Send + 'static,
+{
+    if is_websocket_upgrade(&request) {
+        upgrade_websocket(request, peer, state, config, stream, reader, writer).await
+    } else {
+        serve_event_stream(request, peer, state, config, stream, reader, writer).await
+    }
+}
+
+// what a handler gets for `request`, served under the site its Host header names
+fn view_context(request: &Request, peer: Peer, state: &ServerState, config: &Config) -> ViewContext {
+    let site = config.settings.vhost(request.headers.get("host").map(|h| h.as_str()));
+    ViewContext{settings: Arc::clone(&config.settings), cache: Arc::clone(&state.cache),
+        listings: Arc::clone(&state.listings), minified: Arc::clone(&state.minified), decompressed: Arc::clone(&state.decompressed), missing: Arc::clone(&state.missing), tasks: state.tasks.clone(), templates: Arc::clone(&state.templates), renders: Arc::clone(&state.renders), connections: Arc::clone(&state.connections), peer,
+        site, context: Context::new()}
+}
+
+// in dev mode, a 404 carries the path that wasn't found, for debugging tools
+const NOT_FOUND_HEADER: &str = "X-Not-Found-Path";
+
+// the methods `HttpMethod` names, for `Allow` in the answer to `OPTIONS *`
+const SERVER_METHODS: &str = "GET, HEAD, POST, PUT, DELETE, OPTIONS, PATCH";
+
+// serves a parsed request, returning the status and body size for the access log
+async fn dispatch<W: Write + Unpin + HasSocket>(
+    request: Request, peer: Peer, state: &ServerState, config: &Config,
+    writer: &mut BufWriter<W>, keep_alive: bool, served: usize,
+) -> (Option<i32>, Option<usize>) {
+    let json_errors = config.error_pages.wants_json(&request);
+    let head_only = request.method == HttpMethod::Head;
+    // `OPTIONS *` asks whether the server is there at all, not about any resource; the
+    // answer lists every method it handles, whatever each route makes of them
+    if request.path == "*" {
+        let mut response = Response{status: HttpStatus::HttpOk(200), contents: Arc::default(), headers: None, file: None};
+        response.set_header("Allow", SERVER_METHODS);
+        set_connection_headers(&mut response, keep_alive, served, &config.settings);
+        return write_response(writer, response, false, state, config).await;
+    }
+    if let Some(proxy) = config.settings.proxies.iter().find(|p| p.matches(&request.path)) {
+        return match proxy_request(&request, proxy, peer, writer, &config.settings).await {
+            Ok((status, bytes)) => {
+                if let Err(e) = writer.flush().await {
+                    warn!("failed to flush proxied response: {}", e);
+                }
+                (status, Some(bytes as usize))
+            },
+            Err(e) => {
+                warn!("failed to reach upstream {}: {}", proxy.upstream, e);
+                let code = if e.kind() == std::io::ErrorKind::TimedOut { 504 } else { 502 };
+                let site = config.settings.vhost(request.headers.get("host").map(|h| h.as_str()));
+                let mut response = config.error_pages.response(code, &site, json_errors).await;
+                set_connection_headers(&mut response, false, served, &config.settings);
+                write_response(writer, response, head_only, state, config).await
+            },
+        };
+    }
+
+    let ctx = view_context(&request, peer, state, config);
+    let site = ctx.site.clone();
+    // handler panics are turned into a 500 inside the chain (see `Next::run`), where the
+    // middleware still sees the response; this turns a panicking middleware into one too,
+    // rather than leaving the connection to close without an answer
+    let (method, path) = (request.method.clone(), request.path.clone());
+    let mut response = match AssertUnwindSafe(state.app.handle(request, ctx)).catch_unwind().await {
+        Ok(response) => response,
+        Err(panic) => {
+            error!("middleware panicked on {} {} from {}: {}", method, path, peer, panic_message(&panic));
+            Response::error(500)
+        },
+    };
+    if response.status.code() == 404 {
+        // the path only goes in the log, escaped, and in dev mode a header; never a body
+        info!(target: "not_found", "{} {} from {}: not found", method, path.escape_debug(), peer);
+        if config.settings.dev_mode {
+            response.set_header(NOT_FOUND_HEADER, &header_escape(&path));
+        }
+    }
+    let mut response = config.error_pages.apply(response, &site, json_errors).await;
+    if config.settings.log_bodies {
+        info!(target: "body", "response to {} {}: {}", method, path, body_log::describe_response(&response, &config.settings));
+    }
+    set_connection_headers(&mut response, keep_alive, served, &config.settings);
+    if let Some(file) = response.file.take() {
+        return write_file_response(writer, response, &file, head_only, state, config).await;
+    }
+    write_response(writer, response, head_only, state, config).await
+}
+
+// writes a `Response::file` response: the head, then (unless `head_only`) the file
+// streamed from disk
+async fn write_file_response<W: Write + Unpin + HasSocket>(
+    writer: &mut BufWriter<W>, response: Response, file: &str, head_only: bool, state: &ServerState, config: &Config,
+) -> (Option<i32>, Option<usize>) {
+    let socket = writer.get_ref().socket();
+    // a 304, or an error page put in its place, has no use for the file
+    if response.status.code() != 200 {
+        return write_response(writer, response, head_only, state, config).await;
+    }
+    let path = file.to_string();
+    let opened = async_std::task::spawn_blocking(move || {
+        let file = std::fs::File::open(&path)?;
+        Ok::<_, std::io::Error>((file.metadata()?.len(), file))
+    }).await;
+    let (len, opened) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            let code = if e.kind() == std::io::ErrorKind::NotFound { 404 } else { 500 };
+            warn!("failed to open {}: {}", file, e);
+            let mut error = Response::error(code);
+            if let Some(connection) = response.header("connection") {
+                error.set_header("Connection", connection);
+            }
+            return write_response(writer, error, head_only, state, config).await;
+        },
+    };
+    let logged = Some(response.status.code());
+    let mut head = state.buffers.get();
+    response.write_head(&mut head, Some(len as usize), &config.settings.charset);
+    if let Err(e) = async { writer.write_all(&head).await?; writer.flush().await }.await {
+        warn!("failed to write response: {}", e);
+        return (logged, None);
+    }
+    if head_only {
+        return (logged, Some(0));
+    }
+    match sendfile::send_file(writer, socket, opened, len).await {
+        Ok(sent) => (logged, Some(sent as usize)),
+        Err(e) => {
+            warn!("failed to send {}: {}", file, e);
+            sendfile::abort(socket);
+            (logged, None)
+        },
+    }
+}
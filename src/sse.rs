@@ -0,0 +1,72 @@
+use std::future::Future;
+use std::io::Result;
+use async_std::io::Write;
+use async_std::prelude::*;
+use futures::future::BoxFuture;
+use crate::app::ViewContext;
+use crate::http::Request;
+
+
+/// the stream an `EventStreamHandler` is given, over the connection it took over
+pub type Events = EventStream<Box<dyn Write + Unpin + Send>>;
+
+/// pushes events to clients of a path registered with `App::event_stream`, once the
+/// `text/event-stream` head is sent; the connection closes when it returns. implemented
+/// for every `async fn(Request, ViewContext, Events) -> std::io::Result<()>`, and closures
+/// returning such a future. a send failing means the client went away.
+pub trait EventStreamHandler: Send + Sync + 'static {
+    fn call(&self, request: Request, ctx: ViewContext, events: Events) -> BoxFuture<'static, Result<()>>;
+}
+
+impl<F, Fut> EventStreamHandler for F
+where
+    F: Fn(Request, ViewContext, Events) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    fn call(&self, request: Request, ctx: ViewContext, events: Events) -> BoxFuture<'static, Result<()>> {
+        Box::pin(self(request, ctx, events))
+    }
+}
+
+/// a server-sent events stream over a connection whose `text/event-stream`
+/// response head has already been written. every send is flushed immediately;
+/// a write error means the client went away and the stream should be dropped.
+pub struct EventStream<W> {
+    writer: W,
+}
+
+impl<W: Write + Unpin> EventStream<W> {
+    pub fn new(writer: W) -> EventStream<W> {
+        EventStream { writer }
+    }
+
+    /// sends an unnamed event; multi-line data is split across `data:` lines
+    pub async fn send(&mut self, data: &str) -> Result<()> {
+        self.write_event(None, data).await
+    }
+
+    /// sends an event which clients receive through `addEventListener(event, ...)`
+    pub async fn send_event(&mut self, event: &str, data: &str) -> Result<()> {
+        self.write_event(Some(event), data).await
+    }
+
+    /// sends a comment line, which clients ignore; useful to keep idle proxies from
+    /// timing the stream out and to notice disconnects between events
+    pub async fn keep_alive(&mut self) -> Result<()> {
+        self.writer.write_all(b":\n\n").await?;
+        self.writer.flush().await
+    }
+
+    async fn write_event(&mut self, event: Option<&str>, data: &str) -> Result<()> {
+        let mut frame = String::new();
+        if let Some(event) = event {
+            frame.push_str(&format!("event: {}\n", event));
+        }
+        for line in data.split('\n').map(|line| line.trim_end_matches('\r')) {
+            frame.push_str(&format!("data: {}\n", line));
+        }
+        frame.push('\n');
+        self.writer.write_all(frame.as_bytes()).await?;
+        self.writer.flush().await
+    }
+}
@@ -0,0 +1,2141 @@
+use std::fs;
+use std::io::{self, Write};
+use std::collections::{HashMap, HashSet};
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use std::sync::{Arc, RwLock};
+use std::env;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
+use regex::Regex;
+use log::warn;
+use pulldown_cmark::{html, Options};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, NaiveTime};
+use crate::http::HttpContentType;
+use crate::Settings;
+
+
+type NodeRef = Rc<RefCell<Node>>;
+type WeakNodeRef = Weak<RefCell<Node>>;
+pub type Context = HashMap<String, String>;
+
+/// names the server fills in on its own. any of them can be overridden by
+/// `Settings::global_context` or by the context passed to a render.
+///   now           - current local time, rfc 3339
+///   year          - current year
+///   version       - ferropress version
+///   request_path  - path of the request being served (server renders only)
+///   host          - Host header of the request, or the bind host (server renders only)
+pub const RESERVED_KEYS: &[&str] = &["now", "year", "version", "request_path", "host"];
+
+// built-ins that don't depend on a request
+pub fn builtin_context() -> Context {
+    let now = Local::now();
+    let mut ctx = Context::new();
+    ctx.insert("now".into(), now.to_rfc3339());
+    ctx.insert("year".into(), now.year().to_string());
+    ctx.insert("version".into(), env!("CARGO_PKG_VERSION").into());
+    ctx
+}
+
+// context available to templates and injected static files on every request.
+// built-ins are added first so entries from `Settings::global_context` can override them.
+pub fn global_context(settings: &Settings, request_path: &str, host: &str) -> Context {
+    let mut ctx = builtin_context();
+    ctx.insert("request_path".into(), request_path.into());
+    ctx.insert("host".into(), host.into());
+    ctx.extend(settings.global_context.clone());
+    ctx
+}
+
+// `{{! ... }}`, a comment; it ends at the first `}}`
+fn comment_re() -> Regex {
+    Regex::new(r"(?s)\{\{\s*!.*?\}\}").unwrap()
+}
+
+/// replaces every `{{ key }}` in `target_str` with its value from `ctx`; missing keys
+/// become `CTX MISS`, and `{{! comments }}` are removed
+pub fn inject_context(target_str: &str, ctx: &Context) -> String {
+    let re = Regex::new(r"\{\{\s*(.+?)\s*\}\}").unwrap();
+    let default = String::from("CTX MISS");
+    let target_str = comment_re().replace_all(target_str, "");
+
+    // Replace each placeholder with the corresponding value from the HashMap
+    let result = re.replace_all(&target_str, |caps: &regex::Captures| {
+        let key = caps.get(1).unwrap().as_str();
+        ctx.get(key).unwrap_or(&default)
+    });
+    result.to_string()
+}
+
+/// `inject_context`, or in `strict` mode an error naming the first key `ctx` is missing
+pub fn inject_context_strict(target_str: &str, ctx: &Context, strict: bool) -> Result<String, RenderError> {
+    if strict {
+        let re = Regex::new(r"\{\{\s*(.+?)\s*\}\}").unwrap();
+        let uncommented = comment_re().replace_all(target_str, "");
+        let missing = re.captures_iter(&uncommented).map(|caps| caps.get(1).unwrap().as_str()).find(|key| !ctx.contains_key(*key));
+        if let Some(key) = missing {
+            return Err(RenderError::MissingKey(key.to_string()));
+        }
+    }
+    Ok(inject_context(target_str, ctx))
+}
+
+// block directives are expanded on the template source before it is parsed as html:
+//   {{#macro name(a, b)}} ... {{/macro}}  - defines a macro; the definition itself renders nothing
+//   {{call name "x" key}}                 - expands a macro; arguments are quoted literals,
+//                                           numbers or context keys, bound to the parameters in order
+//   {{#if cond}} ... {{else}} ... {{/if}} - renders one branch; `{{else}}` is optional
+//   {{#with user}} ... {{/with}}          - renders its body with `user.name` named `name`, and
+//                                           so on for every key below `user`
+//   {{#each attrs}} ... {{/each}}         - renders its body once per entry below `attrs`, with
+//                                           `{{@key}}` its name and `{{this}}` its value
+//   {{> path}}                            - includes another template's source in place, with
+//                                           `path` relative to the including file's directory;
+//                                           `{{include "path"}}` is the same
+//   {{> path with user=author title="x"}} - includes it with extra names bound for it alone;
+//                                           a trailing `only` hides everything else from it
+//   {{ date(page.date, "%B %-d, %Y") }}   - calls a function (see `TemplateFunctions`) with
+//                                           comma separated arguments, given like macro arguments
+//   {{! note for editors }}               - a comment, left out of the output entirely; it can
+//                                           span lines, and ends at the first `}}`
+// inside a macro body `{{ a }}` refers to the argument. any other `{{ key }}` is left
+// for `inject_context`, so the body still sees the render context.
+// names resolve from the innermost scope (macro arguments, include bindings) outward to
+// the render context. a plain include shares its includer's scope; an `only` include
+// sees its bindings and the built-ins, and anything else renders as a miss.
+// a `#with` body sees the rest of the enclosing scope too, and names its object shadows
+// resolve to the object's keys. an object with no keys at all renders the block as
+// nothing, or is an error in strict mode. so is a `#each` one. the entries of `attrs` are
+// the first segments of the keys below it (`attrs.id`, `attrs.size.w` are entries `id`
+// and `size`), taken in order of their names, numbers first and numerically, so a list
+// given as `items.0`, `items.1`, ... keeps its order. an entry with keys below it
+// (`size.w`) also has those by their short names (`w`), as in `#with`.
+// macros may be defined after they are called, and may call each other. included files
+// share the includer's macros, and their front-matter is ignored.
+//
+// an `#if` condition is either a single operand, true when it is present and non-empty,
+// or `operand op operand` with op one of `==` `!=` `<` `>` `<=` `>=`. operands are the
+// same as macro arguments. two numbers compare numerically, anything else compares as
+// strings, and a comparison involving a missing key is false.
+//   {{#if page.title}}  {{#if count > 0}}  {{#if status == "active"}}
+
+// calls nested deeper than this (e.g. a macro calling itself) stop expanding
+const MAX_MACRO_DEPTH: usize = 32;
+
+// includes nested deeper than this are skipped, as are includes of a file already being included
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Text(String),
+    // `{{ key }}`, with the original tag kept for keys that aren't bound locally
+    Var(String, String),
+    Call(String, Vec<Arg>),
+    // `{{ name(args) }}`, a call to one of the `TemplateFunctions`
+    Func(String, Vec<Arg>),
+    If(Condition, Vec<Segment>, Vec<Segment>),
+    // `{{#with name}}`: the body, with the keys below `name.` bound without the prefix
+    With(String, Vec<Segment>),
+    // `{{#each name}}`: the body, once for each entry below `name.`
+    Each(String, Vec<Segment>),
+    // an include with bindings, and whether it is isolated from the includer's names
+    Scope(Vec<(String, Arg)>, bool, Vec<Segment>),
+    // an included file's segments, `None` when it couldn't be read; see `Mode::Contained`
+    Partial(String, Option<Vec<Segment>>),
+    // where a syntax error was found; see `Mode`
+    Error(SyntaxError),
+}
+
+#[derive(Debug, Clone)]
+enum Arg {
+    Literal(String),
+    Key(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+#[derive(Debug, Clone)]
+enum Condition {
+    Truthy(Arg),
+    Compare(Arg, CmpOp, Arg),
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Segment>,
+}
+
+type Macros = HashMap<String, Macro>;
+
+// a block directive waiting for its closing tag
+enum OpenBlock {
+    Macro(String, Vec<String>, Vec<Segment>),
+    // the else branch is `Some` once `{{else}}` has been seen
+    If(Condition, Vec<Segment>, Option<Vec<Segment>>),
+    With(String, Vec<Segment>),
+    Each(String, Vec<Segment>),
+}
+
+// an include tag: `path [with name=operand ...] [only]`
+struct IncludeTag {
+    path: String,
+    bindings: Vec<(String, Arg)>,
+    only: bool,
+}
+
+// names bound while rendering, innermost last; see `Scope::get`
+struct Scope<'a> {
+    vars: Context,
+    parent: Option<&'a Scope<'a>>,
+    // an `only` include: nothing outside this scope is visible
+    isolated: bool,
+}
+
+impl Scope<'_> {
+    fn root() -> Scope<'static> {
+        Scope { vars: Context::new(), parent: None, isolated: false }
+    }
+
+    // walks outward from this scope until the name is found or an isolated scope ends it
+    fn get(&self, key: &str) -> Option<&String> {
+        match (self.vars.get(key), self.parent) {
+            (Some(value), _) => Some(value),
+            (None, Some(parent)) if !self.isolated => parent.get(key),
+            _ => None,
+        }
+    }
+
+    // whether the render context is hidden from this scope
+    fn is_isolated(&self) -> bool {
+        self.isolated || self.parent.is_some_and(|parent| parent.is_isolated())
+    }
+}
+
+// the files read while parsing a template
+struct Includes {
+    // the file being parsed and the files including it, outermost first
+    stack: Vec<String>,
+    // every file the template was built from, including itself
+    deps: HashSet<String>,
+    // the syntax errors found in all of them
+    errors: Vec<SyntaxError>,
+}
+
+impl OpenBlock {
+    fn segments(&mut self) -> &mut Vec<Segment> {
+        match self {
+            OpenBlock::Macro(_, _, body) => body,
+            OpenBlock::If(_, _, Some(otherwise)) => otherwise,
+            OpenBlock::If(_, then, None) => then,
+            OpenBlock::With(_, body) | OpenBlock::Each(_, body) => body,
+        }
+    }
+}
+
+fn directive_re() -> Regex {
+    Regex::new(r"(?s)\{\{(.*?)\}\}").unwrap()
+}
+
+// `name(a, b)` from a macro definition
+fn parse_signature(sig: &str) -> Option<(String, Vec<String>)> {
+    let (name, params) = sig.trim().split_once('(')?;
+    let params = params.trim_end().strip_suffix(')')?;
+    let params = params.split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect();
+    Some((name.trim().to_string(), params))
+}
+
+// whitespace separated arguments; double quoted ones (`\"` escapes a quote) and
+// numbers are literals, anything else is a context key
+fn parse_args(src: &str) -> Vec<Arg> {
+    let mut args = Vec::new();
+    let mut chars = src.chars().peekable();
+    while let Some(&ch) = chars.peek() {
+        if ch.is_whitespace() {
+            chars.next();
+        } else if ch == '"' {
+            chars.next();
+            let mut literal = String::new();
+            while let Some(ch) = chars.next() {
+                match ch {
+                    '\\' if chars.peek() == Some(&'"') => literal.push(chars.next().unwrap()),
+                    '"' => break,
+                    ch => literal.push(ch),
+                }
+            }
+            args.push(Arg::Literal(literal));
+        } else {
+            let mut key = String::new();
+            while let Some(&ch) = chars.peek() {
+                if ch.is_whitespace() {
+                    break;
+                }
+                key.push(ch);
+                chars.next();
+            }
+            match key.parse::<f64>() {
+                Ok(_) => args.push(Arg::Literal(key)),
+                Err(_) => args.push(Arg::Key(key)),
+            }
+        }
+    }
+    args
+}
+
+// exactly one operand, or `None`
+fn parse_operand(src: &str) -> Option<Arg> {
+    let mut args = parse_args(src);
+    match args.len() {
+        1 => args.pop(),
+        _ => None,
+    }
+}
+
+fn parse_condition(src: &str) -> Option<Condition> {
+    // the first operator outside of a quoted string splits the operands;
+    // two character operators are listed first so `<=` isn't read as `<`
+    const OPS: &[(&str, CmpOp)] = &[
+        ("==", CmpOp::Eq), ("!=", CmpOp::Ne), ("<=", CmpOp::Le),
+        (">=", CmpOp::Ge), ("<", CmpOp::Lt), (">", CmpOp::Gt),
+    ];
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, ch) in src.char_indices() {
+        match ch {
+            '\\' if quoted => {
+                escaped = !escaped;
+                continue;
+            },
+            '"' if !escaped => quoted = !quoted,
+            _ if !quoted => {
+                if let Some((op, cmp)) = OPS.iter().find(|(op, _)| src[i..].starts_with(op)) {
+                    let lhs = parse_operand(&src[..i])?;
+                    let rhs = parse_operand(&src[i + op.len()..])?;
+                    return Some(Condition::Compare(lhs, *cmp, rhs));
+                }
+            },
+            _ => {},
+        }
+        escaped = false;
+    }
+    parse_operand(src).map(Condition::Truthy)
+}
+
+// `name(a, "b", 3)`, a function call: a name of letters, digits and underscores and comma
+// separated operands. commas inside quoted literals don't separate arguments
+fn parse_function_call(src: &str) -> Option<(String, Vec<Arg>)> {
+    let (name, args) = src.split_once('(')?;
+    let name = name.trim();
+    if name.is_empty() || !name.chars().all(|ch| ch.is_ascii_alphanumeric() || ch == '_') {
+        return None;
+    }
+    let args = args.trim_end().strip_suffix(')')?;
+    if args.trim().is_empty() {
+        return Some((name.to_string(), Vec::new()));
+    }
+    let mut pieces = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, ch) in args.char_indices() {
+        match ch {
+            '\\' if quoted => {
+                escaped = !escaped;
+                continue;
+            },
+            '"' if !escaped => quoted = !quoted,
+            ',' if !quoted => {
+                pieces.push(&args[start..i]);
+                start = i + 1;
+            },
+            _ => {},
+        }
+        escaped = false;
+    }
+    pieces.push(&args[start..]);
+    let args = pieces.into_iter().map(parse_operand).collect::<Option<Vec<_>>>()?;
+    Some((name.to_string(), args))
+}
+
+// reads the body of an include tag; the path may be quoted, and binding values are
+// operands like macro arguments
+fn parse_include(src: &str) -> Option<IncludeTag> {
+    let src = src.trim();
+    let (path, mut rest) = match src.strip_prefix('"') {
+        Some(quoted) => quoted.split_once('"')?,
+        None => src.split_once(char::is_whitespace).unwrap_or((src, "")),
+    };
+    let mut tag = IncludeTag { path: path.to_string(), bindings: Vec::new(), only: false };
+    rest = rest.trim_start();
+    if let Some(only) = rest.strip_suffix("only").filter(|r| r.is_empty() || r.ends_with(char::is_whitespace)) {
+        tag.only = true;
+        rest = only.trim_end();
+    }
+    if rest.is_empty() {
+        return (!tag.path.is_empty()).then_some(tag);
+    }
+    let mut rest = rest.strip_prefix("with")?.strip_prefix(char::is_whitespace)?.trim_start();
+    while !rest.is_empty() {
+        let (name, value) = rest.split_once('=')?;
+        let name = name.trim();
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return None;
+        }
+        let value = value.trim_start();
+        // a quoted value runs to its closing quote, anything else to the next space
+        let end = match value.strip_prefix('"') {
+            Some(quoted) => {
+                let mut escaped = false;
+                1 + quoted.char_indices().find(|&(_, ch)| {
+                    let closes = ch == '"' && !escaped;
+                    escaped = ch == '\\' && !escaped;
+                    closes
+                })?.0 + 1
+            },
+            None => value.find(char::is_whitespace).unwrap_or(value.len()),
+        };
+        tag.bindings.push((name.to_string(), parse_operand(&value[..end])?));
+        rest = value[end..].trim_start();
+    }
+    Some(tag)
+}
+
+// splits a template into segments, moving macro definitions into `macros`.
+// a stray closing tag is kept as text and unclosed blocks run to the end; both are
+// logged as a `SyntaxError`, which is also left in the segments where it was found and
+// added to `includes.errors`. `first_line` is where `src` starts in its file, for
+// reporting positions past a front-matter block.
+fn parse_directives(src: &str, first_line: usize, macros: &mut Macros, includes: &mut Includes) -> Vec<Segment> {
+    // segments go to the innermost open block, or to the template itself
+    fn current<'a>(open: &'a mut [OpenBlock], out: &'a mut Vec<Segment>) -> &'a mut Vec<Segment> {
+        match open.last_mut() {
+            Some(block) => block.segments(),
+            None => out,
+        }
+    }
+    fn report(error: SyntaxError, segments: &mut Vec<Segment>, errors: &mut Vec<SyntaxError>) {
+        warn!("{}", error);
+        segments.push(Segment::Error(error.clone()));
+        errors.push(error);
+    }
+    fn close(block: OpenBlock, open: &mut [OpenBlock], out: &mut Vec<Segment>, macros: &mut Macros) {
+        match block {
+            OpenBlock::Macro(name, params, body) => {
+                macros.insert(name, Macro { params, body });
+            },
+            OpenBlock::If(cond, then, otherwise) => {
+                current(open, out).push(Segment::If(cond, then, otherwise.unwrap_or_default()));
+            },
+            OpenBlock::With(name, body) => current(open, out).push(Segment::With(name, body)),
+            OpenBlock::Each(name, body) => current(open, out).push(Segment::Each(name, body)),
+        }
+    }
+
+    let file = includes.stack.last().cloned().unwrap_or_default();
+    let mut out = Vec::new();
+    let mut open: Vec<OpenBlock> = Vec::new();
+    // the opening tag of each block in `open`, for reporting ones never closed
+    let mut opened_at = Vec::new();
+    let mut last = 0;
+    for caps in directive_re().captures_iter(src) {
+        let tag = caps.get(0).unwrap();
+        let inner = caps.get(1).unwrap().as_str().trim();
+        if tag.start() > last {
+            current(&mut open, &mut out).push(Segment::Text(src[last..tag.start()].to_string()));
+        }
+        last = tag.end();
+        if inner.starts_with('!') {
+            continue;
+        }
+
+        let segment = if let Some((name, params)) = inner.strip_prefix("#macro ").and_then(parse_signature) {
+            open.push(OpenBlock::Macro(name, params, Vec::new()));
+            opened_at.push(tag.range());
+            continue;
+        } else if let Some(cond) = inner.strip_prefix("#if ").and_then(parse_condition) {
+            open.push(OpenBlock::If(cond, Vec::new(), None));
+            opened_at.push(tag.range());
+            continue;
+        } else if let Some(Arg::Key(name)) = inner.strip_prefix("#with ").and_then(parse_operand) {
+            open.push(OpenBlock::With(name, Vec::new()));
+            opened_at.push(tag.range());
+            continue;
+        } else if let Some(Arg::Key(name)) = inner.strip_prefix("#each ").and_then(parse_operand) {
+            open.push(OpenBlock::Each(name, Vec::new()));
+            opened_at.push(tag.range());
+            continue;
+        } else if inner == "else" && matches!(open.last(), Some(OpenBlock::If(_, _, None))) {
+            if let Some(OpenBlock::If(_, _, otherwise)) = open.last_mut() {
+                *otherwise = Some(Vec::new());
+            }
+            continue;
+        } else if (inner == "/macro" && matches!(open.last(), Some(OpenBlock::Macro(..))))
+            || (inner == "/if" && matches!(open.last(), Some(OpenBlock::If(..))))
+            || (inner == "/with" && matches!(open.last(), Some(OpenBlock::With(..))))
+            || (inner == "/each" && matches!(open.last(), Some(OpenBlock::Each(..)))) {
+            let block = open.pop().unwrap();
+            opened_at.pop();
+            close(block, &mut open, &mut out, macros);
+            continue;
+        } else if let Some(args) = inner.strip_prefix('>').or_else(|| inner.strip_prefix("include ")) {
+            match parse_include(args) {
+                Some(IncludeTag { path, bindings, only }) => {
+                    let partial = Segment::Partial(path.clone(), include(&path, macros, includes));
+                    if bindings.is_empty() && !only {
+                        partial
+                    } else {
+                        Segment::Scope(bindings, only, vec![partial])
+                    }
+                },
+                None => {
+                    let message = format!("malformed template include `{}`", tag.as_str());
+                    let error = SyntaxError::new(&file, src, first_line, tag.range(), message);
+                    report(error, current(&mut open, &mut out), &mut includes.errors);
+                    Segment::Text(tag.as_str().to_string())
+                },
+            }
+        } else if let Some(call) = inner.strip_prefix("call ") {
+            let args = parse_args(call);
+            match args.first() {
+                Some(Arg::Key(name)) => Segment::Call(name.clone(), args[1..].to_vec()),
+                _ => Segment::Text(tag.as_str().to_string()),
+            }
+        } else if inner.starts_with('#') || inner.starts_with('/') || inner == "else" {
+            let message = format!("malformed or unbalanced template directive `{}`", tag.as_str());
+            let error = SyntaxError::new(&file, src, first_line, tag.range(), message);
+            report(error, current(&mut open, &mut out), &mut includes.errors);
+            Segment::Text(tag.as_str().to_string())
+        } else if let Some((name, args)) = parse_function_call(inner) {
+            Segment::Func(name, args)
+        } else {
+            Segment::Var(inner.to_string(), tag.as_str().to_string())
+        };
+        current(&mut open, &mut out).push(segment);
+    }
+    if last < src.len() {
+        current(&mut open, &mut out).push(Segment::Text(src[last..].to_string()));
+    }
+    while let (Some(block), Some(at)) = (open.pop(), opened_at.pop()) {
+        let message = format!("template block `{}` is never closed", &src[at.start + 2..at.end - 2].trim());
+        let error = SyntaxError::new(&file, src, first_line, at, message);
+        // the marker goes where the block opened, just ahead of it
+        report(error, current(&mut open, &mut out), &mut includes.errors);
+        close(block, &mut open, &mut out, macros);
+    }
+    out
+}
+
+fn lookup<'a>(arg: &'a Arg, ctx: &'a Context, scope: &'a Scope) -> Option<&'a String> {
+    match arg {
+        Arg::Literal(value) => Some(value),
+        Arg::Key(key) => scope.get(key).or_else(|| {
+            // an isolated include still sees the built-ins
+            let visible = !scope.is_isolated() || RESERVED_KEYS.contains(&key.as_str());
+            ctx.get(key).filter(|_| visible)
+        }),
+    }
+}
+
+// the keys below `name.` visible from `scope`, without that prefix, or `None` when there
+// are none. inner scopes win over outer ones and the render context, as in `lookup`
+fn sub_object(name: &str, ctx: &Context, scope: &Scope) -> Option<Context> {
+    let prefix = format!("{}.", name);
+    let below = |vars: &Context| -> Vec<(String, String)> {
+        vars.iter()
+            .filter_map(|(key, value)| Some((key.strip_prefix(&prefix)?.to_string(), value.clone())))
+            .collect()
+    };
+    let mut object = Context::new();
+    if !scope.is_isolated() {
+        object.extend(below(ctx));
+    }
+    let mut chain = vec![scope];
+    while let Some(parent) = chain.last().filter(|inner| !inner.isolated).and_then(|inner| inner.parent) {
+        chain.push(parent);
+    }
+    for vars in chain.iter().rev().map(|scope| &scope.vars) {
+        object.extend(below(vars));
+    }
+    Some(object).filter(|object| !object.is_empty())
+}
+
+// the entries of a `sub_object`, in `#each` order, as the names each one's body sees:
+// `@key`, `this` when the entry has a value of its own, and the keys below it
+fn entries(object: Context) -> Vec<Context> {
+    let mut entries: HashMap<String, Context> = HashMap::new();
+    for (key, value) in object {
+        let (entry, rest) = match key.split_once('.') {
+            Some((entry, rest)) => (entry.to_string(), rest.to_string()),
+            None => (key, String::from("this")),
+        };
+        entries.entry(entry.clone()).or_insert_with(|| Context::from([(String::from("@key"), entry)])).insert(rest, value);
+    }
+    let mut entries: Vec<(String, Context)> = entries.into_iter().collect();
+    entries.sort_by_key(|(key, _)| (key.parse::<u64>().map_or((1, 0), |n| (0, n)), key.clone()));
+    entries.into_iter().map(|(_, vars)| vars).collect()
+}
+
+fn eval_condition(cond: &Condition, ctx: &Context, scope: &Scope) -> bool {
+    match cond {
+        Condition::Truthy(arg) => lookup(arg, ctx, scope).is_some_and(|v| !v.is_empty()),
+        Condition::Compare(lhs, op, rhs) => {
+            let (Some(lhs), Some(rhs)) = (lookup(lhs, ctx, scope), lookup(rhs, ctx, scope)) else {
+                return false;
+            };
+            let ordering = match (lhs.trim().parse::<f64>(), rhs.trim().parse::<f64>()) {
+                (Ok(l), Ok(r)) => l.partial_cmp(&r),
+                _ => Some(lhs.cmp(rhs)),
+            };
+            // NaN compares unequal to everything
+            let Some(ordering) = ordering else {
+                return *op == CmpOp::Ne;
+            };
+            match op {
+                CmpOp::Eq => ordering.is_eq(),
+                CmpOp::Ne => ordering.is_ne(),
+                CmpOp::Lt => ordering.is_lt(),
+                CmpOp::Gt => ordering.is_gt(),
+                CmpOp::Le => ordering.is_le(),
+                CmpOp::Ge => ordering.is_ge(),
+            }
+        },
+    }
+}
+
+/// a function templates call as `{{ name(arg, ...) }}`: given its arguments' values, the
+/// text to put in place of the tag, or why there is none
+pub type TemplateFunction = Arc<dyn Fn(&[String]) -> Result<String, String> + Send + Sync>;
+
+/// the functions templates can call, by name. `new` has the built-ins:
+///
+/// - `date(value, format)`: an rfc 3339 timestamp (`2024-05-01T09:30:00Z`), or a date and
+///   time or plain date without a zone (taken as utc), formatted with strftime `format`;
+///   `%Y-%m-%d` when the format is left out. `{{ date(now, "%Y") }}`
+/// - `urlencode(value)`: every byte but letters, digits and `-._~` percent-encoded, for a
+///   query parameter or path segment
+/// - `join(separator, value, ...)`: the values that aren't empty, with `separator` between
+#[derive(Clone)]
+pub struct TemplateFunctions {
+    functions: HashMap<String, TemplateFunction>,
+}
+
+impl Default for TemplateFunctions {
+    fn default() -> TemplateFunctions {
+        let mut functions = TemplateFunctions { functions: HashMap::new() };
+        functions.insert("date", date);
+        functions.insert("urlencode", urlencode);
+        functions.insert("join", join);
+        functions
+    }
+}
+
+impl TemplateFunctions {
+    pub fn new() -> TemplateFunctions {
+        TemplateFunctions::default()
+    }
+
+    /// makes `function` callable as `name`, replacing any function of that name
+    pub fn insert(&mut self, name: &str, function: impl Fn(&[String]) -> Result<String, String> + Send + Sync + 'static) {
+        self.functions.insert(name.to_string(), Arc::new(function));
+    }
+}
+
+fn date(args: &[String]) -> Result<String, String> {
+    use std::fmt::Write as _;
+    let (value, format) = match args {
+        [value] => (value.trim(), "%Y-%m-%d"),
+        [value, format] => (value.trim(), format.as_str()),
+        _ => return Err(format!("takes a date and a format, not {} arguments", args.len())),
+    };
+    let date = DateTime::parse_from_rfc3339(value)
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f").map(|date| date.and_utc().fixed_offset()))
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f").map(|date| date.and_utc().fixed_offset()))
+        .or_else(|_| NaiveDate::parse_from_str(value, "%Y-%m-%d").map(|date| date.and_time(NaiveTime::MIN).and_utc().fixed_offset()))
+        .map_err(|_| format!("`{}` isn't a date", value))?;
+    let items: Vec<Item> = StrftimeItems::new(format).collect();
+    if items.contains(&Item::Error) {
+        return Err(format!("`{}` isn't a valid date format", format));
+    }
+    let mut out = String::new();
+    write!(out, "{}", date.format_with_items(items.iter())).map_err(|_| format!("can't format a date as `{}`", format))?;
+    Ok(out)
+}
+
+fn urlencode(args: &[String]) -> Result<String, String> {
+    let [value] = args else {
+        return Err(format!("takes one argument, not {}", args.len()));
+    };
+    Ok(value.bytes().map(|b| match b {
+        b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+        b => format!("%{:02X}", b),
+    }).collect())
+}
+
+fn join(args: &[String]) -> Result<String, String> {
+    let [separator, values @ ..] = args else {
+        return Err(String::from("takes a separator and the values to join"));
+    };
+    Ok(values.iter().filter(|value| !value.is_empty()).map(String::as_str).collect::<Vec<_>>().join(separator))
+}
+
+// what a function tag renders to. a missing argument renders as a miss, and an unknown
+// function or one that fails is logged and renders as `FN MISS`; in `strict` mode all
+// three are errors
+fn call_function(
+    name: &str, args: &[Arg], ctx: &Context, scope: &Scope, functions: &TemplateFunctions, strict: bool,
+) -> Result<String, RenderError> {
+    let mut values = Vec::with_capacity(args.len());
+    for arg in args {
+        match (lookup(arg, ctx, scope), arg) {
+            (Some(value), _) => values.push(value.clone()),
+            (None, Arg::Key(key)) if strict => return Err(RenderError::MissingKey(key.clone())),
+            (None, _) => return Ok(String::from("CTX MISS")),
+        }
+    }
+    let result = match functions.functions.get(name) {
+        Some(function) => function(&values),
+        None => Err(String::from("no such function")),
+    };
+    match result {
+        Ok(text) => Ok(text),
+        Err(message) if strict => Err(RenderError::Function(name.to_string(), message)),
+        Err(message) => {
+            warn!("template function `{}`: {}", name, message);
+            Ok(String::from("FN MISS"))
+        },
+    }
+}
+
+// what a recovering render shows in place of a syntax error. the message is escaped so
+// it reads as written, rather than being parsed as markup or injected into
+fn error_marker(error: &SyntaxError) -> String {
+    let message = error.to_string()
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('{', "&#123;")
+        .replace('}', "&#125;");
+    format!(
+        "<pre class=\"template-error\" style=\"color: #a00; background: #fee; border: 1px solid #a00; padding: 0.5em\">{}</pre>",
+        message,
+    )
+}
+
+// when a render gives up, and the template it was rendering; see
+// `TemplateCache::render_timeout`
+#[derive(Clone, Copy)]
+struct Deadline<'a> {
+    at: Option<Instant>,
+    file: &'a str,
+}
+
+impl Deadline<'_> {
+    fn check(&self) -> Result<(), RenderError> {
+        match self.at {
+            Some(at) if Instant::now() >= at => Err(RenderError::TimedOut(self.file.to_string())),
+            _ => Ok(()),
+        }
+    }
+}
+
+// what stays the same throughout one render
+struct Env<'a> {
+    macros: &'a Macros,
+    functions: &'a TemplateFunctions,
+    deadline: Deadline<'a>,
+}
+
+// renders segments back to template source. `scope` holds the macro arguments and
+// include bindings in effect; everything else is left for `inject_context`. in strict
+// mode a name hidden by an `only` include is an error rather than a miss.
+fn render_directives(
+    segments: &[Segment], ctx: &Context, scope: &Scope, env: &Env, depth: usize, mode: Mode,
+) -> Result<String, RenderError> {
+    let strict = matches!(mode, Mode::Strict | Mode::Partial(_));
+    let mut out = String::new();
+    for segment in segments {
+        // checked at every step, so neither long loops nor deep macro recursion get past it
+        env.deadline.check()?;
+        match segment {
+            Segment::Text(text) => out.push_str(text),
+            Segment::Var(key, tag) => match scope.get(key) {
+                Some(value) => out.push_str(value),
+                None if scope.is_isolated() && !RESERVED_KEYS.contains(&key.as_str()) => {
+                    if strict {
+                        return Err(RenderError::MissingKey(key.clone()));
+                    }
+                    out.push_str("CTX MISS");
+                },
+                // a partial's keys are checked here, since by the time the page's are
+                // filled in there is no telling which came from the partial
+                None if matches!(mode, Mode::Partial(_)) && !ctx.contains_key(key) => {
+                    return Err(RenderError::MissingKey(key.clone()));
+                },
+                None => out.push_str(tag),
+            },
+            Segment::Call(name, args) => {
+                let Some(mac) = env.macros.get(name) else {
+                    warn!("template calls unknown macro `{}`", name);
+                    out.push_str("MACRO MISS");
+                    continue;
+                };
+                if depth >= MAX_MACRO_DEPTH {
+                    warn!("template macro `{}` nested more than {} deep", name, MAX_MACRO_DEPTH);
+                    continue;
+                }
+                // missing arguments leave their parameter unbound
+                let mut bound = Context::new();
+                for (param, arg) in mac.params.iter().zip(args) {
+                    if let Some(value) = lookup(arg, ctx, scope) {
+                        bound.insert(param.clone(), value.clone());
+                    }
+                }
+                // a macro body sees only its arguments, over whatever its caller can see
+                // of the render context
+                let inner = Scope { vars: bound, parent: None, isolated: scope.is_isolated() };
+                out.push_str(&render_directives(&mac.body, ctx, &inner, env, depth + 1, mode)?);
+            },
+            Segment::Func(name, args) => out.push_str(&call_function(name, args, ctx, scope, env.functions, strict)?),
+            Segment::Error(error) => match mode {
+                Mode::Strict | Mode::Partial(_) => return Err(RenderError::Syntax(error.clone())),
+                Mode::Recovering => out.push_str(&error_marker(error)),
+                Mode::Lenient | Mode::Contained(_) => {},
+            },
+            Segment::Partial(path, body) => {
+                let (Mode::Contained(placeholder) | Mode::Partial(placeholder)) = mode else {
+                    match body {
+                        Some(body) => out.push_str(&render_directives(body, ctx, scope, env, depth, mode)?),
+                        None => out.push_str("INCLUDE MISS"),
+                    }
+                    continue;
+                };
+                let rendered = match body {
+                    Some(body) => render_directives(body, ctx, scope, env, depth, Mode::Partial(placeholder)),
+                    None => Err(RenderError::TemplateNotFound(path.clone())),
+                };
+                match rendered {
+                    Ok(rendered) => out.push_str(&rendered),
+                    // running out of time isn't the partial's fault alone
+                    Err(e @ RenderError::TimedOut(_)) => return Err(e),
+                    Err(e) => {
+                        warn!("included template {} failed, rendering the placeholder instead: {}", path, e);
+                        out.push_str(placeholder);
+                    },
+                }
+            },
+            Segment::If(cond, then, otherwise) => {
+                let branch = if eval_condition(cond, ctx, scope) { then } else { otherwise };
+                out.push_str(&render_directives(branch, ctx, scope, env, depth, mode)?);
+            },
+            Segment::With(name, body) => match sub_object(name, ctx, scope) {
+                Some(vars) => {
+                    let inner = Scope { vars, parent: Some(scope), isolated: false };
+                    out.push_str(&render_directives(body, ctx, &inner, env, depth, mode)?);
+                },
+                None if strict => return Err(RenderError::MissingKey(name.clone())),
+                None => {},
+            },
+            Segment::Each(name, body) => match sub_object(name, ctx, scope) {
+                Some(object) => for vars in entries(object) {
+                    let inner = Scope { vars, parent: Some(scope), isolated: false };
+                    out.push_str(&render_directives(body, ctx, &inner, env, depth, mode)?);
+                },
+                None if strict => return Err(RenderError::MissingKey(name.clone())),
+                None => {},
+            },
+            Segment::Scope(bindings, only, body) => {
+                // a binding to a missing key is left unbound
+                let vars = bindings.iter()
+                    .filter_map(|(name, arg)| Some((name.clone(), lookup(arg, ctx, scope)?.clone())))
+                    .collect();
+                let inner = Scope { vars, parent: Some(scope), isolated: *only };
+                out.push_str(&render_directives(body, ctx, &inner, env, depth, mode)?);
+            },
+        }
+    }
+    Ok(out)
+}
+
+// `path` with `.` and `..` resolved without touching the filesystem, so every way of
+// naming a file gives the same key (`./templates/a.html` and `templates/a.html` alike)
+fn normalize_path(path: &Path) -> String {
+    let mut parts: Vec<Component> = Vec::new();
+    for part in path.components() {
+        match part {
+            Component::CurDir => {},
+            Component::ParentDir if matches!(parts.last(), Some(Component::Normal(_))) => {
+                parts.pop();
+            },
+            part => parts.push(part),
+        }
+    }
+    parts.iter().collect::<PathBuf>().to_string_lossy().into_owned()
+}
+
+// parses the file named by a `{{> name}}` tag in place of the tag, or `None` when it
+// can't be read. the file is recorded as a dependency either way, so creating it later
+// is noticed.
+fn include(name: &str, macros: &mut Macros, includes: &mut Includes) -> Option<Vec<Segment>> {
+    let including = Path::new(includes.stack.last().map(String::as_str).unwrap_or_default());
+    let path = normalize_path(&including.parent().unwrap_or(Path::new("")).join(name));
+    includes.deps.insert(path.clone());
+    if includes.stack.contains(&path) || includes.stack.len() > MAX_INCLUDE_DEPTH {
+        warn!("template include of {} is recursive or nested too deeply", path);
+        return Some(Vec::new());
+    }
+    let src = match fs::read_to_string(&path) {
+        Ok(src) => src,
+        Err(e) => {
+            warn!("can't include template {}: {}", path, e);
+            return None;
+        },
+    };
+    let (_, body) = split_front_matter(&src);
+    includes.stack.push(path);
+    let segments = parse_directives(body, front_matter_lines(&src, body), macros, includes);
+    includes.stack.pop();
+    Some(segments)
+}
+
+// splits a leading `---` delimited front-matter block off of a template.
+// only a flat `key: value` subset of yaml is understood; values may be quoted,
+// and blank lines or lines starting with '#' are skipped.
+// returns the metadata (empty if there is no front-matter) and the remaining source.
+fn split_front_matter(src: &str) -> (Context, &str) {
+    let mut meta = Context::new();
+    let Some(rest) = src.strip_prefix("---") else {
+        return (meta, src);
+    };
+    let Some(rest) = rest.strip_prefix('\n').or_else(|| rest.strip_prefix("\r\n")) else {
+        return (meta, src);
+    };
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        offset += line.len();
+        let line = line.trim();
+        if line == "---" {
+            return (meta, &rest[offset..]);
+        }
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once(':') {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"').and_then(|v| v.strip_suffix('"'))
+                .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+                .unwrap_or(value);
+            meta.insert(key.trim().to_string(), value.to_string());
+        }
+    }
+    // no closing delimiter; treat the whole file as markup
+    (Context::new(), src)
+}
+
+// converts a markdown document to html so it can be fed through the html parser.
+// supports commonmark (headings, paragraphs, emphasis, links, images, lists,
+// blockquotes, code spans/blocks, rules) plus tables and strikethrough.
+// raw html in the markdown source is passed through untouched.
+fn markdown_to_html(src: &str) -> String {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    let events = pulldown_cmark::Parser::new_ext(src, options);
+    let mut out = String::new();
+    html::push_html(&mut out, events);
+    out
+}
+
+// elements which never have children or a closing tag
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input",
+    "link", "meta", "source", "track", "wbr",
+];
+
+fn is_void(tag: &str) -> bool {
+    VOID_ELEMENTS.contains(&tag.to_ascii_lowercase().as_str())
+}
+
+// elements whose bodies are kept exactly as written, without being parsed or
+// reindented: script and style hold raw text rather than markup, and pre and textarea
+// show their whitespace. markup inside a pre (a `<code>`, say) is passed through as is
+const RAW_TEXT_ELEMENTS: &[&str] = &["script", "style", "pre", "textarea"];
+
+fn is_raw_text(tag: &str) -> bool {
+    RAW_TEXT_ELEMENTS.contains(&tag.to_ascii_lowercase().as_str())
+}
+
+#[derive(Clone)]
+struct Node {
+    parent: Option<WeakNodeRef>,
+    children: Vec<NodeRef>,
+    tag: Option<String>,
+    attrs: Option<String>,
+    content: Option<String>,
+}
+
+
+#[derive(Debug)]
+enum State {
+    Attr,
+    Content,
+    Comment,
+    Declaration,
+    RawText,
+    Blank,
+    Tag,
+    TagOpen,
+    TagClose,
+}
+
+struct Parser {
+    state: State,
+    buf: String,
+    comment_buf: String,
+    attr_buf: String,
+    doctype: Option<String>,
+    current_node: NodeRef,
+    root: NodeRef,
+}
+
+impl Node {
+    fn new_root() -> Node {
+        Node {
+            parent: None,
+            children: Vec::new(),
+            tag: None,
+            content: None,
+            attrs: None,
+        }
+    }
+
+    fn add_child(&mut self, tag: Option<String>, attrs: Option<String>, parent: &NodeRef) -> NodeRef {
+        let parent = Rc::downgrade(parent);
+        let child = Node {
+            parent: Some(parent),
+            children: Vec::new(),
+            tag,
+            content: None,
+            attrs
+        };
+        
+        // add the child to self.children
+        let child_ref = Rc::new(RefCell::new(child));
+        self.children.push(Rc::clone(&child_ref));
+
+        // return reference to child
+        child_ref
+    }
+
+    fn write_html(&self, out: &mut dyn Write, depth: i32, ctx: &Context, strict: bool, deadline: Deadline) -> Result<(), RenderError> {
+        // a node's context injection is linear in its text, so once per node is enough
+        deadline.check()?;
+        let mut indentation = (0..depth).map(|_| "  ").collect::<String>();
+        let attrs_str = if let Some(attrs) = &self.attrs {
+            format!(" {}", inject_context_strict(attrs, ctx, strict)?)
+        } else {
+            String::from("")
+        };
+        if let Some(tag) = &self.tag {
+            write!(out, "{}<{}{}>", indentation, tag, attrs_str).map_err(RenderError::Write)?;
+            if is_void(tag) {
+                return out.write_all(b"\n").map_err(RenderError::Write);
+            }
+        }
+        if let Some(content) = &self.content {
+            let content = inject_context_strict(content, ctx, strict)?;
+            out.write_all(content.as_bytes()).map_err(RenderError::Write)?;
+            indentation = String::new();
+        } else {
+            out.write_all(b"\n").map_err(RenderError::Write)?;
+        }
+
+        for child in self.children.iter() {
+            child.borrow().write_html(out, depth + 1, ctx, strict, deadline)?;
+        }
+
+        if let Some(tag) = &self.tag {
+            writeln!(out, "{}</{}>", indentation, tag).map_err(RenderError::Write)?;
+        }
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    fn traverse_dfs(&self, depth: i32) {
+        if let Some(tag) = &self.tag {
+            let indentation = (0..depth).map(|_| "\t").collect::<String>();
+            println!("{}{}", indentation, tag);
+        }
+        for child in self.children.iter() {
+            child.borrow().traverse_dfs(depth + 1);
+        }
+    }
+}
+
+impl Parser {
+    fn new() -> Parser {
+        let root = Rc::new(RefCell::new(Node::new_root()));
+        Parser {
+            state: State::Blank,
+            buf: String::new(),
+            comment_buf: String::new(),
+            attr_buf: String::new(),
+            doctype: None,
+            current_node: Rc::clone(&root),
+            root: Rc::clone(&root),
+        }
+    }
+
+    fn add_child_to_current_node(&mut self, tag: Option<String>, attrs: Option<String>) {
+        let child = self.current_node.borrow_mut().add_child(tag, attrs, &self.current_node);
+        self.current_node = child;
+    }
+
+    // moves buffered text into the tree. text directly followed by the closing tag
+    // of an element without children becomes that element's content; anything else
+    // (text mixed with child elements) becomes an untagged text child.
+    fn flush_text(&mut self, closing: bool) {
+        if self.buf.is_empty() {
+            return;
+        }
+        let text = std::mem::take(&mut self.buf);
+        let mut node = self.current_node.borrow_mut();
+        if closing && node.children.is_empty() {
+            node.content = Some(text);
+        } else {
+            let child = node.add_child(None, None, &self.current_node);
+            child.borrow_mut().content = Some(text);
+        }
+    }
+
+    fn is_content(ch: char) -> bool {
+        !"<>\n\t\r\\ ".contains(ch)
+    }
+
+    fn ends_with_raw_close(&self) -> bool {
+        match &self.current_node.borrow().tag {
+            Some(tag) => self.buf.to_ascii_lowercase().ends_with(&format!("</{}", tag.to_ascii_lowercase())),
+            None => false,
+        }
+    }
+
+    #[allow(dead_code)]
+    fn debug_fsm(&self, ch: char) {
+        let buf_ref = &self.buf;
+        let mut parent_tag = String::new();
+        {
+            if let Some(parent_weak) = self.current_node.borrow().parent.clone() {
+                if let Some(parent) = parent_weak.upgrade() {
+                    let parent_borrow = parent.borrow();
+                    if let Some(tag) = &parent_borrow.tag {
+                        parent_tag = tag.clone();
+                    }
+                }
+            }
+        }
+        println!("buf: {} State: {:?}, current_node: {:?}, parent: {}, Char: {}", 
+                 buf_ref, self.state, self.current_node.borrow().tag, parent_tag, ch);
+    }
+
+    fn parse_ch(&mut self, ch: char) {
+        // self.debug_fsm(ch);
+        match (&self.state, ch) {
+            (State::Blank, '<') => {
+                self.state = State::Tag;
+                self.buf.clear();
+            },
+            (State::Blank, ch) if Self::is_content(ch) => {
+                self.state = State::Content;
+                self.buf.push(ch);
+            },
+            (State::Tag, '/') => {
+                self.flush_text(true);
+                self.state = State::TagClose;
+            },
+            (State::Tag, '!') => {
+                self.flush_text(false);
+                self.state = State::Declaration;
+            },
+            (State::Tag, ch) if ch.is_alphanumeric() => {
+                self.flush_text(false);
+                self.state = State::TagOpen;
+                self.buf.push(ch);
+            },
+            (State::Comment, '>') if self.comment_buf.ends_with("--") => {
+                self.comment_buf.clear();
+                self.state = State::Blank;
+            },
+            (State::Comment, _) => {
+                self.comment_buf.push(ch);
+            },
+            // `<!` opens either a comment (`<!--`) or a declaration such as `<!DOCTYPE html>`
+            (State::Declaration, '>') => {
+                let decl = std::mem::take(&mut self.comment_buf);
+                if decl.to_ascii_lowercase().starts_with("doctype") {
+                    self.doctype = Some(decl);
+                }
+                self.state = State::Blank;
+            },
+            (State::Declaration, _) => {
+                self.comment_buf.push(ch);
+                if self.comment_buf == "--" {
+                    self.comment_buf.clear();
+                    self.state = State::Comment;
+                }
+            },
+            (State::TagClose, ch) if ch != '>' => { },
+            (State::TagClose, '>') => {
+                let parent_weak = self.current_node.borrow().parent.clone();
+                if let Some(parent_weak) = parent_weak {
+                    if let Some(parent) = parent_weak.upgrade() {
+                        self.current_node = parent;
+                    } else {
+                        // Handle the error case where the parent has already been dropped.
+                    }
+                }
+                self.state = State::Blank;
+            },
+            (State::TagOpen, ' ') => {
+                self.state = State::Attr;
+            },
+            (State::TagOpen, ch) if ch.is_alphanumeric() => {
+                self.buf.push(ch);
+            },
+            (State::TagOpen | State::Attr, '>') => {
+                let attrs = if !self.attr_buf.is_empty() {
+                    Some(self.attr_buf.clone())
+                } else { None };
+                let tag = self.buf.clone();
+                // let child = self.current_node.borrow_mut().add_child(tag);
+                self.state = if is_raw_text(&tag) { State::RawText } else { State::Blank };
+                if is_void(&tag) {
+                    // void elements are leaves; a trailing self-closing '/' is dropped
+                    let attrs = attrs
+                        .map(|a| a.trim_end().trim_end_matches('/').trim_end().to_string())
+                        .filter(|a| !a.is_empty());
+                    self.current_node.borrow_mut().add_child(Some(tag), attrs, &self.current_node);
+                } else {
+                    self.add_child_to_current_node(Some(tag), attrs);
+                }
+
+                self.buf.clear();
+                self.attr_buf.clear();
+            },
+            (State::Attr, ch) => {
+                self.attr_buf.push(ch);
+            },
+            // raw text bodies are captured verbatim up to their own closing tag
+            (State::RawText, '>') if self.ends_with_raw_close() => {
+                let tag_len = self.current_node.borrow().tag.as_ref().map_or(0, |t| t.len());
+                self.buf.truncate(self.buf.len() - tag_len - 2);
+                let content = std::mem::take(&mut self.buf);
+                if !content.is_empty() {
+                    self.current_node.borrow_mut().content = Some(content);
+                }
+                let parent = self.current_node.borrow().parent.as_ref().and_then(|p| p.upgrade());
+                if let Some(parent) = parent {
+                    self.current_node = parent;
+                }
+                self.state = State::Blank;
+            },
+            (State::RawText, ch) => {
+                self.buf.push(ch);
+            },
+            (State::Content, ch) if ch != '<' => {
+                self.buf.push(ch);
+            },
+            (State::Content, '<') if !self.buf.ends_with('\\') => {
+                // the text is flushed once we know whether a tag opens or closes
+                self.state = State::Tag;
+            },
+            _ => {
+                // Error or other states
+            }
+        };
+    }
+
+    fn write_html(&self, out: &mut dyn Write, ctx: &Context, strict: bool, deadline: Deadline) -> Result<(), RenderError> {
+        if let Some(doctype) = &self.doctype {
+            write!(out, "<!{}>", doctype).map_err(RenderError::Write)?;
+        }
+        self.root.borrow().write_html(out, -1, ctx, strict, deadline)
+    }
+}
+
+#[allow(dead_code)]
+fn get_current_working_dir() -> std::io::Result<PathBuf> {
+    env::current_dir()
+}
+
+// a template parsed as far as it can be without a context
+struct Compiled {
+    // normalized, like the paths in `deps`
+    path: String,
+    meta: Context,
+    segments: Vec<Segment>,
+    macros: Macros,
+    markdown: bool,
+    // normalized paths of the template and every file it includes
+    deps: HashSet<String>,
+    errors: Vec<SyntaxError>,
+}
+
+/// why a template couldn't be rendered
+#[derive(Debug)]
+pub enum RenderError {
+    /// the template file doesn't exist, which usually means a misconfigured path
+    TemplateNotFound(String),
+    /// the template file exists but couldn't be read
+    Io(String, io::Error),
+    /// a strict render referenced a context key that isn't there
+    MissingKey(String),
+    /// a strict render called a function that doesn't exist or failed, with why
+    Function(String, String),
+    /// a strict render of a template with a syntax error, the first one found
+    Syntax(SyntaxError),
+    /// the rendered html couldn't be written out by `TemplateCache::render_to`
+    Write(io::Error),
+    /// the template took longer than `TemplateCache::render_timeout` allows
+    TimedOut(String),
+}
+
+impl RenderError {
+    pub(crate) fn new(path: &str, e: io::Error) -> RenderError {
+        match e.kind() {
+            io::ErrorKind::NotFound => RenderError::TemplateNotFound(path.to_string()),
+            _ => RenderError::Io(path.to_string(), e),
+        }
+    }
+}
+
+impl std::fmt::Display for RenderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RenderError::TemplateNotFound(path) => write!(f, "template not found: {}", path),
+            RenderError::Io(path, e) => write!(f, "can't read template {}: {}", path, e),
+            RenderError::MissingKey(key) => write!(f, "template context has no `{}`", key),
+            RenderError::Function(name, message) => write!(f, "template function `{}`: {}", name, message),
+            RenderError::Syntax(e) => write!(f, "{}", e),
+            RenderError::Write(e) => write!(f, "can't write rendered template: {}", e),
+            RenderError::TimedOut(path) => write!(f, "template {} took too long to render and was cut off", path),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+/// a problem in a template's directives, with where it is. its `Display` shows the
+/// surrounding lines with the offending tag underlined, like a compiler error:
+///
+/// ```text
+/// template block `#if page.draft` is never closed
+///  --> ./templates/post.html:4:1
+///   |
+/// 2 | <h1>{{ page.title }}</h1>
+/// 3 |
+/// 4 | {{#if page.draft}}
+///   | ^^^^^^^^^^^^^^^^^^
+/// ```
+#[derive(Debug, Clone)]
+pub struct SyntaxError {
+    pub file: String,
+    /// 1-based line of the file
+    pub line: usize,
+    /// 1-based column, in characters
+    pub column: usize,
+    pub message: String,
+    // the lines shown, numbered, ending with the offending one
+    context: Vec<(usize, String)>,
+    // characters to underline
+    width: usize,
+}
+
+// lines of context shown above the offending one
+const SNIPPET_LINES_BEFORE: usize = 2;
+
+impl SyntaxError {
+    // `at` is a byte range of `src`, which starts `first_line` lines into `file`
+    fn new(file: &str, src: &str, first_line: usize, at: std::ops::Range<usize>, message: String) -> SyntaxError {
+        let line_start = src[..at.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = src[at.start..].find('\n').map_or(src.len(), |i| at.start + i);
+        let index = src[..at.start].matches('\n').count();
+        let context = src.lines().enumerate()
+            .skip(index.saturating_sub(SNIPPET_LINES_BEFORE))
+            .take(index.min(SNIPPET_LINES_BEFORE) + 1)
+            .map(|(i, line)| (first_line + i + 1, line.to_string()))
+            .collect();
+        SyntaxError {
+            file: file.to_string(),
+            line: first_line + index + 1,
+            column: src[line_start..at.start].chars().count() + 1,
+            message,
+            context,
+            // a tag spanning lines is only underlined to the end of its first
+            width: src[at.start..at.end.min(line_end)].chars().count().max(1),
+        }
+    }
+}
+
+impl std::fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let gutter = self.line.to_string().len();
+        writeln!(f, "{}", self.message)?;
+        writeln!(f, "{:gutter$}--> {}:{}:{}", "", self.file, self.line, self.column)?;
+        writeln!(f, "{:gutter$} |", "")?;
+        for (number, line) in self.context.iter() {
+            writeln!(f, "{:>gutter$} |{}{}", number, if line.trim().is_empty() { "" } else { " " }, line.trim_end())?;
+        }
+        write!(f, "{:gutter$} | {:indent$}{}", "", "", "^".repeat(self.width), indent = self.column - 1)
+    }
+}
+
+impl std::error::Error for SyntaxError {}
+
+// lines taken up by the front-matter block in front of `body`, a suffix of `src`
+fn front_matter_lines(src: &str, body: &str) -> usize {
+    src[..src.len() - body.len()].matches('\n').count()
+}
+
+fn compile(file_name: &str) -> Result<Compiled, RenderError> {
+    let f = fs::read_to_string(file_name).map_err(|e| RenderError::new(file_name, e))?;
+    let (meta, body) = split_front_matter(&f);
+    let path = normalize_path(Path::new(file_name));
+    let mut includes = Includes { stack: vec![path.clone()], deps: HashSet::from([path.clone()]), errors: Vec::new() };
+    let mut macros = Macros::new();
+    // directives are expanded first so markdown never sees (and escapes) their arguments
+    let segments = parse_directives(body, front_matter_lines(&f, body), &mut macros, &mut includes);
+    Ok(Compiled {
+        path, meta, segments, macros, markdown: file_name.ends_with(".md"), deps: includes.deps, errors: includes.errors,
+    })
+}
+
+// how a render deals with what's wrong in a template
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode<'a> {
+    // a missing key or failing function renders as a miss, and syntax errors are only logged
+    Lenient,
+    // the first missing key, failing function or syntax error is an error
+    Strict,
+    // lenient, but with a visible marker where each syntax error was found
+    Recovering,
+    // lenient, but an included template that would be an error in strict mode (or that
+    // can't be read) is logged and rendered as the placeholder instead, so one broken
+    // partial doesn't litter the page with misses; see `TemplateCache::contain_partial_errors`
+    Contained(&'a str),
+    // rendering a partial inside a `Contained` render: strict, with any partial it
+    // includes contained in turn
+    Partial(&'a str),
+}
+
+fn render(
+    compiled: &Compiled, ctx: &Context, functions: &TemplateFunctions, mode: Mode<'_>, deadline: Option<Instant>,
+) -> Result<String, RenderError> {
+    let mut html = Vec::new();
+    render_to(compiled, ctx, functions, mode, deadline, &mut html)?;
+    // everything written came from strings
+    Ok(String::from_utf8(html).expect("rendered html is utf-8"))
+}
+
+// renders like `render`, writing the html to `out` node by node rather than collecting it.
+// past `deadline`, the render stops with `RenderError::TimedOut`
+fn render_to(
+    compiled: &Compiled, ctx: &Context, functions: &TemplateFunctions, mode: Mode<'_>, deadline: Option<Instant>,
+    out: &mut dyn Write,
+) -> Result<(), RenderError> {
+    // built-ins sit below the caller's context, and front-matter is exposed to the
+    // template under the `page` namespace
+    let mut ctx = builtin_context().into_iter().chain(ctx.clone()).collect::<Context>();
+    for (key, value) in compiled.meta.iter() {
+        ctx.insert(format!("page.{}", key), value.clone());
+    }
+
+    // markdown templates are converted once their directives are expanded, then
+    // rendered like any other
+    let deadline = Deadline { at: deadline, file: &compiled.path };
+    let env = Env { macros: &compiled.macros, functions, deadline };
+    let body = render_directives(&compiled.segments, &ctx, &Scope::root(), &env, 0, mode)?;
+    let body = if compiled.markdown {
+        // linear in the expanded body, so checking once it's done is enough
+        let html = markdown_to_html(&body);
+        deadline.check()?;
+        html
+    } else {
+        body
+    };
+
+    let mut parser = Parser::new();
+    for (i, ch) in body.chars().enumerate() {
+        // often enough to stop promptly, rarely enough not to slow the parse
+        if i % 4096 == 0 {
+            deadline.check()?;
+        }
+        parser.parse_ch(ch);
+    }
+    // let _ = parser.root.borrow().traverse_dfs(0);
+     
+    parser.write_html(out, &ctx, mode == Mode::Strict, deadline)
+}
+
+pub fn parse_file(file_name: &str, ctx: &Context) -> String {
+    /* 
+    if let Ok(cwd) = get_current_working_dir() {
+        println!("Current working dir: {:?}", cwd);
+    }
+    */
+    
+    render(&compile(file_name).unwrap(), ctx, &TemplateFunctions::default(), Mode::Lenient, None).unwrap()
+}
+
+/// compiled templates by path, so each file is read and parsed once. every entry
+/// remembers the files it includes, and `invalidate` drops all the templates built
+/// from a file that changed.
+#[derive(Default)]
+pub struct TemplateCache {
+    compiled: RwLock<HashMap<String, Arc<Compiled>>>,
+    functions: TemplateFunctions,
+    // see `contain_partial_errors`
+    partial_placeholder: Option<String>,
+    // see `render_timeout`
+    timeout: Option<Duration>,
+}
+
+impl TemplateCache {
+    pub fn new() -> TemplateCache {
+        TemplateCache::default()
+    }
+
+    /// a cache whose templates can call `functions` rather than just the built-ins
+    pub fn with_functions(functions: TemplateFunctions) -> TemplateCache {
+        TemplateCache { compiled: RwLock::default(), functions, partial_placeholder: None, timeout: None }
+    }
+
+    /// makes `render`, `render_to` and `render_response` contain failures in included
+    /// templates: an include that can't be read, or whose own content has a missing key,
+    /// a failing function or a syntax error, is logged and rendered as `placeholder`
+    /// (`""` for nothing) while the rest of the page renders as usual. strict and
+    /// recovering renders are unaffected, so tests and dev mode still see the error.
+    pub fn contain_partial_errors(mut self, placeholder: &str) -> TemplateCache {
+        self.partial_placeholder = Some(placeholder.to_string());
+        self
+    }
+
+    /// makes every render stop after `timeout` with `RenderError::TimedOut`, so a
+    /// template that loops or recurses far more than intended (by accident or by a
+    /// crafted context) can't keep a thread busy indefinitely. the time is checked as
+    /// each directive is expanded, after the markdown pass, every few thousand
+    /// characters of the html parse and before each element is written out with its
+    /// context filled in.
+    pub fn render_timeout(mut self, timeout: Duration) -> TemplateCache {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    // when a render starting now has to stop
+    fn deadline(&self) -> Option<Instant> {
+        self.timeout.map(|timeout| Instant::now() + timeout)
+    }
+
+    // the mode `render` and friends use
+    fn lenient(&self) -> Mode<'_> {
+        match &self.partial_placeholder {
+            Some(placeholder) => Mode::Contained(placeholder),
+            None => Mode::Lenient,
+        }
+    }
+
+    /// renders `file_name` like `parse_file`, compiling it on first use
+    pub fn render(&self, file_name: &str, ctx: &Context) -> Result<String, RenderError> {
+        render(&*self.compiled(file_name)?, ctx, &self.functions, self.lenient(), self.deadline())
+    }
+
+    /// renders like `render`, but a `{{ key }}` missing from the context is a
+    /// `RenderError::MissingKey` instead of a miss marker, and a syntax error is a
+    /// `RenderError::Syntax` instead of only being logged; for catching template
+    /// mistakes in tests and ci
+    pub fn render_strict(&self, file_name: &str, ctx: &Context) -> Result<String, RenderError> {
+        render(&*self.compiled(file_name)?, ctx, &self.functions, Mode::Strict, self.deadline())
+    }
+
+    /// renders like `render`, but with each syntax error shown where it was found, as a
+    /// `<pre class="template-error">` holding its message, and the rest of the template
+    /// rendered as well as it can be. returns the output and every syntax error in the
+    /// template and the files it includes. `ViewContext::render` renders this way in dev
+    /// mode.
+    pub fn render_recovering(&self, file_name: &str, ctx: &Context) -> Result<(String, Vec<SyntaxError>), RenderError> {
+        let compiled = self.compiled(file_name)?;
+        Ok((render(&compiled, ctx, &self.functions, Mode::Recovering, self.deadline())?, compiled.errors.clone()))
+    }
+
+    /// renders like `render`, but writes the html to `out` as it goes instead of building
+    /// it up in memory, so a large page needn't be held whole. it is written in small
+    /// pieces, so a file or socket is best wrapped in a `BufWriter`. on an error, `out`
+    /// may already have part of the page.
+    pub fn render_to(&self, file_name: &str, ctx: &Context, out: &mut impl Write) -> Result<(), RenderError> {
+        render_to(&*self.compiled(file_name)?, ctx, &self.functions, self.lenient(), self.deadline(), out)
+    }
+
+    /// renders like `render`, along with the content type to serve the output as: the
+    /// front-matter `content_type` (an extension such as `json`) when given, otherwise
+    /// the template's own extension. markdown and anything unrecognised are html.
+    pub fn render_response(&self, file_name: &str, ctx: &Context) -> Result<(Vec<u8>, HttpContentType), RenderError> {
+        self.response(file_name, ctx, self.lenient())
+    }
+
+    // `render_response` rendering like `render_recovering`
+    pub(crate) fn render_response_recovering(
+        &self, file_name: &str, ctx: &Context,
+    ) -> Result<(Vec<u8>, HttpContentType), RenderError> {
+        self.response(file_name, ctx, Mode::Recovering)
+    }
+
+    fn response(&self, file_name: &str, ctx: &Context, mode: Mode<'_>) -> Result<(Vec<u8>, HttpContentType), RenderError> {
+        let compiled = self.compiled(file_name)?;
+        let ext = match compiled.meta.get("content_type") {
+            Some(ext) => ext.trim_start_matches('.'),
+            None if compiled.markdown => "html",
+            None => Path::new(file_name).extension().and_then(|ext| ext.to_str()).unwrap_or_default(),
+        };
+        let content_type = HttpContentType::from_extension(&ext.to_ascii_lowercase());
+        Ok((render(&compiled, ctx, &self.functions, mode, self.deadline())?.into_bytes(), content_type))
+    }
+
+    fn compiled(&self, file_name: &str) -> Result<Arc<Compiled>, RenderError> {
+        let key = normalize_path(Path::new(file_name));
+        // a lock poisoned by a panic elsewhere means the cache can't be trusted, so the
+        // template is compiled from disk each time instead
+        let Ok(cache) = self.compiled.read() else {
+            warn!("template cache poisoned; compiling {} from disk", file_name);
+            return compile(file_name).map(Arc::new);
+        };
+        if let Some(compiled) = cache.get(&key) {
+            return Ok(Arc::clone(compiled));
+        }
+        drop(cache);
+        let compiled = Arc::new(compile(file_name)?);
+        if let Ok(mut cache) = self.compiled.write() {
+            cache.insert(key, Arc::clone(&compiled));
+        }
+        Ok(compiled)
+    }
+
+    /// forgets every compiled template that is or includes `path`, returning how many
+    pub fn invalidate(&self, path: &str) -> usize {
+        let path = normalize_path(Path::new(path));
+        let mut compiled = self.compiled.write().unwrap_or_else(|e| e.into_inner());
+        let before = compiled.len();
+        compiled.retain(|_, template| !template.deps.contains(&path));
+        before - compiled.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a template file of its own under the system temp dir
+    fn template_file(name: &str, source: &str) -> String {
+        let dir = env::temp_dir().join(format!("ferropress-template-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, source).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    // `items.0` to `items.{n - 1}`
+    fn list(name: &str, n: usize) -> Context {
+        (0..n).map(|i| (format!("{}.{}", name, i), i.to_string())).collect()
+    }
+
+    #[test]
+    fn nested_loops_past_the_timeout_are_cut_off() {
+        let file = template_file("nested.html", "<ul>{{#each rows}}{{#each cols}}<li>{{ this }}</li>{{/each}}{{/each}}</ul>\n");
+        let mut ctx = list("rows", 500);
+        ctx.extend(list("cols", 500));
+        let templates = TemplateCache::new().render_timeout(Duration::from_millis(5));
+        let started = Instant::now();
+        match templates.render(&file, &ctx) {
+            Err(RenderError::TimedOut(path)) => assert_eq!(path, templates.compiled(&file).unwrap().path),
+            other => panic!("expected a timeout, got {:?}", other.map(|html| html.len())),
+        }
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn renders_within_the_timeout_finish() {
+        let file = template_file("small.html", "<ul>{{#each rows}}<li>{{ this }}</li>{{/each}}</ul>\n");
+        let templates = TemplateCache::new().render_timeout(Duration::from_secs(10));
+        let html = templates.render(&file, &list("rows", 3)).unwrap();
+        assert!(html.contains("<li>2</li>"), "{}", html);
+    }
+
+    #[test]
+    fn the_later_passes_check_the_deadline_too() {
+        let deadline = Deadline { at: Some(Instant::now()), file: "page.html" };
+        assert!(matches!(deadline.check(), Err(RenderError::TimedOut(file)) if file == "page.html"));
+        assert!(Deadline { at: None, file: "page.html" }.check().is_ok());
+        // writing out a parsed page stops at its first element
+        let mut parser = Parser::new();
+        for ch in "<p>{{ name }}</p>".chars() {
+            parser.parse_ch(ch);
+        }
+        let written = parser.write_html(&mut Vec::new(), &Context::new(), false, deadline);
+        assert!(matches!(written, Err(RenderError::TimedOut(_))));
+    }
+
+    // `source` run through the html parser and written back out
+    fn reparsed(source: &str) -> String {
+        let mut parser = Parser::new();
+        for ch in source.chars() {
+            parser.parse_ch(ch);
+        }
+        let mut out = Vec::new();
+        parser.write_html(&mut out, &Context::new(), false, Deadline { at: None, file: "test.html" }).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn a_doctype_is_kept_and_the_markup_after_it_parsed() {
+        let html = reparsed("<!DOCTYPE html>\n<html><body><p>hello</p></body></html>");
+        assert!(html.starts_with("<!DOCTYPE html>"), "{}", html);
+        assert!(html.contains("<body>"), "{}", html);
+        assert!(html.contains("<p>hello</p>"), "{}", html);
+    }
+
+    #[test]
+    fn doctypes_are_matched_ignoring_case() {
+        assert!(reparsed("<!doctype html><p>x</p>").starts_with("<!doctype html>"));
+    }
+
+    #[test]
+    fn comments_are_still_dropped_after_a_doctype() {
+        let html = reparsed("<!DOCTYPE html><!-- a note -- with dashes --><p>after</p>");
+        assert!(!html.contains("a note"), "{}", html);
+        assert!(html.contains("<p>after</p>"), "{}", html);
+    }
+
+    #[test]
+    fn other_declarations_are_dropped() {
+        let html = reparsed("<![CDATA[x]]><p>after</p>");
+        assert!(!html.contains("CDATA"), "{}", html);
+        assert!(html.contains("<p>after</p>"), "{}", html);
+    }
+
+    #[test]
+    fn script_bodies_are_kept_verbatim() {
+        let script = "if (a < b && c > d) { x = \"<p>\" + '</div>'; } // <b>not a tag</b>";
+        let html = reparsed(&format!("<body><script>{}</script><p>after</p></body>", script));
+        assert!(html.contains(&format!("<script>{}</script>", script)), "{}", html);
+        assert!(html.contains("<p>after</p>"), "{}", html);
+    }
+
+    #[test]
+    fn style_bodies_are_kept_verbatim() {
+        let style = "a > b { content: \"<\"; } p<q {}";
+        let html = reparsed(&format!("<style>{}</style><p>after</p>", style));
+        assert!(html.contains(&format!("<style>{}</style>", style)), "{}", html);
+        assert!(html.contains("<p>after</p>"), "{}", html);
+    }
+
+    #[test]
+    fn a_raw_text_body_ends_at_its_own_closing_tag_only() {
+        let html = reparsed("<script>document.write('</style>');</script><p>after</p>");
+        assert!(html.contains("<script>document.write('</style>');</script>"), "{}", html);
+        assert!(html.contains("<p>after</p>"), "{}", html);
+    }
+
+    #[test]
+    fn context_is_still_injected_into_raw_text() {
+        let mut parser = Parser::new();
+        for ch in "<script>var n = {{ count }}; if (n < 3) {}</script>".chars() {
+            parser.parse_ch(ch);
+        }
+        let ctx = Context::from([(String::from("count"), String::from("2"))]);
+        let mut out = Vec::new();
+        parser.write_html(&mut out, &ctx, false, Deadline { at: None, file: "test.html" }).unwrap();
+        let html = String::from_utf8(out).unwrap();
+        assert!(html.contains("var n = 2; if (n < 3) {}"), "{}", html);
+    }
+
+    fn context(pairs: &[(&str, &str)]) -> Context {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    // `source`, saved as `name`, rendered leniently with `ctx`
+    fn rendered(name: &str, source: &str, ctx: &Context) -> String {
+        TemplateCache::new().render(&template_file(name, source), ctx).unwrap()
+    }
+
+    #[test]
+    fn signatures_name_their_parameters() {
+        assert_eq!(parse_signature(" link(href, text) "), Some((String::from("link"), vec![String::from("href"), String::from("text")])));
+        assert_eq!(parse_signature("rule()"), Some((String::from("rule"), Vec::new())));
+        assert_eq!(parse_signature("link"), None);
+    }
+
+    #[test]
+    fn a_macro_binds_its_arguments_in_order() {
+        let source = "{{#macro link(href, text)}}<a href=\"{{ href }}\">{{ text }}</a>{{/macro}}\
+            <p>{{call link \"/about\" title}}</p><p>{{call link 42 \"the answer\"}}</p>";
+        let html = rendered("macro.html", source, &context(&[("title", "About us")]));
+        assert!(html.contains("<a href=\"/about\">About us</a>"), "{}", html);
+        assert!(html.contains("<a href=\"42\">the answer</a>"), "{}", html);
+        // the definition itself renders nothing
+        assert!(!html.contains("macro"), "{}", html);
+    }
+
+    // `src` parsed as an `#if` condition and evaluated against `ctx`
+    fn holds(src: &str, ctx: &Context) -> bool {
+        let cond = parse_condition(src).unwrap_or_else(|| panic!("`{}` doesn't parse", src));
+        eval_condition(&cond, ctx, &Scope::root())
+    }
+
+    #[test]
+    fn conditions_compare_numbers_numerically_and_the_rest_as_strings() {
+        let ctx = context(&[("count", "10"), ("status", "active"), ("empty", "")]);
+        assert!(holds("count > 9", &ctx));
+        assert!(holds("count >= 10", &ctx));
+        assert!(!holds("count < 9", &ctx));
+        assert!(holds("count == 10.0", &ctx));
+        assert!(holds("count != 2", &ctx));
+        // as strings "10" would sort before "9"
+        assert!(!holds("count <= 9", &ctx));
+        assert!(holds("status == \"active\"", &ctx));
+        assert!(holds("status != \"archived\"", &ctx));
+        assert!(holds("status < \"b\"", &ctx));
+        assert!(holds("\"a < b\" == \"a < b\"", &ctx));
+        assert!(holds("status", &ctx));
+        assert!(!holds("empty", &ctx));
+    }
+
+    #[test]
+    fn a_missing_key_is_false_whatever_the_comparison() {
+        let ctx = context(&[("count", "1")]);
+        for src in ["missing", "missing == 1", "missing != 1", "count < missing", "missing >= \"\""] {
+            assert!(!holds(src, &ctx), "{}", src);
+        }
+    }
+
+    #[test]
+    fn malformed_conditions_dont_parse() {
+        assert!(parse_condition("a b").is_none());
+        assert!(parse_condition("a ==").is_none());
+        assert!(parse_condition("== b").is_none());
+    }
+
+    #[test]
+    fn an_if_renders_one_branch() {
+        let source = "<p>{{#if count > 0}}{{ count }} left{{else}}sold out{{/if}}</p>";
+        assert!(rendered("if.html", source, &context(&[("count", "3")])).contains("<p>3 left</p>"));
+        assert!(rendered("if.html", source, &context(&[("count", "0")])).contains("<p>sold out</p>"));
+        assert!(rendered("if.html", source, &Context::new()).contains("<p>sold out</p>"));
+    }
+
+    #[test]
+    fn a_with_block_names_the_keys_below_its_object() {
+        let source = "{{#with user}}<p>{{ name }} of {{ city }}, on {{ site }}</p>{{/with}}<p>{{ name }}</p>";
+        let ctx = context(&[("user.name", "Ada"), ("user.city", "London"), ("site", "example.org"), ("name", "outer")]);
+        let html = rendered("with.html", source, &ctx);
+        // the body sees the enclosing names too, but the object's shadow them
+        assert!(html.contains("<p>Ada of London, on example.org</p>"), "{}", html);
+        // and the names are only bound inside it
+        assert!(html.contains("<p>outer</p>"), "{}", html);
+    }
+
+    #[test]
+    fn a_with_block_over_nothing_renders_nothing_or_fails_strictly() {
+        let file = template_file("with_missing.html", "<p>before</p>{{#with user}}<p>{{ name }}</p>{{/with}}");
+        let templates = TemplateCache::new();
+        let html = templates.render(&file, &Context::new()).unwrap();
+        assert!(html.contains("<p>before</p>") && !html.contains("CTX MISS"), "{}", html);
+        assert!(matches!(templates.render_strict(&file, &Context::new()), Err(RenderError::MissingKey(key)) if key == "user"));
+    }
+
+    #[test]
+    fn each_takes_numbered_entries_in_numeric_order() {
+        // as strings, `10` and `2` would sort before `3`
+        let html = rendered("each_list.html", "<ul>{{#each items}}<li>{{ @key }}={{ this }}</li>{{/each}}</ul>", &list("items", 12));
+        let keys: Vec<usize> = html.match_indices("<li>").map(|(at, _)| {
+            html[at + 4..].split('=').next().unwrap().parse().unwrap()
+        }).collect();
+        assert_eq!(keys, (0..12).collect::<Vec<_>>(), "{}", html);
+        assert!(html.contains("<li>11=11</li>"), "{}", html);
+    }
+
+    #[test]
+    fn each_entry_of_a_map_has_its_own_keys() {
+        let source = "{{#each sizes}}<p>{{ @key }}: {{ w }}x{{ h }}</p>{{/each}}";
+        let ctx = context(&[
+            ("sizes.small.w", "16"), ("sizes.small.h", "9"), ("sizes.large.w", "160"), ("sizes.large.h", "90"),
+            ("sizes.2.w", "2"), ("sizes.2.h", "1"), ("sizes.10.w", "10"), ("sizes.10.h", "5"),
+        ]);
+        let html = rendered("each_map.html", source, &ctx);
+        let order: Vec<_> = ["<p>2: 2x1</p>", "<p>10: 10x5</p>", "<p>large: 160x90</p>", "<p>small: 16x9</p>"]
+            .iter().map(|entry| html.find(entry).unwrap_or_else(|| panic!("no {} in {}", entry, html))).collect();
+        // numbers first and numerically, then names in order
+        assert!(order.windows(2).all(|pair| pair[0] < pair[1]), "{}", html);
+    }
+
+    #[test]
+    fn comments_never_reach_the_output() {
+        let source = "<p>{{! a secret note }}shown</p>{{!\n  spanning\n  lines, with <b>markup</b>\n}}<p>{{ name }}</p>{{!-- dashes --}}";
+        let html = rendered("comments.html", source, &context(&[("name", "Ada")]));
+        for hidden in ["secret", "spanning", "lines", "markup", "dashes", "{{", "}}", "!"] {
+            assert!(!html.contains(hidden), "{:?} in {}", hidden, html);
+        }
+        assert!(html.contains("<p>shown</p>") && html.contains("<p>Ada</p>"), "{}", html);
+        // nor does a static file's context injection leave them in
+        assert_eq!(inject_context("a{{! x\ny }}b {{ name }}", &context(&[("name", "c")])), "ab c");
+    }
+
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn dates_are_read_in_several_forms_and_formatted() {
+        assert_eq!(date(&args(&["2024-05-01T09:30:00Z", "%B %-d, %Y %H:%M"])).unwrap(), "May 1, 2024 09:30");
+        assert_eq!(date(&args(&["2024-05-01T09:30:00+02:00", "%H:%M %:z"])).unwrap(), "09:30 +02:00");
+        assert_eq!(date(&args(&["2024-05-01 09:30:00", "%d/%m/%y %T"])).unwrap(), "01/05/24 09:30:00");
+        assert_eq!(date(&args(&["2024-05-01"])).unwrap(), "2024-05-01");
+        assert!(date(&args(&["yesterday", "%Y"])).is_err());
+        assert!(date(&args(&["2024-05-01", "%Q"])).is_err());
+        assert!(date(&args(&[])).is_err());
+    }
+
+    #[test]
+    fn urlencode_escapes_everything_but_unreserved_bytes() {
+        assert_eq!(urlencode(&args(&["a-b_c.d~e"])).unwrap(), "a-b_c.d~e");
+        assert_eq!(urlencode(&args(&["rust & c++/é?"])).unwrap(), "rust%20%26%20c%2B%2B%2F%C3%A9%3F");
+        assert!(urlencode(&args(&["a", "b"])).is_err());
+    }
+
+    #[test]
+    fn join_skips_empty_values() {
+        assert_eq!(join(&args(&[", ", "a", "", "b", "c"])).unwrap(), "a, b, c");
+        assert_eq!(join(&args(&["-"])).unwrap(), "");
+        assert!(join(&[]).is_err());
+    }
+
+    #[test]
+    fn functions_are_called_from_templates() {
+        let source = "<p>{{ date(page.date, \"%d %b %Y\") }}</p><a href=\"/search?q={{ urlencode(query) }}\">{{ join(\", \", a, b) }}</a>";
+        let ctx = context(&[("page.date", "2023-12-24"), ("query", "fish & chips"), ("a", "x"), ("b", "y")]);
+        let html = rendered("functions.html", source, &ctx);
+        assert!(html.contains("<p>24 Dec 2023</p>"), "{}", html);
+        assert!(html.contains("href=\"/search?q=fish%20%26%20chips\""), "{}", html);
+        assert!(html.contains(">x, y</a>"), "{}", html);
+        // a failing function is a miss, or an error in strict mode
+        let file = template_file("functions_failing.html", "<p>{{ date(\"soon\") }}</p>");
+        assert!(TemplateCache::new().render(&file, &Context::new()).unwrap().contains("FN MISS"));
+        assert!(matches!(TemplateCache::new().render_strict(&file, &Context::new()), Err(RenderError::Function(name, _)) if name == "date"));
+    }
+
+    #[test]
+    fn include_tags_parse_their_bindings() {
+        let tag = parse_include(" card.html with user=author title=\"a \\\"quoted\\\" title\" only").unwrap();
+        assert_eq!(tag.path, "card.html");
+        assert!(tag.only);
+        assert!(matches!(&tag.bindings[..], [(user, Arg::Key(author)), (title, Arg::Literal(text))]
+            if user == "user" && author == "author" && title == "title" && text == "a \"quoted\" title"));
+        let tag = parse_include("\"dir/a b.html\"").unwrap();
+        assert_eq!(tag.path, "dir/a b.html");
+        assert!(tag.bindings.is_empty() && !tag.only);
+        assert!(parse_include("card.html with").is_none());
+        assert!(parse_include("card.html with user").is_none());
+        assert!(parse_include("card.html and more").is_none());
+    }
+
+    #[test]
+    fn an_include_sees_its_includers_names_unless_only() {
+        template_file("scope_card.html", "<span>{{ user }}/{{ title }}/{{ year }}</span>");
+        let ctx = context(&[("author", "Ada"), ("title", "Notes"), ("user", "guest")]);
+        // a plain include shares everything
+        let html = rendered("scope_plain.html", "<p>{{> scope_card.html}}</p>", &ctx);
+        assert!(html.contains("<span>guest/Notes/"), "{}", html);
+        // bindings are added for it, over the includer's names
+        let html = rendered("scope_with.html", "<p>{{> scope_card.html with user=author}}</p><p>{{ user }}</p>", &ctx);
+        assert!(html.contains("<span>Ada/Notes/"), "{}", html);
+        assert!(html.contains("<p>guest</p>"), "{}", html);
+        // `only` hides everything else but the built-ins
+        let year = Local::now().year().to_string();
+        let html = rendered("scope_only.html", "<p>{{> scope_card.html with user=author only}}</p>", &ctx);
+        assert!(html.contains(&format!("<span>Ada/CTX MISS/{}</span>", year)), "{}", html);
+        let file = template_file("scope_only_strict.html", "<p>{{> scope_card.html with user=author only}}</p>");
+        assert!(matches!(TemplateCache::new().render_strict(&file, &ctx), Err(RenderError::MissingKey(key)) if key == "title"));
+    }
+
+    #[test]
+    fn an_undefined_name_is_a_miss_or_strictly_an_error() {
+        let ctx = context(&[("name", "Ada")]);
+        let source = "{{ name }} {{ nickname }}";
+        assert_eq!(inject_context_strict(source, &ctx, false).unwrap(), "Ada CTX MISS");
+        assert!(matches!(inject_context_strict(source, &ctx, true), Err(RenderError::MissingKey(key)) if key == "nickname"));
+        // a name only mentioned in a comment isn't missing
+        assert_eq!(inject_context_strict("{{ name }}{{! nickname }}", &ctx, true).unwrap(), "Ada");
+        let file = template_file("strict.html", "<p>{{ name }} {{ nickname }}</p>");
+        let templates = TemplateCache::new();
+        assert!(templates.render(&file, &ctx).unwrap().contains("<p>Ada CTX MISS</p>"));
+        assert!(matches!(templates.render_strict(&file, &ctx), Err(RenderError::MissingKey(key)) if key == "nickname"));
+    }
+
+    #[test]
+    fn a_syntax_error_shows_where_it_is() {
+        let file = template_file("unclosed.html", "---\ntitle: Post\n---\n<h1>{{ page.title }}</h1>\n\n{{#if page.draft}}\n<p>draft</p>\n");
+        let Err(RenderError::Syntax(error)) = TemplateCache::new().render_strict(&file, &Context::new()) else {
+            panic!("expected a syntax error");
+        };
+        assert_eq!((error.line, error.column), (6, 1));
+        let expected = format!(
+            "template block `#if page.draft` is never closed\n --> {}:6:1\n  |\n4 | <h1>{{{{ page.title }}}}</h1>\n5 |\n6 | {{{{#if page.draft}}}}\n  | ^^^^^^^^^^^^^^^^^^",
+            normalize_path(Path::new(&file)),
+        );
+        assert_eq!(error.to_string(), expected);
+    }
+
+    #[test]
+    fn the_snippet_underlines_a_tag_in_the_middle_of_a_line() {
+        let file = template_file("stray.html", "<p>one</p>\n<p>two {{/each}} three</p>\n");
+        let Err(RenderError::Syntax(error)) = TemplateCache::new().render_strict(&file, &Context::new()) else {
+            panic!("expected a syntax error");
+        };
+        assert_eq!((error.line, error.column), (2, 8));
+        assert!(error.to_string().ends_with("1 | <p>one</p>\n2 | <p>two {{/each}} three</p>\n  |        ^^^^^^^^^"), "{}", error);
+    }
+
+    #[test]
+    fn a_recovering_render_marks_the_error_and_renders_the_rest() {
+        let file = template_file("recovering.html", "<h1>{{ title }}</h1>\n{{/if}}\n<p>{{ body }} <b>after</b></p>\n");
+        let ctx = context(&[("title", "Hello"), ("body", "Text")]);
+        let (html, errors) = TemplateCache::new().render_recovering(&file, &ctx).unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(html.contains("<h1>Hello</h1>"), "{}", html);
+        assert!(html.contains("Text") && html.contains("<b>after</b>"), "{}", html);
+        // the marker holds the message, escaped so it reads as written
+        assert!(html.contains("<pre class=\"template-error\""), "{}", html);
+        assert!(html.contains("malformed or unbalanced template directive `&#123;&#123;/if&#125;&#125;`"), "{}", html);
+        assert!(html.contains(" --&gt; "), "{}", html);
+        // a lenient render leaves the marker out
+        let html = TemplateCache::new().render(&file, &ctx).unwrap();
+        assert!(!html.contains("template-error") && html.contains("<h1>Hello</h1>"), "{}", html);
+    }
+
+    #[test]
+    fn the_marker_escapes_markup_in_the_message() {
+        let error = SyntaxError::new("a.html", "<b>{{#x}}", 0, 3..9, String::from("bad <b> & {{ }}"));
+        let marker = error_marker(&error);
+        assert!(marker.starts_with("<pre class=\"template-error\""), "{}", marker);
+        assert!(marker.contains("bad &lt;b&gt; &amp; &#123;&#123; &#125;&#125;"), "{}", marker);
+        assert!(!marker[marker.find('>').unwrap()..].contains("<b>"), "{}", marker);
+    }
+
+    #[test]
+    fn a_failing_partial_is_contained_to_its_placeholder() {
+        template_file("contained_ok.html", "<nav>{{ site }}</nav>");
+        template_file("contained_bad.html", "<aside>{{ sidebar_title }} {{ date(\"never\") }}</aside>");
+        let file = template_file("contained.html", "<body>{{> contained_ok.html}}<main>{{ body }}</main>{{> contained_bad.html}}{{> contained_gone.html}}</body>");
+        let ctx = context(&[("site", "Blog"), ("body", "Post")]);
+        let templates = TemplateCache::new().contain_partial_errors("<!-- unavailable -->");
+        let html = templates.render(&file, &ctx).unwrap();
+        assert!(html.contains("<nav>Blog</nav>"), "{}", html);
+        assert!(html.contains("<main>Post</main>"), "{}", html);
+        // the broken partial and the missing one leave nothing of their own behind
+        for leftover in ["aside", "CTX MISS", "FN MISS", "INCLUDE MISS"] {
+            assert!(!html.contains(leftover), "{} in {}", leftover, html);
+        }
+        // the placeholder is markup like any other; a comment is dropped by the parser
+        let templates = TemplateCache::new().contain_partial_errors("<em>unavailable</em>");
+        let html = templates.render(&file, &ctx).unwrap();
+        assert_eq!(html.matches("<em>unavailable</em>").count(), 2, "{}", html);
+        // without containment the misses show
+        let html = TemplateCache::new().render(&file, &ctx).unwrap();
+        assert!(html.contains("CTX MISS") && html.contains("INCLUDE MISS"), "{}", html);
+    }
+
+    #[test]
+    fn invalidating_a_partial_recompiles_its_dependents() {
+        let partial = template_file("invalidate_footer.html", "<footer>old</footer>");
+        let page = template_file("invalidate_page.html", "<body>{{> invalidate_footer.html}}</body>");
+        let other = template_file("invalidate_other.html", "<p>unrelated</p>");
+        let templates = TemplateCache::new();
+        assert!(templates.render(&page, &Context::new()).unwrap().contains("<footer>old</footer>"));
+        templates.render(&other, &Context::new()).unwrap();
+        fs::write(&partial, "<footer>new</footer>").unwrap();
+        // compiled once, so the edit isn't seen until the partial is invalidated
+        assert!(templates.render(&page, &Context::new()).unwrap().contains("<footer>old</footer>"));
+        // by any name for it
+        let dir = Path::new(&partial).parent().unwrap();
+        assert_eq!(templates.invalidate(&dir.join("./sub/../invalidate_footer.html").to_string_lossy()), 1);
+        assert!(templates.render(&page, &Context::new()).unwrap().contains("<footer>new</footer>"));
+        assert_eq!(templates.compiled.read().unwrap().len(), 2);
+        assert_eq!(templates.invalidate(&page), 1);
+        assert_eq!(templates.invalidate(&page), 0);
+    }
+
+    #[test]
+    fn a_missing_template_is_told_apart_from_other_io_errors() {
+        let file = env::temp_dir().join("ferropress-no-such-template.html").to_string_lossy().into_owned();
+        assert!(matches!(TemplateCache::new().render(&file, &Context::new()), Err(RenderError::TemplateNotFound(path)) if path == file));
+        // a directory exists but can't be read as a template
+        let dir = env::temp_dir().to_string_lossy().into_owned();
+        assert!(matches!(TemplateCache::new().render(&dir, &Context::new()), Err(RenderError::Io(path, _)) if path == dir));
+    }
+
+    #[test]
+    fn pre_keeps_its_whitespace_exactly() {
+        let pre = "<pre>\n    fn main() {\n\n        println!(\"<hi>\");\n    }\n\n\n  <code>  x  </code>\n</pre>";
+        let html = reparsed(&format!("<body>\n  <div>{}</div>\n</body>", pre));
+        let expected = format!("\n<body>\n  <div>\n    {}\n  </div>\n</body>\n", pre);
+        assert_eq!(html, expected);
+        // textarea too, whatever the case of its tags
+        let textarea = "<TEXTAREA>  line one\n\n   line three</TEXTAREA>";
+        assert_eq!(reparsed(textarea), format!("\n{}\n", textarea));
+    }
+
+    #[test]
+    fn streamed_output_is_the_buffered_output() {
+        let source = "---\ntitle: Streams\n---\n<!DOCTYPE html><html><head><title>{{ page.title }}</title></head><body>\
+            {{#each items}}<p class=\"n{{ @key }}\">{{ this }}</p>{{/each}}<pre>  kept\n\n  as is</pre></body></html>";
+        let file = template_file("streamed.html", source);
+        let ctx = list("items", 20);
+        let templates = TemplateCache::new();
+        let buffered = templates.render(&file, &ctx).unwrap();
+        // written a byte at a time, to catch anything that assumes whole writes
+        struct Trickle(Vec<u8>);
+        impl Write for Trickle {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.extend(buf.iter().take(1));
+                Ok(buf.len().min(1))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let mut streamed = Trickle(Vec::new());
+        templates.render_to(&file, &ctx, &mut streamed).unwrap();
+        assert_eq!(String::from_utf8(streamed.0).unwrap(), buffered);
+        assert!(buffered.contains("<p class=\"n19\">19</p>"), "{}", buffered);
+    }
+
+    #[test]
+    fn a_failing_writer_is_a_write_error() {
+        struct Broken;
+        impl Write for Broken {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let file = template_file("broken_pipe.html", "<p>hello</p>");
+        assert!(matches!(TemplateCache::new().render_to(&file, &Context::new(), &mut Broken), Err(RenderError::Write(_))));
+    }
+}
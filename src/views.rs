@@ -0,0 +1,604 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use async_std::sync::RwLock;
+use log::{error, info, warn};
+use crate::access_control::AccessControl;
+use crate::app::ViewContext;
+use crate::assets;
+use crate::charset;
+use crate::minify;
+use crate::http::{percent_decode, EntityTag, HttpContentType, HttpHeader, HttpMethod, HttpStatus, Request, Response};
+use crate::template::{global_context, inject_context, RenderError};
+use crate::{path_matches, AliasTarget, MissingIndex, RobotsTxt};
+
+
+// the built-in views registered by `App::new`
+
+// paths recently found missing, with when they were looked for; see
+// `Settings::missing_file_ttl_secs`
+pub(crate) type MissingFiles = Arc<Mutex<HashMap<String, Instant>>>;
+
+// misses remembered at once. a scan for many different paths fills this up, and then
+// it is emptied of expired entries, or entirely, rather than growing further
+const MAX_MISSING_FILES: usize = 10_000;
+
+// whether `path` was found missing within the ttl
+fn known_missing(ctx: &ViewContext, path: &str) -> bool {
+    let Some(ttl) = ctx.settings.missing_file_ttl_secs.filter(|_| ctx.settings.caches_content()) else {
+        return false;
+    };
+    let missing = ctx.missing.lock().unwrap_or_else(|e| e.into_inner());
+    missing.get(path).is_some_and(|since| since.elapsed() < Duration::from_secs(ttl))
+}
+
+fn remember_missing(ctx: &ViewContext, path: &str) {
+    let Some(ttl) = ctx.settings.missing_file_ttl_secs.filter(|_| ctx.settings.caches_content()) else {
+        return;
+    };
+    let mut missing = ctx.missing.lock().unwrap_or_else(|e| e.into_inner());
+    if missing.len() >= MAX_MISSING_FILES {
+        missing.retain(|_, since| since.elapsed() < Duration::from_secs(ttl));
+        if missing.len() >= MAX_MISSING_FILES {
+            missing.clear();
+        }
+    }
+    missing.insert(path.to_string(), Instant::now());
+}
+
+// serves from the content cache when the file is there, otherwise from disk. a cache
+// that can't be read within `Settings::cache_lock_timeout_ms` is skipped, not waited on,
+// and a path recently found missing isn't looked for again until its entry expires.
+// with `Settings::cache_enabled` off every read goes to disk
+pub(crate) async fn read_cached(ctx: &ViewContext, path: &str) -> std::io::Result<Arc<Vec<u8>>> {
+    if !ctx.settings.cache_enabled {
+        return assets::read(path).await.map(Arc::new);
+    }
+    let cached = match ctx.settings.cache_lock_timeout_ms {
+        Some(ms) => match async_std::future::timeout(Duration::from_millis(ms), ctx.cache.read()).await {
+            Ok(cache) => cache.get(path).cloned(),
+            Err(_) => {
+                warn!("content cache unavailable after {}ms; reading {} from disk", ms, path);
+                None
+            },
+        },
+        None => ctx.cache.read().await.get(path).cloned(),
+    };
+    if let Some(contents) = cached {
+        return Ok(contents);
+    }
+    if known_missing(ctx, path) {
+        return Err(ErrorKind::NotFound.into());
+    }
+    let contents = assets::read(path).await;
+    if contents.as_ref().is_err_and(|e| e.kind() == ErrorKind::NotFound) {
+        remember_missing(ctx, path);
+    }
+    contents.map(Arc::new)
+}
+
+// the first of `Settings::index_files` in `dir` that exists, with its contents. when
+// none does, the error is for the first one tried.
+pub(crate) async fn read_index(ctx: &ViewContext, dir: &str) -> Result<(String, Arc<Vec<u8>>), (String, io::Error)> {
+    let mut first_missing = None;
+    for name in ctx.settings.index_files.iter() {
+        let path = format!("{}/{}", dir.trim_end_matches('/'), name);
+        match read_cached(ctx, &path).await {
+            Ok(contents) => return Ok((path, contents)),
+            // a path through a file (`/style.css/`) has no index either
+            Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory) => {
+                first_missing.get_or_insert((path, e));
+            },
+            Err(e) => return Err((path, e)),
+        }
+    }
+    Err(first_missing.unwrap_or_else(|| (dir.to_string(), ErrorKind::NotFound.into())))
+}
+
+// deliberately slow endpoint for exercising concurrent connections. only compiled into
+// debug builds and only served when `Settings::debug_slow_endpoint` is set.
+#[cfg(debug_assertions)]
+pub(crate) async fn slow_view(request: Request, ctx: ViewContext) -> Response {
+    if !ctx.settings.debug_slow_endpoint {
+        return resource_view(request, ctx).await;
+    }
+    async_std::task::sleep(Duration::from_secs(5)).await;
+    index_response(&ctx).await
+}
+
+/// where `Settings::cache_admin` serves the content cache
+pub const CACHE_ADMIN_PATH: &str = "/__cache";
+
+// lists (`GET`) or flushes (`POST`, or `POST ?key=path` for one file) the content cache,
+// for `Settings::cache_admin_ips` only. with `Settings::cache_admin` off the path is an
+// ordinary one
+pub(crate) async fn cache_view(request: Request, ctx: ViewContext) -> Response {
+    let (path, query) = request.path.split_once('?').unwrap_or((&request.path, ""));
+    if !ctx.settings.cache_admin || path != CACHE_ADMIN_PATH {
+        return resource_view(request, ctx).await;
+    }
+    // an empty list lets nobody in, rather than everybody as `allow_ips` would
+    let admins = AccessControl::new(&ctx.settings.cache_admin_ips, &[]);
+    let allowed = !ctx.settings.cache_admin_ips.is_empty() && admins.is_ok_and(|admins| admins.allows(&ctx.peer));
+    if !allowed {
+        warn!("refusing {} {} from {}: not in cache_admin_ips", request.method, request.path, ctx.peer);
+        return Response::error(403);
+    }
+    let key = query.split('&').find_map(|param| param.strip_prefix("key=")).map(|key| percent_decode(&key.replace('+', " ")));
+    let body = match (&request.method, key) {
+        (HttpMethod::Get, _) => {
+            let files = ctx.cached_files().await;
+            let total: usize = files.iter().map(|(_, bytes)| bytes).sum();
+            let files: Vec<_> = files.into_iter().map(|(path, bytes)| serde_json::json!({"key": path, "bytes": bytes})).collect();
+            serde_json::json!({"entries": files, "total_bytes": total})
+        },
+        (HttpMethod::Post, Some(key)) => {
+            let evicted = ctx.evict_cached(&key).await;
+            info!("{} evicted {} from the content cache", ctx.peer, key);
+            serde_json::json!({"flushed": usize::from(evicted)})
+        },
+        (HttpMethod::Post, None) => {
+            let flushed = ctx.flush_cache().await;
+            info!("{} flushed the content cache ({} files)", ctx.peer, flushed);
+            serde_json::json!({"flushed": flushed})
+        },
+        _ => {
+            let mut response = Response::error(405);
+            response.set_header("Allow", "GET, POST");
+            return response;
+        },
+    };
+    let mut response = Response::ok(HttpContentType::Json, body.to_string());
+    response.set_header("Cache-Control", "no-store");
+    response
+}
+
+// the built-in views only serve files, so methods that would change something are
+// refused rather than answered with the file
+fn method_not_allowed(request: &Request) -> Option<Response> {
+    if matches!(request.method, HttpMethod::Get | HttpMethod::Head | HttpMethod::Post) {
+        return None;
+    }
+    let mut response = Response::error(405);
+    response.set_header("Allow", "GET, HEAD, POST");
+    Some(response)
+}
+
+// answers `path` from its entry in `Settings::aliases`, if it has one
+async fn serve_alias(request: &Request, path: &str, ctx: &ViewContext) -> Option<Response> {
+    let alias = ctx.settings.aliases.iter().find(|alias| alias.path == path)?;
+    Some(match &alias.target {
+        AliasTarget::File(file) => match read_cached(ctx, file).await {
+            Ok(contents) => {
+                let content_type = HttpContentType::from_extension(file.rsplit('.').next().unwrap_or_default());
+                let headers = Some(Vec::from([HttpHeader::ContentType(content_type)]));
+                Response{status: HttpStatus::HttpOk(200), contents, headers, file: None}
+            },
+            Err(e) => {
+                warn!("can't serve {} from {}: {}", path, file, e);
+                Response::error(500)
+            },
+        },
+        AliasTarget::Template(template) => {
+            let host = request.headers.get("host").unwrap_or(&ctx.settings.host);
+            ctx.render(template, &global_context(&ctx.settings, path, host)).await
+        },
+    })
+}
+
+// how long browsers may keep a `/favicon.ico` or `/robots.txt` set in the settings
+const WELL_KNOWN_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+
+// answers `/favicon.ico` and `/robots.txt` from `Settings::favicon` and
+// `Settings::robots_txt`, when they are set
+async fn serve_well_known(path: &str, ctx: &ViewContext) -> Option<Response> {
+    let (contents, content_type) = match (path, &ctx.settings.favicon, &ctx.settings.robots_txt) {
+        ("/favicon.ico", Some(file), _) => {
+            let ext = file.rsplit('.').next().unwrap_or_default().to_ascii_lowercase();
+            let content_type = match HttpContentType::from_extension(&ext) {
+                HttpContentType::Png => HttpContentType::Png,
+                HttpContentType::Jpeg => HttpContentType::Jpeg,
+                _ => HttpContentType::Icon,
+            };
+            (read_cached(ctx, file).await.map_err(|e| (file, e)), content_type)
+        },
+        ("/robots.txt", _, Some(RobotsTxt::File(file))) => {
+            (read_cached(ctx, file).await.map_err(|e| (file, e)), HttpContentType::Plain)
+        },
+        ("/robots.txt", _, Some(RobotsTxt::Content(content))) => {
+            (Ok(Arc::new(content.clone().into_bytes())), HttpContentType::Plain)
+        },
+        _ => return None,
+    };
+    let contents = match contents {
+        Ok(contents) => contents,
+        Err((file, e)) => {
+            warn!("can't serve {} from {}: {}", path, file, e);
+            return Some(Response::error(500));
+        },
+    };
+    let headers = Some(Vec::from([HttpHeader::ContentType(content_type)]));
+    let mut response = Response{status: HttpStatus::HttpOk(200), contents, headers, file: None};
+    response.set_header("Cache-Control", &format!("public, max-age={}", WELL_KNOWN_MAX_AGE_SECS));
+    Some(response)
+}
+
+pub(crate) async fn index_view(request: Request, ctx: ViewContext) -> Response {
+    if let Some(response) = method_not_allowed(&request) {
+        return response;
+    }
+    let path = request.path.split_once('?').map_or(&request.path[..], |(path, _)| path);
+    if let Some(response) = serve_alias(&request, path, &ctx).await {
+        return response;
+    }
+    index_response(&ctx).await
+}
+
+// shown for `MissingIndex::Placeholder`
+const PLACEHOLDER_PAGE: &str = "<!DOCTYPE html>\n<html><head><title>Nothing here yet</title></head>\
+    <body><h1>Nothing here yet</h1><p>This site doesn't have a front page yet.</p></body></html>\n";
+
+// the site's index page, or `Settings::missing_index` when it has none
+async fn index_response(ctx: &ViewContext) -> Response {
+    let html = Some(Vec::from([HttpHeader::ContentType(HttpContentType::Html)]));
+    let (path, e) = match read_index(ctx, &ctx.site.templates_dir).await {
+        Ok((_, contents)) => return Response{status: HttpStatus::HttpOk(200), contents, headers: html, file: None},
+        Err(missing) => missing,
+    };
+    let missing = matches!(e.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory);
+    let fallback = ctx.settings.missing_index.as_ref().filter(|_| missing);
+    let Some(fallback) = fallback else {
+        return RenderError::new(&path, e).into();
+    };
+    error!("no index page in {}: tried {}; check templates_dir and index_files",
+           ctx.site.templates_dir, ctx.settings.index_files.join(", "));
+    match fallback {
+        MissingIndex::Placeholder => {
+            let contents = Arc::new(PLACEHOLDER_PAGE.as_bytes().to_vec());
+            Response{status: HttpStatus::HttpOk(200), contents, headers: html, file: None}
+        },
+        MissingIndex::NotFound => Response::error(404),
+        MissingIndex::Redirect(location) => Response::redirect(location, 302),
+    }
+}
+
+// a `Response::file` for a file of a binary type big enough to stream (see
+// `Settings::stream_min_bytes`), unless it is in the content cache. the etag comes from
+// its size and modification time, since hashing it would mean reading it.
+#[cfg(not(feature = "embed"))]
+async fn streamed_file(ctx: &ViewContext, full_path: &str, filetype: &str) -> Option<Response> {
+    let min_bytes = ctx.settings.stream_min_bytes?;
+    let content_type = HttpContentType::from_known_extension(filetype).filter(|content_type| !content_type.is_text())?;
+    if ctx.cache.read().await.contains_key(full_path) {
+        return None;
+    }
+    let meta = async_std::fs::metadata(full_path).await.ok().filter(|meta| meta.is_file() && meta.len() >= min_bytes)?;
+    let mut response = Response::file(full_path, content_type);
+    if ctx.settings.etags {
+        let modified = meta.modified().ok()
+            .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_nanos());
+        response.set_header("ETag", &EntityTag::strong(&format!("{:x}-{:x}", meta.len(), modified)).to_string());
+    }
+    Some(response)
+}
+
+// files are offered as downloads under `Settings::download_paths`, or when the
+// query string has a `download` flag
+fn is_download(path: &str, query: &str, ctx: &ViewContext) -> bool {
+    query.split('&').any(|param| param == "download" || param.starts_with("download="))
+        || ctx.settings.download_paths.iter().any(|pattern| path_matches(pattern, path))
+}
+
+// lowercased path to actual path for every file under a content directory, keyed by
+// the directory; see `Settings::case_insensitive_static`
+pub(crate) type FoldedListings = Arc<RwLock<HashMap<String, Arc<HashMap<String, String>>>>>;
+
+// the file under `dir` whose path matches `full_path` ignoring case. only files listed
+// under `dir` can match, so this can't reach outside it.
+async fn find_case_insensitive(ctx: &ViewContext, dir: &str, full_path: &str) -> Option<String> {
+    let cached = ctx.listings.read().await.get(dir).cloned();
+    let listing = match cached {
+        Some(listing) => listing,
+        None => {
+            let files = assets::list_files(dir).await.ok()?;
+            let listing = Arc::new(files.into_iter().map(|file| (file.to_lowercase(), file)).collect::<HashMap<_, _>>());
+            // like the content cache, listings go stale in dev mode so aren't kept
+            if ctx.settings.caches_content() {
+                ctx.listings.write().await.insert(dir.to_string(), Arc::clone(&listing));
+            }
+            listing
+        },
+    };
+    listing.get(&full_path.to_lowercase()).cloned()
+}
+
+// whether an `Accept-Encoding` header allows `encoding`, by name or through `*`. a
+// quality of 0 refuses it, and a named entry wins over `*`.
+pub(crate) fn accepts_encoding(header: &str, encoding: &str) -> bool {
+    let mut wildcard = None;
+    for entry in header.split(',') {
+        let mut params = entry.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q=").or_else(|| param.trim().strip_prefix("Q=")))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case(encoding) {
+            return quality > 0.0;
+        }
+        if name == "*" {
+            wildcard = Some(quality > 0.0);
+        }
+    }
+    wildcard.unwrap_or(false)
+}
+
+// minified css and js by path; see `Settings::minify_static`
+pub(crate) type MinifiedFiles = Arc<RwLock<HashMap<String, Arc<Vec<u8>>>>>;
+
+// files kept only gzipped, decompressed, by the path of the `.gz`; see
+// `Settings::gzip_only_files`
+pub(crate) type DecompressedFiles = Arc<RwLock<HashMap<String, Arc<Vec<u8>>>>>;
+
+// `compressed`, the contents of the gzip file at `path`, decompressed. more than
+// `Settings::max_decompressed_bytes` of it is an error
+async fn decompressed(ctx: &ViewContext, path: &str, compressed: &[u8]) -> io::Result<Arc<Vec<u8>>> {
+    if let Some(contents) = ctx.decompressed.read().await.get(path) {
+        return Ok(Arc::clone(contents));
+    }
+    let max = ctx.settings.max_decompressed_bytes;
+    let mut contents = Vec::new();
+    // every member, as gunzip would, for files that were appended to
+    let decoder = flate2::read::MultiGzDecoder::new(compressed);
+    io::Read::read_to_end(&mut io::Read::take(decoder, max as u64 + 1), &mut contents)?;
+    if contents.len() > max {
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("decompresses to more than max_decompressed_bytes ({})", max)));
+    }
+    let contents = Arc::new(contents);
+    if ctx.settings.caches_content() {
+        ctx.decompressed.write().await.insert(path.to_string(), Arc::clone(&contents));
+    }
+    Ok(contents)
+}
+
+// `contents` of the css or js file at `path`, minified
+async fn minified(ctx: &ViewContext, path: &str, filetype: &str, contents: Arc<Vec<u8>>) -> Arc<Vec<u8>> {
+    if let Some(minified) = ctx.minified.read().await.get(path) {
+        return Arc::clone(minified);
+    }
+    // a file that isn't utf-8 is served as it is
+    let Ok(src) = std::str::from_utf8(&contents) else {
+        return contents;
+    };
+    let minified = match filetype {
+        "css" => minify::css(src),
+        _ => minify::js(src),
+    };
+    let minified = Arc::new(minified.into_bytes());
+    // like listings, nothing is kept in dev mode, where files change
+    if ctx.settings.caches_content() {
+        ctx.minified.write().await.insert(path.to_string(), Arc::clone(&minified));
+    }
+    minified
+}
+
+pub(crate) async fn resource_view(request: Request, ctx: ViewContext) -> Response {
+    if let Some(response) = method_not_allowed(&request) {
+        return response;
+    }
+    let settings = &ctx.settings;
+    let (path, query) = request.path.split_once('?').unwrap_or((&request.path, ""));
+    // nothing outside the content directories is served
+    if path.split('/').any(|segment| segment == "..") {
+        return Response::error(404);
+    }
+    if let Some(response) = serve_alias(&request, path, &ctx).await {
+        return response;
+    }
+    if let Some(response) = serve_well_known(path, &ctx).await {
+        return response;
+    }
+    if path.ends_with('/') {
+        let dir = format!("{}{}", ctx.site.static_dir, path);
+        return match read_index(&ctx, &dir).await {
+            Ok((_, contents)) => {
+                let headers = Some(Vec::from([HttpHeader::ContentType(HttpContentType::Html)]));
+                Response{status: HttpStatus::HttpOk(200), contents, headers, file: None}
+            },
+            Err((_, e)) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory) => Response::error(404),
+            Err((path, e)) => {
+                warn!("failed to read {}: {}", path, e);
+                Response::error(500)
+            },
+        };
+    }
+    let mut filetype = path.split('.').next_back().unwrap().to_string();
+    if settings.case_insensitive_static {
+        filetype.make_ascii_lowercase();
+    }
+    let filetype = filetype.as_str();
+    let dir = settings.root_for(&ctx.site, filetype);
+    let known_type = HttpContentType::from_known_extension(filetype);
+    let full_path = format!("{}{}", dir, path);
+    #[cfg(not(feature = "embed"))]
+    if let Some(mut response) = streamed_file(&ctx, &full_path, filetype).await {
+        if is_download(path, query, &ctx) {
+            response.set_attachment(&percent_decode(path.rsplit('/').next().unwrap_or_default()));
+        }
+        return response;
+    }
+
+    let mut file = full_path.clone();
+    let mut contents = read_cached(&ctx, &file).await;
+    let missing = contents.as_ref().is_err_and(|e| e.kind() == ErrorKind::NotFound);
+    if missing && settings.case_insensitive_static {
+        if let Some(found) = find_case_insensitive(&ctx, dir, &full_path).await {
+            contents = read_cached(&ctx, &found).await;
+            file = found;
+        }
+    }
+    // a file kept only as its `.gz` goes out as it is to clients that take gzip, and
+    // decompressed to the rest; see `Settings::gzip_only_files`
+    let mut gzip_only = false;
+    if settings.gzip_only_files && contents.as_ref().is_err_and(|e| e.kind() == ErrorKind::NotFound) {
+        let gz = format!("{}.gz", file);
+        if let Ok(compressed) = read_cached(&ctx, &gz).await {
+            gzip_only = true;
+            if request.headers.get("accept-encoding").is_some_and(|header| accepts_encoding(header, "gzip")) {
+                let headers = Some(Vec::from([HttpHeader::ContentType(known_type.unwrap_or(HttpContentType::Html))]));
+                let mut response = Response{status: HttpStatus::HttpOk(200), contents: compressed, headers, file: None};
+                response.set_header("Content-Encoding", "gzip");
+                response.add_vary("Accept-Encoding");
+                if is_download(path, query, &ctx) {
+                    response.set_attachment(&percent_decode(path.rsplit('/').next().unwrap_or_default()));
+                }
+                return response;
+            }
+            contents = decompressed(&ctx, &gz, &compressed).await;
+        }
+    }
+    let contents = match contents {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Response::error(404),
+        // a directory is served by its index, found under the path with a `/`
+        Err(e) if e.kind() == ErrorKind::IsADirectory => {
+            let location = match query {
+                "" => format!("{}/", path),
+                query => format!("{}/?{}", path, query),
+            };
+            return Response::redirect(&location, 301);
+        },
+        Err(e) => {
+            warn!("failed to read {}: {}", full_path, e);
+            return Response::error(500);
+        },
+    };
+    let content_type = match known_type {
+        Some(content_type) => content_type,
+        None if settings.sniff_content_type => HttpContentType::sniff(&contents),
+        None => HttpContentType::Html,
+    };
+    // text in a legacy encoding is converted to utf-8, or else labelled with its charset
+    let mut legacy_charset = None;
+    let mut transcoded = false;
+    let mut contents = contents;
+    if let Some(entry) = settings.file_charset(path).filter(|_| content_type.is_text()) {
+        let (label, bom_len) = charset::bom(&contents).unwrap_or((entry.charset.as_str(), 0));
+        match entry.transcode.then(|| charset::to_utf8(&contents[bom_len..], label)).flatten() {
+            Some(text) => {
+                contents = Arc::new(text.into_bytes());
+                transcoded = true;
+            },
+            None => legacy_charset = Some(label.to_string()),
+        }
+    }
+    let headers = Some(Vec::from([HttpHeader::ContentType(content_type)]));
+    let injected = settings.inject_extensions.iter().any(|ext| ext == filetype);
+    let contents = if injected {
+        let host = request.headers.get("host").unwrap_or(&settings.host);
+        let ctx = global_context(settings, path, host);
+        Arc::new(inject_context(&String::from_utf8_lossy(&contents), &ctx).into_bytes())
+    } else if settings.minify_static && matches!(filetype, "css" | "js") {
+        minified(&ctx, &file, filetype, contents).await
+    } else {
+        contents
+    };
+
+    let mut response = Response{status: HttpStatus::HttpOk(200), contents, headers, file: None};
+    if let Some(label) = legacy_charset {
+        response.set_header("Content-Type", &format!("{}; charset={}", content_type, label));
+    }
+    // a `.br` file holds the bytes on disk, not the converted ones
+    if settings.precompressed_br && settings.compresses(&content_type, response.contents.len()) && !injected && !transcoded {
+        // the answer depends on the header whether or not a `.br` file exists
+        response.add_vary("Accept-Encoding");
+        let accepts_br = request.headers.get("accept-encoding").is_some_and(|header| accepts_encoding(header, "br"));
+        if accepts_br {
+            if let Ok(compressed) = read_cached(&ctx, &format!("{}.br", file)).await {
+                // the compressed bytes are the plain file's content, so they share its
+                // tag, weakened
+                if settings.etags {
+                    let etag = EntityTag { weak: true, ..EntityTag::of(&response.contents) };
+                    response.set_header("ETag", &etag.to_string());
+                }
+                response.contents = compressed;
+                response.set_header("Content-Encoding", "br");
+            }
+        }
+    }
+    if gzip_only {
+        response.add_vary("Accept-Encoding");
+    }
+    if is_download(path, query, &ctx) {
+        let filename = percent_decode(path.rsplit('/').next().unwrap_or_default());
+        response.set_attachment(&filename);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+    use crate::Settings;
+    use super::*;
+
+    // a file of its own under the system temp dir
+    fn scratch_file(name: &str, contents: &str) -> String {
+        let dir = env::temp_dir().join(format!("ferropress-views-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn a_locked_cache_falls_back_to_disk() {
+        let path = scratch_file("locked.txt", "from disk");
+        let ctx = ViewContext::for_tests(Settings{cache_lock_timeout_ms: Some(50), ..Settings::default()});
+        async_std::task::block_on(async {
+            ctx.cache.write().await.insert(path.clone(), Arc::new(b"from the cache".to_vec()));
+            let writer = ctx.cache.write().await;
+            let started = Instant::now();
+            let contents = read_cached(&ctx, &path).await.unwrap();
+            assert_eq!(contents.as_slice(), b"from disk");
+            assert!(started.elapsed() < Duration::from_secs(1));
+            drop(writer);
+            assert_eq!(read_cached(&ctx, &path).await.unwrap().as_slice(), b"from the cache");
+        });
+    }
+
+    #[test]
+    fn a_miss_is_remembered_until_its_entry_expires() {
+        let path = scratch_file("later.txt", "");
+        fs::remove_file(&path).unwrap();
+        let ctx = ViewContext::for_tests(Settings{missing_file_ttl_secs: Some(60), ..Settings::default()});
+        async_std::task::block_on(async {
+            assert_eq!(read_cached(&ctx, &path).await.unwrap_err().kind(), ErrorKind::NotFound);
+            // the second miss doesn't look: the file is there now, but isn't seen
+            fs::write(&path, "made later").unwrap();
+            assert_eq!(read_cached(&ctx, &path).await.unwrap_err().kind(), ErrorKind::NotFound);
+            // once the entry is older than the ttl, the file is looked for again
+            let expired = Instant::now().checked_sub(Duration::from_secs(61)).unwrap();
+            ctx.missing.lock().unwrap().insert(path.clone(), expired);
+            assert_eq!(read_cached(&ctx, &path).await.unwrap().as_slice(), b"made later");
+        });
+    }
+
+    #[test]
+    fn misses_arent_remembered_without_a_ttl_or_in_dev_mode() {
+        for settings in [Settings{missing_file_ttl_secs: None, ..Settings::default()}, Settings{dev_mode: true, ..Settings::default()}] {
+            let path = scratch_file("uncached.txt", "");
+            fs::remove_file(&path).unwrap();
+            let ctx = ViewContext::for_tests(settings);
+            async_std::task::block_on(async {
+                assert!(read_cached(&ctx, &path).await.is_err());
+                assert!(ctx.missing.lock().unwrap().is_empty());
+                fs::write(&path, "found").unwrap();
+                assert_eq!(read_cached(&ctx, &path).await.unwrap().as_slice(), b"found");
+            });
+            fs::remove_file(&path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,308 @@
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use async_std::io::{Read, Write};
+use async_std::prelude::*;
+use base64::Engine;
+use futures::future::BoxFuture;
+use sha1::{Digest, Sha1};
+use crate::app::ViewContext;
+use crate::http::Request;
+
+
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// frames, and messages assembled from fragments, larger than this are refused rather
+// than buffered
+const MAX_PAYLOAD: u64 = 16 * 1024 * 1024;
+// control frames can't be longer, or fragmented (rfc 6455 section 5.5)
+const MAX_CONTROL_PAYLOAD: u64 = 125;
+
+// close codes
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// value for the `Sec-WebSocket-Accept` header answering a client's `Sec-WebSocket-Key`
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.trim().as_bytes());
+    hasher.update(HANDSHAKE_GUID.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(hasher.finalize())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close,
+}
+
+/// the websocket a `WebSocketHandler` is given, over the upgraded connection
+pub type Socket = WebSocket<Box<dyn Read + Unpin + Send>, Box<dyn Write + Unpin + Send>>;
+
+/// serves websocket connections on a path registered with `App::websocket`, once the
+/// handshake is done; the connection closes when it returns. implemented for every
+/// `async fn(Request, ViewContext, Socket) -> std::io::Result<()>`, and closures
+/// returning such a future. an error is logged.
+pub trait WebSocketHandler: Send + Sync + 'static {
+    fn call(&self, request: Request, ctx: ViewContext, socket: Socket) -> BoxFuture<'static, Result<()>>;
+}
+
+impl<F, Fut> WebSocketHandler for F
+where
+    F: Fn(Request, ViewContext, Socket) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    fn call(&self, request: Request, ctx: ViewContext, socket: Socket) -> BoxFuture<'static, Result<()>> {
+        Box::pin(self(request, ctx, socket))
+    }
+}
+
+/// a server-side websocket over an already upgraded connection
+pub struct WebSocket<R, W> {
+    reader: R,
+    writer: W,
+    closed: bool,
+}
+
+impl<R: Read + Unpin, W: Write + Unpin> WebSocket<R, W> {
+    pub fn new(reader: R, writer: W) -> WebSocket<R, W> {
+        WebSocket { reader, writer, closed: false }
+    }
+
+    /// waits for the next text or binary message. pings are answered and pongs are
+    /// skipped along the way; `None` means the peer closed the connection.
+    pub async fn recv(&mut self) -> Result<Option<Message>> {
+        let mut fragments: Option<(u8, Vec<u8>)> = None;
+        loop {
+            if self.closed {
+                return Ok(None);
+            }
+            let (fin, opcode, payload) = match self.read_frame().await {
+                Ok(frame) => frame,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            match opcode {
+                OP_PING => self.send(Message::Pong(payload)).await?,
+                OP_PONG => {},
+                OP_CLOSE => {
+                    self.close().await?;
+                    return Ok(None);
+                },
+                OP_TEXT | OP_BINARY if fragments.is_none() => {
+                    if fin {
+                        return Self::to_message(opcode, payload).map(Some);
+                    }
+                    fragments = Some((opcode, payload));
+                },
+                OP_CONTINUATION if fragments.is_some() => {
+                    let (first_opcode, mut data) = fragments.take().unwrap();
+                    if (data.len() + payload.len()) as u64 > MAX_PAYLOAD {
+                        return Err(self.fail(CLOSE_TOO_BIG, "websocket message too large").await);
+                    }
+                    data.extend_from_slice(&payload);
+                    if fin {
+                        return Self::to_message(first_opcode, data).map(Some);
+                    }
+                    fragments = Some((first_opcode, data));
+                },
+                _ => return Err(self.fail(CLOSE_PROTOCOL_ERROR, "unexpected websocket opcode").await),
+            }
+        }
+    }
+
+    pub async fn send(&mut self, message: Message) -> Result<()> {
+        let (opcode, payload) = match message {
+            Message::Text(text) => (OP_TEXT, text.into_bytes()),
+            Message::Binary(data) => (OP_BINARY, data),
+            Message::Ping(data) => (OP_PING, data),
+            Message::Pong(data) => (OP_PONG, data),
+            Message::Close => (OP_CLOSE, Vec::new()),
+        };
+        self.write_frame(opcode, &payload).await
+    }
+
+    async fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<()> {
+        // server frames are never masked
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            },
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            },
+        }
+        frame.extend_from_slice(payload);
+        self.writer.write_all(&frame).await?;
+        self.writer.flush().await
+    }
+
+    /// sends a close frame; further `recv` calls return `None`
+    pub async fn close(&mut self) -> Result<()> {
+        if !self.closed {
+            self.closed = true;
+            self.send(Message::Close).await?;
+        }
+        Ok(())
+    }
+
+    // closes the connection with `code` for a peer that broke the protocol, returning
+    // the error to hand back. a failure to send the close frame only adds to that
+    async fn fail(&mut self, code: u16, reason: &str) -> Error {
+        if !self.closed {
+            self.closed = true;
+            let _ = self.write_frame(OP_CLOSE, &code.to_be_bytes()).await;
+        }
+        Error::new(ErrorKind::InvalidData, reason)
+    }
+
+    fn to_message(opcode: u8, payload: Vec<u8>) -> Result<Message> {
+        if opcode == OP_TEXT {
+            String::from_utf8(payload)
+                .map(Message::Text)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "websocket text frame is not utf-8"))
+        } else {
+            Ok(Message::Binary(payload))
+        }
+    }
+
+    async fn read_frame(&mut self) -> Result<(bool, u8, Vec<u8>)> {
+        let mut head = [0; 2];
+        self.reader.read_exact(&mut head).await?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0F;
+        let masked = head[1] & 0x80 != 0;
+        let len = match head[1] & 0x7F {
+            126 => {
+                let mut buf = [0; 2];
+                self.reader.read_exact(&mut buf).await?;
+                u16::from_be_bytes(buf) as u64
+            },
+            127 => {
+                let mut buf = [0; 8];
+                self.reader.read_exact(&mut buf).await?;
+                u64::from_be_bytes(buf)
+            },
+            len => len as u64,
+        };
+        if opcode & 0x8 != 0 && (len > MAX_CONTROL_PAYLOAD || !fin) {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "oversized or fragmented websocket control frame").await);
+        }
+        if len > MAX_PAYLOAD {
+            return Err(self.fail(CLOSE_TOO_BIG, "websocket frame too large").await);
+        }
+        // clients must mask every frame they send
+        if !masked {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "unmasked websocket frame from client").await);
+        }
+        let mut mask = [0; 4];
+        self.reader.read_exact(&mut mask).await?;
+        let mut payload = vec![0; len as usize];
+        self.reader.read_exact(&mut payload).await?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok((fin, opcode, payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::Cursor;
+    use async_std::task::block_on;
+
+    // a masked client frame
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![if fin { 0x80 | opcode } else { opcode }];
+        match payload.len() {
+            len if len < 126 => frame.push(0x80 | len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            },
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            },
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        frame
+    }
+
+    // what `recv` returns for `frames`, and what the server wrote back
+    fn receive(frames: &[Vec<u8>]) -> (Result<Option<Message>>, Vec<u8>) {
+        let mut ws = WebSocket::new(Cursor::new(frames.concat()), Vec::new());
+        let received = block_on(ws.recv());
+        (received, ws.writer)
+    }
+
+    fn close_frame(code: u16) -> Vec<u8> {
+        let mut frame = vec![0x80 | OP_CLOSE, 2];
+        frame.extend_from_slice(&code.to_be_bytes());
+        frame
+    }
+
+    #[test]
+    fn fragments_are_assembled() {
+        let (received, written) = receive(&[frame(false, OP_TEXT, b"hel"), frame(false, OP_CONTINUATION, b"l"), frame(true, OP_CONTINUATION, b"o")]);
+        assert_eq!(received.unwrap(), Some(Message::Text(String::from("hello"))));
+        assert!(written.is_empty());
+    }
+
+    #[test]
+    fn pings_between_fragments_are_answered() {
+        let (received, written) = receive(&[frame(false, OP_BINARY, b"a"), frame(true, OP_PING, b"hi"), frame(true, OP_CONTINUATION, b"b")]);
+        assert_eq!(received.unwrap(), Some(Message::Binary(b"ab".to_vec())));
+        assert_eq!(written, [0x80 | OP_PONG, 2, b'h', b'i']);
+    }
+
+    #[test]
+    fn an_oversized_message_closes_with_1009() {
+        let half = vec![b'x'; MAX_PAYLOAD as usize / 2 + 1];
+        let (received, written) = receive(&[frame(false, OP_BINARY, &half), frame(true, OP_CONTINUATION, &half)]);
+        assert_eq!(received.unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(written, close_frame(CLOSE_TOO_BIG));
+    }
+
+    #[test]
+    fn a_long_control_frame_closes_with_1002() {
+        let (received, written) = receive(&[frame(true, OP_PING, &[0; 126])]);
+        assert_eq!(received.unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(written, close_frame(CLOSE_PROTOCOL_ERROR));
+        // 125 bytes is allowed
+        let (received, written) = receive(&[frame(true, OP_PING, &[0; 125]), frame(true, OP_TEXT, b"ok")]);
+        assert_eq!(received.unwrap(), Some(Message::Text(String::from("ok"))));
+        assert_eq!(written.len(), 2 + 125);
+    }
+
+    #[test]
+    fn a_fragmented_control_frame_closes_with_1002() {
+        for opcode in [OP_PING, OP_PONG, OP_CLOSE] {
+            let (received, written) = receive(&[frame(false, opcode, b"")]);
+            assert!(received.is_err());
+            assert_eq!(written, close_frame(CLOSE_PROTOCOL_ERROR));
+        }
+    }
+
+    #[test]
+    fn nothing_more_is_received_after_a_failure() {
+        let mut ws = WebSocket::new(Cursor::new([frame(true, OP_PING, &[0; 200]), frame(true, OP_TEXT, b"late")].concat()), Vec::new());
+        assert!(block_on(ws.recv()).is_err());
+        assert_eq!(block_on(ws.recv()).unwrap(), None);
+    }
+}
@@ -0,0 +1,39 @@
+// the content cache, and turning it off with `cache_enabled`
+mod common;
+
+use common::TestServer;
+
+
+// serves the index page, a preloaded file and a file that is missing at first, then
+// changes all three on disk and serves them again
+fn served_after_changes(cache_enabled: bool) -> [String; 3] {
+    let server = TestServer::start(&[("static/a.txt", b"old\n")], |settings| {
+        settings.cache_enabled = cache_enabled;
+        settings.preload_cache = true;
+    });
+    assert!(server.get("/", "").text().contains("index"));
+    assert_eq!(server.get("/a.txt", "").text(), "old\n");
+    assert_eq!(server.get("/b.txt", "").status, 404);
+
+    std::fs::write(server.dir.join("templates/index.html"), "<html><body><p>changed</p></body></html>\n").unwrap();
+    std::fs::write(server.dir.join("static/a.txt"), "new\n").unwrap();
+    std::fs::write(server.dir.join("static/b.txt"), "created\n").unwrap();
+    ["/", "/a.txt", "/b.txt"].map(|path| server.get(path, "").text())
+}
+
+#[test]
+fn a_cached_server_keeps_serving_what_it_read() {
+    let [index, a, b] = served_after_changes(true);
+    assert!(index.contains("index"), "{}", index);
+    assert_eq!(a, "old\n");
+    // the miss is remembered for `missing_file_ttl_secs`
+    assert!(!b.contains("created"), "{}", b);
+}
+
+#[test]
+fn with_the_cache_off_every_request_reads_from_disk() {
+    let [index, a, b] = served_after_changes(false);
+    assert!(index.contains("changed"), "{}", index);
+    assert_eq!(a, "new\n");
+    assert_eq!(b, "created\n");
+}
@@ -0,0 +1,84 @@
+// inspecting and flushing the content cache through `Settings::cache_admin`
+mod common;
+
+use common::{Response, TestServer};
+use serde_json::Value;
+
+
+fn send(server: &TestServer, method: &str, target: &str) -> Response {
+    let raw = format!("{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", method, target);
+    Response::parse(&server.exchange(raw.as_bytes()))
+}
+
+fn json(response: &Response) -> Value {
+    assert_eq!(response.status, 200, "{}", response.text());
+    assert_eq!(response.header("content-type"), Some("application/json; charset=utf-8"));
+    assert_eq!(response.header("cache-control"), Some("no-store"));
+    serde_json::from_slice(&response.body).unwrap()
+}
+
+// the cached keys listed by the endpoint, with their sizes
+fn entries(server: &TestServer) -> Vec<(String, u64)> {
+    let listing = json(&send(server, "GET", "/__cache"));
+    let entries: Vec<_> = listing["entries"].as_array().unwrap().iter()
+        .map(|entry| (entry["key"].as_str().unwrap().to_string(), entry["bytes"].as_u64().unwrap()))
+        .collect();
+    assert_eq!(listing["total_bytes"].as_u64(), Some(entries.iter().map(|(_, bytes)| bytes).sum()));
+    entries
+}
+
+#[test]
+fn the_cache_can_be_listed_and_flushed() {
+    let files: &[(&str, &[u8])] = &[("static/app.css", b"a {}"), ("static/notes.txt", b"notes")];
+    let server = TestServer::start(files, |settings| {
+        settings.cache_admin = true;
+        settings.preload_cache = true;
+    });
+    let css = server.dir.join("static").join("app.css").to_string_lossy().into_owned();
+    let notes = server.dir.join("static").join("notes.txt").to_string_lossy().into_owned();
+    let cached = entries(&server);
+    assert!(cached.contains(&(css.clone(), 4)), "{:?}", cached);
+    assert!(cached.contains(&(notes.clone(), 5)), "{:?}", cached);
+
+    // a deployed file is only seen once its key is flushed
+    std::fs::write(&css, "b {}").unwrap();
+    std::fs::write(&notes, "new notes").unwrap();
+    assert_eq!(server.get("/app.css", "").text(), "a {}");
+    let flushed = json(&send(&server, "POST", &format!("/__cache?key={}", css)));
+    assert_eq!(flushed["flushed"], 1);
+    assert_eq!(json(&send(&server, "POST", "/__cache?key=/nowhere.css"))["flushed"], 0);
+    assert_eq!(server.get("/app.css", "").text(), "b {}");
+    assert_eq!(server.get("/notes.txt", "").text(), "notes");
+
+    // and everything goes with a flush of the whole cache
+    let cached = entries(&server);
+    assert!(!cached.iter().any(|(key, _)| *key == css), "{:?}", cached);
+    let flushed = json(&send(&server, "POST", "/__cache"));
+    assert_eq!(flushed["flushed"].as_u64(), Some(cached.len() as u64));
+    assert_eq!(entries(&server), []);
+    assert_eq!(server.get("/notes.txt", "").text(), "new notes");
+
+    let response = send(&server, "DELETE", "/__cache");
+    assert_eq!(response.status, 405);
+    assert_eq!(response.header("allow"), Some("GET, POST"));
+}
+
+#[test]
+fn only_admins_reach_the_cache() {
+    let files: &[(&str, &[u8])] = &[("static/__cache", b"a file")];
+    // an empty list admits nobody
+    let server = TestServer::start(files, |settings| {
+        settings.cache_admin = true;
+        settings.cache_admin_ips = Vec::new();
+    });
+    assert_eq!(send(&server, "GET", "/__cache").status, 403);
+    assert_eq!(send(&server, "POST", "/__cache").status, 403);
+    let server = TestServer::start(files, |settings| {
+        settings.cache_admin = true;
+        settings.cache_admin_ips = vec![String::from("10.0.0.0/8")];
+    });
+    assert_eq!(send(&server, "GET", "/__cache").status, 403);
+    // with cache_admin off, the path is an ordinary one
+    let server = TestServer::start(files, |_| {});
+    assert_eq!(server.get("/__cache", "").text(), "a file");
+}
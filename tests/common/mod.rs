@@ -0,0 +1,205 @@
+// a server on an ephemeral port, serving a scratch directory, for the integration tests
+#![allow(dead_code)]
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, Once};
+use std::time::{Duration, Instant};
+use futures::channel::oneshot;
+use ferropress::{serve_with_shutdown, App, Settings};
+
+
+pub struct TestServer {
+    pub port: u16,
+    // holds templates/, static/ and media/
+    pub dir: PathBuf,
+    stop: Option<oneshot::Sender<()>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+/// retries `accepted` until it holds, giving the server a few seconds to catch up
+pub fn eventually(mut accepted: impl FnMut() -> bool) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < Duration::from_secs(5) {
+        if accepted() {
+            return true;
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    false
+}
+
+// a fresh directory under the system temp dir, unique to this process and call
+pub fn scratch_dir() -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!("ferropress-test-{}-{}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed)));
+    for sub in ["templates", "static", "media"] {
+        std::fs::create_dir_all(dir.join(sub)).unwrap();
+    }
+    std::fs::write(dir.join("templates/index.html"), "<html><body><p>index</p></body></html>\n").unwrap();
+    dir
+}
+
+impl TestServer {
+    /// starts the default app with settings pointed at a scratch directory, after
+    /// `configure` has adjusted them and `files` (paths relative to the directory) are
+    /// written
+    pub fn start(files: &[(&str, &[u8])], configure: impl FnOnce(&mut Settings)) -> TestServer {
+        TestServer::start_app(App::new(), files, configure)
+    }
+
+    pub fn start_app(app: App, files: &[(&str, &[u8])], configure: impl FnOnce(&mut Settings)) -> TestServer {
+        let dir = scratch_dir();
+        for (path, contents) in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        // the port is free once the probe is dropped; nothing else should grab it meanwhile
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut settings = Settings {
+            host: String::from("127.0.0.1"),
+            port,
+            templates_dir: dir.join("templates").to_string_lossy().into_owned(),
+            static_dir: dir.join("static").to_string_lossy().into_owned(),
+            media_dir: dir.join("media").to_string_lossy().into_owned(),
+            ..Settings::default()
+        };
+        configure(&mut settings);
+        let (stop, stopped) = oneshot::channel::<()>();
+        let thread = std::thread::spawn(move || {
+            let shutdown = async move {
+                let _ = stopped.await;
+            };
+            if let Err(e) = async_std::task::block_on(serve_with_shutdown(app, settings, shutdown)) {
+                panic!("test server failed: {}", e);
+            }
+        });
+        let server = TestServer { port, dir, stop: Some(stop), thread: Some(thread) };
+        let started = Instant::now();
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(started.elapsed() < Duration::from_secs(10), "test server didn't start listening");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        server
+    }
+
+    pub fn connect(&self) -> TcpStream {
+        let stream = TcpStream::connect(("127.0.0.1", self.port)).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        stream
+    }
+
+    /// sends `raw` on a new connection and returns everything the server sends back
+    /// until it closes the connection
+    pub fn exchange(&self, raw: &[u8]) -> Vec<u8> {
+        let mut stream = self.connect();
+        // a server refusing the request may close before reading all of it
+        let _ = stream.write_all(raw);
+        read_to_close(&mut stream)
+    }
+
+    /// a `GET` of `target` with the extra header lines in `headers` (each ending in
+    /// `\r\n`), on a connection of its own
+    pub fn get(&self, target: &str, headers: &str) -> Response {
+        let raw = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n", target, headers);
+        Response::parse(&self.exchange(raw.as_bytes()))
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+pub fn read_to_close(stream: &mut TcpStream) -> Vec<u8> {
+    let mut received = Vec::new();
+    let _ = stream.read_to_end(&mut received);
+    received
+}
+
+/// one response, split up
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    // lowercased names, in the order sent
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// the first response in `raw`; the body is everything after the head
+    pub fn parse(raw: &[u8]) -> Response {
+        let end = raw.windows(4).position(|w| w == b"\r\n\r\n").expect("no end of head in response");
+        let head = String::from_utf8_lossy(&raw[..end]);
+        let mut lines = head.split("\r\n");
+        let status = lines.next().unwrap().split(' ').nth(1).unwrap().parse().unwrap();
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+            .collect();
+        Response { status, headers, body: raw[end + 4..].to_vec() }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+// every log record since `capture_logs`: level, target and message
+static LOGS: Mutex<Vec<(log::Level, String, String)>> = Mutex::new(Vec::new());
+
+struct Capture;
+
+impl log::Log for Capture {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let line = (record.level(), record.target().to_string(), record.args().to_string());
+        LOGS.lock().unwrap_or_else(|e| e.into_inner()).push(line);
+    }
+
+    fn flush(&self) {}
+}
+
+/// keeps every log record from here on, for `logged`; once per test binary, since the
+/// logger is global
+pub fn capture_logs() {
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| {
+        log::set_logger(&Capture).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+}
+
+/// the level and message of records logged under `target` containing `needle`, waiting
+/// a little for at least one, since the server may log after answering
+pub fn logged(target: &str, needle: &str) -> Vec<(log::Level, String)> {
+    let started = Instant::now();
+    loop {
+        let found: Vec<_> = LOGS.lock().unwrap_or_else(|e| e.into_inner()).iter()
+            .filter(|(_, t, message)| t == target && message.contains(needle))
+            .map(|(level, _, message)| (*level, message.clone()))
+            .collect();
+        if !found.is_empty() || started.elapsed() > Duration::from_secs(2) {
+            return found;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
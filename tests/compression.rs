@@ -0,0 +1,158 @@
+// on-the-fly brotli (`Settings::brotli_quality`) and the policy deciding what it
+// compresses (`Settings::compression`)
+mod common;
+
+use std::collections::HashMap;
+use std::io::Read;
+use common::TestServer;
+use ferropress::app::{Negotiate, ViewContext};
+use ferropress::http::{HttpContentType, Request, Response};
+use ferropress::middleware::Cors;
+use ferropress::{App, CompressionRule, Settings};
+
+
+const BR: &str = "Accept-Encoding: gzip, br\r\n";
+
+// text that compresses well, `len` bytes of it
+fn text(len: usize) -> Vec<u8> {
+    b"p { margin: 0 auto; }\n".iter().copied().cycle().take(len).collect()
+}
+
+fn unbrotli(compressed: &[u8]) -> Vec<u8> {
+    let mut plain = Vec::new();
+    brotli::Decompressor::new(compressed, 4096).read_to_end(&mut plain).unwrap();
+    plain
+}
+
+fn brotli_at(quality: u32) -> impl FnOnce(&mut Settings) {
+    move |settings| settings.brotli_quality = Some(quality)
+}
+
+#[test]
+fn br_clients_get_a_body_that_decompresses_to_the_original() {
+    let css = text(4000);
+    let server = TestServer::start(&[("static/site.css", &css)], |settings| {
+        settings.brotli_quality = Some(5);
+        settings.etags = true;
+    });
+    let response = server.get("/site.css", BR);
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("content-encoding"), Some("br"));
+    assert_eq!(response.header("vary"), Some("Accept-Encoding"));
+    assert!(response.body.len() < css.len());
+    assert_eq!(response.header("content-length"), Some(response.body.len().to_string().as_str()));
+    assert_eq!(unbrotli(&response.body), css);
+    // the tag is the plain body's, weakened
+    assert!(response.header("etag").is_some_and(|etag| etag.starts_with("W/")));
+}
+
+#[test]
+fn other_clients_get_the_plain_body() {
+    let css = text(4000);
+    let server = TestServer::start(&[("static/site.css", &css)], brotli_at(5));
+    for accept in ["", "Accept-Encoding: gzip\r\n", "Accept-Encoding: br;q=0, *\r\n"] {
+        let response = server.get("/site.css", accept);
+        assert_eq!(response.header("content-encoding"), None, "{:?}", accept);
+        assert_eq!(response.header("vary"), Some("Accept-Encoding"));
+        assert_eq!(response.body, css);
+    }
+}
+
+#[test]
+fn nothing_is_compressed_on_the_fly_by_default() {
+    let css = text(4000);
+    let server = TestServer::start(&[("static/site.css", &css)], |_| {});
+    let response = server.get("/site.css", BR);
+    assert_eq!(response.header("content-encoding"), None);
+    assert_eq!(response.body, css);
+}
+
+#[test]
+fn a_br_sibling_is_preferred_to_compressing() {
+    let css = text(4000);
+    let sibling = brotli_compress(b"from the .br file");
+    let server = TestServer::start(&[("static/site.css", &css), ("static/site.css.br", &sibling)], |settings| {
+        settings.brotli_quality = Some(5);
+        settings.precompressed_br = true;
+    });
+    let response = server.get("/site.css", BR);
+    assert_eq!(response.header("content-encoding"), Some("br"));
+    assert_eq!(unbrotli(&response.body), b"from the .br file");
+}
+
+fn brotli_compress(plain: &[u8]) -> Vec<u8> {
+    let mut compressed = Vec::new();
+    {
+        let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, 5, 22);
+        std::io::Write::write_all(&mut writer, plain).unwrap();
+    }
+    compressed
+}
+
+#[test]
+fn the_policy_turns_compression_on_and_off_per_type() {
+    let body = text(4000);
+    let policy = |settings: &mut Settings| {
+        settings.brotli_quality = Some(5);
+        settings.compression = HashMap::from([
+            (String::from("text/css"), CompressionRule { enabled: false, min_bytes: 0 }),
+            (String::from("application/json"), CompressionRule { enabled: true, min_bytes: 0 }),
+        ]);
+    };
+    let server = TestServer::start(&[("static/site.css", &body), ("static/data.json", &body), ("static/notes.txt", &body)], policy);
+    let css = server.get("/site.css", BR);
+    assert_eq!(css.header("content-encoding"), None);
+    assert_eq!(css.body, body);
+    let json = server.get("/data.json", BR);
+    assert_eq!(json.header("content-encoding"), Some("br"));
+    assert_eq!(unbrotli(&json.body), body);
+    // a type missing from the table isn't compressed
+    assert_eq!(server.get("/notes.txt", BR).header("content-encoding"), None);
+}
+
+#[test]
+fn bodies_under_the_minimum_size_are_sent_plain() {
+    let (small, large) = (text(100), text(2000));
+    let server = TestServer::start(&[("static/small.css", &small), ("static/large.css", &large)], |settings| {
+        settings.brotli_quality = Some(5);
+        settings.compression = HashMap::from([(String::from("text/css"), CompressionRule { enabled: true, min_bytes: 1024 })]);
+    });
+    assert_eq!(server.get("/small.css", BR).header("content-encoding"), None);
+    assert_eq!(server.get("/large.css", BR).header("content-encoding"), Some("br"));
+}
+
+#[test]
+fn a_quality_over_11_is_refused() {
+    let settings = Settings { brotli_quality: Some(12), port: 0, ..Settings::default() };
+    let served = async_std::task::block_on(ferropress::serve_with_shutdown(ferropress::App::new(), settings, async {}));
+    assert!(served.is_err_and(|e| e.to_string().contains("brotli_quality")));
+}
+
+async fn page(_request: Request, _ctx: ViewContext) -> Response {
+    Response::ok(HttpContentType::Html, String::from_utf8(text(4000)).unwrap())
+}
+
+async fn api(_request: Request, _ctx: ViewContext) -> Response {
+    Response::ok(HttpContentType::Json, format!("[{}]", "1, ".repeat(1000) + "1"))
+}
+
+#[test]
+fn compressing_keeps_the_other_fields_a_response_varies_on() {
+    let app = App::new()
+        .wrap(Cors::new(&["https://example.com"]))
+        .get("/post", Negotiate::new().with("text/html", page).with("application/json", api));
+    let server = TestServer::start_app(app, &[], brotli_at(5));
+    for accept in ["text/html", "application/json"] {
+        for encoding in [BR, ""] {
+            let headers = format!("Accept: {}\r\nOrigin: https://example.com\r\n{}", accept, encoding);
+            let response = server.get("/post", &headers);
+            assert_eq!(response.status, 200);
+            assert_eq!(response.header("content-encoding"), (!encoding.is_empty()).then_some("br"), "{:?}", headers);
+            let vary: Vec<_> = response.header("vary").unwrap().split(", ").collect();
+            assert_eq!(vary.len(), 3, "{:?}", vary);
+            for field in ["Accept", "Accept-Encoding", "Origin"] {
+                assert!(vary.contains(&field), "{} missing from {:?}", field, vary);
+            }
+        }
+    }
+}
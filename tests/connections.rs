@@ -0,0 +1,81 @@
+// limits on and counts of the connections the server holds
+mod common;
+
+use std::io::Write;
+use std::net::TcpStream;
+use common::{eventually, read_to_close, Response, TestServer};
+use ferropress::app::ViewContext;
+use ferropress::http::{HttpContentType, Request};
+use ferropress::App;
+
+
+// the request's own connection is open as it's answered
+async fn stats(_request: Request, ctx: ViewContext) -> ferropress::http::Response {
+    let connections = ctx.connections();
+    ferropress::http::Response::ok(HttpContentType::Plain, format!("{} {}", connections.open(), connections.total()))
+}
+
+fn request(stream: &mut TcpStream, target: &str) -> Response {
+    let raw = format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", target);
+    stream.write_all(raw.as_bytes()).unwrap();
+    Response::parse(&read_to_close(stream))
+}
+
+#[test]
+fn connections_beyond_the_per_ip_cap_are_refused() {
+    let server = TestServer::start(&[], |settings| settings.max_connections_per_ip = Some(2));
+    // two idle connections, holding their slots
+    let held: Vec<TcpStream> = (0..2).map(|_| server.connect()).collect();
+    let mut refused = None;
+    assert!(eventually(|| {
+        let response = request(&mut server.connect(), "/");
+        refused = Some(response).filter(|response| response.status == 503);
+        refused.is_some()
+    }));
+    assert_eq!(refused.unwrap().header("connection"), Some("close"));
+    // closing one gives its slot back
+    let mut held = held.into_iter();
+    drop(held.next());
+    assert!(eventually(|| request(&mut server.connect(), "/").status == 200));
+    drop(held);
+    // and so does every connection that has finished, whichever way
+    for _ in 0..5 {
+        assert_eq!(request(&mut server.connect(), "/").status, 200);
+        let mut broken = server.connect();
+        broken.write_all(b"NOT A REQUEST\r\n\r\n").unwrap();
+        read_to_close(&mut broken);
+    }
+    let both: Vec<TcpStream> = (0..2).map(|_| server.connect()).collect();
+    assert!(eventually(|| request(&mut server.connect(), "/").status == 503));
+    drop(both);
+    assert!(eventually(|| request(&mut server.connect(), "/").status == 200));
+}
+
+// open and total connections, as the stats route sees them
+fn counts(server: &TestServer) -> (usize, u64) {
+    let text = request(&mut server.connect(), "/stats").text();
+    let (open, total) = text.split_once(' ').unwrap();
+    (open.parse().unwrap(), total.parse().unwrap())
+}
+
+#[test]
+fn open_and_total_connections_are_counted() {
+    let server = TestServer::start_app(App::new().get("/stats", stats), &[], |_| {});
+    // whatever the start-up probes left behind has closed
+    assert!(eventually(|| counts(&server).0 == 1));
+    let (_, before) = counts(&server);
+    let held: Vec<TcpStream> = (0..3).map(|_| server.connect()).collect();
+    assert!(eventually(|| counts(&server).0 == 4));
+    // each count is a connection of its own, and every one of them is counted
+    let (_, total) = counts(&server);
+    assert!(total >= before + 4, "{} then {}", before, total);
+    drop(held);
+    assert!(eventually(|| counts(&server).0 == 1));
+    // a connection that fails counts too, and is closed
+    let mut broken = server.connect();
+    broken.write_all(b"NOT A REQUEST\r\n\r\n").unwrap();
+    read_to_close(&mut broken);
+    let (open, after) = counts(&server);
+    assert_eq!(open, 1);
+    assert!(after > total + 1, "{} then {}", total, after);
+}
@@ -0,0 +1,132 @@
+// the bodies of error responses, from `Settings::error_pages` and json
+mod common;
+
+use std::path::Path;
+use common::{Response as Received, TestServer};
+use ferropress::app::ViewContext;
+use ferropress::http::{HttpContentType, Request, Response};
+use ferropress::{App, ErrorPage};
+
+
+async fn fails(_request: Request, _ctx: ViewContext) -> Response {
+    Response::error(500)
+}
+
+async fn fails_in_its_own_words(_request: Request, _ctx: ViewContext) -> Response {
+    let mut response = Response::ok(HttpContentType::Plain, "the database is down");
+    response.status = Response::error(500).status;
+    response
+}
+
+fn app() -> App {
+    App::new().get("/fails", fails).get("/fails/own", fails_in_its_own_words).get("/api/fails", fails)
+}
+
+#[test]
+fn each_status_uses_its_configured_page() {
+    let files: &[(&str, &[u8])] = &[
+        ("static/404.html", b"<p>nothing here</p>"),
+        ("templates/error.html", b"<h1>{{ status_code }}</h1><p>{{ status }} on {{ site }}</p>"),
+    ];
+    let server = TestServer::start_app(app(), files, |settings| {
+        let static_dir = Path::new(&settings.static_dir);
+        settings.error_pages.insert(404, ErrorPage::File(static_dir.join("404.html").to_string_lossy().into_owned()));
+        settings.error_pages.insert(500, ErrorPage::Template(String::from("error.html")));
+        settings.error_pages.insert(405, ErrorPage::Builtin);
+        settings.global_context.insert(String::from("site"), String::from("example.org"));
+    });
+    // a static file
+    let response = server.get("/no-such-file.txt", "");
+    assert_eq!(response.status, 404);
+    assert_eq!(response.text(), "<p>nothing here</p>");
+    // a template, with the status and the global context
+    let response = server.get("/fails", "");
+    assert_eq!(response.status, 500);
+    assert!(response.text().contains("<h1>500</h1>"), "{}", response.text());
+    assert!(response.text().contains("<p>500 Internal Server Error on example.org</p>"), "{}", response.text());
+    // the built-in one
+    let response = Received::parse(&server.exchange(b"DELETE /notes.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"));
+    assert_eq!(response.status, 405);
+    assert_eq!(response.text(), "405 Method Not Allowed");
+    // a handler's own error body stays
+    assert_eq!(server.get("/fails/own", "").text(), "the database is down");
+    // and again, from the cache
+    assert_eq!(server.get("/other-missing", "").text(), "<p>nothing here</p>");
+    assert!(server.get("/fails", "").text().contains("<h1>500</h1>"));
+}
+
+#[test]
+fn a_page_that_cant_be_produced_falls_back_to_the_built_in_one() {
+    common::capture_logs();
+    let server = TestServer::start_app(app(), &[], |settings| {
+        settings.error_pages.insert(404, ErrorPage::File(String::from("./no-such-dir/404.html")));
+        settings.error_pages.insert(500, ErrorPage::Template(String::from("no-such-error.html")));
+    });
+    let response = server.get("/missing", "");
+    assert_eq!((response.status, response.text()), (404, String::from("404 Not Found")));
+    let response = server.get("/fails", "");
+    assert_eq!((response.status, response.text()), (500, String::from("500 Internal Server Error")));
+    assert!(!common::logged("ferropress::error_pages", "can't produce the 404 error page").is_empty());
+    assert!(!common::logged("ferropress::error_pages", "can't produce the 500 error page").is_empty());
+}
+
+// the body of a json error, checked against the documented schema
+fn json_error(response: &Received) -> serde_json::Value {
+    assert_eq!(response.header("content-type"), Some("application/json; charset=utf-8"));
+    let body: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+    assert_eq!(body.as_object().unwrap().len(), 2, "{}", body);
+    body
+}
+
+#[test]
+fn clients_preferring_json_get_json_errors() {
+    let files: &[(&str, &[u8])] = &[("static/404.html", b"<p>nothing here</p>")];
+    let server = TestServer::start_app(app(), files, |settings| {
+        let page = Path::new(&settings.static_dir).join("404.html").to_string_lossy().into_owned();
+        settings.error_pages.insert(404, ErrorPage::File(page));
+        settings.json_error_paths.push(String::from("/api/*"));
+    });
+    for accept in ["Accept: application/json\r\n", "Accept: text/html;q=0.5, application/problem+json\r\n"] {
+        let response = server.get("/missing", accept);
+        assert_eq!(response.status, 404);
+        assert_eq!(json_error(&response), serde_json::json!({"error": "Not Found", "status": 404}));
+        let response = server.get("/fails", accept);
+        assert_eq!(response.status, 500);
+        assert_eq!(json_error(&response), serde_json::json!({"error": "Internal Server Error", "status": 500}));
+    }
+    // by path, whatever the Accept header says
+    let response = server.get("/api/fails?x=1", "Accept: text/html\r\n");
+    assert_eq!(json_error(&response), serde_json::json!({"error": "Internal Server Error", "status": 500}));
+    assert_eq!(json_error(&server.get("/api/missing", "")), serde_json::json!({"error": "Not Found", "status": 404}));
+    // browsers still get the html page
+    for accept in ["", "Accept: text/html,application/xhtml+xml,*/*;q=0.8\r\n", "Accept: text/html, application/json\r\n"] {
+        let response = server.get("/missing", accept);
+        assert_eq!(response.text(), "<p>nothing here</p>", "{:?}", accept);
+    }
+    // and a handler's own error body is left alone
+    assert_eq!(server.get("/fails/own", "Accept: application/json\r\n").text(), "the database is down");
+}
+
+#[test]
+fn a_404_logs_the_path_but_never_echoes_it() {
+    common::capture_logs();
+    let target = "/<script>alert(1)</script>/caf\u{e9}?q=\"x\"";
+    for dev_mode in [false, true] {
+        let server = TestServer::start_app(app(), &[], |settings| settings.dev_mode = dev_mode);
+        let response = server.get(target, "");
+        assert_eq!(response.status, 404);
+        for raw in ["<script>alert", "alert(1)", "caf"] {
+            assert!(!response.text().contains(raw), "{} in {}", raw, response.text());
+        }
+        // the header is dev mode's, with anything that isn't visible ascii escaped
+        let header = response.header("x-not-found-path");
+        assert_eq!(header, dev_mode.then_some("/<script>alert(1)</script>/caf%C3%A9?q=\"x\""));
+    }
+    let logged = common::logged("not_found", "alert(1)");
+    assert_eq!(logged.len(), 2, "{:?}", logged);
+    for (level, message) in logged {
+        assert_eq!(level, log::Level::Info);
+        assert!(message.starts_with("GET /<script>alert(1)</script>/caf\u{e9}?q=\\\"x\\\" from 127.0.0.1:"), "{}", message);
+        assert!(message.ends_with(": not found"), "{}", message);
+    }
+}
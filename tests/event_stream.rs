@@ -0,0 +1,44 @@
+// server-sent event streams registered with `App::event_stream`
+mod common;
+
+use common::{Response, TestServer};
+use ferropress::app::ViewContext;
+use ferropress::http::Request;
+use ferropress::sse::Events;
+use ferropress::App;
+
+
+// a few events naming the path and site, then done
+async fn greetings(request: Request, ctx: ViewContext, mut events: Events) -> std::io::Result<()> {
+    events.send(&request.path).await?;
+    events.send_event("site", &ctx.site.templates_dir).await?;
+    events.send("two\nlines").await
+}
+
+fn server() -> TestServer {
+    TestServer::start_app(App::new().event_stream("/events/*", greetings), &[], |_| {})
+}
+
+#[test]
+fn a_registered_path_streams_the_handlers_events() {
+    let server = server();
+    let response = server.get("/events/greetings?since=1", "");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("content-type"), Some("text/event-stream; charset=utf-8"));
+    assert_eq!(response.header("cache-control"), Some("no-cache"));
+    let templates = server.dir.join("templates").to_string_lossy().into_owned();
+    assert_eq!(response.text(), format!(
+        "data: /events/greetings?since=1\n\nevent: site\ndata: {}\n\ndata: two\ndata: lines\n\n", templates,
+    ));
+}
+
+#[test]
+fn only_get_requests_get_a_stream() {
+    let server = server();
+    let raw = b"POST /events/greetings HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+    let response = Response::parse(&server.exchange(raw));
+    assert_ne!(response.header("content-type"), Some("text/event-stream; charset=utf-8"));
+    // and nothing streams by default
+    let server = TestServer::start(&[], |_| {});
+    assert_eq!(server.get("/events/counter", "").status, 404);
+}
@@ -0,0 +1,73 @@
+// checking templates against fixtures, from the library and the `parser check` command
+mod common;
+
+use std::path::Path;
+use std::process::Command;
+use ferropress::fixture::{self, FixtureError};
+use ferropress::template::RenderError;
+
+
+const TEMPLATE: &str = "<ul>{{#each items}}<li>{{ this }}</li>{{/each}}</ul>\n<p>{{ title }}</p>\n";
+const CONTEXT: &str = r#"{"title": "Fixtures", "items.0": "one", "items.1": "two"}"#;
+const EXPECTED: &str = "\n<ul>\n  <li>one</li>\n  <li>two</li>\n</ul>\n<p>Fixtures</p>\n";
+
+// the template, context and expected output written to a scratch dir, by path
+fn triple(dir: &Path, context: &str, expected: &str) -> [String; 3] {
+    let files = [("page.html", TEMPLATE), ("page.json", context), ("page.expected.html", expected)];
+    files.map(|(name, contents)| {
+        std::fs::write(dir.join(name), contents).unwrap();
+        dir.join(name).to_string_lossy().into_owned()
+    })
+}
+
+#[test]
+fn a_matching_render_passes() {
+    let dir = common::scratch_dir();
+    let [template, context, expected] = triple(&dir, CONTEXT, EXPECTED);
+    fixture::check(&template, &context, &expected).unwrap();
+    // trailing line endings don't matter
+    let [template, context, expected] = triple(&dir, CONTEXT, EXPECTED.trim_end());
+    fixture::check(&template, &context, &expected).unwrap();
+}
+
+#[test]
+fn a_mismatch_shows_a_diff() {
+    let dir = common::scratch_dir();
+    let [template, context, expected] = triple(&dir, CONTEXT, &EXPECTED.replace("two", "three"));
+    let Err(FixtureError::Mismatch(diff)) = fixture::check(&template, &context, &expected) else {
+        panic!("expected a mismatch");
+    };
+    assert_eq!(diff, format!(
+        "--- {}\n+++ {}\n@@ -2 +2 @@\n <ul>\n   <li>one</li>\n-  <li>three</li>\n+  <li>two</li>\n </ul>\n <p>Fixtures</p>\n",
+        expected, template,
+    ));
+}
+
+#[test]
+fn a_key_missing_from_the_context_is_an_error() {
+    let dir = common::scratch_dir();
+    let [template, context, expected] = triple(&dir, r#"{"items.0": "one"}"#, EXPECTED);
+    let result = fixture::check(&template, &context, &expected);
+    assert!(matches!(result, Err(FixtureError::Render(RenderError::MissingKey(key))) if key == "title"));
+    let [template, context, expected] = triple(&dir, r#"{"title": 3}"#, EXPECTED);
+    assert!(matches!(fixture::check(&template, &context, &expected), Err(FixtureError::Context(..))));
+    assert!(matches!(fixture::check(&template, "no-such-context.json", &expected), Err(FixtureError::Io(..))));
+}
+
+#[test]
+fn the_check_command_exits_with_the_result() {
+    let dir = common::scratch_dir();
+    let [template, context, expected] = triple(&dir, CONTEXT, EXPECTED);
+    let output = Command::new(env!("CARGO_BIN_EXE_parser")).args(["check", &template, &context, &expected]).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), format!("{}: ok\n", template));
+
+    let [template, context, expected] = triple(&dir, CONTEXT, &EXPECTED.replace("two", "three"));
+    let output = Command::new(env!("CARGO_BIN_EXE_parser")).args(["check", &template, &context, &expected]).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("rendered output differs from the fixture") && stderr.contains("+  <li>two</li>"), "{}", stderr);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_parser")).args(["check", &template]).output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+}
@@ -0,0 +1,69 @@
+// `Settings::gzip_only_files`: assets kept only as `.gz`
+mod common;
+
+use std::io::{Read, Write};
+use common::TestServer;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+
+const SCRIPT: &[u8] = b"console.log('only gzipped');\n";
+
+fn gzip(plain: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(plain).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn serving(gz: &[u8], configure: impl FnOnce(&mut ferropress::Settings)) -> TestServer {
+    TestServer::start(&[("static/app.js.gz", gz)], |settings| {
+        settings.gzip_only_files = true;
+        configure(settings);
+    })
+}
+
+#[test]
+fn gzip_clients_get_the_file_as_it_is() {
+    let gz = gzip(SCRIPT);
+    let server = serving(&gz, |_| {});
+    let response = server.get("/app.js", "Accept-Encoding: gzip, deflate\r\n");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("content-encoding"), Some("gzip"));
+    assert_eq!(response.header("vary"), Some("Accept-Encoding"));
+    assert!(response.header("content-type").is_some_and(|t| t.starts_with("text/javascript")));
+    assert_eq!(response.body, gz);
+    let mut plain = Vec::new();
+    flate2::read::GzDecoder::new(&response.body[..]).read_to_end(&mut plain).unwrap();
+    assert_eq!(plain, SCRIPT);
+}
+
+#[test]
+fn other_clients_get_it_decompressed() {
+    let server = serving(&gzip(SCRIPT), |_| {});
+    for accept in ["", "Accept-Encoding: br\r\n", "Accept-Encoding: gzip;q=0\r\n"] {
+        let response = server.get("/app.js", accept);
+        assert_eq!(response.status, 200, "{:?}", accept);
+        assert_eq!(response.header("content-encoding"), None);
+        assert_eq!(response.header("vary"), Some("Accept-Encoding"));
+        assert_eq!(response.body, SCRIPT);
+    }
+}
+
+#[test]
+fn off_by_default() {
+    let server = TestServer::start(&[("static/app.js.gz", &gzip(SCRIPT))], |_| {});
+    assert_eq!(server.get("/app.js", "Accept-Encoding: gzip\r\n").status, 404);
+}
+
+#[test]
+fn decompression_is_bounded() {
+    // a megabyte of zeros is a few kilobytes gzipped
+    let bomb = gzip(&vec![0; 1024 * 1024]);
+    let server = serving(&bomb, |settings| settings.max_decompressed_bytes = 64 * 1024);
+    assert_eq!(server.get("/app.js", "").status, 500);
+    // still fine as it is
+    assert_eq!(server.get("/app.js", "Accept-Encoding: gzip\r\n").body, bomb);
+    drop(server);
+    let server = serving(&bomb, |settings| settings.max_decompressed_bytes = 1024 * 1024);
+    assert_eq!(server.get("/app.js", "").body.len(), 1024 * 1024);
+}
@@ -0,0 +1,41 @@
+// switching the site into maintenance with `Settings::maintenance_file`
+mod common;
+
+use common::{eventually, TestServer};
+use ferropress::app::ViewContext;
+use ferropress::http::{HttpContentType, Request, Response};
+use ferropress::App;
+
+
+async fn health(_request: Request, _ctx: ViewContext) -> Response {
+    Response::ok(HttpContentType::Plain, "ok")
+}
+
+#[test]
+fn maintenance_is_switched_on_and_off_by_its_file() {
+    let files: &[(&str, &[u8])] = &[("static/hello.txt", b"hello")];
+    let server = TestServer::start_app(App::new().get("/healthz", health), files, |settings| {
+        let file = std::path::Path::new(&settings.templates_dir).parent().unwrap().join("maintenance");
+        settings.maintenance_file = Some(file.to_string_lossy().into_owned());
+        settings.maintenance_exempt_paths = vec![String::from("/healthz")];
+        settings.maintenance_retry_after_secs = 120;
+    });
+    let file = server.dir.join("maintenance");
+    assert_eq!(server.get("/hello.txt", "").text(), "hello");
+
+    std::fs::write(&file, "").unwrap();
+    assert!(eventually(|| server.get("/hello.txt", "").status == 503));
+    let response = server.get("/", "");
+    assert_eq!(response.status, 503);
+    assert_eq!(response.header("retry-after"), Some("120"));
+    // health checks still get through, so the instance isn't restarted meanwhile
+    let response = server.get("/healthz", "");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.text(), "ok");
+
+    std::fs::remove_file(&file).unwrap();
+    assert!(eventually(|| server.get("/hello.txt", "").status == 200));
+    let response = server.get("/", "");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("retry-after"), None);
+}
@@ -0,0 +1,98 @@
+// multipart/form-data bodies, buffered and streamed, well-formed or not
+mod common;
+
+use common::{scratch_dir, TestServer};
+use ferropress::app::ViewContext;
+use ferropress::http::{HttpContentType, Request, Response};
+use ferropress::multipart::Form;
+use ferropress::App;
+
+
+// reports each field, then each file with what was saved for it
+async fn report(mut request: Request, ctx: ViewContext) -> Response {
+    let form = match Form::from_request(&mut request, &ctx.settings).await {
+        Ok(form) => form,
+        Err(e) => return e.into(),
+    };
+    let mut names: Vec<_> = form.fields.keys().collect();
+    names.sort();
+    let mut report = String::new();
+    for name in names {
+        report.push_str(&format!("{} = {}\n", name, form.fields[name]));
+    }
+    for file in form.files.iter() {
+        let saved = std::fs::read_to_string(file.path()).unwrap();
+        report.push_str(&format!("{} {} {:?} {}: {}\n", file.name, file.filename, file.content_type, file.size, saved));
+    }
+    Response::ok(HttpContentType::Plain, report)
+}
+
+const FORM: &str = concat!(
+    "preamble\r\n",
+    "--XyZ\r\n",
+    "Content-Disposition: form-data; name=\"title\"\r\n",
+    "\r\n",
+    "my notes\r\n",
+    "--XyZ\r\n",
+    "Content-Disposition: form-data; name=\"file\"; filename=\"notes.txt\"\r\n",
+    "Content-Type: text/plain\r\n",
+    "\r\n",
+    "line one\r\n--XyNot a boundary\r\n",
+    "--XyZ--\r\n",
+);
+
+fn post(server: &TestServer, content_type: &str, body: &str) -> common::Response {
+    let raw = format!(
+        "POST /form HTTP/1.1\r\nHost: localhost\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        content_type, body.len(), body,
+    );
+    common::Response::parse(&server.exchange(raw.as_bytes()))
+}
+
+fn server(streamed: bool, uploads: &std::path::Path) -> TestServer {
+    let uploads = uploads.to_string_lossy().into_owned();
+    TestServer::start_app(App::new().post("/form", report), &[], |settings| {
+        settings.upload_temp_dir = Some(uploads);
+        if streamed {
+            settings.streamed_body_paths.push(String::from("/form"));
+        }
+    })
+}
+
+#[test]
+fn a_field_and_a_file_are_parsed() {
+    for streamed in [false, true] {
+        let uploads = scratch_dir();
+        let server = server(streamed, &uploads);
+        let response = post(&server, "multipart/form-data; boundary=\"XyZ\"", FORM);
+        assert_eq!(response.status, 200, "streamed: {}", streamed);
+        assert_eq!(response.text(), concat!(
+            "title = my notes\n",
+            "file notes.txt Some(\"text/plain\") 28: line one\r\n--XyNot a boundary\n",
+        ), "streamed: {}", streamed);
+        // the upload wasn't persisted, so its temporary file is gone
+        let left: Vec<_> = std::fs::read_dir(&uploads).unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with("ferropress-upload-"))
+            .collect();
+        assert!(left.is_empty(), "{:?}", left);
+    }
+}
+
+#[test]
+fn a_missing_boundary_is_a_400() {
+    let server = server(false, &scratch_dir());
+    for content_type in ["multipart/form-data", "multipart/form-data; boundary=", "text/plain; boundary=XyZ"] {
+        assert_eq!(post(&server, content_type, FORM).status, 400, "{}", content_type);
+    }
+}
+
+#[test]
+fn a_truncated_body_is_a_400() {
+    for streamed in [false, true] {
+        let server = server(streamed, &scratch_dir());
+        for cut in [FORM.len() - 9, FORM.find("my notes").unwrap(), 4] {
+            assert_eq!(post(&server, "multipart/form-data; boundary=XyZ", &FORM[..cut]).status, 400, "cut at {}", cut);
+        }
+    }
+}
@@ -0,0 +1,104 @@
+// a panicking handler or middleware is contained to its own request
+mod common;
+
+use std::io::{Read, Write};
+use common::TestServer;
+use ferropress::app::ViewContext;
+use ferropress::http::{HttpContentType, Request, Response};
+use ferropress::{App, Next};
+
+
+async fn panics(_request: Request, _ctx: ViewContext) -> Response {
+    panic!("this handler always panics");
+}
+
+async fn panics_with_a_string(request: Request, _ctx: ViewContext) -> Response {
+    panic!("{} panicked", request.path);
+}
+
+async fn fine(_request: Request, _ctx: ViewContext) -> Response {
+    Response::ok(HttpContentType::Plain, "fine\n")
+}
+
+// a middleware that can see what came back through the chain
+async fn tags_responses(request: Request, ctx: ViewContext, next: Next) -> Response {
+    let mut response = next.run(request, ctx).await;
+    response.set_header("X-Seen-By", "middleware");
+    response
+}
+
+fn app() -> App {
+    App::new()
+        .wrap(tags_responses)
+        .get("/panic", panics)
+        .get("/panic-string", panics_with_a_string)
+        .get("/fine", fine)
+}
+
+#[test]
+fn a_panicking_route_is_a_500() {
+    let server = TestServer::start_app(app(), &[], |_| {});
+    for path in ["/panic", "/panic-string"] {
+        let response = server.get(path, "");
+        assert_eq!(response.status, 500, "{}", path);
+        // it came back through the middleware like any other response
+        assert_eq!(response.header("x-seen-by"), Some("middleware"));
+    }
+    assert_eq!(server.get("/fine", "").text(), "fine\n");
+}
+
+#[test]
+fn the_connection_carries_on_after_a_handler_panics() {
+    let server = TestServer::start_app(app(), &[], |_| {});
+    let received = server.exchange(concat!(
+        "GET /panic HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        "GET /fine HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    ).as_bytes());
+    let text = String::from_utf8_lossy(&received);
+    assert!(text.starts_with("HTTP/1.1 500"), "{}", text);
+    assert!(text.contains("HTTP/1.1 200") && text.ends_with("fine\n"), "{}", text);
+}
+
+async fn panicking_middleware(request: Request, ctx: ViewContext, next: Next) -> Response {
+    if request.path == "/fine" {
+        panic!("this middleware panics on /fine");
+    }
+    next.run(request, ctx).await
+}
+
+#[test]
+fn a_panicking_middleware_is_a_500() {
+    let server = TestServer::start_app(app().wrap(panicking_middleware), &[], |_| {});
+    let response = server.get("/fine", "");
+    assert_eq!(response.status, 500);
+    // it never got as far as the other middleware
+    assert_eq!(response.header("x-seen-by"), None);
+}
+
+#[test]
+fn other_connections_are_unaffected_by_a_panic() {
+    let server = TestServer::start_app(app(), &[], |_| {});
+    // a keep-alive connection open across the panic
+    let mut open = server.connect();
+    let request = b"GET /fine HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    let read_response = |stream: &mut std::net::TcpStream| {
+        let mut received = Vec::new();
+        let mut buf = [0; 4096];
+        while !String::from_utf8_lossy(&received).ends_with("fine\n") {
+            let n = stream.read(&mut buf).unwrap();
+            assert!(n > 0, "closed after {:?}", String::from_utf8_lossy(&received));
+            received.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8_lossy(&received).into_owned()
+    };
+    open.write_all(request).unwrap();
+    assert!(read_response(&mut open).starts_with("HTTP/1.1 200"));
+
+    for _ in 0..10 {
+        assert_eq!(server.get("/panic", "").status, 500);
+    }
+    // still served on the connection that was already open, and on new ones
+    open.write_all(request).unwrap();
+    assert!(read_response(&mut open).starts_with("HTTP/1.1 200"));
+    assert_eq!(server.get("/fine", "").text(), "fine\n");
+}
@@ -0,0 +1,140 @@
+// `Settings::proxies`: which paths go upstream, and what they're forwarded as
+mod common;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use common::TestServer;
+use ferropress::ProxyRoute;
+
+
+// an upstream answering every request with `answer`, or never when it is `None`,
+// reporting the head of each request it gets
+fn upstream_answering(answer: Option<&'static [u8]>) -> (String, mpsc::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (seen, heads) = mpsc::channel();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut head = String::new();
+            while reader.read_line(&mut head).is_ok_and(|n| n > 2) {}
+            let _ = seen.send(head.trim_end().to_string());
+            match answer {
+                Some(answer) => {
+                    let _ = stream.write_all(answer);
+                },
+                // held open, unanswered, until the test ends
+                None => std::mem::forget(stream),
+            }
+        }
+    });
+    (url, heads)
+}
+
+// an upstream answering every request with `upstream`, reporting each request line
+fn upstream() -> (String, mpsc::Receiver<String>) {
+    let (url, heads) = upstream_answering(Some(b"HTTP/1.1 200 OK\r\nContent-Length: 8\r\nConnection: close\r\n\r\nupstream"));
+    let (seen, lines) = mpsc::channel();
+    std::thread::spawn(move || {
+        for head in heads {
+            let _ = seen.send(head.lines().next().unwrap_or_default().to_string());
+        }
+    });
+    (url, lines)
+}
+
+fn proxying(prefix: &str, upstream: &str) -> TestServer {
+    let route = ProxyRoute { prefix: prefix.to_string(), upstream: upstream.to_string() };
+    TestServer::start(&[("static/apiary", b"bees\n")], move |settings| settings.proxies = vec![route])
+}
+
+#[test]
+fn the_prefix_matches_whole_segments() {
+    let (url, lines) = upstream();
+    let server = proxying("/api", &url);
+    for path in ["/api", "/api/users", "/api?page=2"] {
+        assert_eq!(server.get(path, "").text(), "upstream", "{}", path);
+        assert_eq!(lines.recv().unwrap(), format!("GET {} HTTP/1.1", path));
+    }
+    // a near miss is served locally
+    assert_eq!(server.get("/apiary", "").text(), "bees\n");
+    assert_eq!(server.get("/apis", "").status, 404);
+    assert!(lines.try_recv().is_err());
+}
+
+#[test]
+fn a_prefix_with_a_trailing_slash_matches_the_same() {
+    let (url, lines) = upstream();
+    let server = proxying("/api/", &url);
+    assert_eq!(server.get("/api/users", "").text(), "upstream");
+    assert_eq!(lines.recv().unwrap(), "GET /api/users HTTP/1.1");
+    assert_eq!(server.get("/apiary", "").text(), "bees\n");
+}
+
+#[test]
+fn an_upstream_base_path_replaces_the_prefix() {
+    let (url, lines) = upstream();
+    let server = proxying("/api", &format!("{}/v2", url));
+    assert_eq!(server.get("/api/users", "").text(), "upstream");
+    assert_eq!(lines.recv().unwrap(), "GET /v2/users HTTP/1.1");
+    assert_eq!(server.get("/api", "").text(), "upstream");
+    assert_eq!(lines.recv().unwrap(), "GET /v2 HTTP/1.1");
+    assert_eq!(server.get("/api?x=1", "").text(), "upstream");
+    assert_eq!(lines.recv().unwrap(), "GET /v2?x=1 HTTP/1.1");
+    assert_eq!(server.get("/api/?x=1", "").text(), "upstream");
+    assert_eq!(lines.recv().unwrap(), "GET /v2/?x=1 HTTP/1.1");
+}
+
+#[test]
+fn forwarding_headers_describe_the_connection() {
+    let (url, heads) = upstream_answering(Some(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"));
+    let server = proxying("/api", &url);
+    server.get("/api", "X-Forwarded-Proto: https\r\nX-Forwarded-For: 10.0.0.1\r\n");
+    let head = heads.recv().unwrap().to_ascii_lowercase();
+    // the client's claim is replaced, and its list is added to
+    assert!(head.contains("\r\nx-forwarded-proto: http\r\n"), "{}", head);
+    assert!(!head.contains("https"), "{}", head);
+    assert!(head.contains("\r\nx-forwarded-for: 10.0.0.1, 127.0.0.1\r\n"), "{}", head);
+}
+
+#[test]
+fn a_hung_upstream_times_out() {
+    let (url, _heads) = upstream_answering(None);
+    let route = ProxyRoute { prefix: String::from("/api"), upstream: url };
+    let server = TestServer::start(&[], move |settings| {
+        settings.proxies = vec![route];
+        settings.body_read_timeout_secs = Some(1);
+    });
+    let started = Instant::now();
+    assert_eq!(server.get("/api", "").status, 504);
+    assert!(started.elapsed() < Duration::from_secs(5));
+    // an upstream that isn't there at all
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let route = ProxyRoute { prefix: String::from("/api"), upstream: format!("http://{}", closed) };
+    let server = TestServer::start(&[], move |settings| settings.proxies = vec![route]);
+    assert_eq!(server.get("/api", "").status, 502);
+}
+
+#[test]
+fn the_upstreams_status_is_logged() {
+    common::capture_logs();
+    let (url, _heads) = upstream_answering(Some(b"HTTP/1.1 418 I'm a teapot\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"));
+    let server = proxying("/api", &url);
+    assert_eq!(server.get("/api/teapot", "").status, 418);
+    let logged = common::logged("access", "\"GET /api/teapot HTTP/1.1\" 418 ");
+    assert_eq!(logged.len(), 1, "{:?}", logged);
+}
+
+#[test]
+fn matches_compares_segments() {
+    let route = ProxyRoute { prefix: String::from("/api"), upstream: String::from("http://localhost:1") };
+    assert!(route.matches("/api"));
+    assert!(route.matches("/api/"));
+    assert!(route.matches("/api?x=1"));
+    assert!(!route.matches("/apiary"));
+    assert!(!route.matches("/ap"));
+    assert!(!route.matches("/v1/api"));
+}
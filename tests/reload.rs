@@ -0,0 +1,65 @@
+// rereading the settings file on SIGHUP
+#![cfg(unix)]
+mod common;
+
+use std::path::{Path, PathBuf};
+use common::{eventually, TestServer};
+use ferropress::{Settings, SettingsSource};
+use serde_json::json;
+
+
+// writes the settings file a running `settings` came from, with `changes` on top
+fn write_settings(file: &Path, settings: &Settings, changes: serde_json::Value) {
+    let mut contents = json!({
+        "host": settings.host, "port": settings.port, "templates_dir": settings.templates_dir,
+        "static_dir": settings.static_dir, "media_dir": settings.media_dir,
+    });
+    contents.as_object_mut().unwrap().extend(changes.as_object().unwrap().clone());
+    std::fs::write(file, contents.to_string()).unwrap();
+}
+
+// whether the server has replaced the default SIGHUP action, which would end the process
+fn handling_hangups() -> bool {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        libc::sigaction(libc::SIGHUP, std::ptr::null(), &mut action);
+        action.sa_sigaction != libc::SIG_DFL
+    }
+}
+
+fn hang_up() {
+    assert_eq!(unsafe { libc::raise(libc::SIGHUP) }, 0);
+}
+
+#[test]
+fn a_hangup_applies_the_changed_settings() {
+    common::capture_logs();
+    let mut file = PathBuf::new();
+    let mut running = Settings::default();
+    let server = TestServer::start(&[("static/hello.txt", b"hello")], |settings| {
+        file = Path::new(&settings.templates_dir).parent().unwrap().join("settings.json");
+        write_settings(&file, settings, json!({}));
+        settings.source = Some(SettingsSource { file: file.to_string_lossy().into_owned(), profile: None });
+        running = settings.clone();
+    });
+    assert!(eventually(handling_hangups));
+    assert_eq!(server.get("/hello.txt", "").status, 200);
+
+    // a setting applied live, and one only read at startup
+    write_settings(&file, &running, json!({"deny_ips": ["127.0.0.1"], "port": running.port + 1}));
+    hang_up();
+    assert!(eventually(|| server.get("/hello.txt", "").status == 403));
+    let warned = common::logged("ferropress::server", "port changed in");
+    assert!(warned.iter().any(|(level, message)| *level == log::Level::Warn && message.contains("restart")), "{:?}", warned);
+
+    // invalid settings are refused, keeping the running ones
+    write_settings(&file, &running, json!({"allow_ips": ["not an address"]}));
+    hang_up();
+    let refused = common::logged("ferropress::server", "not reloading settings");
+    assert!(refused.iter().any(|(level, message)| *level == log::Level::Error && message.contains("allow_ips")), "{:?}", refused);
+    assert_eq!(server.get("/hello.txt", "").status, 403);
+
+    write_settings(&file, &running, json!({}));
+    hang_up();
+    assert!(eventually(|| server.get("/hello.txt", "").status == 200));
+}
@@ -0,0 +1,243 @@
+// reading requests off the wire: clients that hang up, stall or send too much
+mod common;
+
+use std::io::Write;
+use std::net::Shutdown;
+use std::time::{Duration, Instant};
+use common::{read_to_close, Response, TestServer};
+use ferropress::app::ViewContext;
+use ferropress::http::{HttpContentType, Request};
+use ferropress::App;
+
+
+#[test]
+fn an_immediate_close_gets_nothing_back() {
+    let server = TestServer::start(&[], |_| {});
+    let mut stream = server.connect();
+    stream.shutdown(Shutdown::Write).unwrap();
+    assert!(read_to_close(&mut stream).is_empty());
+    // and the server carries on
+    assert_eq!(server.get("/", "").status, 200);
+}
+
+#[test]
+fn a_request_cut_off_in_its_headers_is_a_400() {
+    let server = TestServer::start(&[], |_| {});
+    for partial in ["GET / HTTP/1.1\r\nHost: localhost\r\n", "GET / HTTP/1.1\r\nHost: loc", "GET / HTTP/1.1"] {
+        let mut stream = server.connect();
+        stream.write_all(partial.as_bytes()).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let response = Response::parse(&read_to_close(&mut stream));
+        assert_eq!(response.status, 400, "{:?}", partial);
+    }
+    assert_eq!(server.get("/", "").status, 200);
+}
+
+#[test]
+fn blank_lines_before_a_request_are_skipped() {
+    let server = TestServer::start(&[], |_| {});
+    let response = Response::parse(&server.exchange(b"\r\n\r\nGET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"));
+    assert_eq!(response.status, 200);
+}
+
+// a GET of `/` with `headers` (each ending in `\r\n`) on a connection of its own
+fn with_headers(server: &TestServer, headers: &str) -> Response {
+    let raw = format!("GET / HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n", headers);
+    Response::parse(&server.exchange(raw.as_bytes()))
+}
+
+#[test]
+fn too_many_headers_is_a_431() {
+    let server = TestServer::start(&[], |settings| settings.max_headers = 20);
+    // Host and Connection count too
+    let numbered = |n: usize| (0..n).map(|i| format!("X-Header-{}: {}\r\n", i, i)).collect::<String>();
+    assert_eq!(with_headers(&server, &numbered(18)).status, 200);
+    assert_eq!(with_headers(&server, &numbered(19)).status, 431);
+    assert_eq!(with_headers(&server, &numbered(5000)).status, 431);
+}
+
+#[test]
+fn an_overlong_header_line_is_a_431() {
+    let server = TestServer::start(&[], |settings| settings.max_header_line_bytes = 1024);
+    let line = |len: usize| format!("X-Long: {}\r\n", "a".repeat(len - "X-Long: \r\n".len()));
+    assert_eq!(with_headers(&server, &line(1000)).status, 200);
+    assert_eq!(with_headers(&server, &line(2000)).status, 431);
+    assert_eq!(with_headers(&server, &line(100_000)).status, 431);
+}
+
+#[test]
+fn peers_outside_allow_ips_or_inside_deny_ips_get_a_403() {
+    let server = TestServer::start(&[], |settings| settings.allow_ips = vec![String::from("10.0.0.0/8")]);
+    assert_eq!(server.get("/", "").status, 403);
+    drop(server);
+    let server = TestServer::start(&[], |settings| settings.allow_ips = vec![String::from("127.0.0.0/8")]);
+    assert_eq!(server.get("/", "").status, 200);
+    drop(server);
+    let server = TestServer::start(&[], |settings| {
+        settings.allow_ips = vec![String::from("127.0.0.0/8")];
+        settings.deny_ips = vec![String::from("127.0.0.1")];
+    });
+    assert_eq!(server.get("/", "").status, 403);
+}
+
+#[test]
+fn an_overlong_uri_is_a_414() {
+    let server = TestServer::start(&[("static/a.txt", b"a\n")], |settings| settings.max_uri_bytes = 1024);
+    let path = |len: usize| format!("/a.txt?q={}", "a".repeat(len - "/a.txt?q=".len()));
+    assert_eq!(server.get(&path(1024), "").status, 200);
+    let response = server.get(&path(1025), "");
+    assert_eq!(response.status, 414);
+    assert!(response.text().contains("414 URI Too Long"), "{}", response.text());
+    // far longer than the line buffer, too
+    assert_eq!(server.get(&path(1_000_000), "").status, 414);
+}
+
+// each stage of reading a request has a timeout of its own
+fn timing_out(configure: impl FnOnce(&mut ferropress::Settings)) -> TestServer {
+    TestServer::start(&[], |settings| {
+        settings.idle_keepalive_timeout_secs = Some(60);
+        settings.header_read_timeout_secs = Some(60);
+        settings.body_read_timeout_secs = Some(60);
+        configure(settings);
+    })
+}
+
+#[test]
+fn a_stalled_head_is_a_408() {
+    let server = timing_out(|settings| settings.header_read_timeout_secs = Some(1));
+    let mut stream = server.connect();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: loc").unwrap();
+    let started = Instant::now();
+    assert_eq!(Response::parse(&read_to_close(&mut stream)).status, 408);
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn a_stalled_body_is_a_408() {
+    let server = timing_out(|settings| settings.body_read_timeout_secs = Some(1));
+    let mut stream = server.connect();
+    stream.write_all(b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\nabc").unwrap();
+    assert_eq!(Response::parse(&read_to_close(&mut stream)).status, 408);
+}
+
+#[test]
+fn an_idle_connection_is_closed_quietly() {
+    let server = timing_out(|settings| settings.idle_keepalive_timeout_secs = Some(1));
+    let mut stream = server.connect();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    // one response, then nothing until the server hangs up
+    let received = read_to_close(&mut stream);
+    let response = Response::parse(&received);
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body.len(), response.header("content-length").unwrap().parse::<usize>().unwrap());
+    // and with no request at all
+    let mut stream = server.connect();
+    assert!(read_to_close(&mut stream).is_empty());
+}
+
+// a body that doesn't match its Content-Length
+async fn echo(request: Request, _ctx: ViewContext) -> ferropress::http::Response {
+    ferropress::http::Response::ok(HttpContentType::Plain, request.body)
+}
+
+fn echoing() -> TestServer {
+    TestServer::start_app(App::new().post("/echo", echo), &[], |settings| {
+        settings.body_read_timeout_secs = Some(1);
+    })
+}
+
+#[test]
+fn a_body_shorter_than_declared_times_out() {
+    let server = echoing();
+    let mut stream = server.connect();
+    stream.write_all(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\nabc").unwrap();
+    let received = read_to_close(&mut stream);
+    let response = Response::parse(&received);
+    assert_eq!(response.status, 408);
+    assert_eq!(response.header("connection"), Some("close"));
+}
+
+#[test]
+fn a_body_longer_than_declared_is_a_400() {
+    let server = echoing();
+    let received = server.exchange(b"POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\n\r\nabc, and then some more\r\n\r\n");
+    let text = String::from_utf8_lossy(&received);
+    // the extra bytes aren't taken for a request of their own, and nothing follows
+    assert!(text.starts_with("HTTP/1.1 400"), "{}", text);
+    assert_eq!(text.matches("HTTP/1.1 ").count(), 1, "{}", text);
+    assert_eq!(Response::parse(&received).header("connection"), Some("close"));
+}
+
+#[test]
+fn the_next_request_after_an_exact_body_is_answered() {
+    let server = echoing();
+    let received = server.exchange(concat!(
+        "POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\n\r\nabc",
+        // a stray line ending after a body is allowed
+        "\r\n",
+        "POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\nConnection: close\r\n\r\nxyz",
+    ).as_bytes());
+    let text = String::from_utf8_lossy(&received);
+    assert_eq!(text.matches("HTTP/1.1 200").count(), 2, "{}", text);
+    assert!(text.contains("\r\n\r\nabcHTTP/1.1 200") && text.ends_with("\r\n\r\nxyz"), "{}", text);
+}
+
+// max_request_bytes caps the head and body together
+fn capped() -> TestServer {
+    TestServer::start_app(App::new().post("/echo", echo), &[], |settings| {
+        settings.max_request_bytes = Some(1000);
+        settings.max_header_line_bytes = 1000;
+        settings.max_body_bytes = 1000;
+    })
+}
+
+fn post_padded(server: &TestServer, padding: usize, body: usize, chunked: bool) -> u16 {
+    let body = "b".repeat(body);
+    let framing = match chunked {
+        true if body.is_empty() => String::from("Transfer-Encoding: chunked\r\n\r\n0\r\n\r\n"),
+        true => format!("Transfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n0\r\n\r\n", body.len(), body),
+        false => format!("Content-Length: {}\r\n\r\n{}", body.len(), body),
+    };
+    let raw = format!(
+        "POST /echo HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nX-Padding: {}\r\n{}",
+        "p".repeat(padding), framing,
+    );
+    Response::parse(&server.exchange(raw.as_bytes())).status
+}
+
+#[test]
+fn a_request_over_its_overall_limit_is_a_413() {
+    let server = capped();
+    for chunked in [false, true] {
+        // each part is well within its own limit and the cap
+        assert_eq!(post_padded(&server, 500, 0, chunked), 200, "chunked: {}", chunked);
+        assert_eq!(post_padded(&server, 0, 500, chunked), 200, "chunked: {}", chunked);
+        // but not together
+        assert_eq!(post_padded(&server, 500, 500, chunked), 413, "chunked: {}", chunked);
+    }
+    // a head alone can go over it too
+    let long_header = format!("X-One: {}\r\nX-Two: {}\r\n", "1".repeat(600), "2".repeat(600));
+    assert_eq!(with_headers(&server, &long_header).status, 413);
+}
+
+#[test]
+fn any_read_buffer_size_reads_the_same_requests() {
+    let body: String = (0..3000).map(|i| char::from(b'a' + (i % 26) as u8)).collect();
+    let raw = format!(
+        concat!(
+            "POST /echo HTTP/1.1\r\nHost: localhost\r\nX-Padding: {}\r\nContent-Length: {}\r\n\r\n{}",
+            "POST /echo HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+        ),
+        "p".repeat(1500), body.len(), body,
+    );
+    // under the minimum, smaller than a single header line, and bigger than everything
+    for bytes in [0, 100, 1 << 16] {
+        let server = TestServer::start_app(App::new().post("/echo", echo), &[], |settings| {
+            settings.read_buffer_bytes = bytes;
+        });
+        let text = String::from_utf8_lossy(&server.exchange(raw.as_bytes())).into_owned();
+        assert_eq!(text.matches("HTTP/1.1 200").count(), 2, "{} bytes: {}", bytes, text);
+        assert!(text.contains(&format!("\r\n\r\n{}HTTP/1.1 200", body)), "{} bytes", bytes);
+        assert!(text.ends_with("\r\n\r\nok"), "{} bytes", bytes);
+    }
+}
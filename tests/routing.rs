@@ -0,0 +1,146 @@
+// which handler a request reaches, and what route-based settings apply to it
+mod common;
+
+use std::collections::HashMap;
+use std::time::Duration;
+use common::TestServer;
+use ferropress::app::ViewContext;
+use ferropress::http::{HttpContentType, Request, Response};
+use ferropress::{AliasTarget, App, ConcurrencyLimit, Negotiate, RouteAlias, RouteHeaders};
+
+
+async fn hello(request: Request, _ctx: ViewContext) -> Response {
+    Response::ok(HttpContentType::Plain, format!("hello from {}", request.path))
+}
+
+#[test]
+fn a_query_string_doesnt_change_the_route() {
+    let server = TestServer::start_app(App::new().get("/hello", hello), &[], |settings| {
+        settings.route_headers.push(RouteHeaders {
+            pattern: String::from("/hello"),
+            headers: HashMap::from([(String::from("X-Route"), String::from("hello"))]),
+        });
+    });
+    for target in ["/hello", "/hello?x=1", "/hello?utm_source=a&utm_medium=b"] {
+        let response = server.get(target, "");
+        assert_eq!(response.status, 200, "{}", target);
+        // the handler still sees the whole target
+        assert_eq!(response.text(), format!("hello from {}", target));
+        assert_eq!(response.header("x-route"), Some("hello"), "{}", target);
+    }
+    assert_eq!(server.get("/hello/there?x=1", "").status, 404);
+    // the built-in index view too
+    let response = server.get("/?utm=1", "");
+    assert_eq!(response.status, 200);
+    assert!(response.text().contains("index"), "{}", response.text());
+}
+
+async fn page(_request: Request, _ctx: ViewContext) -> Response {
+    Response::ok(HttpContentType::Html, "<p>post</p>")
+}
+
+async fn api(_request: Request, _ctx: ViewContext) -> Response {
+    Response::ok(HttpContentType::Json, r#"{"post": true}"#)
+}
+
+#[test]
+fn negotiate_picks_a_representation_by_accept() {
+    let negotiate = Negotiate::new().with("text/html", page).with("application/json", api);
+    let server = TestServer::start_app(App::new().get("/post", negotiate), &[], |_| {});
+    let cases = [
+        // no preference: the first one added
+        ("", 200, "<p>post</p>"),
+        ("Accept: \r\n", 200, "<p>post</p>"),
+        ("Accept: */*\r\n", 200, "<p>post</p>"),
+        ("Accept: application/json\r\n", 200, r#"{"post": true}"#),
+        ("Accept: text/html;q=0.5, application/json\r\n", 200, r#"{"post": true}"#),
+        ("Accept: application/json;q=0.2, text/*;q=0.9\r\n", 200, "<p>post</p>"),
+        // the more specific range decides, though `*/*` is listed with a higher quality
+        ("Accept: */*;q=1, text/html;q=0.1\r\n", 200, r#"{"post": true}"#),
+        ("Accept: APPLICATION/JSON\r\n", 200, r#"{"post": true}"#),
+        ("Accept: image/png\r\n", 406, ""),
+        ("Accept: text/html;q=0, application/json;q=0\r\n", 406, ""),
+    ];
+    for (accept, status, body) in cases {
+        let response = server.get("/post", accept);
+        assert_eq!(response.status, status, "{:?}", accept);
+        if status == 200 {
+            assert_eq!(response.text(), body, "{:?}", accept);
+        }
+        assert_eq!(response.header("vary"), Some("Accept"), "{:?}", accept);
+    }
+}
+
+async fn expensive(_request: Request, _ctx: ViewContext) -> Response {
+    async_std::task::sleep(Duration::from_millis(500)).await;
+    Response::ok(HttpContentType::Plain, "done")
+}
+
+#[test]
+fn a_limited_route_refuses_requests_beyond_its_limit() {
+    let app = App::new().get("/report", ConcurrencyLimit::new(expensive, 2)).get("/hello", hello);
+    let server = TestServer::start_app(app, &[], |_| {});
+    let statuses: Vec<u16> = std::thread::scope(|scope| {
+        let clients: Vec<_> = (0..6).map(|_| scope.spawn(|| server.get("/report", "").status)).collect();
+        // a route without a limit is answered meanwhile
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(server.get("/hello", "").status, 200);
+        clients.into_iter().map(|client| client.join().unwrap()).collect()
+    });
+    assert_eq!(statuses.iter().filter(|status| **status == 200).count(), 2, "{:?}", statuses);
+    assert_eq!(statuses.iter().filter(|status| **status == 503).count(), 4, "{:?}", statuses);
+    // the slots are given back once the requests finish
+    assert_eq!(server.get("/report", "").text(), "done");
+}
+
+#[test]
+fn an_alias_points_a_path_at_another_file() {
+    let files: &[(&str, &[u8])] = &[
+        ("static/landing.html", b"<p>landing</p>"), ("static/site.css", b"site {}"),
+        ("templates/welcome.html", b"<p>welcome to {{ request_path }}</p>"),
+    ];
+    let app = App::new().get("/hello", hello);
+    let server = TestServer::start_app(app, files, |settings| {
+        let landing = std::path::Path::new(&settings.static_dir).join("landing.html");
+        let alias = |path: &str, target| RouteAlias { path: path.into(), target };
+        settings.aliases = vec![
+            alias("/", AliasTarget::File(landing.to_string_lossy().into_owned())),
+            alias("/home", AliasTarget::Template(String::from("welcome.html"))),
+            alias("/site.css", AliasTarget::File(String::from("/nowhere/site.css"))),
+            alias("/hello", AliasTarget::Template(String::from("welcome.html"))),
+        ];
+    });
+    // `/` serves the chosen file instead of templates/index.html
+    let response = server.get("/", "");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("content-type"), Some("text/html; charset=utf-8"));
+    assert!(response.text().starts_with("<p>landing</p>"), "{}", response.text());
+    assert!(server.get("/?from=search", "").text().starts_with("<p>landing</p>"));
+    assert!(server.get("/home", "").text().contains("<p>welcome to /home</p>"));
+    // an alias wins over the static file at its path, even when its own file is missing
+    assert_eq!(server.get("/site.css", "").status, 500);
+    // but not over a route added to the app
+    assert_eq!(server.get("/hello", "").text(), "hello from /hello");
+}
+
+#[test]
+fn only_requests_over_the_threshold_are_logged_as_slow() {
+    common::capture_logs();
+    let app = || App::new().get("/report", expensive).get("/hello", hello);
+    let server = TestServer::start_app(app(), &[], |settings| settings.slow_request_ms = Some(300));
+    assert_eq!(server.get("/report?watched", "").status, 200);
+    assert_eq!(server.get("/hello?watched", "").status, 200);
+    let slow = common::logged("slow", "/report?watched");
+    assert_eq!(slow.len(), 1, "{:?}", slow);
+    let (level, message) = &slow[0];
+    assert_eq!(*level, log::Level::Warn);
+    assert!(message.starts_with("GET /report?watched from 127.0.0.1:") && message.ends_with("ms (200)"), "{}", message);
+    let took: u64 = message.rsplit(" took ").next().unwrap().split("ms").next().unwrap().parse().unwrap();
+    assert!(took >= 500, "{}", message);
+    assert_eq!(common::logged("slow", "/hello?watched"), []);
+
+    // without a threshold nothing is
+    let server = TestServer::start_app(app(), &[], |_| {});
+    assert_eq!(server.get("/report?unwatched", "").status, 200);
+    assert_eq!(common::logged("slow", "/report?unwatched"), []);
+}
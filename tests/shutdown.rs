@@ -0,0 +1,33 @@
+// what `serve_with_shutdown` finishes before it returns
+mod common;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use common::{Response, TestServer};
+use ferropress::app::ViewContext;
+use ferropress::http::{self, HttpContentType, Request};
+use ferropress::App;
+
+
+static AUDITED: AtomicBool = AtomicBool::new(false);
+
+// answers at once, and records the request a while later
+async fn audited(_request: Request, ctx: ViewContext) -> http::Response {
+    ctx.spawn(async {
+        async_std::task::sleep(Duration::from_millis(300)).await;
+        AUDITED.store(true, Ordering::SeqCst);
+    });
+    http::Response::ok(HttpContentType::Plain, "done")
+}
+
+#[test]
+fn a_spawned_task_completes_before_shutdown_returns() {
+    let server = TestServer::start_app(App::new().post("/audited", audited), &[], |_| {});
+    let raw = b"POST /audited HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+    assert_eq!(Response::parse(&server.exchange(raw)).text(), "done");
+    // the response didn't wait for the task
+    assert!(!AUDITED.load(Ordering::SeqCst));
+    // dropping the server returns once `serve_with_shutdown` has
+    drop(server);
+    assert!(AUDITED.load(Ordering::SeqCst));
+}
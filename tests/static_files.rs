@@ -0,0 +1,287 @@
+// how files in the content directories are found and served
+mod common;
+
+use common::TestServer;
+use std::path::Path;
+use ferropress::{ErrorPage, ExtensionRoot, FileCharset, MissingIndex, RobotsTxt, VirtualHost};
+
+
+#[test]
+fn a_legacy_file_is_labelled_or_transcoded() {
+    // "café €" in windows-1252, and in utf-16le behind its byte order mark
+    let latin: &[u8] = b"caf\xe9 \x80";
+    let utf16: &[u8] = b"\xff\xfec\x00a\x00f\x00\xe9\x00";
+    let files: &[(&str, &[u8])] = &[
+        ("static/legacy/labelled.txt", latin), ("static/legacy/converted.txt", latin),
+        ("static/legacy/marked.txt", utf16), ("static/plain.txt", "café".as_bytes()),
+    ];
+    let server = TestServer::start(files, |settings| {
+        let entry = |path: &str, charset: &str, transcode| FileCharset { path: path.into(), charset: charset.into(), transcode };
+        settings.file_charsets = vec![
+            entry("/legacy/labelled.txt", "windows-1252", false),
+            entry("/legacy/converted.txt", "windows-1252", true),
+            entry("/legacy/marked.txt", "windows-1252", true),
+        ];
+    });
+    // advertised as it is
+    let response = server.get("/legacy/labelled.txt", "");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("content-type"), Some("text/plain; charset=windows-1252"));
+    assert_eq!(response.headers.iter().filter(|(name, _)| name == "content-type").count(), 1);
+    assert_eq!(response.body, latin);
+    // converted to utf-8
+    let response = server.get("/legacy/converted.txt", "");
+    assert_eq!(response.header("content-type"), Some("text/plain; charset=utf-8"));
+    assert_eq!(response.text(), "café €");
+    assert_eq!(response.header("content-length"), Some("9"));
+    // a byte order mark wins over the configured charset
+    let response = server.get("/legacy/marked.txt", "");
+    assert_eq!(response.header("content-type"), Some("text/plain; charset=utf-8"));
+    assert_eq!(response.text(), "café");
+    // files without an entry are utf-8, untouched
+    let response = server.get("/plain.txt", "");
+    assert_eq!(response.header("content-type"), Some("text/plain; charset=utf-8"));
+    assert_eq!(response.body, "café".as_bytes());
+}
+
+// a GET of `target` with the Host header `host`
+fn get_from(server: &TestServer, host: &str, target: &str) -> common::Response {
+    let raw = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", target, host);
+    common::Response::parse(&server.exchange(raw.as_bytes()))
+}
+
+#[test]
+fn each_host_has_its_own_site() {
+    let files: &[(&str, &[u8])] = &[
+        ("blog/templates/index.html", b"<p>the blog</p>"), ("blog/static/site.css", b"blog {}"),
+        ("shop/templates/index.html", b"<p>the shop</p>"), ("shop/static/site.css", b"shop {}"),
+        ("static/site.css", b"default {}"),
+    ];
+    let server = TestServer::start(files, |settings| {
+        let base = Path::new(&settings.templates_dir).parent().unwrap().to_path_buf();
+        let site = |name: &str, hosts: &[&str]| VirtualHost {
+            hosts: hosts.iter().map(|host| host.to_string()).collect(),
+            templates_dir: base.join(name).join("templates").to_string_lossy().into_owned(),
+            static_dir: base.join(name).join("static").to_string_lossy().into_owned(),
+            media_dir: base.join(name).join("media").to_string_lossy().into_owned(),
+        };
+        settings.vhosts = vec![site("blog", &["blog.example"]), site("shop", &["shop.example", "www.shop.example"])];
+    });
+    for (host, index, css) in [
+        ("blog.example", "the blog", "blog {}"),
+        // the port isn't part of the name, and case doesn't matter
+        ("blog.example:8080", "the blog", "blog {}"),
+        ("SHOP.example", "the shop", "shop {}"),
+        ("www.shop.example:443", "the shop", "shop {}"),
+        // anything else is the default site
+        ("elsewhere.example", "index", "default {}"),
+        ("localhost", "index", "default {}"),
+    ] {
+        assert!(get_from(&server, host, "/").text().contains(index), "{}", host);
+        assert_eq!(get_from(&server, host, "/site.css").text(), css, "{}", host);
+    }
+}
+
+#[test]
+fn downloads_are_sent_as_attachments() {
+    let files: &[(&str, &[u8])] = &[
+        ("static/files/report.pdf", b"%PDF-1.4"), ("static/notes.txt", b"notes"),
+        ("media/photo.png", b"\x89PNG\r\n\x1a\n"),
+    ];
+    let server = TestServer::start(files, |settings| {
+        settings.download_paths.push(String::from("/files/*"));
+        // so the png goes out streamed from disk, and the rest from memory
+        settings.stream_min_bytes = Some(1);
+    });
+    let disposition = |target: &str| {
+        let response = server.get(target, "");
+        assert_eq!(response.status, 200, "{}", target);
+        response.header("content-disposition").map(String::from)
+    };
+    assert_eq!(disposition("/files/report.pdf").as_deref(), Some("attachment; filename=\"report.pdf\""));
+    assert_eq!(disposition("/notes.txt"), None);
+    assert_eq!(disposition("/notes.txt?download").as_deref(), Some("attachment; filename=\"notes.txt\""));
+    assert_eq!(disposition("/notes.txt?v=2&download=1").as_deref(), Some("attachment; filename=\"notes.txt\""));
+    assert_eq!(disposition("/photo.png?download").as_deref(), Some("attachment; filename=\"photo.png\""));
+    assert_eq!(disposition("/photo.png"), None);
+}
+
+// a server whose index page is missing, answering `/` with `fallback`
+fn without_an_index(fallback: MissingIndex) -> TestServer {
+    let files: &[(&str, &[u8])] = &[("static/404.html", b"<p>no such page</p>")];
+    TestServer::start(files, |settings| {
+        settings.index_files = vec![String::from("home.html")];
+        settings.missing_index = Some(fallback);
+        let page = Path::new(&settings.static_dir).join("404.html").to_string_lossy().into_owned();
+        settings.error_pages.insert(404, ErrorPage::File(page));
+    })
+}
+
+#[test]
+fn a_missing_index_gets_the_configured_fallback() {
+    common::capture_logs();
+    let response = without_an_index(MissingIndex::Placeholder).get("/", "");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("content-type"), Some("text/html; charset=utf-8"));
+    assert!(response.text().contains("Nothing here yet"), "{}", response.text());
+    let logged = common::logged("ferropress::views", "no index page in");
+    assert!(logged.iter().any(|(level, message)| *level == log::Level::Error && message.contains("home.html")), "{:?}", logged);
+
+    let response = without_an_index(MissingIndex::NotFound).get("/", "");
+    assert_eq!(response.status, 404);
+    // with the configured error page
+    assert_eq!(response.text(), "<p>no such page</p>");
+
+    let response = without_an_index(MissingIndex::Redirect(String::from("/blog/"))).get("/?from=home", "");
+    assert_eq!(response.status, 302);
+    assert_eq!(response.header("location"), Some("/blog/"));
+}
+
+#[test]
+fn a_mismatched_case_finds_the_file_only_when_enabled() {
+    let files: &[(&str, &[u8])] = &[
+        ("static/Docs/ReadMe.TXT", b"read me"), ("static/Docs/index.html", b"<p>docs</p>"),
+        ("secret.txt", b"secret"),
+    ];
+    let exact = TestServer::start(files, |_| {});
+    assert_eq!(exact.get("/Docs/ReadMe.TXT", "").text(), "read me");
+    assert_eq!(exact.get("/docs/readme.txt", "").status, 404);
+
+    let folded = TestServer::start(files, |settings| settings.case_insensitive_static = true);
+    for target in ["/Docs/ReadMe.TXT", "/docs/readme.txt", "/DOCS/README.txt"] {
+        let response = folded.get(target, "");
+        assert_eq!(response.status, 200, "{}", target);
+        assert_eq!(response.header("content-type"), Some("text/plain; charset=utf-8"), "{}", target);
+        assert_eq!(response.text(), "read me", "{}", target);
+    }
+    // a directory without its trailing slash is sent to its index
+    let response = folded.get("/Docs", "");
+    assert_eq!(response.status, 301);
+    assert_eq!(response.header("location"), Some("/Docs/"));
+    assert!(folded.get("/Docs/", "").text().contains("docs"));
+    // nothing outside the static directory is found by its folded name either
+    assert_eq!(folded.get("/../SECRET.txt", "").status, 404);
+    assert_eq!(folded.get("/docs/missing.txt", "").status, 404);
+}
+
+#[test]
+fn the_first_index_file_that_exists_is_served() {
+    let files: &[(&str, &[u8])] = &[
+        ("static/old/index.htm", b"<p>old docs</p>"),
+        ("static/both/index.html", b"<p>new docs</p>"), ("static/both/index.htm", b"<p>old docs</p>"),
+        ("templates/index.htm", b"<p>old home</p>"),
+    ];
+    let server = TestServer::start(files, |settings| {
+        settings.index_files = vec![String::from("index.html"), String::from("index.htm")];
+        std::fs::remove_file(Path::new(&settings.templates_dir).join("index.html")).unwrap();
+    });
+    // index.html is absent, so index.htm is served
+    let response = server.get("/old/", "");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("content-type"), Some("text/html; charset=utf-8"));
+    assert!(response.text().contains("old docs"), "{}", response.text());
+    assert!(server.get("/", "").text().contains("old home"));
+    // and where both exist, the earlier entry wins
+    assert!(server.get("/both/", "").text().contains("new docs"));
+    assert_eq!(server.get("/neither/", "").status, 404);
+}
+
+#[test]
+fn favicon_and_robots_txt_come_from_the_settings_or_the_content_directories() {
+    let files: &[(&str, &[u8])] = &[
+        ("media/favicon.ico", b"\x00\x00\x01\x00"), ("static/robots.txt", b"User-agent: *\n"),
+        ("config/logo.png", b"\x89PNG\r\n\x1a\n"), ("config/robots.txt", b"Disallow: /admin/\n"),
+    ];
+    let config = |settings: &ferropress::Settings, name: &str| {
+        Path::new(&settings.templates_dir).parent().unwrap().join("config").join(name).to_string_lossy().into_owned()
+    };
+    let max_age = "public, max-age=86400";
+
+    // unset, both are found like any other file
+    let server = TestServer::start(files, |_| {});
+    let response = server.get("/favicon.ico", "");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("content-type"), Some("image/x-icon"));
+    assert_eq!(response.body, b"\x00\x00\x01\x00");
+    assert_ne!(response.header("cache-control"), Some(max_age));
+    let response = server.get("/robots.txt", "");
+    assert_eq!(response.text(), "User-agent: *\n");
+    assert_ne!(response.header("cache-control"), Some(max_age));
+
+    // set, they come from the chosen file or text
+    let server = TestServer::start(files, |settings| {
+        settings.favicon = Some(config(settings, "logo.png"));
+        settings.robots_txt = Some(RobotsTxt::Content(String::from("User-agent: *\nDisallow: /\n")));
+    });
+    let response = server.get("/favicon.ico", "");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("content-type"), Some("image/png"));
+    assert_eq!(response.body, b"\x89PNG\r\n\x1a\n");
+    assert_eq!(response.header("cache-control"), Some(max_age));
+    let response = server.get("/robots.txt", "");
+    assert_eq!(response.header("content-type"), Some("text/plain; charset=utf-8"));
+    assert_eq!(response.text(), "User-agent: *\nDisallow: /\n");
+    assert_eq!(response.header("cache-control"), Some(max_age));
+
+    let server = TestServer::start(files, |settings| {
+        settings.robots_txt = Some(RobotsTxt::File(config(settings, "robots.txt")));
+    });
+    let response = server.get("/robots.txt", "");
+    assert_eq!(response.text(), "Disallow: /admin/\n");
+    assert_eq!(response.header("cache-control"), Some(max_age));
+    // the favicon still comes from the media directory
+    assert_eq!(server.get("/favicon.ico", "").body, b"\x00\x00\x01\x00");
+}
+
+#[test]
+fn an_extensionless_file_is_sniffed_when_enabled() {
+    let files: &[(&str, &[u8])] = &[
+        ("static/logo", b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), ("static/LICENSE", b"Permission is hereby granted\n"),
+        ("static/blob", b"\x7fELF\x02\x01\x01\0"), ("media/fake.png", b"not a png\n"),
+    ];
+    let content_type = |server: &TestServer, target: &str| {
+        let response = server.get(target, "");
+        assert_eq!(response.status, 200, "{}", target);
+        response.header("content-type").map(String::from)
+    };
+    let server = TestServer::start(files, |settings| settings.sniff_content_type = true);
+    assert_eq!(content_type(&server, "/logo").as_deref(), Some("image/png"));
+    assert_eq!(content_type(&server, "/LICENSE").as_deref(), Some("text/plain; charset=utf-8"));
+    assert_eq!(content_type(&server, "/blob").as_deref(), Some("application/octet-stream"));
+    // a known extension is trusted over the contents
+    assert_eq!(content_type(&server, "/fake.png").as_deref(), Some("image/png"));
+
+    // off by default, where files without a known extension are html
+    let server = TestServer::start(files, |_| {});
+    assert_eq!(content_type(&server, "/logo").as_deref(), Some("text/html; charset=utf-8"));
+    assert_eq!(content_type(&server, "/LICENSE").as_deref(), Some("text/html; charset=utf-8"));
+}
+
+#[test]
+fn each_extension_is_served_from_its_own_root() {
+    let files: &[(&str, &[u8])] = &[
+        ("images/logo.png", b"\x89PNG\r\n\x1a\n"), ("assets/app.js", b"run();"), ("assets/site.css", b"body {}"),
+        ("static/notes.txt", b"notes"), ("static/app.js", b"stale();"), ("media/favicon.ico", b"\x00\x00\x01\x00"),
+        ("images/secret.txt", b"secret"),
+    ];
+    let server = TestServer::start(files, |settings| {
+        let base = Path::new(&settings.templates_dir).parent().unwrap().to_path_buf();
+        let root = |extensions: &[&str], dir: &str| ExtensionRoot {
+            extensions: extensions.iter().map(|ext| ext.to_string()).collect(),
+            dir: base.join(dir).to_string_lossy().into_owned(),
+        };
+        settings.extension_roots = vec![root(&["jpg", "png"], "images"), root(&["css", "js"], "assets")];
+    });
+    for (target, body) in [
+        ("/logo.png", &b"\x89PNG\r\n\x1a\n"[..]), ("/app.js", b"run();"), ("/site.css", b"body {}"),
+        // extensions without a root of their own are where they were
+        ("/notes.txt", b"notes"), ("/favicon.ico", b"\x00\x00\x01\x00"),
+    ] {
+        let response = server.get(target, "");
+        assert_eq!(response.status, 200, "{}", target);
+        assert_eq!(response.body, body, "{}", target);
+    }
+    // a root only serves its own extensions, and nothing above it
+    assert_eq!(server.get("/secret.txt", "").status, 404);
+    assert_eq!(server.get("/../images/secret.txt", "").status, 404);
+}
@@ -0,0 +1,142 @@
+// templates rendered by the server for a view
+mod common;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use chrono::Datelike;
+use common::TestServer;
+use ferropress::app::ViewContext;
+use ferropress::http::{Request, Response};
+use ferropress::template::Context;
+use ferropress::{AliasTarget, App, RouteAlias};
+
+
+async fn missing_template(_request: Request, ctx: ViewContext) -> Response {
+    ctx.render("no-such-page.html", &Context::new()).await
+}
+
+#[test]
+fn a_missing_template_is_a_clean_500() {
+    common::capture_logs();
+    let app = App::new().get("/missing", missing_template);
+    let server = TestServer::start_app(app, &[], |_| {});
+    let response = server.get("/missing", "");
+    assert_eq!(response.status, 500);
+    assert!(!response.text().contains("no-such-page"), "{}", response.text());
+    let logged = common::logged("ferropress::app", "template not found");
+    assert!(logged.iter().any(|(level, message)| *level == log::Level::Error
+        && message.contains("no-such-page.html") && message.contains("check templates_dir")), "{:?}", logged);
+    // and the server carries on
+    assert_eq!(server.get("/", "").status, 200);
+}
+
+async fn busy_page(_request: Request, ctx: ViewContext) -> Response {
+    ctx.render("busy.html", &Context::new()).await
+}
+
+// the most renders that were running at once, with the limit at `limit`, over
+// `requests` requests sent together
+fn peak_renders(limit: usize, requests: usize) -> usize {
+    let (running, peak) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+    let (counted, highest) = (Arc::clone(&running), Arc::clone(&peak));
+    let app = App::new().get("/busy", busy_page).template_function("busy", move |_| {
+        let now = counted.fetch_add(1, Ordering::SeqCst) + 1;
+        highest.fetch_max(now, Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(100));
+        counted.fetch_sub(1, Ordering::SeqCst);
+        Ok(String::from("done"))
+    });
+    let files: &[(&str, &[u8])] = &[("templates/busy.html", b"<p>{{ busy() }}</p>")];
+    let server = TestServer::start_app(app, files, |settings| settings.max_concurrent_renders = Some(limit));
+    std::thread::scope(|scope| {
+        let clients: Vec<_> = (0..requests).map(|_| scope.spawn(|| server.get("/busy", ""))).collect();
+        for client in clients {
+            let response = client.join().unwrap();
+            assert_eq!(response.status, 200);
+            assert!(response.text().contains("<p>done</p>"), "{}", response.text());
+        }
+    });
+    peak.load(Ordering::SeqCst)
+}
+
+#[test]
+fn renders_beyond_the_limit_wait_their_turn() {
+    assert_eq!(peak_renders(1, 4), 1);
+    assert_eq!(peak_renders(2, 6), 2);
+}
+
+const BUILT_INS: &str = "<p>{{ year }}|{{ version }}|{{ request_path }}|{{ host }}|{{ now }}</p>";
+
+#[test]
+fn the_built_ins_render_in_templates_and_injected_files() {
+    let files: &[(&str, &[u8])] = &[("templates/about.html", BUILT_INS.as_bytes()), ("static/info.txt", BUILT_INS.as_bytes())];
+    let server = TestServer::start(files, |settings| {
+        settings.aliases.push(RouteAlias { path: String::from("/about"), target: AliasTarget::Template(String::from("about.html")) });
+        settings.inject_extensions.push(String::from("txt"));
+    });
+    let year = chrono::Local::now().year();
+    for (target, path) in [("/about?from=home", "/about"), ("/info.txt", "/info.txt")] {
+        let text = server.get(target, "").text();
+        let expected = format!("<p>{}|{}|{}|localhost|", year, env!("CARGO_PKG_VERSION"), path);
+        assert!(text.contains(&expected), "{}: {}", target, text);
+        assert!(!text.contains("CTX MISS"), "{}: {}", target, text);
+    }
+    // the host is the request's, port and all
+    let raw = server.exchange(b"GET /about HTTP/1.1\r\nHost: example.org:8080\r\nConnection: close\r\n\r\n");
+    let text = common::Response::parse(&raw).text();
+    assert!(text.contains("|/about|example.org:8080|"), "{}", text);
+}
+
+#[test]
+fn the_global_context_overrides_the_built_ins() {
+    let files: &[(&str, &[u8])] = &[("templates/about.html", BUILT_INS.as_bytes())];
+    let server = TestServer::start(files, |settings| {
+        settings.aliases.push(RouteAlias { path: String::from("/about"), target: AliasTarget::Template(String::from("about.html")) });
+        settings.global_context.insert(String::from("year"), String::from("1999"));
+        settings.global_context.insert(String::from("version"), String::from("deploy-42"));
+    });
+    let text = server.get("/about", "").text();
+    assert!(text.contains("<p>1999|deploy-42|/about|localhost|"), "{}", text);
+}
+
+
+// `{{ visitor.name }}` from the `name` query parameter; refuses an empty one
+fn visitor(request: &Request, ctx: &mut Context) -> Result<(), Response> {
+    let query = request.path.split_once('?').map_or("", |(_, query)| query);
+    match query.split('&').find_map(|param| param.strip_prefix("name=")) {
+        Some("") => return Err(Response::error(400)),
+        Some(name) => ctx.insert("visitor.name".into(), name.to_string()),
+        None => ctx.insert("visitor.name".into(), String::from("stranger")),
+    };
+    Ok(())
+}
+
+// runs after `visitor`, so it sees what that one added
+fn greeting(_request: &Request, ctx: &mut Context) -> Result<(), Response> {
+    let greeting = format!("hello, {}", ctx["visitor.name"]);
+    ctx.insert("greeting".into(), greeting);
+    Ok(())
+}
+
+async fn greet(_request: Request, ctx: ViewContext) -> Response {
+    let mut page = Context::new();
+    page.insert("title".into(), String::from("Greetings"));
+    ctx.render("greet.html", &page).await
+}
+
+#[test]
+fn provided_values_appear_in_the_rendered_page() {
+    let files: &[(&str, &[u8])] = &[("templates/greet.html", b"<h1>{{ title }}</h1><p>{{ greeting }}, {{ visitor.name }}</p>")];
+    let app = App::new().provide(visitor).provide(greeting).get("/greet", greet);
+    let server = TestServer::start_app(app, files, |_| {});
+    let response = server.get("/greet?name=Ada", "");
+    assert_eq!(response.status, 200);
+    assert!(response.text().contains("<h1>Greetings</h1>"), "{}", response.text());
+    assert!(response.text().contains("<p>hello, Ada, Ada</p>"), "{}", response.text());
+    assert!(server.get("/greet", "").text().contains("<p>hello, stranger, stranger</p>"));
+    // a provider's response answers the request, without the rest running
+    let response = server.get("/greet?name=", "");
+    assert_eq!(response.status, 400);
+    assert!(!response.text().contains("hello"), "{}", response.text());
+}
@@ -0,0 +1,86 @@
+// serving on `Settings::unix_socket`, whose peers have no ip address
+#![cfg(unix)]
+mod common;
+
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+use common::{Response, TestServer};
+use ferropress::Settings;
+
+
+// a server also listening on `ferropress.sock` in its scratch directory
+fn start(configure: impl FnOnce(&mut Settings)) -> TestServer {
+    TestServer::start(&[("static/hello.txt", b"hello\n")], |settings| {
+        let dir = Path::new(&settings.templates_dir).parent().unwrap();
+        settings.unix_socket = Some(dir.join("ferropress.sock").to_string_lossy().into_owned());
+        settings.cache_admin = true;
+        configure(settings);
+    })
+}
+
+fn unix_connect(server: &TestServer) -> UnixStream {
+    let stream = UnixStream::connect(server.dir.join("ferropress.sock")).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream
+}
+
+fn unix_get(server: &TestServer, target: &str) -> Response {
+    let mut stream = unix_connect(server);
+    // a refused peer may be answered and hung up on before the request is written
+    let _ = write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", target);
+    let mut received = Vec::new();
+    let _ = stream.read_to_end(&mut received);
+    Response::parse(&received)
+}
+
+#[test]
+fn serves_over_the_socket() {
+    let server = start(|_| {});
+    let response = unix_get(&server, "/hello.txt");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.text(), "hello\n");
+}
+
+#[test]
+fn a_loopback_allow_list_doesnt_admit_socket_peers() {
+    let server = start(|settings| settings.allow_ips = vec![String::from("127.0.0.1")]);
+    assert_eq!(unix_get(&server, "/hello.txt").status, 403);
+    assert_eq!(server.get("/hello.txt", "").status, 200);
+}
+
+#[test]
+fn unix_in_the_lists_admits_or_refuses_socket_peers() {
+    let server = start(|settings| settings.allow_ips = vec![String::from("unix")]);
+    assert_eq!(unix_get(&server, "/hello.txt").status, 200);
+    // the list is no longer empty, so tcp peers are refused
+    assert_eq!(server.get("/hello.txt", "").status, 403);
+    drop(server);
+    let server = start(|settings| settings.deny_ips = vec![String::from("unix")]);
+    assert_eq!(unix_get(&server, "/hello.txt").status, 403);
+    assert_eq!(server.get("/hello.txt", "").status, 200);
+}
+
+#[test]
+fn socket_peers_arent_cache_admins_by_default() {
+    let server = start(|_| {});
+    assert_eq!(unix_get(&server, "/__cache").status, 403);
+    assert_eq!(server.get("/__cache", "").status, 200);
+    drop(server);
+    let server = start(|settings| settings.cache_admin_ips.push(String::from("unix")));
+    assert_eq!(unix_get(&server, "/__cache").status, 200);
+}
+
+#[test]
+fn socket_peers_arent_limited_per_ip() {
+    let server = start(|settings| settings.max_connections_per_ip = Some(1));
+    // held open while the others are served
+    let _held = [unix_connect(&server), unix_connect(&server)];
+    std::thread::sleep(Duration::from_millis(50));
+    assert_eq!(unix_get(&server, "/hello.txt").status, 200);
+    // tcp peers still are
+    let _tcp = server.connect();
+    std::thread::sleep(Duration::from_millis(50));
+    assert_eq!(server.get("/hello.txt", "").status, 503);
+}
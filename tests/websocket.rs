@@ -0,0 +1,104 @@
+// websocket routes registered with `App::websocket`
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use common::{Response, TestServer};
+use ferropress::app::ViewContext;
+use ferropress::http::Request;
+use ferropress::websocket::{Message, Socket};
+use ferropress::App;
+
+
+// greets with the path it was reached on, then echoes every message
+async fn echo(request: Request, _ctx: ViewContext, mut socket: Socket) -> std::io::Result<()> {
+    socket.send(Message::Text(request.path)).await?;
+    while let Some(message) = socket.recv().await? {
+        socket.send(message).await?;
+    }
+    Ok(())
+}
+
+fn upgrade(method: &str, path: &str) -> String {
+    format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        method, path,
+    )
+}
+
+// a masked frame, as clients send them
+fn client_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mask = [1, 2, 3, 4];
+    let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    frame
+}
+
+// an unmasked frame with a short payload, as the server sends them: opcode and payload
+fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut head = [0; 2];
+    stream.read_exact(&mut head).unwrap();
+    assert_eq!(head[1] & 0x80, 0, "server frames aren't masked");
+    let mut payload = vec![0; (head[1] & 0x7f) as usize];
+    stream.read_exact(&mut payload).unwrap();
+    (head[0] & 0x0f, payload)
+}
+
+fn read_head(stream: &mut TcpStream) -> String {
+    let mut head = Vec::new();
+    let mut byte = [0; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    String::from_utf8(head).unwrap()
+}
+
+fn server() -> TestServer {
+    // requests that aren't upgraded keep the connection alive; don't wait long for it
+    TestServer::start_app(App::new().websocket("/ws/*", echo), &[], |settings| settings.idle_keepalive_timeout_secs = Some(1))
+}
+
+#[test]
+fn a_registered_path_is_upgraded_and_handed_to_its_handler() {
+    let server = server();
+    let mut stream = server.connect();
+    // a frame sent along with the handshake isn't lost
+    let mut sent = upgrade("GET", "/ws/echo?room=1").into_bytes();
+    sent.extend(client_frame(0x1, b"hello"));
+    stream.write_all(&sent).unwrap();
+
+    let head = read_head(&mut stream);
+    assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+    assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"), "{}", head);
+    assert_eq!(read_frame(&mut stream), (0x1, b"/ws/echo?room=1".to_vec()));
+    assert_eq!(read_frame(&mut stream), (0x1, b"hello".to_vec()));
+    stream.write_all(&client_frame(0x2, &[0, 1, 2])).unwrap();
+    assert_eq!(read_frame(&mut stream), (0x2, vec![0, 1, 2]));
+    // a close is answered with one, and the connection ends
+    stream.write_all(&client_frame(0x8, &[])).unwrap();
+    assert_eq!(read_frame(&mut stream).0, 0x8);
+    assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+}
+
+#[test]
+fn only_get_requests_are_upgraded() {
+    let server = server();
+    for method in ["POST", "PUT"] {
+        let response = Response::parse(&server.exchange(upgrade(method, "/ws/echo").as_bytes()));
+        assert_ne!(response.status, 101, "{}", method);
+    }
+}
+
+#[test]
+fn other_paths_arent_upgraded() {
+    let server = server();
+    let response = Response::parse(&server.exchange(upgrade("GET", "/chat").as_bytes()));
+    assert_eq!(response.status, 404);
+    // nothing is registered by default
+    let server = TestServer::start(&[], |settings| settings.idle_keepalive_timeout_secs = Some(1));
+    let response = Response::parse(&server.exchange(upgrade("GET", "/ws/echo").as_bytes()));
+    assert_eq!(response.status, 404);
+}